@@ -0,0 +1,59 @@
+/* eslint-disable @typescript-eslint/no-unused-vars */
+import { JsonlDB } from "../";
+
+// Compares how long opening and compressing a large DB takes with different I/O buffer sizes
+
+const filename = `test-buffer.txt`;
+const noObjects = 250000;
+const bufferSizes = [8 * 1024, 64 * 1024, 256 * 1024];
+
+function makeObj(i: number) {
+	return {
+		_id: `benchmark.0.test.${i}`,
+		type: i % 10 === 0 ? "state" : "meta",
+		common: {
+			name: i.toString(),
+			read: true,
+			write: true,
+			role: "state",
+			type: "number",
+		},
+		native: {},
+	};
+}
+
+async function prepare() {
+	const db = new JsonlDB(filename);
+	await db.open();
+	db.clear();
+	for (let i = 1; i <= noObjects; i++) {
+		db.set(`benchmark.0.test.${i}`, makeObj(i));
+	}
+	await db.close();
+}
+
+async function measure(ioBufferSizeBytes: number) {
+	const db = new JsonlDB(filename, { ioBufferSizeBytes });
+	const label = `(${ioBufferSizeBytes / 1024} KiB)`;
+
+	console.time(`open ${label}`);
+	await db.open();
+	console.timeEnd(`open ${label}`);
+
+	console.time(`compress ${label}`);
+	await db.compress();
+	console.timeEnd(`compress ${label}`);
+
+	await db.close();
+}
+
+async function run() {
+	await prepare();
+	for (const size of bufferSizes) {
+		await measure(size);
+	}
+}
+
+run().catch((e) => {
+	console.error(e);
+});
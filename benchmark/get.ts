@@ -0,0 +1,42 @@
+/* eslint-disable @typescript-eslint/no-unused-vars */
+import { JsonlDB } from "../";
+
+// Measures how long reading large strings that are stored as primitives takes
+
+const filename = `test-get.txt`;
+const noEntries = 100;
+const valueSize = 100 * 1024;
+const noReads = 100;
+
+async function prepare() {
+	const db = new JsonlDB(filename);
+	await db.open();
+	db.clear();
+	for (let i = 1; i <= noEntries; i++) {
+		db.set(`benchmark.0.blob.${i}`, "x".repeat(valueSize));
+	}
+	await db.close();
+}
+
+async function run() {
+	await prepare();
+
+	const db = new JsonlDB(filename);
+	await db.open();
+
+	for (let i = 1; i <= 3; i++) {
+		console.time(`get ${noReads}x${noEntries} strings #${i}`);
+		for (let j = 0; j < noReads; j++) {
+			for (let k = 1; k <= noEntries; k++) {
+				db.get(`benchmark.0.blob.${k}`);
+			}
+		}
+		console.timeEnd(`get ${noReads}x${noEntries} strings #${i}`);
+	}
+
+	await db.close();
+}
+
+run().catch((e) => {
+	console.error(e);
+});
@@ -0,0 +1,60 @@
+/* eslint-disable @typescript-eslint/no-unused-vars */
+import { JsonlDB } from "../";
+
+// Measures how long range queries over many primitive and object values take
+
+const filename = `test-getMany.txt`;
+const noEntries = 20000;
+
+async function prepare() {
+	const db = new JsonlDB(filename);
+	await db.open();
+	db.clear();
+	for (let i = 1; i <= noEntries; i++) {
+		db.set(`benchmark.0.number.${i}`, i);
+		db.set(`benchmark.0.object.${i}`, { value: i });
+	}
+	await db.close();
+}
+
+async function run() {
+	await prepare();
+
+	const db = new JsonlDB(filename);
+	await db.open();
+
+	for (const kind of ["number", "object"]) {
+		for (let i = 1; i <= 3; i++) {
+			console.time(`getMany ${kind} #${i}`);
+			db.getMany(`benchmark.0.${kind}.`, `benchmark.0.${kind}.\uffff`);
+			console.timeEnd(`getMany ${kind} #${i}`);
+		}
+	}
+
+	// Compare with transferring the results as one string. Release the objects
+	// first, so getMany has to convert them again like on the first call.
+	for (const kind of ["number", "object"]) {
+		for (let i = 1; i <= 3; i++) {
+			db.dematerializeAll();
+			console.time(`getMany (cold) ${kind} #${i}`);
+			db.getMany(`benchmark.0.${kind}.`, `benchmark.0.${kind}.\uffff`);
+			console.timeEnd(`getMany (cold) ${kind} #${i}`);
+
+			db.dematerializeAll();
+			console.time(`getManyStringified + JSON.parse ${kind} #${i}`);
+			JSON.parse(
+				db.getManyStringified(
+					`benchmark.0.${kind}.`,
+					`benchmark.0.${kind}.\uffff`,
+				),
+			);
+			console.timeEnd(`getManyStringified + JSON.parse ${kind} #${i}`);
+		}
+	}
+
+	await db.close();
+}
+
+run().catch((e) => {
+	console.error(e);
+});
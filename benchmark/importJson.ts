@@ -0,0 +1,50 @@
+/* eslint-disable @typescript-eslint/no-unused-vars */
+import fs from "fs-extra";
+import { JsonlDB } from "../";
+
+// Measures how long importing a large JSON file into an indexed DB takes,
+// with the index updated in batches and rebuilt once after the import
+
+const filename = `test-importJson.txt`;
+const jsonFilename = `test-importJson.json`;
+const noObjects = 500000;
+
+async function prepare() {
+	const json: Record<string, any> = {};
+	for (let i = 1; i <= noObjects; i++) {
+		json[`benchmark.0.test.${i}`] = {
+			type: i % 10 === 0 ? "state" : "meta",
+			common: { name: i.toString(), role: `role${i % 100}` },
+		};
+	}
+	await fs.writeFile(jsonFilename, JSON.stringify(json));
+}
+
+async function measure(rebuildIndexAfterImport: boolean) {
+	await fs.remove(filename);
+	const db = new JsonlDB(filename, {
+		indexPaths: ["/type", "/common/role"],
+		rebuildIndexAfterImport,
+	});
+	await db.open();
+	const label = rebuildIndexAfterImport ? "(rebuild index)" : "(batched index)";
+
+	console.time(`importJson ${label}`);
+	await db.importJson(jsonFilename);
+	console.timeEnd(`importJson ${label}`);
+
+	await db.close();
+}
+
+async function run() {
+	await prepare();
+	for (let i = 1; i <= 3; i++) {
+		await measure(false);
+		await measure(true);
+	}
+	await fs.remove(jsonFilename);
+}
+
+run().catch((e) => {
+	console.error(e);
+});
@@ -0,0 +1,65 @@
+/* eslint-disable @typescript-eslint/no-unused-vars */
+import { JsonlDB } from "../";
+
+// Measures how long it takes to open a large DB and read all values for the first time
+
+const filename = `test-open.txt`;
+const noObjects = 100000;
+
+function makeObj(i: number) {
+	return {
+		_id: `benchmark.0.test.${i}`,
+		type: i % 10 === 0 ? "state" : "meta",
+		common: {
+			name: i.toString(),
+			read: true,
+			write: true,
+			role: "state",
+			type: "number",
+		},
+		native: {},
+	};
+}
+
+async function prepare() {
+	const db = new JsonlDB(filename);
+	await db.open();
+	db.clear();
+	for (let i = 1; i <= noObjects; i++) {
+		db.set(`benchmark.0.test.${i}`, makeObj(i));
+	}
+	await db.close();
+}
+
+async function measure(indexPaths: string[]) {
+	const db = new JsonlDB(filename, { indexPaths });
+	const label = indexPaths.length ? "(indexed)" : "(not indexed)";
+
+	console.time(`open ${label}`);
+	await db.open();
+	console.timeEnd(`open ${label}`);
+
+	console.time(`first read ${label}`);
+	for (const key of db.keys()) {
+		db.get(key);
+	}
+	console.timeEnd(`first read ${label}`);
+
+	console.time(`second read ${label}`);
+	for (const key of db.keys()) {
+		db.get(key);
+	}
+	console.timeEnd(`second read ${label}`);
+
+	await db.close();
+}
+
+async function run() {
+	await prepare();
+	await measure([]);
+	await measure(["/type"]);
+}
+
+run().catch((e) => {
+	console.error(e);
+});
@@ -1,5 +1,312 @@
-import { JsonlDB as JsonlDBNative, JsonlDBOptions } from "./lib";
+import {
+	JsonlDB as JsonlDBNative,
+	JsonlDBCompressEstimate,
+	JsonlDBCsvExportOptions,
+	JsonlDBCsvImportOptions,
+	JsonlDBDumpResult,
+	JsonlDBEntryCursorOptions,
+	JsonlDBEntryMeta,
+	JsonlDBJsonExportResult,
+	JsonlDBJsonImportResult,
+	JsonlDBLockStatus,
+	JsonlDBMaterializeProgress,
+	JsonlDBMemoryUsage,
+	JsonlDBMetrics,
+	JsonlDBOpenDiagnostics,
+	JsonlDBOptions as JsonlDBNativeOptions,
+	JsonlDBOptionsAutoCompress,
+	JsonlDBOptionsDedicatedRuntime,
+	JsonlDBQueryExplain,
+	JsonlDBSelfCheckReport,
+	JsonlDBSnapshotResult,
+	JsonlDBStats,
+	JsonlDBStringifiedEntry,
+} from "./lib";
+
+export interface FlushInfo {
+	/** How many lines were written */
+	entries: number;
+	/** How many bytes were written */
+	bytes: number;
+	/** How long writing took */
+	durationMs: number;
+}
+
+export interface JsonlDBEvents {
+	compressStart: Record<string, never>;
+	compressDone: FlushInfo & {
+		/**
+		 * Why the DB was compressed: `"manual"`, `"onOpen"`, `"onClose"`, `"import"` or
+		 * the automatic trigger, e.g. `"size"` or `"interval"`
+		 */
+		trigger: string;
+	};
+	dumpDone: {
+		/** The file the dump was written to */
+		filename: string;
+		bytes: number;
+		durationMs: number;
+	};
+	/**
+	 * Errors in background work. If the `source` is `"persistence"`, nothing
+	 * is written to disk anymore until the DB is reopened, or if `paused` is set,
+	 * until `retryPersistence()` succeeds.
+	 */
+	error: {
+		message: string;
+		/** What failed, e.g. `"autoDump"` or `"persistence"` */
+		source: string;
+		/** Whether the DB file disappeared and the changes are kept in memory until `retryPersistence()` is called */
+		paused?: boolean;
+		/**
+		 * For the source `"consistencyCheck"`: the key whose line does not match the entry in memory.
+		 * For the source `"queuedWrite"`: the key of the write, unless it was a `clear()`
+		 */
+		key?: string;
+	};
+	flush: FlushInfo;
+	/** Reports the progress of the `preMaterialize` option after each batch */
+	materializeProgress: {
+		/** How many entries were converted to JS objects so far */
+		converted: number;
+		/** How many entries were looked at so far */
+		scanned: number;
+		/** How many entries there are */
+		total: number;
+		/** Whether all entries were looked at */
+		done: boolean;
+	};
+	/**
+	 * Another process took over the lockfile and it could not be re-acquired.
+	 * From now on, the DB is read-only and nothing is written to the DB file.
+	 */
+	lockLost: {
+		message: string;
+	};
+	/** Hints at possible mistakes, e.g. an index path that does not match any entry */
+	warning: {
+		message: string;
+		/** What the warning is about, e.g. `"index"` */
+		source: string;
+	};
+	recovered: {
+		/** The DB file that was restored */
+		filename: string;
+		/** The backup or dump file it was restored from */
+		from: string;
+	};
+}
+
+/** How keys are compared to the bounds of range queries, see `getMany` */
+export type JsonlDBCollation = "binary" | "unicode";
+
+export interface JsonlDBImportOptions {
+	/**
+	 * Rewrite the DB file with all entries like `compress()` does instead of appending each imported entry.
+	 * This is done automatically when more than 10000 entries are imported right after `clear()`.
+	 */
+	viaCompress?: boolean;
+	/**
+	 * Delete the entries whose imported value is `null` instead of storing `null` (default: false).
+	 * Use this to import JSON that was exported with `omitNullValues` on top of existing entries.
+	 */
+	nullMeansDelete?: boolean;
+}
+
+export interface JsonlDBExportOptions {
+	/** Indent the JSON to make it readable */
+	pretty?: boolean;
+	/** Compress the output. This is also done if the filename ends with `.gz` */
+	gzip?: boolean;
+	/** Leave out the entries whose value is `null` (default: false) */
+	omitNullValues?: boolean;
+}
+
+/** Matches the entries whose value at an indexed `path` equals `value` */
+export interface JsonlDBObjectFilter {
+	/** A JSON pointer like `"/type"`, which should be one of the `indexPaths` */
+	path: string;
+	value: string | number | boolean;
+}
+
+export type JsonlDBEventListener<E extends keyof JsonlDBEvents> = (
+	payload: JsonlDBEvents[E],
+) => void;
+
+export interface JsonlDBOptions
+	extends Omit<
+		JsonlDBNativeOptions,
+		"autoCompress" | "dedicatedRuntime" | "keyOrdering"
+	> {
+	autoCompress?: Omit<
+		JsonlDBOptionsAutoCompress,
+		"intervalAnchor" | "onOpenIfWastedBytesExceed"
+	> & {
+		/**
+		 * What `intervalMs` is measured from:
+		 * - `"lastCompress"` (default): compress every `intervalMs` as long as there were `intervalMinChanges` changes
+		 * - `"lastWrite"`: compress once nothing was written for `intervalMs`, so an idle DB is never rewritten
+		 */
+		intervalAnchor?: "lastCompress" | "lastWrite";
+		/**
+		 * Compress after opening, but only if that would free up more than this many bytes.
+		 * Can be combined with `onOpen`, which compresses regardless of the size.
+		 */
+		onOpenIfWastedBytesExceed?: number;
+	};
+	/** Run the background work on a dedicated runtime instead of the shared one */
+	dedicatedRuntime?: boolean | JsonlDBOptionsDedicatedRuntime;
+	/**
+	 * The permissions (e.g. `0o600`) for the files created by the DB, including dumps and exports.
+	 * Directories for the lockfile additionally get the execute bits. Ignored on Windows.
+	 */
+	fileMode?: number;
+	/**
+	 * The size of the buffers used to read and write the DB file (default: 8 KiB, minimum: 4 KiB).
+	 * Larger buffers (e.g. 256 KiB) can speed up opening and compressing large DBs, especially on
+	 * network storage, at the cost of keeping that much memory allocated while the DB is open.
+	 */
+	ioBufferSizeBytes?: number;
+	/**
+	 * Lines of the DB file that are longer than this are treated as invalid without reading them
+	 * into memory (default: 64 MiB). With `ignoreReadErrors`, they are skipped and reported by
+	 * `getOpenDiagnostics()`.
+	 */
+	maxLineLengthBytes?: number;
+	/**
+	 * Files are refused if they contain binary data or more than `maxInvalidLineRatio` of their
+	 * first 100 lines are invalid, even if `ignoreReadErrors` is set. Set this to `true` to open them anyways.
+	 */
+	forceOpen?: boolean;
+	/** Which ratio of invalid lines makes a file look like it is no DB file (default: 0.9) */
+	maxInvalidLineRatio?: number;
+	/**
+	 * How keys are compared in range queries like `getMany`:
+	 * - `"lexicographic"` (default): by their UTF-8 bytes, so `"10"` comes before `"2"`
+	 * - `"numeric"`: keys that are finite numbers come first and are compared by value,
+	 *   all other keys follow and are compared lexicographically
+	 * - `"natural"`: runs of digits are compared by value and everything in between lexicographically,
+	 *   so `"node2"` comes before `"node10"`
+	 *
+	 * Keys that are equal according to the chosen ordering (e.g. `"1"` and `"01"`) are compared lexicographically.
+	 */
+	keyOrdering?: "lexicographic" | "numeric" | "natural";
+	/**
+	 * Store when each entry was written, which can be queried with `getMeta`.
+	 * This adds a `"t"` field to each line of the DB file.
+	 */
+	trackTimestamps?: boolean;
+	/**
+	 * Keep deleted entries as tombstones instead of removing them, so they can be queried with `getDeletedKeys`.
+	 * Tombstones that are older than `retainMs` are removed for good when the DB is compressed.
+	 */
+	softDelete?: {
+		retainMs: number;
+	};
+	/**
+	 * Queue `set`, `setDetached`, `setNativeObject`, `delete` and `clear` calls that are made while `open()` is still pending
+	 * instead of throwing. They are applied in order before `open()` resolves. A queued write that fails then
+	 * does not fail `open()`, but is reported with an `error` event whose `source` is `"queuedWrite"`.
+	 * At most 10000 writes can be queued. Reads still require the DB to be open.
+	 */
+	queueWritesBeforeOpen?: boolean;
+	/**
+	 * How often to try re-acquiring the lockfile when another process took it over (default: 3).
+	 * If that fails, the `lockLost` event is emitted and the DB becomes read-only.
+	 */
+	lockLostRetries?: number;
+	/**
+	 * `set()` keeps a reference to the given object, but writes the value it had at the time of the call.
+	 * Later changes to the object are visible through `get()`, but are not written to disk unless the entry
+	 * is set again or `refreshSnapshot()` is called.
+	 *
+	 * If this is `true`, objects are serialized again right before they are written, so changes made
+	 * in the meantime are persisted too. Indexes are not updated by this.
+	 */
+	resnapshotOnWrite?: boolean;
+	/**
+	 * Where compressing writes the compressed copy of the DB file before it replaces the original,
+	 * relative to the DB file. This needs as much space as the DB file. By default, it is written next to the DB file.
+	 * If the directory is on another device, the copy is copied over instead of renamed.
+	 */
+	scratchDirectory?: string;
+	/**
+	 * What is appended to the DB filename for the dump that compressing writes and that replaces the
+	 * DB file (default: `".dump"`). Opening the DB also recovers leftovers with the default suffix.
+	 */
+	compressDumpSuffix?: string;
+	/**
+	 * What is appended to the DB filename for the backup that compressing keeps until the dump has
+	 * replaced the DB file (default: `".bak"`). Opening the DB also recovers leftovers with the default suffix.
+	 */
+	compressBackupSuffix?: string;
+	/**
+	 * How `importJson` and `importCsv` update the index for `indexPaths`. By default, the imported entries
+	 * are indexed in chunks. If this is `true`, the whole index is rebuilt once the import is done instead,
+	 * which also reindexes the entries that were already in the DB. Importing into an empty DB takes
+	 * about equally long both ways (see `benchmark/importJson.ts`).
+	 */
+	rebuildIndexAfterImport?: boolean;
+	/**
+	 * After opening, convert the values of the entries to JS objects in small batches while the
+	 * event loop is idle, so the first `get()` of each entry is cheap. Progress is reported with the
+	 * `materializeProgress` event. This stops when the DB is closed or `cancelPreMaterialize()` is called.
+	 */
+	preMaterialize?: {
+		/** Only convert the entries whose key starts with this prefix */
+		keysPrefix?: string;
+		/** How long each batch may block the event loop */
+		budgetMsPerTick: number;
+	};
+	/**
+	 * Split the DB into this many files, `<filename>.0.jsonl` to `<filename>.<shards-1>.jsonl`, instead of
+	 * storing it in a single file (default: 1). Each key is stored in the shard its hash points to.
+	 * The shards are read concurrently when opening, and compressing only rewrites the shards that
+	 * contain redundant lines. `open()` refuses files that were written with another number of shards,
+	 * use `reshard()` to convert existing files to another number of shards.
+	 */
+	shards?: number;
+	/**
+	 * Development aid: Parse each line again before it is written and compare it with the entry in memory.
+	 * Mismatches are reported with an `error` event with the source `"consistencyCheck"`.
+	 * This slows down writing noticeably and should not be enabled in production.
+	 */
+	debugConsistencyChecks?: boolean;
+	/**
+	 * Measure how long the main thread, the persistence thread and compressing wait for each other
+	 * to access the entries. The results are part of `getMetrics()`. This adds a little overhead to
+	 * every access, so it is meant for diagnosing performance problems.
+	 */
+	measureLockContention?: boolean;
+	/**
+	 * Key prefixes whose number of entries is counted from the start, as if they were registered with
+	 * `registerPrefixCounter()` right after opening.
+	 */
+	prefixCounters?: string[];
+	/**
+	 * How deeply arrays and objects may be nested in a value (default and maximum: 128). Values that are nested deeper
+	 * are refused when they are set or imported. Opening a DB file that contains such values fails,
+	 * unless `ignoreReadErrors` is set, which skips them.
+	 */
+	maxValueDepth?: number;
+	/**
+	 * How numbers that JSON cannot represent (`NaN`, `Infinity` and `-Infinity`) are handled when they are set,
+	 * either directly or inside an object:
+	 * - `"error"` (default): the value is refused with an error
+	 * - `"null"`: they are stored as `null`
+	 * - `"string"`: they are stored as the strings `"NaN"`, `"Infinity"` and `"-Infinity"`
+	 *
+	 * Objects that are stored by reference keep the original numbers in memory until the DB is reopened.
+	 */
+	nonFiniteNumbers?: "error" | "null" | "string";
+	/**
+	 * Whether setting a key to `undefined` deletes it (default: `false`). Without this option, `undefined`
+	 * values are refused with an error, so they are never silently stored as `null`.
+	 */
+	undefinedIsDelete?: boolean;
+}
 import path from "path";
+import { Readable } from "stream";
 
 function wrapNativeErrorSync<T extends (...args: any[]) => any>(
 	executor: T,
@@ -21,6 +328,84 @@ async function wrapNativeErrorAsync<T extends (...args: any[]) => Promise<any>>(
 	}
 }
 
+// How many keys are transferred from the native DB at once
+const KEYS_PAGE_SIZE = 10000;
+// How many writes can be queued while the DB is being opened
+const MAX_QUEUED_WRITES_BEFORE_OPEN = 10000;
+// The key of the line that holds the DB header, which is not an entry
+const HEADER_KEY = "$jsonl-db-meta";
+// From which size getAsync() parses values instead of converting them
+const GET_ASYNC_MIN_BYTES = 64 * 1024;
+
+// Matches UTF-16 surrogates that are not part of a pair
+const loneSurrogateRegex =
+	/[\uD800-\uDBFF](?![\uDC00-\uDFFF])|(?<![\uD800-\uDBFF])[\uDC00-\uDFFF]/;
+
+/** Calls the listeners that are registered for an event */
+function emitTo<E extends keyof JsonlDBEvents>(
+	listeners: Map<keyof JsonlDBEvents, Set<JsonlDBEventListener<any>>>,
+	event: E,
+	payload: JsonlDBEvents[E],
+): void {
+	const forEvent = listeners.get(event);
+	if (!forEvent) return;
+	for (const listener of forEvent) {
+		listener(payload);
+	}
+}
+
+/**
+ * Keys with lone surrogates cannot be represented in UTF-8, so they would be stored in a mangled form.
+ * Refuse them instead.
+ */
+function assertValidKey(key: string): void {
+	if (key === HEADER_KEY) {
+		throw new Error(
+			`Invalid key ${JSON.stringify(
+				key,
+			)}: it is reserved for the DB header`,
+		);
+	}
+	const match = loneSurrogateRegex.exec(key);
+	if (match) {
+		throw new Error(
+			`Invalid key ${JSON.stringify(
+				key,
+			)}: it contains a lone surrogate at index ${match.index}`,
+		);
+	}
+}
+
+/** Whether the value is or contains a number that JSON cannot represent */
+function hasNonFiniteNumber(value: unknown): boolean {
+	// Not recursive, so values that are nested too deeply are refused by the native DB
+	// instead of overflowing the stack here. Each object is only looked at once, so circular
+	// objects end up there too instead of being walked forever.
+	const stack = [value];
+	const seen = new Set<object>();
+	while (stack.length) {
+		const v = stack.pop();
+		if (typeof v === "number" && !Number.isFinite(v)) return true;
+		if (typeof v === "object" && v !== null && !seen.has(v)) {
+			seen.add(v);
+			for (const item of Object.values(v)) stack.push(item);
+		}
+	}
+	return false;
+}
+
+function assertSupportedValue(value: unknown): void {
+	switch (typeof value) {
+		case "number":
+		case "boolean":
+		case "string":
+		case "object":
+			return;
+		default:
+			throw new Error("unsupported value type");
+	}
+}
+
 export class JsonlDB<V = any> implements Map<string, V> {
 	private readonly db: JsonlDBNative;
 	private readonly options: JsonlDBOptions;
@@ -31,7 +416,13 @@ export class JsonlDB<V = any> implements Map<string, V> {
 			filename = path.resolve(filename);
 		}
 		this.options = options;
-		this.db = new JsonlDBNative(filename, options);
+		this.db = new JsonlDBNative(filename, {
+			...options,
+			dedicatedRuntime:
+				options.dedicatedRuntime === true
+					? {}
+					: options.dedicatedRuntime || undefined,
+		});
 	}
 
 	private validateOptions(options: JsonlDBOptions /*<V>*/): void {
@@ -41,6 +432,10 @@ export class JsonlDB<V = any> implements Map<string, V> {
 				sizeFactorMinimumSize,
 				intervalMs,
 				intervalMinChanges,
+				intervalAnchor,
+				onOpenIfWastedBytesExceed,
+				maxOverwriteRatio,
+				maxTombstones,
 			} = options.autoCompress;
 			if (sizeFactor != undefined && sizeFactor <= 1) {
 				throw new Error("sizeFactor must be > 1");
@@ -57,25 +452,278 @@ export class JsonlDB<V = any> implements Map<string, V> {
 			if (intervalMinChanges != undefined && intervalMinChanges < 1) {
 				throw new Error("intervalMinChanges must be >= 1");
 			}
+			if (
+				intervalAnchor != undefined &&
+				intervalAnchor !== "lastCompress" &&
+				intervalAnchor !== "lastWrite"
+			) {
+				throw new Error(
+					'intervalAnchor must be "lastCompress" or "lastWrite"',
+				);
+			}
+			if (
+				maxOverwriteRatio != undefined &&
+				(maxOverwriteRatio <= 0 || maxOverwriteRatio >= 1)
+			) {
+				throw new Error("maxOverwriteRatio must be > 0 and < 1");
+			}
+			if (maxTombstones != undefined && maxTombstones < 1) {
+				throw new Error("maxTombstones must be >= 1");
+			}
+			if (
+				onOpenIfWastedBytesExceed != undefined &&
+				onOpenIfWastedBytesExceed < 0
+			) {
+				throw new Error("onOpenIfWastedBytesExceed must be >= 0");
+			}
+		}
+		if (typeof options.dedicatedRuntime === "object") {
+			const { workerThreads } = options.dedicatedRuntime;
+			if (workerThreads != undefined && workerThreads < 1) {
+				throw new Error("workerThreads must be >= 1");
+			}
+		}
+		if (
+			options.fileMode != undefined &&
+			(!Number.isInteger(options.fileMode) ||
+				options.fileMode < 0 ||
+				options.fileMode > 0o777)
+		) {
+			throw new Error("fileMode must be an integer between 0o000 and 0o777");
+		}
+		if (
+			options.ioBufferSizeBytes != undefined &&
+			options.ioBufferSizeBytes < 4096
+		) {
+			throw new Error("ioBufferSizeBytes must be >= 4096");
+		}
+		if (
+			options.maxLineLengthBytes != undefined &&
+			(!Number.isInteger(options.maxLineLengthBytes) ||
+				options.maxLineLengthBytes < 1)
+		) {
+			throw new Error("maxLineLengthBytes must be a positive integer");
+		}
+		if (
+			options.maxInvalidLineRatio != undefined &&
+			(options.maxInvalidLineRatio < 0 || options.maxInvalidLineRatio > 1)
+		) {
+			throw new Error("maxInvalidLineRatio must be >= 0 and <= 1");
+		}
+		if (
+			options.lockLostRetries != undefined &&
+			(!Number.isInteger(options.lockLostRetries) ||
+				options.lockLostRetries < 0)
+		) {
+			throw new Error("lockLostRetries must be an integer >= 0");
+		}
+		if (
+			options.shards != undefined &&
+			(!Number.isInteger(options.shards) || options.shards < 1)
+		) {
+			throw new Error("shards must be an integer >= 1");
+		}
+		for (const option of [
+			"compressDumpSuffix",
+			"compressBackupSuffix",
+		] as const) {
+			const suffix = options[option];
+			if (suffix != undefined && (!suffix || /[\\/]/.test(suffix))) {
+				throw new Error(
+					`${option} must be a non-empty string without path separators`,
+				);
+			}
+		}
+		if (
+			(options.compressDumpSuffix ?? ".dump") ===
+			(options.compressBackupSuffix ?? ".bak")
+		) {
+			throw new Error(
+				"compressDumpSuffix and compressBackupSuffix must be different",
+			);
+		}
+		if (
+			options.maxValueDepth != undefined &&
+			(!Number.isInteger(options.maxValueDepth) ||
+				options.maxValueDepth < 1 ||
+				options.maxValueDepth > 128)
+		) {
+			throw new Error("maxValueDepth must be an integer between 1 and 128");
+		}
+		if (
+			options.nonFiniteNumbers != undefined &&
+			!["error", "null", "string"].includes(options.nonFiniteNumbers)
+		) {
+			throw new Error(
+				'nonFiniteNumbers must be "error", "null" or "string"',
+			);
+		}
+		if (options.softDelete && options.softDelete.retainMs < 0) {
+			throw new Error("retainMs must be >= 0");
+		}
+		if (options.autoDump) {
+			const { intervalMs, filenameTemplate, keep } = options.autoDump;
+			if (intervalMs < 10) {
+				throw new Error("intervalMs must be >= 10");
+			}
+			if (!path.basename(filenameTemplate).includes("{timestamp}")) {
+				throw new Error(
+					"The filename in filenameTemplate must contain {timestamp}",
+				);
+			}
+			if (!Number.isInteger(keep) || keep < 1) {
+				throw new Error("keep must be an integer >= 1");
+			}
+		}
+		if (
+			options.preMaterialize &&
+			!(options.preMaterialize.budgetMsPerTick >= 1)
+		) {
+			throw new Error("budgetMsPerTick must be >= 1");
 		}
 		if (options.throttleFS) {
-			const { intervalMs, maxBufferedCommands } = options.throttleFS;
+			const {
+				intervalMs,
+				maxBufferedCommands,
+				coalesceWindowMs,
+				fsyncIdleMs,
+				fsyncMaxIntervalMs,
+			} = options.throttleFS;
 			if (intervalMs < 0) {
 				throw new Error("intervalMs must be >= 0");
 			}
 			if (maxBufferedCommands != undefined && maxBufferedCommands < 0) {
 				throw new Error("maxBufferedCommands must be >= 0");
 			}
+			if (coalesceWindowMs != undefined && coalesceWindowMs < 0) {
+				throw new Error("coalesceWindowMs must be >= 0");
+			}
+			if (fsyncIdleMs != undefined && fsyncIdleMs < 0) {
+				throw new Error("fsyncIdleMs must be >= 0");
+			}
+			if (fsyncMaxIntervalMs != undefined && fsyncMaxIntervalMs < 0) {
+				throw new Error("fsyncMaxIntervalMs must be >= 0");
+			}
 		}
 	}
 
 	public async open(): Promise<void> {
+		return this.openInternal(() => this.db.open());
+	}
+
+	/**
+	 * Moves the entries of the closed DB from the number of shards its files were written with into the
+	 * given one, e.g. `1` to convert a sharded DB back into a single file. Afterwards, `open()` uses the new layout.
+	 */
+	public async reshard(shards: number): Promise<void> {
+		if (!Number.isInteger(shards) || shards < 1) {
+			throw new Error("shards must be an integer >= 1");
+		}
+		return wrapNativeErrorAsync(() => this.db.reshard(shards));
+	}
+
+	/**
+	 * Opens the DB and passes each entry to the `migrate` function once, e.g. to rename a field after a schema change.
+	 * It receives the key and the value serialized as JSON, and returns the new value serialized as JSON, or `null`
+	 * to delete the entry. Returning the given string unchanged leaves the entry as it is.
+	 *
+	 * The `version` is stored in the DB file next to the DB header after migrating. It is not an entry.
+	 * If the DB already has this or a higher version, nothing is migrated. Resolves to whether the migration ran.
+	 *
+	 * The migration runs before the DB is opened, so writes can't be made in the meantime, unless they are queued
+	 * with `queueWritesBeforeOpen`. The entries are passed in batches, so other tasks can run in between.
+	 * The changes are only applied once all entries were migrated, so if `migrate` throws, the DB is not opened
+	 * and nothing is changed.
+	 */
+	public async openWithMigration(
+		migrate: (key: string, valueJsonString: string) => string | null,
+		options: { version: number },
+	): Promise<boolean> {
+		const { version } = options;
+		if (!Number.isInteger(version) || version < 1 || version > 0xffffffff) {
+			throw new Error("version must be a positive integer");
+		}
+
+		wrapNativeErrorSync(() =>
+			this.db.setMigration((keys, valueJsonStrings, done) => {
+				const changedKeys: string[] = [];
+				const newValueJsonStrings: (string | null)[] = [];
+				try {
+					for (let i = 0; i < keys.length; i++) {
+						const result = migrate(keys[i], valueJsonStrings[i]);
+						if (result === valueJsonStrings[i]) continue;
+						changedKeys.push(keys[i]);
+						newValueJsonStrings.push(result ?? null);
+					}
+				} catch (e: any) {
+					done(String(e?.message ?? e));
+					return;
+				}
+				done(null, changedKeys, newValueJsonStrings);
+			}, version),
+		);
+		return this.openInternal(() => this.db.openWithMigration());
+	}
+
+	/** Opens the native DB with `openNative` and applies the writes that were queued while opening */
+	private async openInternal<T>(openNative: () => Promise<T>): Promise<T> {
 		this._keysCache = undefined;
-		await wrapNativeErrorAsync(() => this.db.open());
+		// The event callback is released when closing, so it needs to be registered again.
+		// This has to happen before opening to receive the "recovered" event.
+		if (this.listeners.size > 0) this.registerEventCallback();
+		if (this.options.queueWritesBeforeOpen && !this.isOpen) {
+			this.preOpenQueue = [];
+		}
+		let ret: T;
+		try {
+			ret = await wrapNativeErrorAsync(openNative);
+		} catch (e) {
+			this.preOpenQueue = undefined;
+			// Don't keep the process alive
+			this.db.clearEventCallback();
+			throw e;
+		}
+
+		// Apply the writes that happened while opening
+		const queue = this.preOpenQueue;
+		this.preOpenQueue = undefined;
+		if (queue) {
+			for (const { key, write } of queue) {
+				try {
+					write();
+				} catch (e) {
+					// The DB is open at this point, so one refused write must not fail open()
+					this.emit("error", {
+						message: (e as Error).message,
+						source: "queuedWrite",
+						key,
+					});
+				}
+			}
+		}
+
+		if (this.options.preMaterialize) this.startPreMaterialize();
+		return ret;
+	}
+
+	// Writes that happened while the DB was being opened. Only defined while opening.
+	private preOpenQueue: { key?: string; write: () => void }[] | undefined;
+
+	/** Queues the given write to `key` if the DB is being opened. Returns whether it was queued. */
+	private queueWrite(key: string | undefined, write: () => void): boolean {
+		if (!this.preOpenQueue) return false;
+		if (this.preOpenQueue.length >= MAX_QUEUED_WRITES_BEFORE_OPEN) {
+			throw new Error(
+				`Cannot queue more than ${MAX_QUEUED_WRITES_BEFORE_OPEN} writes while the DB is being opened`,
+			);
+		}
+		this.preOpenQueue.push({ key, write });
+		return true;
 	}
 
 	public async close(): Promise<void> {
 		if (!this.isOpen) return;
+		this.cancelPreMaterialize();
 
 		await wrapNativeErrorAsync(async () => {
 			await this.db.halfClose();
@@ -83,30 +731,274 @@ export class JsonlDB<V = any> implements Map<string, V> {
 		});
 	}
 
+	/**
+	 * Registers a callback that is invoked after data was written to disk. Only one callback can be registered at a time.
+	 */
+	public onFlush(callback: (info: FlushInfo) => void): void {
+		wrapNativeErrorSync(() => this.db.onFlush(callback));
+	}
+
+	/** Removes the callback registered with `onFlush` */
+	public offFlush(): void {
+		wrapNativeErrorSync(() => this.db.offFlush());
+	}
+
+	private listeners = new Map<
+		keyof JsonlDBEvents,
+		Set<JsonlDBEventListener<any>>
+	>();
+
+	private registerEventCallback(): void {
+		// The native DB holds on to the callback until it is closed for good. Capturing `this` would
+		// keep a half-closed DB from being garbage-collected.
+		const listeners = this.listeners;
+		this.db.setEventCallback((event, payload) => {
+			emitTo(listeners, event as keyof JsonlDBEvents, payload);
+		});
+	}
+
+	private emit<E extends keyof JsonlDBEvents>(
+		event: E,
+		payload: JsonlDBEvents[E],
+	): void {
+		emitTo(this.listeners, event, payload);
+	}
+
+	/**
+	 * Registers a listener for a lifecycle event. Listeners can be added before opening the DB
+	 * and are kept when it is closed and opened again.
+	 */
+	public on<E extends keyof JsonlDBEvents>(
+		event: E,
+		listener: JsonlDBEventListener<E>,
+	): this {
+		let listeners = this.listeners.get(event);
+		if (!listeners) {
+			listeners = new Set();
+			this.listeners.set(event, listeners);
+		}
+		listeners.add(listener);
+		// While the DB is closed, the callback is registered when opening it
+		if (this.isOpen && this.listeners.size === 1 && listeners.size === 1) {
+			this.registerEventCallback();
+		}
+		return this;
+	}
+
+	/** Removes a listener that was registered with `on` */
+	public off<E extends keyof JsonlDBEvents>(
+		event: E,
+		listener: JsonlDBEventListener<E>,
+	): this {
+		const listeners = this.listeners.get(event);
+		if (listeners?.delete(listener) && listeners.size === 0) {
+			this.listeners.delete(event);
+		}
+		if (this.listeners.size === 0) {
+			this.db.clearEventCallback();
+		}
+		return this;
+	}
+
+	/**
+	 * Checks whether the DB file is locked by an open DB, e.g. in another process,
+	 * without opening it or acquiring the lock. Only `lockfileDirectory` is used from the options.
+	 */
+	public static isLocked(
+		filename: string,
+		options?: Pick<JsonlDBOptions, "lockfileDirectory">,
+	): JsonlDBLockStatus {
+		return wrapNativeErrorSync(() =>
+			JsonlDBNative.isLocked(filename, {
+				lockfileDirectory: options?.lockfileDirectory,
+			}),
+		);
+	}
+
+	/**
+	 * Parses a single line of a DB file the same way opening the DB does, e.g. for tools that inspect DB files.
+	 * Lines that delete an entry have no `value`, soft-delete tombstones also have `deletedAt`.
+	 * The header line is not an entry. It has `isHeader` set, the application-defined header as `value`
+	 * (if there is one) and the `migrationVersion` (if a migration was applied).
+	 * Returns `undefined` for empty lines, which opening the DB skips. Throws if the line is malformed.
+	 */
+	public static parseLine(line: string):
+		| {
+				key: string;
+				value?: unknown;
+				isDelete: boolean;
+				isHeader: boolean;
+				deletedAt?: number;
+				migrationVersion?: number;
+		  }
+		| undefined {
+		const parsed = wrapNativeErrorSync(() => JsonlDBNative.parseLine(line));
+		if (!parsed) return undefined;
+		const {
+			key,
+			valueJsonString,
+			isDelete,
+			isHeader,
+			deletedAt,
+			migrationVersion,
+		} = parsed;
+		return {
+			key,
+			...(valueJsonString != undefined
+				? { value: JSON.parse(valueJsonString) }
+				: {}),
+			isDelete,
+			isHeader,
+			...(deletedAt != undefined ? { deletedAt } : {}),
+			...(migrationVersion != undefined ? { migrationVersion } : {}),
+		};
+	}
+
+	/**
+	 * Renders the line (without line break) that sets the key to the given value serialized as JSON,
+	 * the same way writing the DB does. Throws if the value is not valid JSON.
+	 */
+	public static formatLine(key: string, valueJson: string): string {
+		return wrapNativeErrorSync(() =>
+			JsonlDBNative.formatLine(key, valueJson),
+		);
+	}
+
 	public get isOpen(): boolean {
 		return this.db.isOpen();
 	}
 
-	public dump(filename: string): Promise<void> {
-		return wrapNativeErrorAsync(() => this.db.dump(filename));
+	/**
+	 * The state of the native DB: `"closed"`, `"open"`, or `"half-closed"` while `close()` is in progress.
+	 * Methods that are called in the wrong state throw an error that says how to get to the right one.
+	 */
+	public getState(): "closed" | "half-closed" | "open" {
+		return this.db.getState();
+	}
+
+	/**
+	 * Writes a compressed copy of the database to the given file.
+	 * The output is gzipped if `gzip` is true or the filename ends with `.gz`.
+	 *
+	 * The copy is created from the entries in memory, so this does not wait for a running compress.
+	 * `duringCompress` in the result tells whether the DB was compressed in the meantime.
+	 */
+	public dump(filename: string, gzip?: boolean): Promise<JsonlDBDumpResult> {
+		return wrapNativeErrorAsync(() => this.db.dump(filename, gzip));
 	}
 
 	public compress(): Promise<void> {
 		return wrapNativeErrorAsync(() => this.db.compress());
 	}
 
-	public clear(): void {
+	/**
+	 * Copies the DB file to the given path once all changes are on disk, e.g. for backup tools.
+	 * Unlike `dump()`, the entries are not serialized again. Compressing cannot happen in between,
+	 * and later changes don't affect the copy. With `shards`, each shard is copied to `<filename>.<shard>.jsonl`.
+	 */
+	public createConsistentSnapshotFile(
+		filename: string,
+	): Promise<JsonlDBSnapshotResult> {
+		return wrapNativeErrorAsync(() =>
+			this.db.createConsistentSnapshotFile(filename),
+		);
+	}
+
+	/**
+	 * Resumes persisting after the DB file disappeared, e.g. because its drive was removed.
+	 * The directory and the DB file are created again and all entries are written to it.
+	 */
+	public retryPersistence(): Promise<void> {
+		return wrapNativeErrorAsync(() => this.db.retryPersistence());
+	}
+
+	/**
+	 * Removes all entries. With `durable: true`, the returned promise only resolves
+	 * once the DB file was truncated on disk.
+	 */
+	public clear(): void;
+	public clear(options: { durable: true }): Promise<void>;
+	public clear(options?: { durable?: boolean }): void | Promise<void> {
+		if (
+			!options?.durable &&
+			this.queueWrite(undefined, () => this.clear())
+		) {
+			return;
+		}
 		this._keysCache?.clear();
+		if (options?.durable) {
+			return wrapNativeErrorAsync(() => this.db.clearDurable());
+		}
 		wrapNativeErrorSync(() => this.db.clear());
 	}
 
+	/**
+	 * Deletes the given entry and returns whether it existed.
+	 * Deletes that are queued while the DB is being opened always return `false`.
+	 * To keep the order of the other keys, this takes time proportional to the number of entries.
+	 * Use `deleteMatching()` to delete many keys at once.
+	 */
 	public delete(key: string): boolean {
+		assertValidKey(key);
+		if (this.queueWrite(key, () => this.delete(key))) return false;
 		this._keysCache?.delete(key);
 		return wrapNativeErrorSync(() => this.db.delete(key));
 	}
 
+	/**
+	 * Deletes all entries whose keys match the given pattern and returns how many were deleted.
+	 * Glob patterns support `*` (any sequence of characters), `?` (any single character) and `\\` to escape those.
+	 * Regular expressions use Rust's regex syntax and match anywhere in the key unless anchored with `^` and `$`.
+	 */
+	public deleteMatching(
+		pattern: string,
+		kind: "glob" | "regex" = "glob",
+	): number {
+		this._keysCache = undefined;
+		return wrapNativeErrorSync(() => this.db.deleteMatching(pattern, kind));
+	}
+
+	/** Converts a number that JSON cannot represent as configured with the `nonFiniteNumbers` option */
+	private convertNonFinite(value: number): null | string {
+		switch (this.options.nonFiniteNumbers) {
+			case "null":
+				return null;
+			case "string":
+				return String(value);
+			default:
+				throw new Error(
+					`${value} cannot be stored as JSON. Use the nonFiniteNumbers option to store it as null or as a string`,
+				);
+		}
+	}
+
+	/** Like `JSON.stringify`, but handles numbers that JSON cannot represent as configured */
+	private stringify(value: unknown): string {
+		const json = JSON.stringify(value);
+		// JSON.stringify turns them into null, so only values that contain null need a closer look
+		if (this.options.nonFiniteNumbers === "null" || !json.includes("null")) {
+			return json;
+		}
+		return JSON.stringify(value, (_key, v) =>
+			typeof v === "number" && !Number.isFinite(v)
+				? this.convertNonFinite(v)
+				: v,
+		);
+	}
+
+	/** Returns the primitive value with numbers that JSON cannot represent converted as configured */
+	private toStorablePrimitive(value: V): V {
+		return typeof value === "number" && !Number.isFinite(value)
+			? (this.convertNonFinite(value) as any)
+			: value;
+	}
+
 	public set(key: string, value: V): this {
-		this._keysCache?.add(key);
+		assertValidKey(key);
+		if (value === undefined) return this.setMaybe(key, value);
+		assertSupportedValue(value);
+		value = this.toStorablePrimitive(value);
+		if (this.queueWrite(key, () => this.set(key, value))) return this;
 		switch (typeof value) {
 			case "number":
 			case "boolean":
@@ -121,8 +1013,7 @@ export class JsonlDB<V = any> implements Map<string, V> {
 						this.db.setObject(
 							key,
 							value as any,
-							JSON.stringify(value),
-							this.deriveIndexKeys(value),
+							this.stringify(value),
 						),
 					);
 				}
@@ -130,30 +1021,585 @@ export class JsonlDB<V = any> implements Map<string, V> {
 			default:
 				throw new Error("unsupported value type");
 		}
+		// Only after the value was stored, so a refused value doesn't leave a key behind
+		this._keysCache?.add(key);
+		return this;
+	}
+
+	/**
+	 * Like `set`, but an `undefined` value deletes the key. This requires the `undefinedIsDelete` option,
+	 * otherwise `undefined` is refused with an error instead of being stored as `null`.
+	 */
+	public setMaybe(key: string, value: V | undefined): this {
+		if (value !== undefined) return this.set(key, value);
+		assertValidKey(key);
+		if (
+			this.options.undefinedIsDelete &&
+			this.queueWrite(key, () => this.setMaybe(key, value))
+		) {
+			return this;
+		}
+		wrapNativeErrorSync(() => this.db.setMaybe(key, undefined));
+		this._keysCache?.delete(key);
+		return this;
+	}
+
+	/**
+	 * Like `set`, but the returned promise only resolves once the value was written to disk.
+	 */
+	public setDurable(key: string, value: V): Promise<void> {
+		try {
+			assertValidKey(key);
+			value = this.toStorablePrimitive(value);
+		} catch (e) {
+			return Promise.reject(e);
+		}
+		if (value === undefined) {
+			// Deletes the key with undefinedIsDelete and fails otherwise
+			return wrapNativeErrorAsync(() => {
+				const flushed = this.db.setPrimitiveDurable(key, value);
+				this._keysCache?.delete(key);
+				return flushed;
+			});
+		}
+		switch (typeof value) {
+			case "number":
+			case "boolean":
+			case "string":
+				return wrapNativeErrorAsync(() => {
+					const flushed = this.db.setPrimitiveDurable(key, value);
+					this._keysCache?.add(key);
+					return flushed;
+				});
+			case "object":
+				if (value === null) {
+					return wrapNativeErrorAsync(() => {
+						const flushed = this.db.setPrimitiveDurable(key, value);
+						this._keysCache?.add(key);
+						return flushed;
+					});
+				} else {
+					let stringified: string;
+					try {
+						stringified = this.stringify(value);
+					} catch (e) {
+						return Promise.reject(e);
+					}
+					return wrapNativeErrorAsync(() => {
+						const flushed = this.db.setObjectDurable(
+							key,
+							value as any,
+							stringified,
+						);
+						this._keysCache?.add(key);
+						return flushed;
+					});
+				}
+			default:
+				return Promise.reject(new Error("unsupported value type"));
+		}
+	}
+
+	/**
+	 * Stores a plain JSON value (e.g. from `JSON.parse`) which is converted to a native representation.
+	 * No reference to the object is kept, so later changes to it are not reflected in the DB.
+	 * The index keys are derived from the value.
+	 */
+	public setNativeObject(key: string, value: V): this {
+		assertValidKey(key);
+		if (value === undefined) return this.setMaybe(key, value);
+		if (hasNonFiniteNumber(value)) {
+			value =
+				typeof value === "number"
+					? this.toStorablePrimitive(value)
+					: JSON.parse(this.stringify(value));
+		}
+		if (this.preOpenQueue) {
+			// Like the native conversion, the queued write must not keep a reference to the value
+			const json = this.stringify(value);
+			this.queueWrite(key, () =>
+				this.setNativeObject(key, JSON.parse(json)),
+			);
+			return this;
+		}
+		wrapNativeErrorSync(() => this.db.setNative(key, value));
+		this._keysCache?.add(key);
+		return this;
+	}
+
+	/**
+	 * Like `set`, but objects are only stored in their serialized form instead of keeping a reference to them.
+	 * Use this for write-heavy workloads that rarely read back the values.
+	 */
+	public setDetached(key: string, value: V): this {
+		if (typeof value !== "object" || value === null) {
+			return this.set(key, value);
+		}
+		assertValidKey(key);
+		if (this.queueWrite(key, () => this.setDetached(key, value))) {
+			return this;
+		}
+		wrapNativeErrorSync(() =>
+			this.db.setObjectDetached(key, this.stringify(value)),
+		);
+		this._keysCache?.add(key);
 		return this;
 	}
 
 	public get(key: string): V | undefined {
+		assertValidKey(key);
 		return wrapNativeErrorSync(() => this.db.get(key) as any);
 	}
 
+	/**
+	 * Like `get`, but large objects are serialized in the background and parsed with `JSON.parse`,
+	 * which blocks the event loop much shorter than converting them. Other values resolve immediately.
+	 * Like with `get`, the returned object is kept and returned again by later calls.
+	 */
+	public async getAsync(key: string): Promise<V | undefined> {
+		assertValidKey(key);
+		const stringified = await wrapNativeErrorAsync(() =>
+			this.db.getLargeStringified(key, GET_ASYNC_MIN_BYTES),
+		);
+		if (stringified == undefined) return this.get(key);
+		const value = JSON.parse(stringified);
+		return wrapNativeErrorSync(
+			() => this.db.adoptParsed(key, value, stringified) as any,
+		);
+	}
+
+	/**
+	 * Returns the values of the given keys, in the same order as the keys. Like with `get`, the value
+	 * of a key that doesn't exist is `undefined`. This is much faster than calling `get` for each key.
+	 */
+	public getBatch(keys: string[]): (V | undefined)[] {
+		keys.forEach(assertValidKey);
+		return wrapNativeErrorSync(() => this.db.getBatch(keys) as any);
+	}
+
+	/** Like `getBatch`, but returns the keys with their values and skips keys that don't exist */
+	public getBatchEntries(keys: string[]): { key: string; value: V }[] {
+		keys.forEach(assertValidKey);
+		return wrapNativeErrorSync(() => this.db.getBatchEntries(keys) as any);
+	}
+
+	/**
+	 * Passes all entries to the callback in batches, with the values serialized as JSON, e.g. to implement custom export formats.
+	 * The keys are collected first, so changes made in the meantime don't break the iteration. Each value is read when its
+	 * batch is built, entries that were deleted in the meantime are skipped.
+	 * If the callback returns a promise, the next batch is only built once it resolves.
+	 */
+	public async streamEntries(
+		callback: (batch: JsonlDBStringifiedEntry[]) => void | Promise<void>,
+		options: { batchSize?: number } = {},
+	): Promise<void> {
+		const batchSize = options.batchSize ?? 1000;
+		if (!Number.isInteger(batchSize) || batchSize < 1) {
+			throw new Error("batchSize must be an integer >= 1");
+		}
+
+		const keys = wrapNativeErrorSync(() => [...this.getKeysCached()]);
+		for (let offset = 0; offset < keys.length; offset += batchSize) {
+			const batch = wrapNativeErrorSync(() =>
+				this.db.getBatchStringified(
+					keys.slice(offset, offset + batchSize),
+				),
+			);
+			if (batch.length > 0) await callback(batch);
+		}
+	}
+
+	/**
+	 * Iterates over the entries whose keys are in the range `start...end` and start with `prefix`,
+	 * with the values serialized as JSON. The matching keys are collected when this is called,
+	 * so changes made in the meantime don't break the iteration. Entries that were deleted in the meantime are skipped.
+	 * The entries are read from the DB in batches of `batchSize` (default: 1000).
+	 * The range is checked with the given `collation` like in `getMany`.
+	 */
+	public iterateEntriesStringified(
+		options: JsonlDBEntryCursorOptions & { batchSize?: number } = {},
+	): AsyncIterableIterator<JsonlDBStringifiedEntry> {
+		const { batchSize = 1000, ...cursorOptions } = options;
+		if (!Number.isInteger(batchSize) || batchSize < 1) {
+			throw new Error("batchSize must be an integer >= 1");
+		}
+
+		const id = wrapNativeErrorSync(() =>
+			this.db.createEntryCursor(cursorOptions),
+		);
+		return this.readEntryCursor(id, batchSize);
+	}
+
+	private async *readEntryCursor(
+		id: number,
+		batchSize: number,
+	): AsyncIterableIterator<JsonlDBStringifiedEntry> {
+		try {
+			while (true) {
+				const batch = wrapNativeErrorSync(() =>
+					this.db.readEntryCursor(id, batchSize),
+				);
+				if (batch.length === 0) return;
+				yield* batch;
+			}
+		} finally {
+			// Cursors are discarded when closing the DB anyways
+			if (this.isOpen) this.db.closeEntryCursor(id);
+		}
+	}
+
+	/**
+	 * Like `iterateEntriesStringified`, but returns a `Readable` in object mode, e.g. for piping the entries
+	 * into a compression or upload pipeline
+	 */
+	public createReadStream(
+		options: JsonlDBEntryCursorOptions & { batchSize?: number } = {},
+	): Readable {
+		return Readable.from(this.iterateEntriesStringified(options));
+	}
+
+	/**
+	 * Returns the values of all keys in the range `startkey...endkey`.
+	 * If a key comparator is set, the values are returned in the order it defines.
+	 * Otherwise they are returned in the order the entries are stored in, whether or not an index was used.
+	 * This order stays the same between calls as long as the data is not changed.
+	 * With `explain: true`, the result also describes whether the index was used to answer the query.
+	 *
+	 * The `objectFilter` should be an object like `{ path: "/type", value: "foo" }`.
+	 * The legacy string form `"/type=foo"` still works, but only matches string values.
+	 *
+	 * The `collation` defines how the keys are compared to `startkey` and `endkey`:
+	 * - `"binary"` (default): by their UTF-8 bytes, i.e. by code point. This differs from `localeCompare`,
+	 *   e.g. `"ä"` comes after `"z"`, and a decomposed `"a\u0308"` comes before `"b"`.
+	 * - `"unicode"`: like `"binary"`, but the keys are normalized to NFC first, so composed and decomposed
+	 *   characters are ordered alike
+	 */
+	public getMany(
+		startkey: string,
+		endkey: string,
+		objectFilter?: JsonlDBObjectFilter | string,
+		options?: { explain?: false; collation?: JsonlDBCollation },
+	): V[];
+	public getMany(
+		startkey: string,
+		endkey: string,
+		objectFilter: JsonlDBObjectFilter | string | undefined,
+		options: { explain: true; collation?: JsonlDBCollation },
+	): { results: V[]; explain: JsonlDBQueryExplain };
 	public getMany(
 		startkey: string,
 		endkey: string,
-		objectFilter?: string,
-	): V[] {
+		objectFilter?: JsonlDBObjectFilter | string,
+		options?: { explain?: boolean; collation?: JsonlDBCollation },
+	): V[] | { results: V[]; explain: JsonlDBQueryExplain } {
+		if (options?.explain) {
+			return wrapNativeErrorSync(
+				() =>
+					this.db.getManyExplain(
+						startkey,
+						endkey,
+						objectFilter,
+						this.keyComparator,
+						options.collation,
+					) as any,
+			);
+		}
 		return wrapNativeErrorSync(
-			() => this.db.getMany(startkey, endkey, objectFilter) as any,
+			() =>
+				this.db.getMany(
+					startkey,
+					endkey,
+					objectFilter,
+					this.keyComparator,
+					options?.collation,
+				) as any,
+		);
+	}
+
+	/**
+	 * Like `getMany`, but returns the results as a single JSON string instead of converting each value
+	 * to a JS object. Parsing it with `JSON.parse` is faster than `getMany` for many objects that were not
+	 * read or set since opening the DB. For primitives and objects that are already in memory, it is not.
+	 * With `keyed: true`, the string is an object keyed by the entry keys, otherwise it is an array.
+	 */
+	public getManyStringified(
+		startkey: string,
+		endkey: string,
+		objectFilter?: JsonlDBObjectFilter | string,
+		options?: { keyed?: boolean; collation?: JsonlDBCollation },
+	): string {
+		return wrapNativeErrorSync(() =>
+			this.db.getManyStringified(
+				startkey,
+				endkey,
+				objectFilter,
+				this.keyComparator,
+				options?.collation,
+				options?.keyed,
+			),
+		);
+	}
+
+	/** Returns the paths configured with the `indexPaths` option */
+	public getIndexPaths(): string[] {
+		return wrapNativeErrorSync(() => this.db.getIndexPaths());
+	}
+
+	/** Returns whether the given path is indexed, so it can be used as an object filter in `getMany` */
+	public isIndexed(path: string): boolean {
+		return wrapNativeErrorSync(() => this.db.isIndexed(path));
+	}
+
+	/** Returns how many distinct values the given index path has among the current entries */
+	public getIndexSize(path: string): number {
+		return wrapNativeErrorSync(() => this.db.getIndexSize(path));
+	}
+
+	/**
+	 * Starts counting the entries whose key starts with the given prefix. The count is kept up to date
+	 * on every change, so `getPrefixCount()` doesn't need to look at all keys.
+	 * Returns `false` if the prefix is already counted.
+	 */
+	public registerPrefixCounter(prefix: string): boolean {
+		return wrapNativeErrorSync(() => this.db.registerPrefixCounter(prefix));
+	}
+
+	/** Stops counting the entries with the given prefix. Returns `false` if it wasn't counted. */
+	public unregisterPrefixCounter(prefix: string): boolean {
+		return wrapNativeErrorSync(() =>
+			this.db.unregisterPrefixCounter(prefix),
+		);
+	}
+
+	/**
+	 * Returns how many entries have a key that starts with the given prefix, or `undefined` if the prefix
+	 * was not registered with `registerPrefixCounter()` or the `prefixCounters` option.
+	 */
+	public getPrefixCount(prefix: string): number | undefined {
+		return (
+			wrapNativeErrorSync(() => this.db.getPrefixCount(prefix)) ??
+			undefined
+		);
+	}
+
+	/**
+	 * Returns cumulative statistics about the `getMany` calls and the lines written since the DB
+	 * was opened or `resetMetrics()` was called, and how many entries are currently held as JS objects
+	 */
+	public getMetrics(): JsonlDBMetrics {
+		return wrapNativeErrorSync(() => this.db.getMetrics());
+	}
+
+	/** Resets the cumulative statistics returned by `getMetrics()` */
+	public resetMetrics(): void {
+		wrapNativeErrorSync(() => this.db.resetMetrics());
+	}
+
+	/**
+	 * Writes the current state of the object stored under the given key, after it was changed in place.
+	 * If `stringified` is not given, the object is serialized with `JSON.stringify`.
+	 * Returns `false` if the entry does not exist or is not an object.
+	 */
+	public refreshSnapshot(key: string, stringified?: string): boolean {
+		assertValidKey(key);
+		return wrapNativeErrorSync(() => {
+			if (stringified == undefined) {
+				const value = this.db.get(key);
+				if (typeof value !== "object" || value === null) return false;
+				stringified = this.stringify(value);
+			}
+			return this.db.refreshSnapshot(key, stringified);
+		});
+	}
+
+	/**
+	 * Reading an object keeps it alive as a JS object, so it can be returned again without converting it.
+	 * This releases the JS object of the given entry, so it can be garbage collected.
+	 * Changes to the object that were not saved with `set` are lost.
+	 * Returns whether the entry was held as a JS object.
+	 */
+	public dematerialize(key: string): boolean {
+		return wrapNativeErrorSync(() => this.db.dematerialize(key));
+	}
+
+	/** Like `dematerialize`, but for all entries. Returns how many JS objects were released. */
+	public dematerializeAll(): number {
+		return wrapNativeErrorSync(() => this.db.dematerializeAll());
+	}
+
+	// The next batch of the preMaterialize option, if one is scheduled
+	private preMaterializeTimer: NodeJS.Immediate | undefined;
+
+	private startPreMaterialize(): void {
+		const { keysPrefix, budgetMsPerTick } = this.options.preMaterialize!;
+		let next = 0;
+		let converted = 0;
+		const step = (): void => {
+			this.preMaterializeTimer = undefined;
+			let progress: JsonlDBMaterializeProgress;
+			try {
+				progress = this.db.materialize(
+					keysPrefix,
+					next,
+					budgetMsPerTick,
+				);
+			} catch (e) {
+				// Closing the DB stops the conversion
+				if (this.isOpen) {
+					this.emit("error", {
+						message: (e as Error).message,
+						source: "preMaterialize",
+					});
+				}
+				return;
+			}
+			next = progress.next;
+			converted += progress.converted;
+			const done = next >= progress.total;
+			this.emit("materializeProgress", {
+				converted,
+				scanned: Math.min(next, progress.total),
+				total: progress.total,
+				done,
+			});
+			if (!done) this.preMaterializeTimer = setImmediate(step).unref();
+		};
+		this.preMaterializeTimer = setImmediate(step).unref();
+	}
+
+	/** Stops converting entries to JS objects in the background, see the `preMaterialize` option */
+	public cancelPreMaterialize(): void {
+		if (this.preMaterializeTimer) {
+			clearImmediate(this.preMaterializeTimer);
+			this.preMaterializeTimer = undefined;
+		}
+	}
+
+	private keyComparator: ((keys: string[]) => string[]) | undefined;
+
+	/**
+	 * Sets a callback that defines a custom order for the keys returned by `getMany` and `getSortedKeys`.
+	 * It is called once per query with all affected keys and must return the same keys in the desired order.
+	 * The callback must not access the DB. Pass `undefined` to restore the default order.
+	 */
+	public setKeyComparator(
+		comparator: ((keys: string[]) => string[]) | undefined,
+	): void {
+		this.keyComparator = comparator;
+	}
+
+	/**
+	 * Returns all keys, sorted by the key comparator if one is set or the `keyOrdering` option otherwise
+	 */
+	public getSortedKeys(): string[] {
+		return wrapNativeErrorSync(() =>
+			this.db.getKeysSorted(this.keyComparator),
 		);
 	}
 
 	public has(key: string): boolean {
+		assertValidKey(key);
 		return wrapNativeErrorSync(() => this.db.has(key));
 	}
 	public get size(): number {
 		return wrapNativeErrorSync(() => this.db.size);
 	}
 
+	/** The absolute path of the DB file. Also available while the DB is closed. */
+	public get path(): string {
+		return wrapNativeErrorSync(() => this.db.path);
+	}
+
+	/** The size of the DB file on disk in bytes. Data that is not yet written is not included. */
+	public get fileSizeBytes(): number {
+		return wrapNativeErrorSync(() => this.db.fileSizeBytes);
+	}
+
+	/** The absolute path of the lockfile that is held while the DB is open */
+	public get lockfilePath(): string {
+		return wrapNativeErrorSync(() => this.db.lockfilePath);
+	}
+
+	/**
+	 * Whether another process took over the lockfile while the DB was open.
+	 * The DB is read-only then. Unsaved changes can still be saved elsewhere with `dump()`.
+	 */
+	public get isLockLost(): boolean {
+		return wrapNativeErrorSync(() => this.db.isLockLost);
+	}
+
+	/**
+	 * Returns when the given entry was written. Requires the `trackTimestamps` option.
+	 * Returns `undefined` if the entry does not exist or was written without timestamps.
+	 * `createdAt` is best-effort and only known if the creation was recorded with timestamps enabled.
+	 */
+	public getMeta(key: string): JsonlDBEntryMeta | undefined {
+		return wrapNativeErrorSync(() => this.db.getMeta(key) ?? undefined);
+	}
+
+	/**
+	 * Stores application-defined metadata like the schema version with the DB, without it being an entry.
+	 * The header must be serialized JSON. `null` removes it. It is not included in `keys()`, `size` or exports,
+	 * but survives compressing and `clear()`.
+	 */
+	public setHeader(header: string | null): void {
+		wrapNativeErrorSync(() => this.db.setHeader(header));
+	}
+
+	/** Returns the serialized DB header, or `null` if there is none */
+	public getHeader(): string | null {
+		return wrapNativeErrorSync(() => this.db.getHeader());
+	}
+
+	/**
+	 * Returns the keys of entries that were deleted in soft-delete mode,
+	 * optionally only those deleted at or after `since` (epoch ms).
+	 */
+	public getDeletedKeys(since?: number): string[] {
+		return wrapNativeErrorSync(() => this.db.getDeletedKeys(since));
+	}
+
+	/** Returns statistics about the DB file which can be used to tune the auto-compress options */
+	public getStats(): JsonlDBStats {
+		return wrapNativeErrorSync(() => this.db.getStats());
+	}
+
+	/**
+	 * Returns the sizes of the DB file when it was opened, e.g. how many bytes compressing would free up,
+	 * and how many of its lines overwrote or deleted entries
+	 */
+	public getOpenDiagnostics(): JsonlDBOpenDiagnostics {
+		return wrapNativeErrorSync(() => this.db.getOpenDiagnostics());
+	}
+
+	/**
+	 * Returns an estimate of the memory used by the DB in bytes.
+	 * The actual usage may be higher due to allocator overhead and values that are cached in JS.
+	 */
+	public getMemoryUsage(): Promise<JsonlDBMemoryUsage> {
+		return wrapNativeErrorAsync(() => this.db.getMemoryUsage());
+	}
+
+	/**
+	 * Estimates how much compressing the DB file would gain, without touching any files.
+	 * Writes that are still buffered are included in the estimated size, but not in the current one.
+	 */
+	public estimateCompress(): Promise<JsonlDBCompressEstimate> {
+		return wrapNativeErrorAsync(() => this.db.estimateCompress());
+	}
+
+	/**
+	 * Checks the internal invariants of the DB, e.g. to attach the result to a bug report.
+	 * All violations are returned instead of throwing. Changes made while checking may be reported.
+	 */
+	public selfCheck(): Promise<JsonlDBSelfCheckReport> {
+		return wrapNativeErrorAsync(() => {
+			// Objects can only be serialized on the main thread
+			this.db.prepareSelfCheck();
+			return this.db.selfCheck();
+		});
+	}
+
 	public forEach(
 		callback: (value: V, key: string, map: Map<string, V>) => void,
 		thisArg?: any,
@@ -163,25 +1609,37 @@ export class JsonlDB<V = any> implements Map<string, V> {
 		});
 	}
 
+	/**
+	 * Returns up to `limit` keys, starting at the `offset`th key. This can be used to iterate over
+	 * large DBs without transferring all keys at once.
+	 * Like the keys of a `Map`, they are in the order in which they were first set. Deleting a key and
+	 * setting it again moves it to the end. This order is kept when compressing and reopening the DB,
+	 * but with the `shards` option, reopening only keeps it within each shard. The keys of a shard
+	 * then follow those of the previous one.
+	 * Pages are only consistent with each other if the DB is not changed in between.
+	 * Otherwise, later pages reflect the new state, so keys may be skipped or returned twice.
+	 */
+	public getKeysPage(offset: number, limit: number): string[] {
+		return wrapNativeErrorSync(() => this.db.getKeysPage(offset, limit));
+	}
+
 	private _keysCache: Set<string> | undefined;
 	private getKeysCached(): Set<string> {
 		if (!this._keysCache) {
-			this._keysCache = new Set(JSON.parse(this.db.getKeysStringified()));
+			// Transfer the keys in pages, so we don't need one huge string
+			const keys = new Set<string>();
+			const count = this.db.keyCount;
+			for (let offset = 0; offset < count; offset += KEYS_PAGE_SIZE) {
+				const page: string[] = JSON.parse(
+					this.db.getKeysStringifiedPage(offset, KEYS_PAGE_SIZE),
+				);
+				for (const key of page) keys.add(key);
+			}
+			this._keysCache = keys;
 		}
 		return this._keysCache;
 	}
 
-	private deriveIndexKeys(obj: Record<string, any>): string[] {
-		if (!this.options.indexPaths?.length) return [];
-		return this.options.indexPaths
-			.map((p) => {
-				const val = pointer(obj, p);
-				if (typeof val !== "string") return undefined;
-				return `${p}=${val}`;
-			})
-			.filter((k): k is string => !!k);
-	}
-
 	public keys(): IterableIterator<string> {
 		return wrapNativeErrorSync(() =>
 			this.getKeysCached()[Symbol.iterator](),
@@ -213,51 +1671,129 @@ export class JsonlDB<V = any> implements Map<string, V> {
 		return "JsonlDB";
 	}
 
+	/**
+	 * Exports the database contents as a JSON object.
+	 * The output is gzipped if `gzip` is true or the filename ends with `.gz`.
+	 */
+	public exportJson(
+		filename: string,
+		options?: JsonlDBExportOptions,
+	): Promise<JsonlDBJsonExportResult>;
+	public exportJson(
+		filename: string,
+		pretty?: boolean,
+		gzip?: boolean,
+	): Promise<JsonlDBJsonExportResult>;
 	public async exportJson(
 		filename: string,
-		pretty: boolean = false,
+		prettyOrOptions: boolean | JsonlDBExportOptions = false,
+		gzip?: boolean,
+	): Promise<JsonlDBJsonExportResult> {
+		const options: JsonlDBExportOptions =
+			typeof prettyOrOptions === "boolean"
+				? { pretty: prettyOrOptions, gzip }
+				: prettyOrOptions;
+		return wrapNativeErrorAsync(() =>
+			this.db.exportJson(
+				filename,
+				!!options.pretty,
+				options.gzip,
+				options.omitNullValues,
+			),
+		);
+	}
+
+	private async importJsonFile(
+		filename: string,
+		options?: JsonlDBImportOptions,
+	): Promise<JsonlDBJsonImportResult> {
+		// The file is read in the background, but imported on the main thread
+		const id = await wrapNativeErrorAsync(() =>
+			this.db.loadJsonImport(filename),
+		);
+		this._keysCache = undefined;
+		return wrapNativeErrorSync(() =>
+			this.db.importLoaded(
+				id,
+				options?.viaCompress,
+				options?.nullMeansDelete,
+			),
+		);
+	}
+
+	public async exportCsv(
+		filename: string,
+		options?: JsonlDBCsvExportOptions & {
+			valueMode?: "json" | "flatten";
+		},
+	): Promise<void> {
+		await wrapNativeErrorAsync(() => this.db.exportCsv(filename, options));
+	}
+
+	public async importCsv(
+		filename: string,
+		options?: JsonlDBCsvImportOptions,
 	): Promise<void> {
-		await wrapNativeErrorAsync(() => this.db.exportJson(filename, pretty));
+		// The file is read in the background, but imported on the main thread
+		const id = await wrapNativeErrorAsync(() =>
+			this.db.loadCsvImport(filename, options),
+		);
+		this._keysCache = undefined;
+		wrapNativeErrorSync(() => this.db.importLoaded(id));
 	}
 
-	public importJson(filename: string): Promise<void>;
-	public importJson(json: Record<string, any>): void;
+	public importJson(
+		filename: string,
+		options?: JsonlDBImportOptions,
+	): Promise<JsonlDBJsonImportResult>;
+	public importJson(
+		json: Record<string, any>,
+		options?: JsonlDBImportOptions,
+	): JsonlDBJsonImportResult;
 	public importJson(
 		jsonOrFile: Record<string, any> | string,
-	): void | Promise<void> {
-		this._keysCache = undefined;
+		options?: JsonlDBImportOptions,
+	): JsonlDBJsonImportResult | Promise<JsonlDBJsonImportResult> {
 		if (typeof jsonOrFile === "string") {
-			return wrapNativeErrorAsync(() =>
-				this.db.importJsonFile(jsonOrFile),
-			);
+			return this.importJsonFile(jsonOrFile, options);
 		} else {
+			this._keysCache = undefined;
 			// Yeah, this is weird but more performant for large objects
 			return wrapNativeErrorSync(() =>
-				this.db.importJsonString(JSON.stringify(jsonOrFile)),
+				this.db.importJsonString(
+					this.stringify(jsonOrFile),
+					options?.viaCompress,
+					options?.nullMeansDelete,
+				),
 			);
 		}
 	}
 }
 
-export { JsonlDBOptions, JsonlDBOptionsThrottleFS } from "./lib";
-
-// Matches the rust implementation of serde_json::Value::pointer
-function pointer(object: Record<string, any>, path: string): unknown {
-	if (path === "") {
-		return object;
-	} else if (!path.startsWith("/")) {
-		return undefined;
-	}
-	function _pointer(obj: Record<string, any>, pathArr: string[]): unknown {
-		// are we there yet? then return obj
-		if (!pathArr.length) return obj;
-		// go deeper
-		let propName: string | number = pathArr.shift()!;
-		if (/\[\d+\]/.test(propName)) {
-			// this is an array index
-			propName = +propName.slice(1, -1);
-		}
-		return _pointer(obj[propName], pathArr);
-	}
-	return _pointer(object, path.split("/").slice(1));
-}
+export {
+	getLiveTaskCount,
+	JsonlDBCompressEstimate,
+	JsonlDBCsvExportOptions,
+	JsonlDBCsvImportOptions,
+	JsonlDBDumpResult,
+	JsonlDBEntryCursorOptions,
+	JsonlDBEntryMeta,
+	JsonlDBJsonExportResult,
+	JsonlDBJsonImportResult,
+	JsonlDBLockContention,
+	JsonlDBLockStatus,
+	JsonlDBLockWaits,
+	JsonlDBMemoryUsage,
+	JsonlDBMetrics,
+	JsonlDBOpenDiagnostics,
+	JsonlDBOptionsAutoDump,
+	JsonlDBOptionsDedicatedRuntime,
+	JsonlDBOptionsThrottleFS,
+	JsonlDBQueryExplain,
+	JsonlDBReadError,
+	JsonlDBSelfCheckReport,
+	JsonlDBSelfCheckViolation,
+	JsonlDBSnapshotResult,
+	JsonlDBStats,
+	JsonlDBStringifiedEntry,
+} from "./lib";
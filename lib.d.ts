@@ -11,49 +11,580 @@ export interface JsonlDBOptions {
 	throttleFS?: JsonlDBOptionsThrottleFS | undefined | null;
 	autoCompress?: JsonlDBOptionsAutoCompress | undefined | null;
 	lockfileDirectory?: string | undefined | null;
+	lockLostRetries?: number | undefined | null;
 	indexPaths?: Array<string> | undefined | null;
+	dedicatedRuntime?: JsonlDBOptionsDedicatedRuntime | undefined | null;
+	fileMode?: number | undefined | null;
+	ioBufferSizeBytes?: number | undefined | null;
+	forceOpen?: boolean | undefined | null;
+	maxInvalidLineRatio?: number | undefined | null;
+	/** "lexicographic" (default), "numeric" or "natural" */
+	keyOrdering?: string | undefined | null;
+	trackTimestamps?: boolean | undefined | null;
+	softDelete?: JsonlDBOptionsSoftDelete | undefined | null;
+	autoDump?: JsonlDBOptionsAutoDump | undefined | null;
+	resnapshotOnWrite?: boolean | undefined | null;
+	scratchDirectory?: string | undefined | null;
+	compressDumpSuffix?: string | undefined | null;
+	compressBackupSuffix?: string | undefined | null;
+	rebuildIndexAfterImport?: boolean | undefined | null;
+	maxLineLengthBytes?: number | undefined | null;
+	shards?: number | undefined | null;
+	debugConsistencyChecks?: boolean | undefined | null;
+	measureLockContention?: boolean | undefined | null;
+	prefixCounters?: Array<string> | undefined | null;
+	maxValueDepth?: number | undefined | null;
+	undefinedIsDelete?: boolean | undefined | null;
 }
 export interface JsonlDBOptionsThrottleFS {
+	/**
+	 * How long to wait before writing changes to the DB file (ms). Written changes are only handed
+	 * to the OS, which may lose them if the machine crashes, until they are synced to disk.
+	 */
 	intervalMs: number;
+	/** Write earlier if more changes than this are waiting */
 	maxBufferedCommands?: number | undefined | null;
+	/** Only write the last change of a key within this time (ms). Earlier changes are never written. */
+	coalesceWindowMs?: number | undefined | null;
+	/**
+	 * Sync the written changes to disk once nothing was written for this long (ms).
+	 * Bounds the changes a crash of the machine can lose while writing pauses. Disabled by default,
+	 * then changes are only synced by `close()`, compressing and the promises of durable writes.
+	 */
+	fsyncIdleMs?: number | undefined | null;
+	/**
+	 * Sync the written changes to disk at least this often (ms), even while writing continuously.
+	 * Bounds the changes a crash of the machine can lose under constant load. Disabled by default.
+	 */
+	fsyncMaxIntervalMs?: number | undefined | null;
+}
+export interface JsonlDBOptionsDedicatedRuntime {
+	workerThreads?: number | undefined | null;
+}
+export interface JsonlDBOptionsSoftDelete {
+	retainMs: number;
+}
+export interface JsonlDBOptionsAutoDump {
+	intervalMs: number;
+	/** Where to write the dumps, relative to the DB file. `{timestamp}` is replaced with the current time. */
+	filenameTemplate: string;
+	/** How many automatic dumps to keep, at least 1 */
+	keep: number;
 }
 export interface JsonlDBOptionsAutoCompress {
 	sizeFactor?: number | undefined | null;
 	sizeFactorMinimumSize?: number | undefined | null;
 	intervalMs?: number | undefined | null;
 	intervalMinChanges?: number | undefined | null;
+	/** "lastCompress" (default) or "lastWrite" */
+	intervalAnchor?: string | undefined | null;
 	onClose?: boolean | undefined | null;
 	onOpen?: boolean | undefined | null;
+	onOpenIfWastedBytesExceed?: number | undefined | null;
+	maxOverwriteRatio?: number | undefined | null;
+	maxTombstones?: number | undefined | null;
+}
+export interface JsonlDBCsvExportOptions {
+	/** "json" (default) or "flatten" */
+	valueMode?: string | undefined | null;
+}
+/** Which entries an entry cursor iterates over. All given conditions must match. */
+export interface JsonlDBEntryCursorOptions {
+	/** The first key to include */
+	start?: string | undefined | null;
+	/** The last key to include */
+	end?: string | undefined | null;
+	/** Only include keys that start with this */
+	prefix?: string | undefined | null;
+	/** How `start` and `end` are compared to the keys: "binary" (default) or "unicode" */
+	collation?: string | undefined | null;
+}
+export interface JsonlDBCsvImportOptions {
+	keyColumn?: string | undefined | null;
+	valueColumn?: string | undefined | null;
+	valueIsJson?: boolean | undefined | null;
+}
+export interface JsonlDBStats {
+	/** How many lines the DB file consists of */
+	uncompressedSize: number;
+	/** How many lines written since the last compress overwrote a key that was already in the file */
+	overwrites: number;
+	/** How many delete lines were written since the last compress */
+	tombstones: number;
+	/** When the DB file was last compressed (epoch ms). Not set if it wasn't since opening the DB */
+	lastCompressAt?: number | undefined | null;
+	/** How many lines were written since the last compress */
+	changesSinceCompress: number;
+	/**
+	 * When compressing by interval will be possible next (epoch ms), if enabled. This is
+	 * an estimate, compressing also requires `autoCompress.intervalMinChanges` changes.
+	 */
+	nextCompressEligibleAt?: number | undefined | null;
+}
+export interface JsonlDBEntryMeta {
+	/** When the entry was first written (epoch ms), if known */
+	createdAt?: number | undefined | null;
+	/** When the entry was last written (epoch ms) */
+	updatedAt: number;
+}
+/** Estimated memory usage in bytes */
+export interface JsonlDBMemoryUsage {
+	/** Keys and values of all entries */
+	entries: number;
+	/** Writes that are not yet persisted */
+	journal: number;
+	/** The lookup tables for `indexPaths` */
+	index: number;
+	total: number;
+}
+/** What compressing the DB file would gain */
+export interface JsonlDBCompressEstimate {
+	/** How many bytes the DB file currently occupies */
+	currentFileBytes: number;
+	/** How many bytes the DB file would occupy after compressing it */
+	estimatedCompactBytes: number;
+	/** How many bytes compressing would free up */
+	wastedBytes: number;
+	/** How many entries the DB has */
+	liveEntries: number;
+	/** How many lines the DB file currently consists of */
+	fileLines: number;
+}
+/** Whether a DB file is locked by an open DB */
+export interface JsonlDBLockStatus {
+	/** Whether the lockfile exists and was updated recently */
+	locked: boolean;
+	/** Whether the lockfile exists, but is so old that opening the DB would take it over */
+	stale: boolean;
+	/** When the lockfile was last updated (epoch ms), if it exists */
+	mtime?: number | undefined | null;
+}
+/** A single line of a DB file */
+export interface JsonlDBParsedLine {
+	key: string;
+	/**
+	 * The value serialized as JSON, missing if the line deletes the entry.
+	 * For the header line, this is the application-defined header
+	 */
+	valueJsonString?: string | undefined | null;
+	isDelete: boolean;
+	/** Whether this is the line with the DB header, which is not an entry */
+	isHeader: boolean;
+	/** When the entry was deleted (epoch ms), if the line is a soft-delete tombstone */
+	deletedAt?: number | undefined | null;
+	/** Which migration was applied, only in the header line */
+	migrationVersion?: number | undefined | null;
+}
+export interface JsonlDBStringifiedEntry {
+	key: string;
+	/** The value serialized as JSON */
+	valueJsonString: string;
+}
+/** The result of a call to `dump` */
+export interface JsonlDBDumpResult {
+	/** How many bytes were written */
+	bytes: number;
+	/** Whether the DB was compressed while the dump was written */
+	duringCompress: boolean;
+}
+/** How far a call to `materialize` got */
+export interface JsonlDBMaterializeProgress {
+	/** The position of the entry to continue at */
+	next: number;
+	/** How many entries were converted to JS objects */
+	converted: number;
+	/** How many entries there are */
+	total: number;
+}
+/** The result of a call to `createConsistentSnapshotFile` */
+export interface JsonlDBSnapshotResult {
+	/** How many bytes the snapshot occupies */
+	bytes: number;
+	/** How many entries the DB had when the snapshot was created */
+	entries: number;
+}
+/** Sizes of the DB file when it was opened, in bytes, and how many of its lines were redundant */
+export interface JsonlDBOpenDiagnostics {
+	/** How many bytes the file occupied */
+	fileBytes: number;
+	/** How many bytes the file would occupy after compressing it */
+	compactedBytes: number;
+	/** How many bytes compressing the file would free up */
+	wastedBytes: number;
+	/** How many non-empty lines the file contained */
+	totalLines: number;
+	/** How many entries were left after reading all lines */
+	liveEntries: number;
+	/** How many lines overwrote an existing entry */
+	overwrites: number;
+	/** How many lines deleted an entry, including tombstones */
+	deletes: number;
+	/** How many lines were skipped because they were longer than `maxLineLengthBytes` */
+	oversizedLines: number;
+	/** The byte offset of the first line that was too long */
+	oversizedLineOffset?: number | undefined | null;
+	/**
+	 * How many invalid lines still contained a valid entry after some garbage, e.g. because
+	 * two processes wrote to the file at once. Only with `ignoreReadErrors`.
+	 */
+	resyncedLines: number;
+	/** How many bytes of invalid lines were skipped. Only with `ignoreReadErrors`. */
+	skippedBytes: number;
+	/**
+	 * How many lines were skipped because they are invalid, too long or nested too deeply.
+	 * Only with `ignoreReadErrors`.
+	 */
+	readErrorCount: number;
+	/** Why the first 100 of these lines were skipped */
+	readErrors: Array<JsonlDBReadError>;
+}
+/** A line that was skipped while opening the DB with `ignoreReadErrors` */
+export interface JsonlDBReadError {
+	/** The number of the line, starting at 1. With shards, this is relative to the shard file */
+	line: number;
+	/** The byte offset where the line starts. With shards, this is relative to the shard file */
+	offset: number;
+	/** Why the line was skipped, including an excerpt of it */
+	reason: string;
+}
+export interface JsonlDBQueryExplain {
+	/** Whether the object filter was resolved using the index */
+	usedIndex: boolean;
+	/** How many keys were considered before limiting them to the range */
+	candidates: number;
+	/** How many keys were within the range and looked up */
+	scanned: number;
+}
+/** What `exportJson` wrote */
+export interface JsonlDBJsonExportResult {
+	/** How many entries were exported */
+	entries: number;
+	/** How many entries were left out, because their value is `null` */
+	omittedNullValues: number;
+}
+/** What `importJson` changed */
+export interface JsonlDBJsonImportResult {
+	/** How many entries were set */
+	entries: number;
+	/** How many existing entries were deleted, because their imported value is `null` */
+	deletedNullValues: number;
+}
+/** A broken invariant that `selfCheck()` found */
+export interface JsonlDBSelfCheckViolation {
+	/** Which check failed: `"journal"`, `"counters"`, `"values"`, `"index"` or `"file"` */
+	check: string;
+	/** The affected entry, if the violation concerns a single one */
+	key?: string | undefined | null;
+	message: string;
+}
+/** What `selfCheck()` found */
+export interface JsonlDBSelfCheckReport {
+	/** Whether no violations were found */
+	ok: boolean;
+	/** How many entries were checked */
+	checkedEntries: number;
+	violations: Array<JsonlDBSelfCheckViolation>;
+}
+/** Cumulative statistics about `getMany` calls and writes since the DB was opened */
+export interface JsonlDBMetrics {
+	getManyCalls: number;
+	/** Calls with an object filter that was found in the index */
+	indexHits: number;
+	/** Calls with an object filter that was not found in the index */
+	indexMisses: number;
+	/** How many keys were considered in total */
+	candidates: number;
+	/** How many keys were looked up in total */
+	scanned: number;
+	/**
+	 * How many entries are currently held as JS objects, e.g. because they were read.
+	 * These can be released with `dematerialize` or `dematerializeAll`.
+	 */
+	references: number;
+	/**
+	 * How many objects of cleared entries are not released yet. They are released in chunks,
+	 * so clearing a huge DB doesn't block the main thread.
+	 */
+	pendingUnrefs: number;
+	/**
+	 * How many lines were appended to the DB file, including tombstones. Compressing doesn't
+	 * reset this, and the lines it writes are not counted.
+	 */
+	linesWritten: number;
+	/** How many of the lines written deleted an entry */
+	tombstonesWritten: number;
+	/** How often the DB file was truncated because the DB was cleared */
+	truncations: number;
+	/** How many bytes the lines written occupy */
+	bytesWritten: number;
+	/** How often the DB file was synced to disk, e.g. by `fsyncIdleMs`, `flush()` or compressing */
+	syncs: number;
+	/**
+	 * How often the persistence thread woke up to look for work. An idle DB only wakes up to refresh
+	 * its lockfile and for timed tasks like `autoCompress.intervalMs`.
+	 */
+	persistenceWakeups: number;
+	/**
+	 * How long the main thread, the persistence thread and compressing waited for each other.
+	 * Only measured with the `measureLockContention` option.
+	 */
+	lockContention?: JsonlDBLockContention | undefined | null;
+}
+/** How often one part of the DB had to wait for another to access the entries */
+export interface JsonlDBLockWaits {
+	/** How often the entries were accessed */
+	acquisitions: number;
+	/** How many of the accesses had to wait, because another thread was accessing the entries */
+	contended: number;
+	/** How long the accesses waited in total (ms) */
+	totalWaitMs: number;
+	/** The longest wait (ms) */
+	maxWaitMs: number;
+}
+/** The waits for access to the entries, by who was waiting */
+export interface JsonlDBLockContention {
+	/** Calls from JS */
+	main: JsonlDBLockWaits;
+	/** Writing changes to the DB file */
+	persistence: JsonlDBLockWaits;
+	/** Writing the compressed DB file */
+	compress: JsonlDBLockWaits;
 }
 export class JsonlDB {
 	constructor(filename: string, options?: JsonlDBOptions | undefined | null);
+	/** Checks whether the DB file is locked by an open DB, without opening it or acquiring the lock */
+	static isLocked(
+		filename: string,
+		options?: JsonlDBOptions | undefined | null,
+	): JsonlDBLockStatus;
+	/**
+	 * Parses a single line of a DB file the same way opening the DB does.
+	 * Returns `null` for empty lines, which opening the DB skips
+	 */
+	static parseLine(line: string): JsonlDBParsedLine | null;
+	/** Renders the line that sets the key to the serialized value, the same way writing the DB does */
+	static formatLine(key: string, valueJson: string): string;
 	open(): Promise<void>;
+	/** Sets the migration for the next call to `openWithMigration()` */
+	setMigration(
+		migrate: (
+			keys: string[],
+			valueJsonStrings: string[],
+			done: (
+				error: string | null,
+				changedKeys?: string[],
+				newValueJsonStrings?: (string | null)[],
+			) => void,
+		) => void,
+		version: number,
+	): void;
+	/**
+	 * Opens the DB, but passes all entries through the migration function first, unless the DB
+	 * already has its version. Resolves to whether the migration ran.
+	 */
+	openWithMigration(): Promise<boolean>;
+	reshard(shards: number): Promise<void>;
 	halfClose(): Promise<void>;
 	close(): void;
-	dump(filename: string): Promise<void>;
+	dump(
+		filename: string,
+		gzip?: boolean | undefined | null,
+	): Promise<JsonlDBDumpResult>;
 	compress(): Promise<void>;
+	createConsistentSnapshotFile(
+		filename: string,
+	): Promise<JsonlDBSnapshotResult>;
+	retryPersistence(): Promise<void>;
+	onFlush(
+		callback: (info: {
+			entries: number;
+			bytes: number;
+			durationMs: number;
+		}) => void,
+	): void;
+	offFlush(): void;
+	/** Registers the callback for lifecycle events. It is released when the DB is closed. */
+	setEventCallback(callback: (event: string, payload: any) => void): void;
+	clearEventCallback(): void;
+	/** The current state of the DB: `"closed"`, `"half-closed"` or `"open"` */
+	getState(): "closed" | "half-closed" | "open";
 	isOpen(): boolean;
+	/**
+	 * `undefined` is not converted to `null`, but deletes the key with the `undefinedIsDelete`
+	 * option and is refused otherwise
+	 */
 	setPrimitive(key: string, value: any): void;
+	setPrimitiveDurable(key: string, value: any): Promise<void>;
 	setObject(
 		key: string,
 		value: object,
 		stringified: string,
-		indexKeys: Array<string>,
+		indexKeys?: Array<string> | undefined | null,
+	): void;
+	setObjectDurable(
+		key: string,
+		value: object,
+		stringified: string,
+		indexKeys?: Array<string> | undefined | null,
+	): Promise<void>;
+	/**
+	 * Stores any JSON value without keeping a reference to the JS object.
+	 * The index keys are derived from the value.
+	 */
+	setNative(key: string, value: any): void;
+	/**
+	 * Like `setNative()`, but an absent or `undefined` value deletes the key with the
+	 * `undefinedIsDelete` option. Returns whether a value was stored.
+	 */
+	setMaybe(key: string, value?: any): boolean;
+	setObjectDetached(
+		key: string,
+		stringified: string,
+		indexKeys?: Array<string> | undefined | null,
 	): void;
 	delete(key: string): boolean;
+	deleteMatching(pattern: string, kind: "glob" | "regex"): number;
 	has(key: string): boolean;
 	get(key: string): unknown;
+	/**
+	 * Returns the serialized value of a large object that is not held as a JS object yet, so it can
+	 * be parsed without blocking the main thread for long. `null` for other entries.
+	 */
+	getLargeStringified(key: string, minBytes: number): Promise<string | null>;
+	/**
+	 * Keeps the object parsed from the result of `getLargeStringified` like `get` does and returns
+	 * the entry, which is another value if it was changed in the meantime
+	 */
+	adoptParsed(key: string, value: object, stringified: string): unknown;
+	getBatch(keys: Array<string>): unknown[];
+	getBatchEntries(keys: Array<string>): { key: string; value: unknown }[];
+	getBatchStringified(keys: Array<string>): Array<JsonlDBStringifiedEntry>;
+	/** Takes a snapshot of the matching keys, whose entries can then be read in batches */
+	createEntryCursor(
+		options?: JsonlDBEntryCursorOptions | undefined | null,
+	): number;
+	readEntryCursor(
+		id: number,
+		batchSize: number,
+	): Array<JsonlDBStringifiedEntry>;
+	closeEntryCursor(id: number): boolean;
 	getMany(
 		startKey: string,
 		endKey: string,
-		objFilter?: string | undefined | null,
+		objFilter?:
+			| string
+			| { path: string; value: string | number | boolean }
+			| undefined
+			| null,
+		sortKeys?: ((keys: string[]) => string[]) | undefined | null,
+		collation?: string | undefined | null,
 	): unknown[];
+	/**
+	 * Like `get_many`, but returns the results as a single JSON string, which is faster to transfer
+	 * than many small objects. The string is an object keyed by the entry keys if `keyed` is true.
+	 */
+	getManyStringified(
+		startKey: string,
+		endKey: string,
+		objFilter?:
+			| string
+			| { path: string; value: string | number | boolean }
+			| undefined
+			| null,
+		sortKeys?: ((keys: string[]) => string[]) | undefined | null,
+		collation?: string | undefined | null,
+		keyed?: boolean | undefined | null,
+	): string;
+	/** Like `get_many`, but also returns how the query was answered */
+	getManyExplain(
+		startKey: string,
+		endKey: string,
+		objFilter?:
+			| string
+			| { path: string; value: string | number | boolean }
+			| undefined
+			| null,
+		sortKeys?: ((keys: string[]) => string[]) | undefined | null,
+		collation?: string | undefined | null,
+	): { results: unknown[]; explain: JsonlDBQueryExplain };
+	clearDurable(): Promise<void>;
 	clear(): void;
 	get size(): number;
+	get keyCount(): number;
+	get path(): string;
+	get fileSizeBytes(): number;
+	get lockfilePath(): string;
+	get isLockLost(): boolean;
+	getMeta(key: string): JsonlDBEntryMeta | null;
+	setHeader(header?: string | undefined | null): void;
+	getHeader(): string | null;
+	getDeletedKeys(since?: number | undefined | null): Array<string>;
+	getIndexPaths(): Array<string>;
+	isIndexed(path: string): boolean;
+	getIndexSize(path: string): number;
+	registerPrefixCounter(prefix: string): boolean;
+	unregisterPrefixCounter(prefix: string): boolean;
+	getPrefixCount(prefix: string): number | null;
+	/** Updates the serialized value of an entry after its object was changed, so the change is persisted */
+	refreshSnapshot(key: string, stringified: string): boolean;
+	dematerialize(key: string): boolean;
+	materialize(
+		prefix: string | undefined | null,
+		start: number,
+		budgetMs: number,
+	): JsonlDBMaterializeProgress;
+	dematerializeAll(): number;
+	getMetrics(): JsonlDBMetrics;
+	resetMetrics(): void;
+	getStats(): JsonlDBStats;
+	getOpenDiagnostics(): JsonlDBOpenDiagnostics;
+	getMemoryUsage(): Promise<JsonlDBMemoryUsage>;
+	estimateCompress(): Promise<JsonlDBCompressEstimate>;
+	/**
+	 * Serializes the referenced objects that are about to be written for the next call to
+	 * `selfCheck()`. This needs the main thread, which `selfCheck()` doesn't run on.
+	 */
+	prepareSelfCheck(): void;
+	selfCheck(): Promise<JsonlDBSelfCheckReport>;
 	forEach(callback: (value: any, key: string) => void): void;
 	getKeys(): Array<string>;
+	getKeysPage(offset: number, limit: number): Array<string>;
+	getKeysStringifiedPage(offset: number, limit: number): string;
+	getKeysSorted(
+		sortKeys?: ((keys: string[]) => string[]) | undefined | null,
+	): Array<string>;
 	getKeysStringified(): string;
-	exportJson(filename: string, pretty: boolean): Promise<void>;
-	importJsonFile(filename: string): Promise<void>;
-	importJsonString(json: string): void;
+	exportJson(
+		filename: string,
+		pretty: boolean,
+		gzip?: boolean | undefined | null,
+		omitNullValues?: boolean | undefined | null,
+	): Promise<JsonlDBJsonExportResult>;
+	exportCsv(
+		filename: string,
+		options?: JsonlDBCsvExportOptions | undefined | null,
+	): Promise<void>;
+	/**
+	 * Reads and parses a CSV file in the background for `importLoaded()`, which imports it on the
+	 * main thread. Resolves to the id to pass to `importLoaded()`.
+	 */
+	loadCsvImport(
+		filename: string,
+		options?: JsonlDBCsvImportOptions | undefined | null,
+	): Promise<number>;
+	/** Like `loadCsvImport()`, but for a JSON file, which may be gzipped */
+	loadJsonImport(filename: string): Promise<number>;
+	/** Imports a file that was read by `loadCsvImport()` or `loadJsonImport()` */
+	importLoaded(
+		id: number,
+		viaCompress?: boolean | undefined | null,
+		nullMeansDelete?: boolean | undefined | null,
+	): JsonlDBJsonImportResult;
+	importJsonString(
+		json: string,
+		viaCompress?: boolean | undefined | null,
+		nullMeansDelete?: boolean | undefined | null,
+	): JsonlDBJsonImportResult;
 }
+/** Returns how many background tasks are currently alive. Only meant for debugging. */
+export function getLiveTaskCount(): number;
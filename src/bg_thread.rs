@@ -1,18 +1,183 @@
-use std::sync::Arc;
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Arc, Mutex,
+};
+
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use serde::Serialize;
 use tokio::{
-  sync::{mpsc::Sender, Notify},
+  sync::{mpsc::Sender, oneshot},
   task::JoinHandle,
 };
 
+use crate::db::HalfClosed;
 use crate::error::JsonlDBError;
+use crate::storage::SharedStorage;
+
+/// Settles a command once it is done, or tells the caller why it failed
+pub(crate) type Callback = oneshot::Sender<Result<(), JsonlDBError>>;
+
+// How many background tasks are currently alive. Used to detect leaks.
+static LIVE_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn live_tasks() -> usize {
+  LIVE_TASKS.load(Ordering::SeqCst)
+}
+
+/// Counts a background task as alive until it is dropped
+pub(crate) struct LiveTaskGuard;
+
+impl LiveTaskGuard {
+  pub fn new() -> Self {
+    LIVE_TASKS.fetch_add(1, Ordering::SeqCst);
+    Self
+  }
+}
+
+impl Drop for LiveTaskGuard {
+  fn drop(&mut self) {
+    LIVE_TASKS.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/// Information about data that was written to disk, passed to the onFlush callback
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FlushInfo {
+  pub entries: u32,
+  pub bytes: u32,
+  pub duration_ms: u32,
+}
+
+/// A copy of the DB file, created by the persistence thread
+#[derive(Debug)]
+pub(crate) struct SnapshotInfo {
+  // The combined size of the copied files
+  pub bytes: u64,
+  // How many entries the DB had when the copy was created
+  pub entries: usize,
+}
+
+/// A lifecycle event, passed to the event callback as `(name, payload)`
+#[derive(Debug)]
+pub(crate) struct DBEvent {
+  pub name: &'static str,
+  pub payload: serde_json::Value,
+}
+
+/// Asks the main thread to serialize the JS objects in the journal again before they are written
+pub(crate) struct ResnapshotRequest {
+  pub storage: SharedStorage,
+  pub done: oneshot::Sender<()>,
+}
+
+/// A batch of entries for the migration function of `openWithMigration()`, which runs on the main thread
+pub(crate) struct MigrationBatch {
+  pub keys: Vec<String>,
+  pub values: Vec<String>,
+  // The keys whose values the migration changed, with the new value or None to delete them.
+  // Or why it failed.
+  pub done: oneshot::Sender<Result<Vec<(String, Option<String>)>, String>>,
+}
+
+pub(crate) type FlushCallback = ThreadsafeFunction<FlushInfo, ErrorStrategy::Fatal>;
+pub(crate) type EventCallback = ThreadsafeFunction<DBEvent, ErrorStrategy::Fatal>;
+pub(crate) type ResnapshotCallback = ThreadsafeFunction<ResnapshotRequest, ErrorStrategy::Fatal>;
+pub(crate) type MigrateCallback = ThreadsafeFunction<MigrationBatch, ErrorStrategy::Fatal>;
+/// Releases the JS objects of a half-closed DB that was garbage-collected without calling `close()`
+pub(crate) type ReleaseCallback = ThreadsafeFunction<HalfClosed, ErrorStrategy::Fatal>;
 
-pub(crate) type Callback = Arc<Notify>;
+/// A JS callback, which can be (un)registered while the persistence thread is running
+pub(crate) struct SharedCallback<T: 'static>(
+  Arc<Mutex<Option<ThreadsafeFunction<T, ErrorStrategy::Fatal>>>>,
+);
+
+// Deriving these would require T to implement them too
+impl<T: 'static> Clone for SharedCallback<T> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+impl<T: 'static> Default for SharedCallback<T> {
+  fn default() -> Self {
+    Self(Arc::new(Mutex::new(None)))
+  }
+}
+
+impl<T: 'static> SharedCallback<T> {
+  pub fn set(&self, callback: Option<ThreadsafeFunction<T, ErrorStrategy::Fatal>>) {
+    if let Ok(mut cb) = self.0.lock() {
+      *cb = callback;
+    }
+  }
+
+  pub fn call(&self, value: T) {
+    if let Ok(cb) = self.0.lock() {
+      if let Some(cb) = cb.as_ref() {
+        cb.call(value, ThreadsafeFunctionCallMode::NonBlocking);
+      }
+    }
+  }
+}
+
+pub(crate) type SharedFlushCallback = SharedCallback<FlushInfo>;
+pub(crate) type SharedEventCallback = SharedCallback<DBEvent>;
+pub(crate) type SharedResnapshotCallback = SharedCallback<ResnapshotRequest>;
+
+impl SharedEventCallback {
+  pub fn emit(&self, name: &'static str, payload: serde_json::Value) {
+    self.call(DBEvent { name, payload });
+  }
+}
+
+/// Why the DB file is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompressTrigger {
+  Manual,
+  Size,
+  Interval,
+  Overwrites,
+  Tombstones,
+  OnOpen,
+  OnClose,
+  // A large import replaced the journal with a full rewrite
+  Import,
+}
+
+impl CompressTrigger {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      CompressTrigger::Manual => "manual",
+      CompressTrigger::Size => "size",
+      CompressTrigger::Interval => "interval",
+      CompressTrigger::Overwrites => "overwrites",
+      CompressTrigger::Tombstones => "tombstones",
+      CompressTrigger::OnOpen => "onOpen",
+      CompressTrigger::OnClose => "onClose",
+      CompressTrigger::Import => "import",
+    }
+  }
+}
 
 #[derive(Debug)]
 pub(crate) enum Command {
   Stop,
-  Dump { filename: String, done: Callback },
-  Compress { done: Option<Callback> },
+  Compress {
+    done: Option<Callback>,
+    trigger: CompressTrigger,
+  },
+  // Write everything in the journal and make sure it is on disk
+  Flush { done: Callback },
+  // Recreate the DB file after it disappeared and resume persisting
+  RetryPersistence {
+    done: oneshot::Sender<Result<(), JsonlDBError>>,
+  },
+  // Write everything in the journal, then copy the DB file to the target
+  Snapshot {
+    target: String,
+    done: oneshot::Sender<Result<SnapshotInfo, JsonlDBError>>,
+  },
 }
 
 pub(crate) struct ThreadHandle<T> {
@@ -22,7 +187,11 @@ pub(crate) struct ThreadHandle<T> {
 
 impl<T> ThreadHandle<T> {
   pub async fn stop_and_join(&mut self) -> Result<T, JsonlDBError> {
-    self.send_command(Command::Stop).await?;
+    if let Err(e) = self.send_command(Command::Stop).await {
+      // The task no longer listens to commands, make sure it does not live on
+      self.thread.abort();
+      return Err(e);
+    }
     self.thread.as_mut().await.or_else(|e| {
       Err(JsonlDBError::AsyncError {
         reason: "Joining the background task failed".to_owned(),
@@ -31,13 +200,17 @@ impl<T> ThreadHandle<T> {
     })
   }
 
-  pub async fn send_command(&mut self, cmd: Command) -> Result<(), JsonlDBError> {
-    self.tx.send(cmd).await.or_else(|e| {
-      Err(JsonlDBError::AsyncError {
-        reason: "Failed to send command to background task".to_owned(),
-        source: e.into(),
-      })
-    })?;
-    Ok(())
+  pub async fn send_command(&self, cmd: Command) -> Result<(), JsonlDBError> {
+    send_command(&self.tx, cmd).await
   }
 }
+
+pub(crate) async fn send_command(tx: &Sender<Command>, cmd: Command) -> Result<(), JsonlDBError> {
+  tx.send(cmd).await.or_else(|e| {
+    Err(JsonlDBError::AsyncError {
+      reason: "Failed to send command to background task".to_owned(),
+      source: e.into(),
+    })
+  })?;
+  Ok(())
+}
@@ -0,0 +1,139 @@
+use indexmap::IndexSet;
+use serde_json::Value;
+
+use crate::error::{JsonlDBError, Result};
+use crate::storage::check_key;
+
+// The column that holds non-object values when flattening
+const VALUE_COLUMN: &str = "value";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CsvValueMode {
+  // key,value with the value as compact JSON
+  Json,
+  // key and one column per property of shallow objects
+  Flatten,
+}
+
+impl TryFrom<&str> for CsvValueMode {
+  type Error = JsonlDBError;
+
+  fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+    match value {
+      "json" => Ok(CsvValueMode::Json),
+      "flatten" => Ok(CsvValueMode::Flatten),
+      other => Err(JsonlDBError::InvalidOptions {
+        source: anyhow::anyhow!("Unsupported CSV value mode \"{other}\""),
+      }),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CsvImportOptions {
+  pub key_column: String,
+  pub value_column: String,
+  pub value_is_json: bool,
+}
+
+impl Default for CsvImportOptions {
+  fn default() -> Self {
+    Self {
+      key_column: "key".to_owned(),
+      value_column: VALUE_COLUMN.to_owned(),
+      value_is_json: false,
+    }
+  }
+}
+
+fn to_cell(value: &Value) -> Result<String> {
+  match value {
+    // Strings are written as-is, everything else as JSON
+    Value::String(s) => Ok(s.to_owned()),
+    v => serde_json::to_string(v).map_err(|e| JsonlDBError::serde_to_string_failed(e)),
+  }
+}
+
+pub(crate) fn render_csv(entries: &[(String, Value)], mode: CsvValueMode) -> Result<Vec<u8>> {
+  let mut writer = csv::Writer::from_writer(Vec::new());
+
+  match mode {
+    CsvValueMode::Json => {
+      writer.write_record(["key", VALUE_COLUMN])?;
+      for (key, value) in entries {
+        let value =
+          serde_json::to_string(value).map_err(|e| JsonlDBError::serde_to_string_failed(e))?;
+        writer.write_record([key.as_str(), value.as_str()])?;
+      }
+    }
+    CsvValueMode::Flatten => {
+      // Collect the columns in the order they first appear
+      let mut columns = IndexSet::<String>::new();
+      for (_, value) in entries {
+        match value {
+          Value::Object(obj) => columns.extend(obj.keys().cloned()),
+          _ => {
+            columns.insert(VALUE_COLUMN.to_owned());
+          }
+        }
+      }
+
+      let mut header = vec!["key"];
+      header.extend(columns.iter().map(|c| c.as_str()));
+      writer.write_record(header)?;
+
+      for (key, value) in entries {
+        let mut row = vec![key.to_owned()];
+        for column in columns.iter() {
+          let cell = match value {
+            Value::Object(obj) => match obj.get(column) {
+              Some(v) => to_cell(v)?,
+              None => String::new(),
+            },
+            v if column == VALUE_COLUMN => to_cell(v)?,
+            _ => String::new(),
+          };
+          row.push(cell);
+        }
+        writer.write_record(row)?;
+      }
+    }
+  }
+
+  writer
+    .into_inner()
+    .map_err(|e| JsonlDBError::io_error_from_reason(format!("Could not render CSV: {e}")))
+}
+
+pub(crate) fn parse_csv(data: &[u8], options: &CsvImportOptions) -> Result<Vec<(String, Value)>> {
+  let mut reader = csv::Reader::from_reader(data);
+
+  let headers = reader.headers()?.clone();
+  let find_column = |name: &str| {
+    headers.iter().position(|h| h == name).ok_or_else(|| {
+      JsonlDBError::io_error_from_reason(format!("The CSV file has no column \"{name}\""))
+    })
+  };
+  let key_index = find_column(&options.key_column)?;
+  let value_index = find_column(&options.value_column)?;
+
+  let mut ret = Vec::new();
+  for (row, record) in reader.records().enumerate() {
+    let record = record?;
+    let key = record.get(key_index).unwrap_or_default().to_owned();
+    check_key(&key)?;
+    let value = record.get(value_index).unwrap_or_default();
+    let value = if options.value_is_json {
+      serde_json::from_str(value).map_err(|e| JsonlDBError::SerializeError {
+        // +2 because of the header and 1-based line numbers
+        reason: format!("Invalid JSON value in CSV row {}", row + 2),
+        source: e,
+      })?
+    } else {
+      Value::String(value.to_owned())
+    };
+    ret.push((key, value));
+  }
+
+  Ok(ret)
+}
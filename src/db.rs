@@ -1,43 +1,246 @@
-use std::path::Path;
-use std::sync::Arc;
-
-use indexmap::map::Entry;
-use napi::{JsObject, Ref};
-use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use indexmap::IndexMap;
+use napi::threadsafe_function::ThreadsafeFunctionCallMode;
+use napi::{JsFunction, JsObject, Ref};
+use serde::de::IgnoredAny;
+use serde_json::{json, Map, Value};
 use tokio::fs::{self, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{mpsc, Notify};
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
 
-use crate::bg_thread::{Command, ThreadHandle};
+use crate::bg_thread::{
+  send_command, Command, CompressTrigger, EventCallback, FlushCallback, LiveTaskGuard,
+  MigrateCallback, MigrationBatch, ResnapshotCallback, SharedEventCallback, SharedFlushCallback, SharedResnapshotCallback,
+  SnapshotInfo, ThreadHandle,
+};
+use crate::csv_io::{parse_csv, render_csv, CsvImportOptions, CsvValueMode};
 use crate::db_options::DBOptions;
 use crate::error::{JsonlDBError, Result};
-use crate::js_values::{value_to_js_object, JsValue};
-use crate::lockfile::Lockfile;
-use crate::persistence::persistence_thread;
+#[cfg(feature = "test-hooks")]
+use crate::fs_ops::CrashingFs;
+use crate::fs_ops::{FsOps, RealFs};
+use crate::js_values::{primitive_to_js, value_to_js_object, JsValue};
+use crate::key_matcher::KeyMatcher;
+use crate::key_ordering::Collation;
+use crate::lockfile::{LockStatus, Lockfile, ProcessLock, SharedLockfile};
+use crate::memory_usage::{entry_size, journal_size, value_size, MemoryUsage};
+use crate::persistence::{
+  compress_leftover_filenames, dump_to, persistence_thread, write_shards, OpenedShards,
+  CompressOutcome, PersistenceChannels, PersistenceContext, WriteCounters,
+};
+use crate::shards::ShardLayout;
 use crate::storage::{
-  drop_safe, parse_entries, DBEntry, Index, JournalEntry, SharedStorage, Storage,
+  check_key, drop_safe, filter_to_index_key, json_exceeds_depth, normalize_value_json,
+  parse_entries, parse_line, value_exceeds_depth, CompressEstimate, DBEntry, DeferredUnrefs,
+  EntryMeta, EntryMetaMap, Index, JournalEntry,
+  Key, LockContention, LockSite, LockWaits, OpenDiagnostics, ParsedEntries, PrefixCounters,
+  SharedStorage, Storage, WriteStats,
+};
+use crate::util::{
+  self, gunzip_if_needed, is_gzip_filename, parent_dir, replace_dirname, resolve_path,
 };
-use crate::util::{parent_dir, replace_dirname};
+
+// How long a lockfile may go without being updated before it is considered stale
+const LOCK_STALE_INTERVAL_MS: u128 = 10000;
+// How many entries are looked at while the storage is locked to estimate the memory usage
+const MEMORY_USAGE_CHUNK_SIZE: usize = 10000;
+// How many entries are looked at while the storage is locked to estimate the compressed file size
+const COMPRESS_ESTIMATE_CHUNK_SIZE: usize = 10000;
+// How many entries may be imported after a clear before the DB file is rewritten instead of
+// journaling each of them
+const IMPORT_REWRITE_THRESHOLD: usize = 10000;
+// How many imported entries are indexed at once
+const IMPORT_INDEX_CHUNK_SIZE: usize = 10000;
+// How many entries are materialized between checking whether the time budget is used up
+const MATERIALIZE_CLOCK_INTERVAL: usize = 64;
+// How many references of cleared entries are released at once
+const DEFERRED_UNREF_CHUNK_SIZE: usize = 5000;
+// How many entries or index keys are looked at while the storage is locked to check them
+const SELF_CHECK_CHUNK_SIZE: usize = 10000;
+// How many entries are passed to the migration function at once
+const MIGRATION_BATCH_SIZE: usize = 1000;
 
 pub(crate) struct RsonlDB<S: DBState> {
   pub filename: String,
   options: DBOptions,
+  // Receives lifecycle events. Unlike the other callbacks, this can be registered before opening
+  events: SharedEventCallback,
+  // Serializes the JS objects in the journal again before they are written, if that is enabled
+  resnapshot: SharedResnapshotCallback,
+  // Performs the critical filesystem operations. Can be replaced to simulate crashes
+  fs_ops: Arc<dyn FsOps>,
   pub state: S,
 }
 
 // Data that's only present in certain DB states
 pub(crate) struct Closed;
 
+#[derive(Clone)]
 pub(crate) struct HalfClosed {
   storage: SharedStorage,
+  deferred_unrefs: DeferredUnrefs,
+}
+
+impl HalfClosed {
+  /// Unrefs all native objects, including those of cleared entries that weren't released yet.
+  /// This has to happen on the Node.js thread.
+  pub fn release(&self, env: napi::Env) {
+    self.deferred_unrefs.release(env, usize::MAX);
+    let mut storage = self.storage.lock();
+    for entry in storage.entries.iter_mut() {
+      if let DBEntry::Reference(_, r) = entry.1 {
+        r.unref(env).ok();
+      }
+    }
+  }
 }
 
 pub(crate) struct Opened {
   storage: SharedStorage,
   index: Index,
   persistence_thread: ThreadHandle<()>,
-  compress_promise: Option<Arc<Notify>>,
+  // Holds the result of the running compress once it is done, including the compress on open.
+  // Behind a mutex, so compressing doesn't need exclusive access
+  compress_promise: Mutex<Option<watch::Receiver<CompressOutcome>>>,
   is_closing: bool,
+  // The runtime the persistence thread runs on, if it is not the shared one
+  runtime: Option<Runtime>,
+  // Prevents other instances in this process from opening the same file
+  process_lock: Option<ProcessLock>,
+  lockfile_path: PathBuf,
+  // Shared with the persistence thread, so it can be released even if that thread died
+  lockfile: SharedLockfile,
+  on_flush: SharedFlushCallback,
+  metrics: QueryMetrics,
+  // Updated by the persistence thread
+  write_counters: Arc<WriteCounters>,
+  // Only with the measure_lock_contention option
+  lock_contention: Option<Arc<LockContention>>,
+  // Set by the persistence thread when another process took over the lockfile
+  lock_lost: Arc<AtomicBool>,
+  open_diagnostics: OpenDiagnostics,
+  // References of cleared entries that still need to be released
+  deferred_unrefs: DeferredUnrefs,
+  // The open entry cursors by their id
+  cursors: HashMap<u32, EntryCursor>,
+  next_cursor_id: u32,
+}
+
+/// Iterates over a snapshot of the keys, so it stays valid when the DB is changed
+struct EntryCursor {
+  keys: Vec<Key>,
+  position: usize,
+}
+
+/// Selects the entries of a `get_many` call
+pub(crate) struct RangeQuery<'a> {
+  pub start_key: &'a str,
+  pub end_key: &'a str,
+  // Only entries whose value has this value at the indexed path, if given
+  pub obj_filter: Option<Value>,
+  // Sorts the keys of the results in JS instead of returning them in the stored order
+  pub sort_keys: Option<&'a JsFunction>,
+  pub collation: Collation,
+}
+
+/// How a single `get_many` call was answered
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct QueryExplain {
+  // Whether the object filter was resolved using the index
+  pub used_index: bool,
+  // How many keys were considered before limiting them to the range
+  pub candidates: usize,
+  // How many keys were within the range and looked up
+  pub scanned: usize,
+}
+
+/// What `export_json` wrote
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct JsonExportStats {
+  pub entries: usize,
+  // Entries that were left out, because their value is null
+  pub omitted_null_values: usize,
+}
+
+/// What importing a JSON object changed
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct JsonImportStats {
+  // How many entries were set
+  pub entries: usize,
+  // Existing entries that were deleted, because their imported value is null
+  pub deleted_null_values: usize,
+}
+
+/// A broken invariant that `self_check` found
+#[derive(Debug, Clone)]
+pub(crate) struct SelfCheckViolation {
+  // Which check failed: "journal", "counters", "values", "index" or "file"
+  pub check: &'static str,
+  // The affected entry, if the violation concerns a single one
+  pub key: Option<String>,
+  pub message: String,
+}
+
+/// What `self_check` found
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SelfCheckReport {
+  pub checked_entries: usize,
+  pub violations: Vec<SelfCheckViolation>,
+}
+
+impl SelfCheckReport {
+  fn push(&mut self, check: &'static str, key: Option<Key>, message: String) {
+    self.violations.push(SelfCheckViolation {
+      check,
+      key: key.map(|key| key.to_string()),
+      message,
+    });
+  }
+}
+
+/// Cumulative statistics about `get_many` calls
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct QueryMetrics {
+  pub get_many_calls: usize,
+  // Calls with an object filter that was found in the index
+  pub index_hits: usize,
+  // Calls with an object filter that was not found in the index, so all keys were considered
+  pub index_misses: usize,
+  pub candidates: usize,
+  pub scanned: usize,
+  // How many entries are currently held as JS objects. Not cumulative, counted when requested
+  pub references: usize,
+  // How many references of cleared entries are not released yet
+  pub pending_unrefs: usize,
+  // What the persistence thread wrote to the DB file. Counted by the thread itself
+  pub lines_written: usize,
+  pub tombstones_written: usize,
+  pub truncations: usize,
+  pub bytes_written: usize,
+  pub syncs: usize,
+  pub persistence_wakeups: usize,
+  // The waits for the storage lock by LockSite, if they are measured
+  pub lock_contention: Option<[LockWaits; 3]>,
+}
+
+impl QueryMetrics {
+  fn record(&mut self, has_filter: bool, explain: &QueryExplain) {
+    self.get_many_calls += 1;
+    if explain.used_index {
+      self.index_hits += 1;
+    } else if has_filter {
+      self.index_misses += 1;
+    }
+    self.candidates += explain.candidates;
+    self.scanned += explain.scanned;
+  }
 }
 
 // Turn Opened/Closed into DB states
@@ -60,25 +263,50 @@ impl DBState for HalfClosed {
   }
 }
 
+impl<S: DBState> RsonlDB<S> {
+  /// The absolute path of the DB file
+  pub fn path(&self) -> Result<PathBuf> {
+    resolve_path(&self.filename)
+  }
+
+  pub fn set_event_callback(&mut self, callback: Option<EventCallback>) {
+    self.events.set(callback);
+  }
+
+  pub fn set_resnapshot_callback(&mut self, callback: Option<ResnapshotCallback>) {
+    self.resnapshot.set(callback);
+  }
+}
+
 impl RsonlDB<Closed> {
+  /// Makes the filesystem operations of compressing, dumping and recovering fail from the given
+  /// step on, as if the process crashed there. Only meant for tests.
+  #[cfg(feature = "test-hooks")]
+  pub fn simulate_crash_at(&mut self, step: usize) {
+    self.fs_ops = Arc::new(CrashingFs::new(step));
+  }
+
   pub fn new(filename: String, options: DBOptions) -> Self {
     RsonlDB {
       filename,
       options,
+      events: SharedEventCallback::default(),
+      resnapshot: SharedResnapshotCallback::default(),
+      fs_ops: Arc::new(RealFs),
       state: Closed,
     }
   }
 
-  async fn try_recover_db_files(&self) -> Result<()> {
-    let filename = self.filename.to_owned();
-    let dump_filename = format!("{}.dump", &filename);
-    let backup_filename = format!("{}.bak", &filename);
+  async fn try_recover_db_files(&self, filename: &str) -> Result<()> {
+    let filename = filename.to_owned();
+    let leftovers = compress_leftover_filenames(&filename, &self.options)?;
 
     // During the compression, the following sequence of events happens:
     // 1. A .jsonl.dump file gets written with a compressed copy of the data
     // 2. Files get renamed: .jsonl -> .jsonl.bak, .jsonl.dump -> .jsonl
     // 3. .bak file gets removed
     // 4. Buffered data gets written to the .jsonl file
+    // The .dump and .bak suffixes are configurable, see compress_leftover_filenames
 
     // This means if the .jsonl file is absent or truncated, we should be able to pick either the .dump or the .bak file
     // and restore the .jsonl file from it
@@ -89,96 +317,275 @@ impl RsonlDB<Closed> {
 
     // Prefer the DB file if it exists, remove the others in case they exist
     if db_file_ok {
-      fs::remove_file(&backup_filename).await.ok();
-      fs::remove_file(&dump_filename).await.ok();
-      return Ok(());
-    }
-
-    // The backup file should have complete data - the dump file could be subject to an incomplete write
-    let mut bak_file_ok = false;
-    if let Ok(meta) = fs::metadata(&backup_filename).await {
-      bak_file_ok = meta.is_file() && meta.len() > 0;
-    }
-
-    if bak_file_ok {
-      // Overwrite the broken db file with it and delete the dump file
-      fs::rename(&backup_filename, &filename).await?;
-      fs::remove_file(&dump_filename).await.ok();
+      for leftover in &leftovers {
+        self.fs_ops.remove_file(Path::new(leftover)).await.ok();
+      }
       return Ok(());
     }
 
-    // Try the dump file as a last attempt
-    let mut dump_file_ok = false;
-    if let Ok(meta) = fs::metadata(&dump_filename).await {
-      dump_file_ok = meta.is_file() && meta.len() > 0;
-    }
+    // The backup files come first - they should have complete data, while the dump files could be
+    // subject to an incomplete write
+    for from in &leftovers {
+      let mut file_ok = false;
+      if let Ok(meta) = fs::metadata(from).await {
+        file_ok = meta.is_file() && meta.len() > 0;
+      }
 
-    if dump_file_ok {
-      // Overwrite the broken db file with it and delete the backup file
-      fs::rename(&dump_filename, &filename).await?;
-      fs::remove_file(&backup_filename).await.ok();
-      return Ok(());
+      if file_ok {
+        // Overwrite the broken db file with it and delete the other files
+        self
+          .fs_ops
+          .move_file(Path::new(from), Path::new(&filename))
+          .await?;
+        for leftover in leftovers.iter().filter(|leftover| *leftover != from) {
+          self.fs_ops.remove_file(Path::new(leftover)).await.ok();
+        }
+        self
+          .events
+          .emit("recovered", json!({ "filename": filename, "from": from }));
+        return Ok(());
+      }
     }
 
     Ok(())
   }
 
   pub async fn open(&self) -> Result<RsonlDB<Opened>> {
+    Ok(self.open_with_migration(None).await?.0)
+  }
+
+  /// Like `open`, but passes the entries through the migration before the persistence thread
+  /// starts, unless the DB already has its version. Also returns whether the migration ran.
+  pub async fn open_with_migration(
+    &self,
+    migration: Option<Migration>,
+  ) -> Result<(RsonlDB<Opened>, bool)> {
+    // A directory may have been created in place of the DB file since the DB was constructed
+    for shard_filename in ShardLayout::new(&self.filename, self.options.shards).filenames() {
+      util::ensure_not_directory(&shard_filename)?;
+    }
+
     // Make sure the DB dir exists
     let db_dir = parent_dir(&self.filename)?;
     fs::create_dir_all(&db_dir).await?;
 
+    // Make sure that no other instance in this process uses the same file
+    let process_lock = ProcessLock::acquire(resolve_path(&self.filename)?)?;
+
     // Try to acquire a lock on the DB
-    let lockfile_directory = match self.options.lockfile_directory.as_str() {
-      "." => &db_dir,
-      dir => Path::new(dir),
-    };
-    fs::create_dir_all(&lockfile_directory).await?;
-    let lockfile_name = replace_dirname(format!("{}.lock", &self.filename), lockfile_directory)
-      .ok_or_else(|| {
-        JsonlDBError::io_error_from_reason(format!(
-          "Could not determine lockfile name for \"{}\"",
-          &self.filename
-        ))
-      })?;
-    let mut lock = Lockfile::new(lockfile_name, 10000);
-    lock.lock()?;
+    let lockfile_name = lockfile_name(&self.filename, &self.options)?;
+    util::create_dir_all(parent_dir(&lockfile_name)?, self.options.file_mode)?;
+    let lockfile_path = resolve_path(&lockfile_name.to_string_lossy())?;
+    let mut lock = Lockfile::new(lockfile_name, LOCK_STALE_INTERVAL_MS, self.options.file_mode);
+    lock.lock().await?;
+    let lock = SharedLockfile::new(lock);
+
+    // Compressing writes its dump to the scratch directory, if one is configured
+    if let Some(scratch_directory) = &self.options.scratch_directory {
+      util::create_dir_all(db_dir.join(scratch_directory), self.options.file_mode)?;
+    }
+
+    // A dump that was interrupted before it could be moved to its target is useless
+    let export_filename = format!("{}.export", &self.filename);
+    self.fs_ops.remove_file(Path::new(&export_filename)).await.ok();
+    // The same goes for a partial copy of a file that was moved across devices
+    for shard_filename in ShardLayout::new(&self.filename, self.options.shards).filenames() {
+      let temp_filename = util::move_temp_filename(&shard_filename);
+      self.fs_ops.remove_file(&temp_filename).await.ok();
+    }
 
     // Make sure that there are no remains of a previous broken compress attempt
     // and restore a DB backup if it exists.
-    self.try_recover_db_files().await?;
-
-    let mut file = OpenOptions::new()
-      .create(true)
-      .read(true)
-      .write(true)
-      .open(&self.filename)
-      .await?;
+    let layout = ShardLayout::new(&self.filename, self.options.shards);
+    // Opening files of another layout would look like an empty DB and spread the keys over both
+    if let Some(found) = detect_shard_count(&self.filename).await? {
+      if found != layout.count() {
+        return Err(JsonlDBError::ShardCountMismatch {
+          found,
+          configured: layout.count(),
+        });
+      }
+    }
+    let mut files = Vec::with_capacity(layout.count());
+    let mut is_new_file = false;
+    for shard_filename in layout.filenames() {
+      self.try_recover_db_files(&shard_filename).await?;
+
+      is_new_file |= !fs::try_exists(&shard_filename).await?;
+      let file = util::open_options(self.options.file_mode)
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(&shard_filename)
+        .await?;
+      files.push(file);
+    }
+    // Make sure the directory entry of a new DB file survives a crash
+    if is_new_file {
+      util::fsync_dir(&db_dir).await?;
+    }
 
-    // Read the entire file. This also puts the cursor at the end, so we can start writing
-    let entries = parse_entries(&mut file, self.options.ignore_read_errors).await?;
-    let journal = Vec::<JournalEntry>::new();
+    // Read the entire files. This also puts the cursors at the end, so we can start writing
+    let (files, parsed) = if layout.is_sharded() {
+      parse_shards(files, &self.options).await?
+    } else {
+      let mut file = files.pop().unwrap();
+      let parsed = parse_entries(&mut file, &self.options).await?;
+      let lines = parsed.lines;
+      (vec![(file, lines)], parsed)
+    };
+    let ParsedEntries {
+      mut entries,
+      mut meta,
+      tombstones,
+      header,
+      mut migration_version,
+      lines,
+      diagnostics,
+    } = parsed;
+    let mut journal = Vec::<JournalEntry>::new();
+    let migrated = match migration {
+      Some(migration) if migration_version.map_or(true, |v| v < migration.version) => {
+        migrate_entries(&migration, &self.options, &mut entries, meta.as_mut(), &mut journal)
+          .await?;
+        migration_version = Some(migration.version);
+        journal.push(JournalEntry::Header);
+        true
+      }
+      _ => false,
+    };
     let mut index = Index::new(self.options.index_paths.clone());
     index.add_entries_checked(&entries);
+    // An index path that matches nothing is most likely a typo
+    if !entries.is_empty() {
+      for path in index.unused_paths() {
+        self.events.emit(
+          "warning",
+          json!({
+            "message": format!("The index path \"{path}\" does not match any entry"),
+            "source": "index",
+          }),
+        );
+      }
+    }
 
-    let storage = SharedStorage::new(Storage { entries, journal });
+    let prefix_counters = PrefixCounters::new(&self.options.prefix_counters, &entries);
+    let lock_contention = self
+      .options
+      .measure_lock_contention
+      .then(|| Arc::new(LockContention::default()));
+    let storage = SharedStorage::new(
+      Storage {
+        entries,
+        journal,
+        // The persistence thread continues counting from the actual file size
+        stats: WriteStats {
+          uncompressed_size: lines,
+          ..WriteStats::default()
+        },
+        coalesce_window_ms: self.options.throttle_fs.coalesce_window_ms,
+        max_buffered_commands: self.options.throttle_fs.max_buffered_commands,
+        journal_full: Arc::new(Notify::new()),
+        changed: Arc::new(Notify::new()),
+        wake_on_change: false,
+        pending_sets: HashMap::new(),
+        meta,
+        tombstones,
+        header,
+        migration_version,
+        needs_rewrite: false,
+        open_dumps: 0,
+        dump_tap: Vec::new(),
+        compress_epoch: 0,
+        prefix_counters,
+        stale_index_keys: Vec::new(),
+      },
+      lock_contention.clone(),
+    );
 
     let filename = self.filename.clone();
-    let opts = self.options.clone();
-    let shared_storage = storage.clone();
-
-    // Start the write thread
+    let mut opts = self.options.clone();
+    // Only compress on open if that frees up enough space
+    if let Some(threshold) = opts.auto_compress.on_open_if_wasted_bytes_exceed {
+      if diagnostics.wasted_bytes() > threshold {
+        opts.auto_compress.on_open = true;
+      }
+    }
+    let shared_storage = storage.at_site(LockSite::Persistence);
+    // Calling compress() before the compress on open is done waits for it instead of compressing again
+    let (compressed_on_open, compress_promise) = watch::channel(None);
+    let compress_promise = opts.auto_compress.on_open.then_some(compress_promise);
+
+    // Start the write thread, optionally on its own runtime
+    let runtime = match self.options.dedicated_runtime {
+      Some(worker_threads) => Some(
+        runtime::Builder::new_multi_thread()
+          .worker_threads(worker_threads)
+          .thread_name("rsonl-db")
+          .enable_all()
+          .build()?,
+      ),
+      None => None,
+    };
+    let on_flush = SharedFlushCallback::default();
+    let lock_lost = Arc::new(AtomicBool::new(false));
+    let write_counters = Arc::new(WriteCounters::default());
+    let lockfile = lock.clone();
     let (tx, rx) = mpsc::channel(32);
-    let thread = tokio::spawn(async move {
-      persistence_thread(&filename, file, shared_storage, lock, rx, &opts)
+    let (started, thread_started) = oneshot::channel();
+    let ctx = PersistenceContext {
+      filename,
+      opts,
+      storage: shared_storage,
+      lock,
+      fs_ops: self.fs_ops.clone(),
+      on_flush: on_flush.clone(),
+      events: self.events.clone(),
+      resnapshot: self.resnapshot.clone(),
+      lock_lost: lock_lost.clone(),
+      write_counters: write_counters.clone(),
+    };
+    let channels = PersistenceChannels {
+      rx,
+      compressed_on_open,
+      started,
+    };
+    // Count the task as alive right away, not only once it is first polled
+    let guard = LiveTaskGuard::new();
+    let task = async move {
+      let _guard = guard;
+      persistence_thread(ctx, OpenedShards { layout, files }, channels)
         .await
         .unwrap();
-    });
+    };
+    let thread = match &runtime {
+      Some(rt) => rt.spawn(task),
+      None => tokio::spawn(task),
+    };
+
+    // Only return an opened DB once the persistence thread is running. Otherwise, release the
+    // lockfile right away instead of leaving it to the dead thread.
+    let started = match thread_started.await {
+      Ok(result) => result,
+      // The thread panicked before it could report back
+      Err(_) => Err(JsonlDBError::other("The persistence thread could not be started")),
+    };
+    if let Err(e) = started {
+      thread.await.ok();
+      lockfile.lock().release();
+      if let Some(runtime) = runtime {
+        runtime.shutdown_background();
+      }
+      return Err(e);
+    }
 
     // Now change the state to Opened
-    Ok(RsonlDB {
+    let db = RsonlDB {
       filename: self.filename.to_owned(),
       options: self.options.clone(),
+      events: self.events.clone(),
+      resnapshot: self.resnapshot.clone(),
+      fs_ops: self.fs_ops.clone(),
       state: Opened {
         storage,
         index,
@@ -187,23 +594,115 @@ impl RsonlDB<Closed> {
           tx,
         },
         is_closing: false,
-        compress_promise: None,
+        compress_promise: Mutex::new(compress_promise),
+        runtime,
+        process_lock: Some(process_lock),
+        lockfile_path,
+        lockfile,
+        on_flush,
+        metrics: QueryMetrics::default(),
+        write_counters,
+        lock_contention,
+        lock_lost,
+        open_diagnostics: diagnostics,
+        deferred_unrefs: DeferredUnrefs::default(),
+        cursors: HashMap::new(),
+        next_cursor_id: 1,
       },
-    })
+    };
+    Ok((db, migrated))
+  }
+
+  /// Moves the entries of the closed DB into the given number of shards. The DB must not be
+  /// opened elsewhere. Afterwards, this instance opens the new layout.
+  pub async fn reshard(&mut self, shards: usize) -> Result<()> {
+    // Same as opening, nobody else may touch the files while they are rewritten
+    let _process_lock = ProcessLock::acquire(resolve_path(&self.filename)?)?;
+    let lockfile_name = lockfile_name(&self.filename, &self.options)?;
+    util::create_dir_all(parent_dir(&lockfile_name)?, self.options.file_mode)?;
+    let mut lock = Lockfile::new(lockfile_name, LOCK_STALE_INTERVAL_MS, self.options.file_mode);
+    lock.lock().await?;
+
+    // The files on disk may have been written with another number of shards than configured
+    let from_count = detect_shard_count(&self.filename)
+      .await?
+      .unwrap_or(self.options.shards);
+    let from = ShardLayout::new(&self.filename, from_count);
+    let to = ShardLayout::new(&self.filename, shards);
+    if from.count() == to.count() {
+      self.options.shards = to.count();
+      return Ok(());
+    }
+
+    let mut files = Vec::with_capacity(from.count());
+    for filename in from.filenames() {
+      self.try_recover_db_files(&filename).await?;
+      if fs::try_exists(&filename).await? {
+        files.push(OpenOptions::new().read(true).open(&filename).await?);
+      }
+    }
+    // Without any files, the new layout is created when opening the DB
+    if files.is_empty() {
+      self.options.shards = to.count();
+      return Ok(());
+    }
+    let (
+      _,
+      ParsedEntries {
+        entries,
+        meta,
+        tombstones,
+        header,
+        migration_version,
+        ..
+      },
+    ) = parse_shards(files, &self.options).await?;
+
+    let mut storage = SharedStorage::new(
+      Storage {
+        entries,
+        journal: Vec::new(),
+        stats: WriteStats::default(),
+        coalesce_window_ms: self.options.throttle_fs.coalesce_window_ms,
+        max_buffered_commands: self.options.throttle_fs.max_buffered_commands,
+        journal_full: Arc::new(Notify::new()),
+        changed: Arc::new(Notify::new()),
+        wake_on_change: false,
+        pending_sets: HashMap::new(),
+        meta,
+        tombstones,
+        header,
+        migration_version,
+        needs_rewrite: false,
+        open_dumps: 0,
+        dump_tap: Vec::new(),
+        compress_epoch: 0,
+        prefix_counters: PrefixCounters::default(),
+        stale_index_keys: Vec::new(),
+      },
+      None,
+    );
+    write_shards(&to, &mut storage, &self.options, self.fs_ops.as_ref()).await?;
+
+    // The files of the old layout are now redundant
+    let new_filenames = to.filenames();
+    for filename in from.filenames() {
+      if !new_filenames.contains(&filename) {
+        self.fs_ops.remove_file(Path::new(&filename)).await.ok();
+      }
+    }
+    util::fsync_dir(&parent_dir(&self.filename)?).await?;
+
+    self.options.shards = to.count();
+    Ok(())
   }
 }
 
 impl RsonlDB<HalfClosed> {
   pub fn close(&mut self, env: napi::Env) -> Result<RsonlDB<Closed>> {
-    {
-      // Unref all native objects
-      let mut storage = self.state.storage.lock();
-      for entry in storage.entries.iter_mut() {
-        if let DBEntry::Reference(_, r) = entry.1 {
-          r.unref(env).ok();
-        }
-      }
-    }
+    self.state.release(env);
+    // Release the event callback, so it doesn't keep the event loop alive
+    self.events.set(None);
 
     // Free memory
     drop(&self.state);
@@ -211,38 +710,138 @@ impl RsonlDB<HalfClosed> {
     Ok(RsonlDB {
       options: self.options.clone(),
       filename: self.filename.to_owned(),
+      events: self.events.clone(),
+      resnapshot: self.resnapshot.clone(),
+      fs_ops: self.fs_ops.clone(),
       state: Closed,
     })
   }
+
+  /// For when the DB is garbage-collected without calling `close()`. Returns what needs to be
+  /// released on the Node.js thread instead.
+  pub fn abandon(&self) -> HalfClosed {
+    self.events.emit(
+      "warning",
+      json!({
+        "message": format!(
+          "The DB \"{}\" was garbage-collected without calling close() after halfClose()",
+          self.filename
+        ),
+        "source": "close",
+      }),
+    );
+    self.state.clone()
+  }
 }
 
 impl RsonlDB<Opened> {
   pub async fn close(&mut self) -> Result<RsonlDB<HalfClosed>> {
     // Compress if that is desired
-    if self.options.auto_compress.on_close {
-      self.compress().await?;
+    // Failing to compress must not keep the DB from closing, the changes are written anyways
+    if self.options.auto_compress.on_close && !self.state.persistence_thread.thread.is_finished() {
+      if let Err(e) = self.compress(CompressTrigger::OnClose).await {
+        self.events.emit(
+          "error",
+          json!({ "message": e.to_string(), "source": "compress" }),
+        );
+      }
     }
 
     self.state.is_closing = true;
 
-    // End the all threads and wait for them to end
-    self.state.persistence_thread.stop_and_join().await?;
+    // End the all threads and wait for them to end. If the persistence thread already died, there
+    // is nothing to stop. Closing must not fail then, or the DB could never be opened again.
+    if let Err(e) = self.state.persistence_thread.stop_and_join().await {
+      self.events.emit(
+        "error",
+        json!({ "message": e.to_string(), "source": "persistence" }),
+      );
+    }
+    // A dead persistence thread may not have released the lockfile
+    self.state.lockfile.lock().release();
+
+    // Dropping a runtime blocks, so let it shut down in the background
+    if let Some(runtime) = self.state.runtime.take() {
+      runtime.shutdown_background();
+    }
+
+    // Release the callbacks, so they don't keep the event loop alive. The event callback is kept
+    // until the DB is closed for good, so it can still warn if that never happens. It does not
+    // keep the event loop alive anyways.
+    self.state.on_flush.set(None);
+
+    // The file may now be opened by other instances
+    self.state.process_lock.take();
 
     // Change DB state to half-closed
     // Freeing memory has to happen on the Node.js thread
     Ok(RsonlDB {
       options: self.options.clone(),
       filename: self.filename.to_owned(),
+      events: self.events.clone(),
+      resnapshot: self.resnapshot.clone(),
+      fs_ops: self.fs_ops.clone(),
       state: HalfClosed {
         storage: self.state.storage.to_owned(),
+        deferred_unrefs: self.state.deferred_unrefs.clone(),
       },
     })
   }
 
-  pub fn set_native(&mut self, env: napi::Env, key: String, value: serde_json::Value) {
+  pub fn set_native(
+    &mut self,
+    env: napi::Env,
+    key: String,
+    value: serde_json::Value,
+  ) -> Result<()> {
+    check_key(&key)?;
+    if value_exceeds_depth(&value, self.options.max_value_depth) {
+      return Err(self.too_deep(key));
+    }
+    let key = self.state.storage.intern(key);
     self.state.index.add_value_checked(&key, &value);
     let old = self.state.storage.insert(key, DBEntry::Native(value));
     drop_safe(env, old);
+    Ok(())
+  }
+
+  /// Refuses serialized values that are nested deeper than the `max_value_depth` option
+  pub fn check_value_depth(&self, key: &str, stringified: &str) -> Result<()> {
+    if json_exceeds_depth(stringified, self.options.max_value_depth) {
+      return Err(self.too_deep(key.to_owned()));
+    }
+    Ok(())
+  }
+
+  pub fn max_value_depth(&self) -> usize {
+    self.options.max_value_depth
+  }
+
+  fn too_deep(&self, key: String) -> JsonlDBError {
+    JsonlDBError::TooDeep {
+      key,
+      max_depth: self.options.max_value_depth,
+    }
+  }
+
+  /// Replaces or removes the application-defined DB header, which must be valid JSON
+  pub fn set_header(&mut self, header: Option<String>) -> Result<()> {
+    if let Some(header) = header.as_deref() {
+      serde_json::from_str::<IgnoredAny>(header).map_err(|e| JsonlDBError::SerializeError {
+        reason: "The DB header is not valid JSON".to_owned(),
+        source: e,
+      })?;
+    }
+    self.state.storage.set_header(header);
+    Ok(())
+  }
+
+  /// Uses the given index keys if there are any, otherwise derives them from the serialized value
+  fn update_index(&mut self, key: &Key, stringified: &str, index_keys: Option<Vec<String>>) {
+    match index_keys {
+      Some(index_keys) => self.state.index.add_many(key, index_keys),
+      None => self.state.index.add_stringified_checked(key, stringified),
+    }
   }
 
   pub fn set_reference(
@@ -251,14 +850,66 @@ impl RsonlDB<Opened> {
     key: String,
     obj: Ref<()>,
     stringified: String,
-    index_keys: Vec<String>,
-  ) {
-    self.state.index.add_many(&key, index_keys);
+    index_keys: Option<Vec<String>>,
+  ) -> Result<()> {
+    if let Err(e) = check_key(&key) {
+      // The reference must be released, even though it is not stored
+      drop_safe(env, Some(DBEntry::Reference(stringified, obj)));
+      return Err(e);
+    }
+    let key = self.state.storage.intern(key);
+    self.update_index(&key, &stringified, index_keys);
     let old = self
       .state
       .storage
       .insert(key, DBEntry::Reference(stringified, obj));
     drop_safe(env, old);
+    Ok(())
+  }
+
+  pub fn set_stringified(
+    &mut self,
+    env: napi::Env,
+    key: String,
+    stringified: String,
+    index_keys: Option<Vec<String>>,
+  ) -> Result<()> {
+    check_key(&key)?;
+    // Make sure we never write invalid JSON to the DB file
+    serde_json::from_str::<IgnoredAny>(&stringified).map_err(|e| JsonlDBError::SerializeError {
+      reason: format!("The value for key \"{key}\" is not valid JSON"),
+      source: e,
+    })?;
+    self.check_value_depth(&key, &stringified)?;
+
+    let key = self.state.storage.intern(key);
+    self.update_index(&key, &stringified, index_keys);
+    let old = self
+      .state
+      .storage
+      .insert(key, DBEntry::Stringified(stringified));
+    drop_safe(env, old);
+    Ok(())
+  }
+
+  /// Updates the serialized value of an entry that is held as a JS object, after the object was
+  /// changed. Returns whether the entry is held as a JS object.
+  pub fn refresh_snapshot(&mut self, key: &str, stringified: String) -> Result<bool> {
+    // Make sure we never write invalid JSON to the DB file
+    serde_json::from_str::<IgnoredAny>(&stringified).map_err(|e| JsonlDBError::SerializeError {
+      reason: format!("The value for key \"{key}\" is not valid JSON"),
+      source: e,
+    })?;
+    self.check_value_depth(key, &stringified)?;
+
+    let key = match self.state.storage.refresh_snapshot(key, stringified) {
+      Some(key) => key,
+      None => return Ok(false),
+    };
+    if let Some(DBEntry::Reference(str, _)) = self.state.storage.lock().entries.get(&key) {
+      self.state.index.add_stringified_checked(&key, str);
+    }
+    Ok(true)
   }
 
   pub fn delete(&mut self, env: napi::Env, key: String) -> bool {
@@ -266,153 +917,936 @@ impl RsonlDB<Opened> {
       return false;
     };
 
-    self.state.index.remove(&key);
-    let old = self.state.storage.remove(key);
+    let old = self.remove_existing(&key);
     drop_safe(env, old);
     true
   }
 
-  pub fn clear(&mut self, env: napi::Env) {
-    self.state.index.clear();
-    let old = self.state.storage.clear();
+  /// Handles a value that was `undefined` in JS. With the `undefined_is_delete` option, the entry
+  /// is deleted and the return value tells whether it existed. Otherwise the value is refused.
+  pub fn set_undefined(&mut self, env: napi::Env, key: String) -> Result<bool> {
+    if !self.options.undefined_is_delete {
+      return Err(JsonlDBError::UndefinedValue(key));
+    }
+    Ok(self.delete(env, key))
+  }
+
+  /// Removes an entry that is known to exist from the index and the storage
+  fn remove_existing(&mut self, key: &str) -> Option<DBEntry> {
+    self.state.index.remove(key);
+    if self.options.soft_delete_retain_ms.is_some() {
+      self.state.storage.soft_remove(key)
+    } else {
+      self.state.storage.remove(key)
+    }
+  }
+
+  /// Deletes all entries whose keys match the given pattern and returns how many were deleted
+  pub fn delete_matching(&mut self, env: napi::Env, pattern: &str, kind: &str) -> Result<u32> {
+    // Compile the pattern first, so invalid patterns don't delete anything
+    let matcher = KeyMatcher::new(pattern, kind)?;
+    let keys: Vec<Key> = self
+      .state
+      .storage
+      .lock()
+      .entries
+      .keys()
+      .filter(|key| matcher.is_match(key))
+      .cloned()
+      .collect();
 
-    for e in old {
-      drop_safe(env, Some(e));
+    let removed = self.remove_many(&keys);
+    let deleted = removed.len() as u32;
+    for entry in removed {
+      drop_safe(env, Some(entry));
     }
+    Ok(deleted)
   }
 
-  pub fn has(&mut self, key: &String) -> bool {
-    self.state.storage.lock().entries.contains_key(key)
+  /// Removes many existing entries at once from the index and the storage
+  fn remove_many(&mut self, keys: &[Key]) -> Vec<DBEntry> {
+    for key in keys {
+      self.state.index.remove(key);
+    }
+    let keys: HashSet<&str> = keys.iter().map(|key| &**key).collect();
+    let soft = self.options.soft_delete_retain_ms.is_some();
+    self.state.storage.remove_many(&keys, soft)
   }
 
-  pub fn get(&mut self, env: napi::Env, key: &str) -> Result<Option<JsValue>> {
-    let entries = &mut self.state.storage.lock().entries;
-    let mut entry = entries.entry(key.to_owned());
+  pub fn clear(&mut self, env: napi::Env) -> Result<()> {
+    self.state.index.clear();
+    let old = self.state.storage.clear();
 
-    get_or_convert_entry(env, &mut entry)
+    // Releasing many references at once blocks the main thread, so that happens in chunks
+    if self.state.deferred_unrefs.push(old) {
+      release_deferred_unrefs(env, self.state.deferred_unrefs.clone())?;
+    }
+    Ok(())
   }
 
-  pub fn get_many(
-    &mut self,
-    env: napi::Env,
-    start_key: &str,
-    end_key: &str,
-    obj_filter: Option<String>,
-  ) -> Result<Vec<JsValue>> {
-    let mut ret = Vec::new();
+  pub fn has(&self, key: &String) -> bool {
+    self.state.storage.lock().entries.contains_key(key.as_str())
+  }
 
+  pub fn get(&self, env: napi::Env, key: &str) -> Result<Option<JsValue>> {
     let entries = &mut self.state.storage.lock().entries;
+    entries
+      .get_mut(key)
+      .map(|entry| get_or_convert_entry(env, entry))
+      .transpose()
+  }
 
-    let mut keys: Vec<String> = { entries.keys().cloned().into_iter().collect() };
+  /// Returns the serialized value of an object or array that is at least `min_bytes` large and not
+  /// held as a JS object yet. Converting it would block the main thread for long, parsing it with
+  /// `JSON.parse` is much faster. Other entries are cheap to get, so they are `None`.
+  pub fn get_large_stringified(&self, key: &str, min_bytes: usize) -> Result<Option<String>> {
+    let mut storage = self.state.storage.lock();
+    let entry = match storage.entries.get_mut(key) {
+      Some(entry) => entry,
+      None => return Ok(None),
+    };
+    match entry {
+      DBEntry::Stringified(str) if str.len() >= min_bytes && str.starts_with(&['{', '['][..]) => {
+        Ok(Some(str.clone()))
+      }
+      DBEntry::Native(val)
+        if (val.is_array() || val.is_object()) && value_size(val) >= min_bytes =>
+      {
+        // Keep the serialized value, so the parsed object can be adopted like the others
+        let stringified =
+          serde_json::to_string(val).map_err(JsonlDBError::serde_to_string_failed)?;
+        *entry = DBEntry::Stringified(stringified.clone());
+        Ok(Some(stringified))
+      }
+      _ => Ok(None),
+    }
+  }
 
-    // If a filter is given, check if we have index entries that match it
-    if let Some(obj_filter) = obj_filter {
-      if let Some(index_keys) = self.state.index.get_keys(&obj_filter) {
-        keys = index_keys;
+  /// Keeps the JS object that was parsed from the result of `get_large_stringified`, like `get`
+  /// keeps the objects it converts, and returns the entry like `get` does. If the entry was changed
+  /// in the meantime, the object is discarded.
+  pub fn adopt_parsed(
+    &self,
+    env: napi::Env,
+    key: &str,
+    value: JsObject,
+    stringified: String,
+  ) -> Result<Option<JsValue>> {
+    {
+      let mut storage = self.state.storage.lock();
+      if let Some(entry) = storage.entries.get_mut(key) {
+        if matches!(entry, DBEntry::Stringified(str) if *str == stringified) {
+          let reference = env.create_reference(&value)?;
+          *entry = DBEntry::Reference(stringified, reference);
+        }
       }
     }
+    self.get(env, key)
+  }
 
-    // Limit the results to the start_key...end_key range
-    keys = keys
+  /// Looks up the given keys at once. The results are in the same order as the keys, missing keys
+  /// are `None`.
+  pub fn get_batch(&self, env: napi::Env, keys: &[String]) -> Result<Vec<Option<JsValue>>> {
+    let entries = &mut self.state.storage.lock().entries;
+    keys
       .iter()
-      .filter(|key| key.as_str().ge(start_key) && key.as_str().le(end_key))
-      .map(|k| k.to_owned())
-      .collect();
+      .map(|key| {
+        entries
+          .get_mut(key.as_str())
+          .map(|entry| get_or_convert_entry(env, entry))
+          .transpose()
+      })
+      .collect()
+  }
 
+  /// Like `get_batch`, but returns the keys with their values and skips missing keys
+  pub fn get_batch_entries(
+    &self,
+    env: napi::Env,
+    keys: Vec<String>,
+  ) -> Result<Vec<(String, JsValue)>> {
+    let entries = &mut self.state.storage.lock().entries;
+    let mut ret = Vec::with_capacity(keys.len());
     for key in keys {
-      let mut entry = entries.entry(key.to_owned());
-
-      if let Some(v) = get_or_convert_entry(env, &mut entry)? {
-        ret.push(v);
+      if let Some(entry) = entries.get_mut(key.as_str()) {
+        let value = get_or_convert_entry(env, entry)?;
+        ret.push((key, value));
       }
     }
     Ok(ret)
   }
 
-  pub fn size(&mut self) -> usize {
-    self.state.storage.lock().entries.len()
-  }
-
-  pub fn all_keys(&mut self) -> Vec<String> {
+  /// Returns the given keys with their values serialized as JSON and skips missing keys.
+  /// Unlike `get_batch`, this does not create JS objects for the values.
+  pub fn get_batch_stringified(&self, keys: Vec<String>) -> Vec<(String, String)> {
     let entries = &self.state.storage.lock().entries;
-    entries.keys().cloned().collect()
+    keys
+      .into_iter()
+      .filter_map(|key| {
+        let value: String = entries.get(key.as_str())?.into();
+        Some((key, value))
+      })
+      .collect()
   }
 
-  pub async fn dump(&mut self, filename: &str) -> Result<()> {
-    // Don't do anything while the DB is being closed
-    if self.state.is_closing {
-      return Ok(());
-    }
-
-    // Send command to the persistence thread
-    let notify = Arc::new(Notify::new());
-    self
+  /// Takes a snapshot of the keys in the `start...end` range that start with `prefix` and returns
+  /// the id of a cursor to read their entries with
+  pub fn create_entry_cursor(
+    &mut self,
+    start: Option<&str>,
+    end: Option<&str>,
+    prefix: Option<&str>,
+    collation: Collation,
+  ) -> u32 {
+    let ordering = self.options.key_ordering;
+    let keys: Vec<Key> = self
       .state
-      .persistence_thread
-      .send_command(Command::Dump {
-        filename: filename.to_owned(),
-        done: notify.clone(),
+      .storage
+      .lock()
+      .entries
+      .keys()
+      .filter(|key| {
+        start.map_or(true, |start| {
+          ordering.compare_collated(key, start, collation) != std::cmp::Ordering::Less
+        }) && end.map_or(true, |end| {
+          ordering.compare_collated(key, end, collation) != std::cmp::Ordering::Greater
+        })
+          && prefix.map_or(true, |prefix| key.starts_with(prefix))
       })
-      .await?;
-
-    // and wait until it is done
-    notify.notified().await;
+      .cloned()
+      .collect();
 
-    Ok(())
+    let id = self.state.next_cursor_id;
+    self.state.next_cursor_id = id.wrapping_add(1).max(1);
+    self.state.cursors.insert(id, EntryCursor { keys, position: 0 });
+    id
   }
 
-  pub async fn compress(&mut self) -> Result<()> {
-    // Don't do anything while the DB is being closed
-    if self.state.is_closing {
-      return Ok(());
-    }
-
-    // Don't compress twice in parallel and block all further calls
-    if let Some(notify) = self.state.compress_promise.as_ref() {
-      notify.clone().notified().await;
-      return Ok(());
-    } else {
-      let notify = Arc::new(Notify::new());
-      self.state.compress_promise = Some(notify.clone());
-
-      // Send command to the persistence thread
-      self
-        .state
-        .persistence_thread
-        .send_command(Command::Compress {
-          done: Some(notify.clone()),
-        })
-        .await?;
-
-      // and wait until it is done
-      notify.clone().notified().await;
-
-      self.state.compress_promise = None;
+  /// Returns the next entries of a cursor with their values serialized as JSON.
+  /// Entries that were deleted since creating the cursor are skipped. An empty result means the
+  /// cursor is exhausted.
+  pub fn read_entry_cursor(&mut self, id: u32, batch_size: usize) -> Result<Vec<(String, String)>> {
+    let cursor = self
+      .state
+      .cursors
+      .get_mut(&id)
+      .ok_or_else(|| JsonlDBError::other(&format!("Unknown entry cursor {id}")))?;
+    let entries = &self.state.storage.lock().entries;
+    let mut ret = Vec::new();
+    while ret.len() < batch_size && cursor.position < cursor.keys.len() {
+      let key = &cursor.keys[cursor.position];
+      cursor.position += 1;
+      if let Some(entry) = entries.get(key) {
+        ret.push((key.to_string(), entry.into()));
+      }
     }
+    Ok(ret)
+  }
 
-    Ok(())
+  /// Releases the key snapshot of a cursor. Returns whether the cursor existed.
+  pub fn close_entry_cursor(&mut self, id: u32) -> bool {
+    self.state.cursors.remove(&id).is_some()
   }
 
-  pub async fn export_json(&mut self, filename: &str, pretty: bool) -> Result<()> {
-    let mut file = OpenOptions::new()
-      .create(true)
+  pub fn get_many(
+    &mut self,
+    env: napi::Env,
+    query: &RangeQuery,
+  ) -> Result<(Vec<JsValue>, QueryExplain)> {
+    let mut ret = Vec::new();
+    let ordering = self.options.key_ordering;
+    let mut explain = QueryExplain::default();
+    let RangeQuery {
+      start_key,
+      end_key,
+      collation,
+      ..
+    } = *query;
+    let keys = self.query_keys(env, query, &mut explain)?;
+
+    let entries = &mut self.state.storage.lock().entries;
+    match keys {
+      Some(keys) => {
+        for key in keys {
+          if let Some(entry) = entries.get_mut(key.as_str()) {
+            ret.push(get_or_convert_entry(env, entry)?);
+          }
+        }
+      }
+      None => {
+        // Walk the entries once and convert them in place.
+        // This avoids copying every key and looking it up again.
+        explain.candidates = entries.len();
+        for (key, entry) in entries.iter_mut() {
+          if ordering.in_range(key, start_key, end_key, collation) {
+            ret.push(get_or_convert_entry(env, entry)?);
+          }
+        }
+        explain.scanned = ret.len();
+      }
+    }
+    self.state.metrics.record(query.obj_filter.is_some(), &explain);
+    Ok((ret, explain))
+  }
+
+  /// Like `get_many`, but serializes the results into a single JSON string without converting
+  /// the values to JS. The result is an object keyed by the entry keys if `keyed` is true,
+  /// otherwise an array.
+  pub fn get_many_stringified(
+    &mut self,
+    env: napi::Env,
+    query: &RangeQuery,
+    keyed: bool,
+  ) -> Result<String> {
+    let mut ret = String::from(if keyed { "{" } else { "[" });
+    let ordering = self.options.key_ordering;
+    let mut explain = QueryExplain::default();
+    let RangeQuery {
+      start_key,
+      end_key,
+      collation,
+      ..
+    } = *query;
+    let keys = self.query_keys(env, query, &mut explain)?;
+
+    let entries = &self.state.storage.lock().entries;
+    match keys {
+      Some(keys) => {
+        for key in keys {
+          if let Some(entry) = entries.get(key.as_str()) {
+            push_stringified(&mut ret, keyed.then(|| key.as_str()), entry);
+          }
+        }
+      }
+      None => {
+        explain.candidates = entries.len();
+        for (key, entry) in entries.iter() {
+          if ordering.in_range(key, start_key, end_key, collation) {
+            push_stringified(&mut ret, keyed.then(|| &**key), entry);
+            explain.scanned += 1;
+          }
+        }
+      }
+    }
+    self.state.metrics.record(query.obj_filter.is_some(), &explain);
+    ret.push(if keyed { '}' } else { ']' });
+    Ok(ret)
+  }
+
+  /// Selects the keys in the `start_key...end_key` range in the order they are returned by range
+  /// queries. Returns `None` if there is neither an index hit nor a custom sort order, so the
+  /// entries can be walked in the order they are stored in.
+  fn query_keys(
+    &mut self,
+    env: napi::Env,
+    query: &RangeQuery,
+    explain: &mut QueryExplain,
+  ) -> Result<Option<Vec<String>>> {
+    let ordering = self.options.key_ordering;
+    let RangeQuery {
+      start_key,
+      end_key,
+      sort_keys,
+      collation,
+      ..
+    } = *query;
+
+    // If a filter is given, check if we have index entries that match it
+    let obj_filter = match &query.obj_filter {
+      Some(obj_filter) => filter_to_index_key(obj_filter)?,
+      None => None,
+    };
+    if obj_filter.is_some() {
+      self.update_stale_index_keys();
+    }
+    let index_keys = obj_filter.and_then(|obj_filter| self.state.index.get_keys(&obj_filter));
+    explain.used_index = index_keys.is_some();
+
+    let mut keys: Vec<String> = match (index_keys, sort_keys) {
+      (Some(mut index_keys), _) => {
+        // The index has no order. Return the results in the same order as without an index,
+        // so repeated queries are stable
+        let entries = &self.state.storage.lock().entries;
+        index_keys.sort_by_cached_key(|key| entries.get_index_of(key.as_str()));
+        index_keys
+      }
+      (None, Some(_)) => self.all_keys(),
+      (None, None) => return Ok(None),
+    };
+    explain.candidates = keys.len();
+
+    // Limit the results to the start_key...end_key range
+    keys.retain(|key| ordering.in_range(key, start_key, end_key, collation));
+    explain.scanned = keys.len();
+
+    // The storage must not be locked while calling into JS
+    if let Some(sort_keys) = sort_keys {
+      keys = sort_keys_with(env, keys, sort_keys)?;
+    }
+    Ok(Some(keys))
+  }
+
+  pub fn index_paths(&self) -> Vec<String> {
+    self.state.index.paths().to_vec()
+  }
+
+  pub fn is_indexed(&self, path: &str) -> bool {
+    self.state.index.is_indexed(path)
+  }
+
+  /// How many distinct values the given index path has. 0 if the path is not indexed.
+  pub fn index_size(&mut self, path: &str) -> usize {
+    self.update_stale_index_keys();
+    self.state.index.distinct_values(path)
+  }
+
+  /// Indexes the entries again whose objects were serialized again before writing them
+  pub fn update_stale_index_keys(&mut self) {
+    let storage = &mut *self.state.storage.lock();
+    for key in storage.stale_index_keys.drain(..) {
+      // Entries that were replaced or deleted in the meantime are already indexed correctly
+      if let Some(DBEntry::Reference(str, _)) = storage.entries.get(&key) {
+        self.state.index.add_stringified_checked(&key, str);
+      }
+    }
+  }
+
+  /// Starts counting the entries with the given key prefix. Returns false if it is already counted.
+  pub fn register_prefix_counter(&self, prefix: &str) -> bool {
+    let mut storage = self.state.storage.lock();
+    let storage = &mut *storage;
+    storage.prefix_counters.register(prefix, &storage.entries)
+  }
+
+  pub fn unregister_prefix_counter(&self, prefix: &str) -> bool {
+    self.state.storage.lock().prefix_counters.unregister(prefix)
+  }
+
+  /// How many entries have the given key prefix. None if the prefix is not counted.
+  pub fn prefix_count(&self, prefix: &str) -> Option<usize> {
+    self.state.storage.lock().prefix_counters.get(prefix)
+  }
+
+  pub fn metrics(&self) -> QueryMetrics {
+    let references = self
+      .state
+      .storage
+      .lock()
+      .entries
+      .values()
+      .filter(|entry| matches!(entry, DBEntry::Reference(..)))
+      .count();
+    let written = &self.state.write_counters;
+    QueryMetrics {
+      references,
+      pending_unrefs: self.state.deferred_unrefs.len(),
+      lines_written: written.lines.load(Ordering::Relaxed),
+      tombstones_written: written.tombstones.load(Ordering::Relaxed),
+      truncations: written.truncations.load(Ordering::Relaxed),
+      bytes_written: written.bytes.load(Ordering::Relaxed),
+      syncs: written.syncs.load(Ordering::Relaxed),
+      persistence_wakeups: written.wakeups.load(Ordering::Relaxed),
+      lock_contention: self.state.lock_contention.as_ref().map(|contention| {
+        [LockSite::Main, LockSite::Persistence, LockSite::Compress].map(|site| contention.get(site))
+      }),
+      ..self.state.metrics
+    }
+  }
+
+  /// Resets the cumulative metrics, including what was written to the DB file
+  pub fn reset_metrics(&mut self) {
+    self.state.metrics = QueryMetrics::default();
+    self.state.write_counters.reset();
+    if let Some(contention) = &self.state.lock_contention {
+      contention.reset();
+    }
+  }
+
+  /// Converts an entry that is held as a JS object back to a native value, so the JS object can be
+  /// garbage collected. Returns whether the entry was held as a JS object.
+  pub fn dematerialize(&mut self, env: napi::Env, key: &str) -> Result<bool> {
+    let old = match self.state.storage.lock().entries.get_mut(key) {
+      Some(entry) => dematerialize_entry(entry)?,
+      None => None,
+    };
+    let ret = old.is_some();
+    // The references must be released on the main thread
+    drop_safe(env, old);
+    Ok(ret)
+  }
+
+  /// Converts the entries starting at position `start` into JS objects until `budget_ms` is used up,
+  /// so reading them later is cheap. Only keys with the given prefix are converted.
+  /// Returns the position to continue at and how many entries were converted.
+  pub fn materialize(
+    &mut self,
+    env: napi::Env,
+    prefix: Option<&str>,
+    start: usize,
+    budget_ms: u32,
+  ) -> Result<(usize, usize)> {
+    let started = Instant::now();
+    let budget = Duration::from_millis(budget_ms as u64);
+    let entries = &mut self.state.storage.lock().entries;
+    let mut pos = start;
+    let mut converted = 0;
+    while let Some((key, entry)) = entries.get_index_mut(pos) {
+      pos += 1;
+      if prefix.map_or(true, |prefix| key.starts_with(prefix)) && needs_materializing(entry) {
+        get_or_convert_entry(env, entry)?;
+        converted += 1;
+      }
+      // Looking at the clock for every entry would be wasteful
+      if pos % MATERIALIZE_CLOCK_INTERVAL == 0 && started.elapsed() >= budget {
+        break;
+      }
+    }
+    Ok((pos, converted))
+  }
+
+  /// Like `dematerialize`, but for all entries. Returns how many entries were converted.
+  pub fn dematerialize_all(&mut self, env: napi::Env) -> Result<u32> {
+    let mut old = Vec::new();
+    for entry in self.state.storage.lock().entries.values_mut() {
+      if let Some(entry) = dematerialize_entry(entry)? {
+        old.push(entry);
+      }
+    }
+    let ret = old.len() as u32;
+    for entry in old {
+      drop_safe(env, Some(entry));
+    }
+    Ok(ret)
+  }
+
+  pub fn size(&self) -> usize {
+    self.state.storage.lock().entries.len()
+  }
+
+  pub fn stats(&self) -> WriteStats {
+    self.state.storage.stats()
+  }
+
+  /// When the given entry was written. None if timestamps are not tracked or unknown for this entry.
+  pub fn meta(&self, key: &str) -> Option<EntryMeta> {
+    self.state.storage.lock().meta(key).copied()
+  }
+
+  /// The application-defined DB header as serialized JSON
+  pub fn header(&self) -> Option<String> {
+    self.state.storage.header()
+  }
+
+  /// The keys of entries that were deleted in soft-delete mode, optionally only those deleted since the given time
+  pub fn deleted_keys(&self, since: Option<u64>) -> Vec<String> {
+    self.state.storage.lock().deleted_keys(since)
+  }
+
+  /// The size of the DB file on disk. Writes that are still buffered are not included.
+  /// With sharding, this is the combined size of all shards.
+  pub fn file_size(&self) -> Result<u64> {
+    let layout = ShardLayout::new(&self.filename, self.options.shards);
+    let mut ret = 0;
+    for filename in layout.filenames() {
+      ret += std::fs::metadata(filename)?.len();
+    }
+    Ok(ret)
+  }
+
+  /// Whether the lockfile was lost, which makes the DB read-only
+  pub fn is_lock_lost(&self) -> bool {
+    self.state.lock_lost.load(Ordering::SeqCst)
+  }
+
+  /// What reading the DB file revealed when it was opened
+  pub fn open_diagnostics(&self) -> OpenDiagnostics {
+    self.state.open_diagnostics.clone()
+  }
+
+  pub fn lockfile_path(&self) -> &Path {
+    &self.state.lockfile_path
+  }
+
+  /// Kills the persistence thread, as if it panicked. Only meant for tests.
+  #[cfg(feature = "test-hooks")]
+  pub fn abort_persistence(&mut self) {
+    self.state.persistence_thread.thread.abort();
+  }
+
+  pub fn all_keys(&self) -> Vec<String> {
+    let entries = &self.state.storage.lock().entries;
+    entries.keys().map(|key| key.to_string()).collect()
+  }
+
+  /// Estimates how much memory the DB uses. The entries are looked at in chunks to avoid locking
+  /// the storage for too long, so changes made in the meantime may be partially reflected.
+  pub async fn memory_usage(&self) -> MemoryUsage {
+    let index = self.state.index.estimated_size();
+    let journal = {
+      let storage = self.state.storage.lock();
+      journal_size(&storage.journal)
+    };
+
+    let mut entries: usize = 0;
+    let mut offset: usize = 0;
+    loop {
+      let (size, count) = {
+        let storage = self.state.storage.lock();
+        storage
+          .entries
+          .iter()
+          .skip(offset)
+          .take(MEMORY_USAGE_CHUNK_SIZE)
+          .fold((0, 0), |(size, count), (key, entry)| {
+            (size + entry_size(key, entry), count + 1)
+          })
+      };
+      entries += size;
+      offset += count;
+      if count < MEMORY_USAGE_CHUNK_SIZE {
+        break;
+      }
+      tokio::task::yield_now().await;
+    }
+
+    MemoryUsage {
+      entries,
+      journal,
+      index,
+    }
+  }
+
+  /// Estimates how big the DB file would be after compressing it, without touching any files.
+  /// Like `memory_usage`, this looks at the entries in chunks.
+  pub async fn estimate_compress(&self) -> Result<CompressEstimate> {
+    let current_file_bytes = self.file_size()?;
+    let (file_lines, tombstones) = {
+      let storage = self.state.storage.lock();
+      (storage.stats.uncompressed_size, storage.compacted_tombstones_len())
+    };
+
+    let mut entries: usize = 0;
+    let mut live_entries: usize = 0;
+    loop {
+      let (size, count) = self
+        .state
+        .storage
+        .lock()
+        .compacted_entries_len(live_entries, COMPRESS_ESTIMATE_CHUNK_SIZE);
+      entries += size;
+      live_entries += count;
+      if count < COMPRESS_ESTIMATE_CHUNK_SIZE {
+        break;
+      }
+      tokio::task::yield_now().await;
+    }
+
+    Ok(CompressEstimate {
+      current_file_bytes,
+      estimated_compact_bytes: (entries + tombstones) as u64,
+      live_entries,
+      file_lines,
+    })
+  }
+
+  /// Serializes the referenced objects that are about to be written again, see `self_check`
+  pub fn serialize_pending_references(&self, env: napi::Env) -> HashMap<Key, String> {
+    self.state.storage.lock().serialize_pending_references(env)
+  }
+
+  /// Checks the internal invariants of the DB and returns all violations instead of failing on the
+  /// first one. The entries and the index are looked at in chunks like in `memory_usage`, so
+  /// changes made in the meantime may be reported as violations.
+  /// `references` are the referenced objects with pending writes, serialized on the main thread
+  /// with `serialize_pending_references`.
+  pub async fn self_check(&self, references: HashMap<Key, String>) -> Result<SelfCheckReport> {
+    let mut report = SelfCheckReport::default();
+
+    // The journal and the counters must be consistent with the entries at any time
+    {
+      let storage = self.state.storage.lock();
+      for (key, message) in storage.check_journal(&references) {
+        report.push("journal", key, message);
+      }
+      for (key, message) in storage.check_counters() {
+        report.push("counters", key, message);
+      }
+    }
+
+    // Serialized values are written as they are, so they must be valid JSON
+    let mut offset: usize = 0;
+    loop {
+      let (invalid, count) = {
+        let storage = self.state.storage.lock();
+        let mut invalid: Vec<(Key, String)> = Vec::new();
+        let mut count: usize = 0;
+        for (key, entry) in storage.entries.iter().skip(offset).take(SELF_CHECK_CHUNK_SIZE) {
+          count += 1;
+          if let DBEntry::Reference(str, _) | DBEntry::Stringified(str) = entry {
+            if let Err(e) = serde_json::from_str::<IgnoredAny>(str) {
+              invalid.push((key.clone(), format!("The value of \"{key}\" is not valid JSON: {e}")));
+            }
+          }
+        }
+        (invalid, count)
+      };
+      for (key, message) in invalid {
+        report.push("values", Some(key), message);
+      }
+      offset += count;
+      if count < SELF_CHECK_CHUNK_SIZE {
+        break;
+      }
+      tokio::task::yield_now().await;
+    }
+    report.checked_entries = offset;
+
+    // Every indexed key must belong to an entry
+    for (key, message) in self.state.index.reverse_mismatches() {
+      report.push("index", Some(key), message);
+    }
+    let indexed_keys = self.state.index.keys();
+    for chunk in indexed_keys.chunks(SELF_CHECK_CHUNK_SIZE) {
+      let missing: Vec<&Key> = {
+        let storage = self.state.storage.lock();
+        chunk
+          .iter()
+          .filter(|key| !storage.entries.contains_key(*key))
+          .collect()
+      };
+      // Entries that were deleted in the meantime are no longer indexed either
+      for key in missing.into_iter().filter(|key| self.state.index.contains_key(key)) {
+        report.push(
+          "index",
+          Some(key.clone()),
+          format!("The index contains \"{key}\", which is not an entry"),
+        );
+      }
+      tokio::task::yield_now().await;
+    }
+
+    // Buffered writes may end the file in the middle of a line
+    self.flush().await?;
+    let layout = ShardLayout::new(&self.filename, self.options.shards);
+    for filename in layout.filenames() {
+      if let Some(message) = check_file_tail(&filename).await {
+        report.push("file", None, message);
+      }
+    }
+
+    Ok(report)
+  }
+
+  /// Returns up to `limit` keys, starting at the `offset`th key in storage order.
+  /// Pages are only consistent with each other if the DB was not changed in between.
+  pub fn keys_page(&self, offset: usize, limit: usize) -> Vec<String> {
+    let entries = &self.state.storage.lock().entries;
+    entries
+      .keys()
+      .skip(offset)
+      .take(limit)
+      .map(|key| key.to_string())
+      .collect()
+  }
+
+  /// Like `keys_page`, but serializes the keys to a JSON array without copying them first
+  pub fn keys_page_stringified(&self, offset: usize, limit: usize) -> Result<String> {
+    let entries = &self.state.storage.lock().entries;
+    let keys: Vec<&str> = entries.keys().skip(offset).take(limit).map(|key| &**key).collect();
+    serde_json::to_string(&keys).map_err(JsonlDBError::serde_to_string_failed)
+  }
+
+  /// Returns all keys, sorted with the given callback or the configured key ordering
+  pub fn sorted_keys(
+    &mut self,
+    env: napi::Env,
+    sort_keys: Option<&JsFunction>,
+  ) -> Result<Vec<String>> {
+    let mut keys = self.all_keys();
+    match sort_keys {
+      Some(sort_keys) => sort_keys_with(env, keys, sort_keys),
+      None => {
+        let ordering = self.options.key_ordering;
+        keys.sort_by(|a, b| ordering.compare(a, b));
+        Ok(keys)
+      }
+    }
+  }
+
+  /// Dumps the DB to the given file. This works from the entries in memory, so it does not have to
+  /// wait for a compress to finish. Returns the size of the dump and whether a compress ran while
+  /// it was written.
+  pub async fn dump(&self, filename: &str, gzip: bool) -> Result<(usize, bool)> {
+    // Don't do anything while the DB is being closed
+    if self.state.is_closing {
+      return Ok((0, false));
+    }
+
+    let dump_start = Instant::now();
+    let mut storage = self.state.storage.clone();
+    let compress_epoch = storage.lock().compress_epoch;
+    let dump_size = dump_to(
+      &self.filename,
+      filename,
+      &mut storage,
+      gzip || is_gzip_filename(filename),
+      self.options.file_mode,
+      self.fs_ops.as_ref(),
+    )
+    .await?;
+    // The epoch is odd while compressing and changes when a compress starts or ends
+    let during_compress =
+      compress_epoch % 2 == 1 || storage.lock().compress_epoch != compress_epoch;
+    self.events.emit(
+      "dumpDone",
+      json!({
+        "filename": filename,
+        "bytes": dump_size,
+        "durationMs": dump_start.elapsed().as_millis() as u32,
+      }),
+    );
+
+    Ok((dump_size, during_compress))
+  }
+
+  /// Recreates the DB file after it disappeared and resumes persisting
+  pub async fn retry_persistence(&self) -> Result<()> {
+    let (done, rx) = oneshot::channel();
+    self
+      .state
+      .persistence_thread
+      .send_command(Command::RetryPersistence { done })
+      .await?;
+    rx.await
+      .map_err(|_| JsonlDBError::other("Persisting the DB failed"))?
+  }
+
+  /// Copies the DB file to the given path once everything in the journal is on disk. The
+  /// persistence thread does both, so compressing cannot happen in between.
+  pub async fn create_snapshot_file(&self, target: &str) -> Result<SnapshotInfo> {
+    if self.state.is_closing {
+      return Err(JsonlDBError::NotOpen);
+    }
+    let (done, rx) = oneshot::channel();
+    self
+      .state
+      .persistence_thread
+      .send_command(Command::Snapshot {
+        target: target.to_owned(),
+        done,
+      })
+      .await?;
+    rx.await
+      .map_err(|_| JsonlDBError::other("Persisting the DB failed"))?
+  }
+
+  pub fn set_flush_callback(&mut self, callback: Option<FlushCallback>) {
+    self.state.on_flush.set(callback);
+  }
+
+  /// Returns a future that resolves once everything that is currently in the journal is on disk
+  pub fn flush(&self) -> impl Future<Output = Result<()>> {
+    let tx = self.state.persistence_thread.tx.clone();
+    async move {
+      let (done, rx) = oneshot::channel();
+      send_command(&tx, Command::Flush { done }).await?;
+      // The persistence thread settles every command, so a dropped sender means it is gone
+      rx.await
+        .map_err(|_| JsonlDBError::other("Persisting the DB failed"))?
+    }
+  }
+
+  fn compress_promise(&self) -> MutexGuard<'_, Option<watch::Receiver<CompressOutcome>>> {
+    // Like the storage, a poisoned lock is not recoverable
+    self
+      .state
+      .compress_promise
+      .lock()
+      .map_err(|_| JsonlDBError::other("Failed to acquire lock on compress promise"))
+      .unwrap()
+  }
+
+  pub async fn compress(&self, trigger: CompressTrigger) -> Result<()> {
+    // Don't do anything while the DB is being closed
+    if self.state.is_closing {
+      return Ok(());
+    }
+
+    // Don't compress twice in parallel and block all further calls. The promise of the compress
+    // on open stays around after it is done, because nobody awaits it to remove it.
+    let running = self.compress_promise().clone();
+    if let Some(mut running) = running.filter(|outcome| outcome.borrow().is_none()) {
+      loop {
+        if let Some(outcome) = running.borrow().clone() {
+          return outcome.map_err(|e| JsonlDBError::other(&e));
+        }
+        if running.changed().await.is_err() {
+          // The persistence thread is gone without saying how the compress went
+          return Err(JsonlDBError::other("Persisting the DB failed"));
+        }
+      }
+    } else {
+      let (compressed, promise) = watch::channel(None);
+      *self.compress_promise() = Some(promise);
+      let (done, rx) = oneshot::channel();
+
+      // Send command to the persistence thread
+      let sent = self
+        .state
+        .persistence_thread
+        .send_command(Command::Compress {
+          done: Some(done),
+          trigger,
+        })
+        .await;
+
+      // and wait until it is done
+      let result = match sent {
+        Ok(()) => rx
+          .await
+          .unwrap_or_else(|_| Err(JsonlDBError::other("Persisting the DB failed"))),
+        Err(e) => Err(e),
+      };
+
+      *self.compress_promise() = None;
+      // Also wake up everyone who started waiting after the persistence thread was done
+      compressed
+        .send(Some(result.as_ref().map(|_| ()).map_err(|e| e.to_string())))
+        .ok();
+      result?;
+    }
+
+    Ok(())
+  }
+
+  pub async fn export_json(
+    &self,
+    filename: &str,
+    pretty: bool,
+    gzip: bool,
+    omit_null_values: bool,
+  ) -> Result<JsonExportStats> {
+    let new_dirs = util::create_parent_dirs(filename, self.options.file_mode)?;
+    let mut file = util::open_options(self.options.file_mode)
+      .create(true)
       .truncate(true)
       .write(true)
       .open(filename)
       .await?;
+    util::set_file_mode(&file, self.options.file_mode).await?;
 
+    let mut stats = JsonExportStats::default();
     let json: String = {
-      let entries = &self.state.storage.lock().entries;
-
-      let normalized_entries: Vec<(String, Value)> = entries
-        .iter()
-        .map(|(k, v)| match Value::try_from(v) {
-          Ok(v) => Ok((k.to_owned(), v)),
-          Err(e) => Err(e),
-        })
-        .collect::<Result<_>>()?;
-
-      let map = Map::<String, Value>::from_iter(normalized_entries.into_iter());
+      let normalized_entries = self.normalized_entries()?;
+      let total = normalized_entries.len();
+
+      let map = Map::<String, Value>::from_iter(
+        normalized_entries
+          .into_iter()
+          .filter(|(_, v)| !(omit_null_values && v.is_null())),
+      );
+      stats.entries = map.len();
+      stats.omitted_null_values = total - map.len();
       if pretty {
         serde_json::to_string_pretty(&map).map_err(|e| JsonlDBError::serde_to_string_failed(e))?
       } else {
@@ -420,75 +1854,508 @@ impl RsonlDB<Opened> {
       }
     };
 
-    file.write_all(json.as_bytes()).await?;
+    if gzip || is_gzip_filename(filename) {
+      file.write_all(&util::gzip(json.as_bytes())?).await?;
+    } else {
+      file.write_all(json.as_bytes()).await?;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    for dir in new_dirs {
+      self.fs_ops.sync_dir(&dir).await?;
+    }
 
-    Ok(())
+    Ok(stats)
   }
 
-  pub async fn import_json_file(&mut self, filename: &str) -> Result<()> {
-    let buffer = {
-      let mut buffer = Vec::new();
-      let mut file = OpenOptions::new().read(true).open(filename).await?;
-      file.read_to_end(&mut buffer).await?;
-      buffer
+  pub async fn export_csv(&self, filename: &str, mode: CsvValueMode) -> Result<()> {
+    let new_dirs = util::create_parent_dirs(filename, self.options.file_mode)?;
+    let mut file = util::open_options(self.options.file_mode)
+      .create(true)
+      .truncate(true)
+      .write(true)
+      .open(filename)
+      .await?;
+    util::set_file_mode(&file, self.options.file_mode).await?;
+
+    let csv = {
+      let normalized_entries = self.normalized_entries()?;
+      render_csv(&normalized_entries, mode)?
     };
 
-    let json: Map<String, Value> =
-      serde_json::from_slice(&buffer).map_err(|e| JsonlDBError::SerializeError {
-        reason: "Could not import JSON file".to_owned(),
-        source: e,
-      })?;
-    self.import_json_map(json)?;
+    file.write_all(&csv).await?;
+    file.flush().await?;
+    for dir in new_dirs {
+      self.fs_ops.sync_dir(&dir).await?;
+    }
+
     Ok(())
   }
 
-  pub fn import_json_string(&mut self, json: &str) -> Result<()> {
+  fn normalized_entries(&self) -> Result<Vec<(String, Value)>> {
+    let entries = &self.state.storage.lock().entries;
+
+    entries
+      .iter()
+      .map(|(k, v)| match Value::try_from(v) {
+        Ok(v) => Ok((k.to_string(), v)),
+        Err(e) => Err(e),
+      })
+      .collect::<Result<_>>()
+  }
+
+  pub fn import_json_string(
+    &mut self,
+    json: &str,
+    via_compress: bool,
+    null_means_delete: bool,
+  ) -> Result<JsonImportStats> {
     let json: Map<String, Value> =
       serde_json::from_str(&json).map_err(|e| JsonlDBError::SerializeError {
         reason: "Could not import JSON string".to_owned(),
         source: e,
       })?;
-    self.import_json_map(json)?;
-    Ok(())
+    self.import_json_map(json, via_compress, null_means_delete)
   }
 
-  fn import_json_map(&mut self, map: Map<String, Value>) -> Result<()> {
+  /// Imports the entries of a JSON object. If `via_compress` is set or a large import follows a
+  /// clear, the DB file is rewritten like when compressing instead of appending each entry.
+  /// With `null_means_delete`, null values delete the entry instead of storing null.
+  pub fn import_json_map(
+    &mut self,
+    map: Map<String, Value>,
+    via_compress: bool,
+    null_means_delete: bool,
+  ) -> Result<JsonImportStats> {
+    // Check all entries first, so nothing is imported if one is refused
+    map.keys().try_for_each(|key| check_key(key))?;
+    let max_depth = self.options.max_value_depth;
+    if let Some((key, _)) = map.iter().find(|(_, v)| value_exceeds_depth(v, max_depth)) {
+      return Err(self.too_deep(key.clone()));
+    }
+
+    let mut stats = JsonImportStats::default();
+    let mut null_keys = Vec::new();
     let mut storage = self.state.storage.lock();
+    if via_compress
+      || (storage.has_pending_clear()
+        && storage.journal.len() - 1 + map.len() > IMPORT_REWRITE_THRESHOLD)
+    {
+      storage.schedule_rewrite();
+    }
+
+    // The index is either rebuilt at the end or updated in chunks, which is much faster than
+    // updating it for each entry
+    let rebuild_index = self.options.rebuild_index_after_import;
+    let batch_index = !rebuild_index && !self.state.index.paths().is_empty();
+    let mut index_batch = Vec::new();
+
     for (key, value) in map.into_iter() {
-      self.state.index.add_value_checked(&key, &value);
-      storage.entries.insert(key.clone(), DBEntry::Native(value));
-      storage.journal.push(JournalEntry::Set(key));
+      if null_means_delete && value.is_null() {
+        null_keys.push(key);
+        continue;
+      }
+      stats.entries += 1;
+      let key = storage.intern(key);
+      if batch_index {
+        index_batch.push((key.clone(), self.state.index.index_keys(&value)));
+        if index_batch.len() >= IMPORT_INDEX_CHUNK_SIZE {
+          self.state.index.add_batch(std::mem::take(&mut index_batch));
+        }
+      }
+      storage.touch(&key);
+      // Like `schedule_set`, which is skipped here, because it would look through the journal
+      storage.forget_tombstone(&key);
+      if storage.entries.insert(key.clone(), DBEntry::Native(value)).is_none() {
+        storage.prefix_counters.added(&key);
+      }
+      if !storage.needs_rewrite {
+        storage.journal.push(JournalEntry::Set(key));
+      } else if storage.open_dumps > 0 {
+        // Dumps that are being rendered still need to know about the change
+        storage.dump_tap.push(JournalEntry::Set(key));
+      }
     }
 
-    Ok(())
+    if rebuild_index {
+      self.state.index.clear();
+      self.state.index.add_entries_checked(&storage.entries);
+    } else {
+      self.state.index.add_batch(index_batch);
+    }
+    storage.notify_persistence();
+    drop(storage);
+
+    // There is no env to release the JS objects of deleted entries here, so that happens later
+    let null_keys: Vec<Key> = null_keys
+      .into_iter()
+      .filter(|key| self.has(key))
+      .map(Key::from)
+      .collect();
+    let removed = self.remove_many(&null_keys);
+    stats.deleted_null_values += removed.len();
+    self.state.deferred_unrefs.push(removed);
+
+    Ok(stats)
   }
 }
 
-fn get_or_convert_entry(
+/// Reads a CSV file for `import_json_map`. This does not need the DB, so the file can be read and
+/// parsed in the background and the entries imported on the main thread afterwards.
+pub(crate) async fn read_csv_import(
+  filename: &str,
+  options: &CsvImportOptions,
+) -> Result<Map<String, Value>> {
+  let mut buffer = Vec::new();
+  let mut file = OpenOptions::new().read(true).open(filename).await?;
+  file.read_to_end(&mut buffer).await?;
+
+  let rows = parse_csv(&buffer, options)?;
+  Ok(Map::from_iter(rows.into_iter()))
+}
+
+/// Like `read_csv_import`, but for a JSON file, which may be gzipped
+pub(crate) async fn read_json_import(filename: &str) -> Result<Map<String, Value>> {
+  let buffer = {
+    let mut buffer = Vec::new();
+    let mut file = OpenOptions::new().read(true).open(filename).await?;
+    file.read_to_end(&mut buffer).await?;
+    // Transparently decompress gzipped files
+    gunzip_if_needed(buffer)?
+  };
+
+  serde_json::from_slice(&buffer).map_err(|e| JsonlDBError::SerializeError {
+    reason: "Could not import JSON file".to_owned(),
+    source: e,
+  })
+}
+
+/// Reads the files of all shards concurrently and combines their entries. Returns the files with
+/// the cursors at the end and how many lines each of them consists of.
+async fn parse_shards(
+  files: Vec<fs::File>,
+  options: &DBOptions,
+) -> Result<(Vec<(fs::File, usize)>, ParsedEntries)> {
+  let tasks: Vec<_> = files
+    .into_iter()
+    .map(|mut file| {
+      let options = options.clone();
+      tokio::spawn(async move {
+        let parsed = parse_entries(&mut file, &options).await?;
+        Ok::<_, JsonlDBError>((file, parsed))
+      })
+    })
+    .collect();
+
+  let mut files = Vec::with_capacity(tasks.len());
+  let mut ret: Option<ParsedEntries> = None;
+  for task in tasks {
+    let (file, parsed) = task.await.map_err(anyhow::Error::from)??;
+    files.push((file, parsed.lines));
+    match ret.as_mut() {
+      Some(ret) => ret.merge(parsed),
+      None => ret = Some(parsed),
+    }
+  }
+  // There is always at least one file
+  Ok((files, ret.unwrap()))
+}
+
+/// Rewrites the entries when opening the DB, see `open_with_migration`
+pub(crate) struct Migration {
+  pub callback: MigrateCallback,
+  // Stored with the DB, so the migration only runs once
+  pub version: u32,
+}
+
+/// Passes all entries through the migration function on the main thread, in batches so it isn't
+/// blocked for long. The changes are only applied if all of them succeeded. They are journaled, so
+/// they are written once the persistence thread is started.
+async fn migrate_entries(
+  migration: &Migration,
+  options: &DBOptions,
+  entries: &mut IndexMap<Key, DBEntry>,
+  mut meta: Option<&mut EntryMetaMap>,
+  journal: &mut Vec<JournalEntry>,
+) -> Result<()> {
+  let keys: Vec<Key> = entries.keys().cloned().collect();
+  let mut changes: Vec<(String, Option<String>)> = Vec::new();
+  for batch in keys.chunks(MIGRATION_BATCH_SIZE) {
+    let (done, rx) = oneshot::channel();
+    migration.callback.call(
+      MigrationBatch {
+        keys: batch.iter().map(|key| key.to_string()).collect(),
+        values: batch.iter().map(|key| (&entries[key]).into()).collect(),
+        done,
+      },
+      ThreadsafeFunctionCallMode::NonBlocking,
+    );
+    match rx.await {
+      Ok(Ok(batch_changes)) => changes.extend(batch_changes),
+      Ok(Err(reason)) => {
+        return Err(JsonlDBError::other(&format!("The migration failed: {reason}")));
+      }
+      Err(_) => return Err(JsonlDBError::other("The migration was aborted")),
+    }
+  }
+
+  // Check everything first, so a failed migration doesn't leave some entries migrated
+  let mut checked = Vec::with_capacity(changes.len());
+  for (key, value) in changes {
+    let key = match entries.get_key_value(key.as_str()) {
+      Some((key, _)) => key.clone(),
+      None => continue,
+    };
+    let value = match value {
+      Some(value) => {
+        let value = normalize_value_json(&value).map_err(|_| {
+          JsonlDBError::other(&format!("The migrated value for key \"{key}\" is not valid JSON"))
+        })?;
+        if json_exceeds_depth(&value, options.max_value_depth) {
+          return Err(JsonlDBError::other(&format!(
+            "The migrated value for key \"{key}\" is nested deeper than maxValueDepth ({} levels)",
+            options.max_value_depth
+          )));
+        }
+        Some(value)
+      }
+      None => None,
+    };
+    checked.push((key, value));
+  }
+  let mut deleted: HashSet<Key> = HashSet::new();
+  for (key, value) in checked {
+    match value {
+      Some(value) => {
+        entries.insert(key.clone(), DBEntry::Stringified(value));
+        journal.push(JournalEntry::Set(key));
+      }
+      None => {
+        if let Some(meta) = meta.as_deref_mut() {
+          meta.remove(&key);
+        }
+        deleted.insert(key.clone());
+        journal.push(JournalEntry::Delete(key));
+      }
+    }
+  }
+  // Removing them one by one would shift the later entries once per deleted key
+  if !deleted.is_empty() {
+    entries.retain(|key, _| !deleted.contains(key));
+  }
+  Ok(())
+}
+
+/// Finds out how many shards the existing files of the DB were written with, including their
+/// backups. `None` if there are no files with content yet, so nothing can get lost.
+async fn detect_shard_count(filename: &str) -> Result<Option<usize>> {
+  let path = Path::new(filename);
+  let basename = match path.file_name().and_then(|name| name.to_str()) {
+    Some(name) => name.to_owned(),
+    None => return Ok(None),
+  };
+  let shard_prefix = format!("{basename}.");
+
+  let mut has_single_file = false;
+  // Empty shard files are counted too, otherwise a trailing empty shard would change the count
+  let mut shard_count = 0;
+  let mut shards_have_data = false;
+  let mut dir = match fs::read_dir(parent_dir(filename)?).await {
+    Ok(dir) => dir,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  while let Some(entry) = dir.next_entry().await? {
+    let name = entry.file_name();
+    let name = match name.to_str() {
+      Some(name) => name.strip_suffix(".bak").unwrap_or(name),
+      None => continue,
+    };
+    let has_data = matches!(entry.metadata().await, Ok(m) if m.is_file() && m.len() > 0);
+    if name == basename {
+      has_single_file |= has_data;
+    } else if let Some(shard) = name
+      .strip_prefix(&shard_prefix)
+      .and_then(|rest| rest.strip_suffix(".jsonl"))
+      .and_then(|shard| shard.parse::<usize>().ok())
+    {
+      shard_count = shard_count.max(shard + 1);
+      shards_have_data |= has_data;
+    }
+  }
+
+  match (has_single_file, shards_have_data) {
+    (true, true) => Err(JsonlDBError::MixedShardLayouts(filename.to_owned())),
+    (true, false) => Ok(Some(1)),
+    (false, true) => Ok(Some(shard_count)),
+    (false, false) => Ok(None),
+  }
+}
+
+/// Where the lockfile for the given DB file is, depending on the `lockfile_directory` option
+fn lockfile_name(filename: &str, options: &DBOptions) -> Result<PathBuf> {
+  let db_dir = parent_dir(filename)?;
+  let lockfile_directory = match options.lockfile_directory.as_str() {
+    "." => &db_dir,
+    dir => Path::new(dir),
+  };
+  replace_dirname(format!("{}.lock", filename), lockfile_directory).ok_or_else(|| {
+    JsonlDBError::io_error_from_reason(format!(
+      "Could not determine lockfile name for \"{}\"",
+      filename
+    ))
+  })
+}
+
+/// Checks whether the given DB file is locked, without opening it or acquiring the lock
+pub(crate) fn lock_status(filename: &str, options: &DBOptions) -> Result<LockStatus> {
+  let mut lock = Lockfile::new(
+    lockfile_name(filename, options)?,
+    LOCK_STALE_INTERVAL_MS,
+    options.file_mode,
+  );
+  lock.status()
+}
+
+/// Lets a JS callback sort a batch of keys at once, which is much faster than calling into JS for
+/// each comparison
+fn sort_keys_with(
   env: napi::Env,
-  entry: &mut Entry<String, DBEntry>,
-) -> Result<Option<JsValue>> {
+  keys: Vec<String>,
+  sort_keys: &JsFunction,
+) -> Result<Vec<String>> {
+  let arg = env.to_js_value(&keys)?;
+  let sorted: Vec<String> = env.from_js_value(sort_keys.call(None, &[arg])?)?;
+
+  // Make sure the callback only changed the order
+  let mut expected = keys;
+  let mut actual = sorted.clone();
+  expected.sort();
+  actual.sort();
+  if expected != actual {
+    return Err(JsonlDBError::other(
+      "The key comparator must return the same keys it was given",
+    ));
+  }
+
+  Ok(sorted)
+}
+
+/// Releases a chunk of the deferred references per macrotask until none are left
+fn release_deferred_unrefs(env: napi::Env, deferred_unrefs: DeferredUnrefs) -> Result<()> {
+  env.execute_tokio_future(async { Ok::<(), napi::Error>(()) }, move |env, _| {
+    if deferred_unrefs.release(*env, DEFERRED_UNREF_CHUNK_SIZE) > 0 {
+      release_deferred_unrefs(*env, deferred_unrefs)?;
+    }
+    Ok(())
+  })?;
+  Ok(())
+}
+
+/// Replaces an entry that is held as a JS object with the native value parsed from its serialized
+/// form. Returns the replaced entry, whose reference still needs to be released.
+fn dematerialize_entry(entry: &mut DBEntry) -> Result<Option<DBEntry>> {
+  let val: Value = match entry {
+    DBEntry::Reference(str, _) => {
+      serde_json::from_str(str).map_err(|e| JsonlDBError::SerializeError {
+        reason: format!("Could not parse stringified entry {str}"),
+        source: e,
+      })?
+    }
+    _ => return Ok(None),
+  };
+  Ok(Some(std::mem::replace(entry, DBEntry::Native(val))))
+}
+
+/// Appends an entry to a JSON array, or to an object if the key is given. The opening bracket
+/// must already be written.
+fn push_stringified(out: &mut String, key: Option<&str>, entry: &DBEntry) {
+  if out.len() > 1 {
+    out.push(',');
+  }
+  if let Some(key) = key {
+    out.push_str(&serde_json::to_string(key).unwrap());
+    out.push(':');
+  }
+  match entry {
+    DBEntry::Reference(str, _) | DBEntry::Stringified(str) => out.push_str(str),
+    DBEntry::Native(val) => out.push_str(&serde_json::to_string(val).unwrap()),
+  }
+}
+
+/// Whether reading the entry would create a JS object
+fn needs_materializing(entry: &DBEntry) -> bool {
+  match entry {
+    DBEntry::Reference(..) => false,
+    DBEntry::Native(val) => val.is_array() || val.is_object(),
+    DBEntry::Stringified(_) => true,
+  }
+}
+
+fn get_or_convert_entry(env: napi::Env, entry: &mut DBEntry) -> Result<JsValue> {
   let result = match entry {
-    Entry::Occupied(e) => match e.get_mut() {
-      DBEntry::Reference(_, r) => {
-        let obj: JsObject = env.get_reference_value(r)?;
-        Some(JsValue::Object(obj))
-      }
+    DBEntry::Reference(_, r) => {
+      let obj: JsObject = env.get_reference_value(r)?;
+      JsValue::Object(obj)
+    }
 
-      DBEntry::Native(val) if val.is_array() || val.is_object() => {
-        let stringified =
-          serde_json::to_string(&val).map_err(|e| JsonlDBError::serde_to_string_failed(e))?;
+    DBEntry::Native(val) if val.is_array() || val.is_object() => {
+      let stringified =
+        serde_json::to_string(&val).map_err(|e| JsonlDBError::serde_to_string_failed(e))?;
+
+      let obj = unsafe { value_to_js_object(env.raw(), val.to_owned()) }?;
+      let reference = env.create_reference(&obj)?;
+      *entry = DBEntry::Reference(stringified, reference);
+
+      JsValue::Object(obj)
+    }
+
+    // Primitives are returned as they are, there is nothing to gain from a reference
+    DBEntry::Native(val) => JsValue::Primitive(primitive_to_js(env, val)?),
 
-        let obj = unsafe { value_to_js_object(env.raw(), val.to_owned()) }?;
+    DBEntry::Stringified(str) => {
+      let val: Value = serde_json::from_str(str).map_err(|e| JsonlDBError::SerializeError {
+        reason: format!("Could not parse stringified entry {str}"),
+        source: e,
+      })?;
+      if val.is_array() || val.is_object() {
+        let obj = unsafe { value_to_js_object(env.raw(), val) }?;
         let reference = env.create_reference(&obj)?;
-        e.insert(DBEntry::Reference(stringified, reference));
+        let stringified = std::mem::take(str);
+        *entry = DBEntry::Reference(stringified, reference);
 
-        Some(JsValue::Object(obj))
+        JsValue::Object(obj)
+      } else {
+        let primitive = primitive_to_js(env, &val)?;
+        // Remember the parsed primitive so we don't need to parse it again
+        *entry = DBEntry::Native(val);
+        JsValue::Primitive(primitive)
       }
-
-      DBEntry::Native(val) => Some(JsValue::Primitive(val.clone())),
-    },
-    Entry::Vacant(_) => None,
+    }
   };
   Ok(result)
 }
+
+/// Checks that the DB file ends with a complete and valid line. Returns what is wrong otherwise.
+async fn check_file_tail(filename: &str) -> Option<String> {
+  let tail = async {
+    let mut file = OpenOptions::new().read(true).open(filename).await?;
+    let incomplete = util::file_needs_lf(&mut file).await?;
+    let last_line = util::read_last_line(&mut file).await?;
+    Ok::<_, JsonlDBError>((incomplete, last_line))
+  }
+  .await;
+  match tail {
+    Err(e) => Some(format!("Cannot read the DB file \"{filename}\": {e}")),
+    Ok((true, _)) => Some(format!("The DB file \"{filename}\" ends with an incomplete line")),
+    Ok((false, line)) if line.is_empty() => None,
+    Ok((false, line)) => {
+      let parsed = std::str::from_utf8(&line)
+        .map_err(|e| e.to_string())
+        .and_then(|line| parse_line(line).map_err(|e| e.to_string()));
+      parsed
+        .err()
+        .map(|e| format!("The last line of the DB file \"{filename}\" is invalid: {e}"))
+    }
+  }
+}
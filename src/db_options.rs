@@ -1,3 +1,13 @@
+use crate::error::JsonlDBError;
+use crate::key_ordering::KeyOrdering;
+
+/// The suffixes of the files compressing writes next to the DB file, unless configured otherwise
+pub(crate) const DEFAULT_COMPRESS_DUMP_SUFFIX: &str = ".dump";
+pub(crate) const DEFAULT_COMPRESS_BACKUP_SUFFIX: &str = ".bak";
+/// The highest `max_value_depth` that can be configured. Converting values between JS and Rust is recursive,
+/// and serde_json refuses to parse deeper values anyways, so they could not be read back
+pub(crate) const MAX_VALUE_DEPTH_LIMIT: usize = 128;
+
 #[derive(Debug, Clone, Builder)]
 #[builder(default)]
 pub struct DBOptions {
@@ -7,7 +17,53 @@ pub struct DBOptions {
   pub(crate) auto_compress: AutoCompressOptions,
   pub(crate) throttle_fs: ThrottleFSOptions,
   pub(crate) lockfile_directory: String,
+  // How often to try re-acquiring the lockfile if it was taken over by another process
+  pub(crate) lock_lost_retries: u32,
   pub(crate) index_paths: Vec<String>,
+  // How many worker threads the dedicated runtime should use, if any
+  pub(crate) dedicated_runtime: Option<usize>,
+  // The permissions for newly created files (unix only)
+  pub(crate) file_mode: Option<u32>,
+  // The size of the buffers used for reading and writing the DB file
+  pub(crate) io_buffer_size: usize,
+  // Open files that don't look like DB files anyway
+  pub(crate) force_open: bool,
+  // Which ratio of invalid lines at the start of the file makes it look like it is no DB file
+  pub(crate) max_invalid_line_ratio: f64,
+  // How keys are compared in range queries
+  pub(crate) key_ordering: KeyOrdering,
+  // Whether to store when each entry was written
+  pub(crate) track_timestamps: bool,
+  // How long deleted entries are kept as tombstones. None disables soft-delete mode
+  pub(crate) soft_delete_retain_ms: Option<u64>,
+  // Rolling backups written by the persistence thread
+  pub(crate) auto_dump: Option<AutoDumpOptions>,
+  // Serialize JS objects again right before they are written, so later changes to them are persisted
+  pub(crate) resnapshot_on_write: bool,
+  // Where compressing writes its dump. None means next to the DB file
+  pub(crate) scratch_directory: Option<String>,
+  // Appended to the DB filename for the dump that replaces it when compressing
+  pub(crate) compress_dump_suffix: String,
+  // Appended to the DB filename for the backup that is kept until the dump replaced it
+  pub(crate) compress_backup_suffix: String,
+  // Rebuild the whole index after importing instead of updating it for the imported entries
+  pub(crate) rebuild_index_after_import: bool,
+  // Lines of the DB file that are longer than this are not read into memory
+  pub(crate) max_line_length: usize,
+  // How many files the entries are spread across by the hash of their key. With 1, the DB file
+  // itself is used
+  pub(crate) shards: usize,
+  // Parse each line again before it is written and compare it with the entry in memory
+  pub(crate) debug_consistency_checks: bool,
+  // Measure how long the main and persistence threads wait for the lock on the storage
+  pub(crate) measure_lock_contention: bool,
+  // The key prefixes whose number of entries is counted from the start
+  pub(crate) prefix_counters: Vec<String>,
+  // How deeply arrays and objects may be nested in a value. Deeper values are refused, because
+  // converting them would overflow the stack
+  pub(crate) max_value_depth: usize,
+  // Setting a key to `undefined` deletes it instead of being refused
+  pub(crate) undefined_is_delete: bool,
 }
 
 impl Default for DBOptions {
@@ -17,7 +73,29 @@ impl Default for DBOptions {
       auto_compress: AutoCompressOptions::default(),
       throttle_fs: ThrottleFSOptions::default(),
       lockfile_directory: ".".to_owned(),
+      lock_lost_retries: 3,
       index_paths: Vec::new(),
+      dedicated_runtime: None,
+      file_mode: None,
+      io_buffer_size: 8 * 1024,
+      force_open: false,
+      max_invalid_line_ratio: 0.9,
+      key_ordering: KeyOrdering::default(),
+      track_timestamps: false,
+      soft_delete_retain_ms: None,
+      auto_dump: None,
+      resnapshot_on_write: false,
+      scratch_directory: None,
+      compress_dump_suffix: DEFAULT_COMPRESS_DUMP_SUFFIX.to_owned(),
+      compress_backup_suffix: DEFAULT_COMPRESS_BACKUP_SUFFIX.to_owned(),
+      rebuild_index_after_import: false,
+      max_line_length: 64 * 1024 * 1024,
+      shards: 1,
+      debug_consistency_checks: false,
+      measure_lock_contention: false,
+      prefix_counters: Vec::new(),
+      max_value_depth: MAX_VALUE_DEPTH_LIMIT,
+      undefined_is_delete: false,
     }
   }
 }
@@ -29,8 +107,14 @@ pub struct AutoCompressOptions {
   pub(crate) size_factor_min_size: u32,
   pub(crate) interval_ms: u32,
   pub(crate) interval_min_changes: u32,
+  // What the compress interval is measured from
+  pub(crate) interval_anchor: IntervalAnchor,
   pub(crate) on_close: bool,
   pub(crate) on_open: bool,
+  // Compress on open only if that would free up more than this many bytes
+  pub(crate) on_open_if_wasted_bytes_exceed: Option<u64>,
+  pub(crate) max_overwrite_ratio: f64,
+  pub(crate) max_tombstones: u32,
 }
 
 impl Default for AutoCompressOptions {
@@ -40,8 +124,41 @@ impl Default for AutoCompressOptions {
       size_factor_min_size: 0,
       interval_ms: 0,
       interval_min_changes: 1,
+      interval_anchor: IntervalAnchor::default(),
       on_close: false,
       on_open: false,
+      on_open_if_wasted_bytes_exceed: None,
+      max_overwrite_ratio: 0.0,
+      max_tombstones: 0,
+    }
+  }
+}
+
+/// What the compress interval is measured from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntervalAnchor {
+  // Compress every interval_ms, as long as there were enough changes
+  LastCompress,
+  // Compress once no changes were written for interval_ms, so an idle DB is left alone
+  LastWrite,
+}
+
+impl Default for IntervalAnchor {
+  fn default() -> Self {
+    IntervalAnchor::LastCompress
+  }
+}
+
+impl TryFrom<&str> for IntervalAnchor {
+  type Error = JsonlDBError;
+
+  fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+    match value {
+      "lastCompress" => Ok(IntervalAnchor::LastCompress),
+      "lastWrite" => Ok(IntervalAnchor::LastWrite),
+      other => Err(JsonlDBError::InvalidOptions {
+        source: anyhow::anyhow!("Unsupported interval anchor \"{other}\""),
+      }),
     }
   }
 }
@@ -51,6 +168,11 @@ impl Default for AutoCompressOptions {
 pub struct ThrottleFSOptions {
   pub(crate) interval_ms: u32,
   pub(crate) max_buffered_commands: usize,
+  pub(crate) coalesce_window_ms: u32,
+  // Sync written data to disk once nothing was written for this long. 0 disables this
+  pub(crate) fsync_idle_ms: u32,
+  // Sync written data to disk at least this often while writing continuously. 0 disables this
+  pub(crate) fsync_max_interval_ms: u32,
 }
 
 impl Default for ThrottleFSOptions {
@@ -58,6 +180,18 @@ impl Default for ThrottleFSOptions {
     Self {
       interval_ms: 0,
       max_buffered_commands: usize::MAX,
+      coalesce_window_ms: 0,
+      fsync_idle_ms: 0,
+      fsync_max_interval_ms: 0,
     }
   }
 }
+
+#[derive(Debug, Clone)]
+pub struct AutoDumpOptions {
+  pub(crate) interval_ms: u32,
+  // May contain {timestamp}, which is replaced with the current time (epoch ms)
+  pub(crate) filename_template: String,
+  // How many automatic dumps are kept
+  pub(crate) keep: u32,
+}
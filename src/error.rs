@@ -4,17 +4,43 @@ pub type Result<T> = std::result::Result<T, JsonlDBError>;
 
 #[derive(Error, Debug)]
 pub enum JsonlDBError {
-  #[error("The DB is already open")]
-  AlreadyOpen,
   #[error("The DB is not open")]
   NotOpen,
-  #[error("The DB must be stopped to close the DB files")]
-  NotStopped,
+  #[error("{method} requires the DB to be {required}, but the DB is not {required} (current state: {current}). {hint}")]
+  WrongState {
+    method: &'static str,
+    required: &'static str,
+    current: &'static str,
+    hint: &'static str,
+  },
+  #[error("The DB file \"{0}\" is already opened by another instance in this process")]
+  OpenedTwice(String),
+  #[error("Cannot open DB file: This does not look like a jsonl-db file, {0}")]
+  NotADBFile(String),
+  #[error("Invalid DB filename: {0}")]
+  InvalidFilename(String),
+  #[error("The DB files were written with {found} shard(s), but the shards option is {configured}. Set shards to {found}, or call reshard({configured}) to convert the files")]
+  ShardCountMismatch { found: usize, configured: usize },
+  #[error("Cannot open DB file \"{0}\": Both the DB file and shard files of it contain data. Remove the files that are outdated")]
+  MixedShardLayouts(String),
+  #[error("The lock on the DB file was lost, the DB is read-only. Use dump() to save unsaved changes to another file")]
+  LockLost,
+
+  #[error("Simulated a crash")]
+  SimulatedCrash,
 
   #[error("The value {0:?} is not a primitive")]
   NotPrimitive(serde_json::Value),
+  #[error("Invalid key \"{0}\": it is reserved for the DB header")]
+  ReservedKey(String),
+  #[error("The value for key \"{0}\" is undefined. Use null to store an empty value or enable the undefinedIsDelete option")]
+  UndefinedValue(String),
+  #[error("The value for key \"{key}\" is nested deeper than maxValueDepth ({max_depth} levels)")]
+  TooDeep { key: String, max_depth: usize },
+  #[error("The value for key \"{key}\" contains {value}, which cannot be stored as JSON")]
+  NonFiniteNumber { key: String, value: f64 },
 
-  #[error("Invalid options")]
+  #[error("Invalid options: {source}")]
   InvalidOptions { source: anyhow::Error },
 
   #[error(transparent)]
@@ -34,6 +60,9 @@ pub enum JsonlDBError {
     reason: String,
   },
 
+  #[error(transparent)]
+  CsvError(#[from] csv::Error),
+
   #[error(transparent)]
   NapiError(#[from] napi::Error),
 
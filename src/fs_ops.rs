@@ -0,0 +1,125 @@
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+#[cfg(feature = "test-hooks")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+use crate::error::{JsonlDBError, Result};
+use crate::util;
+
+pub(crate) type FsFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// The filesystem operations that compressing, dumping and the recovery on open consist of.
+/// Each of them is a point where the process may crash, which is what tests use this for.
+pub(crate) trait FsOps: Send + Sync {
+  fn write_all<'a>(&'a self, file: &'a mut File, data: &'a [u8]) -> FsFuture<'a>;
+  fn sync_file<'a>(&'a self, file: &'a File) -> FsFuture<'a>;
+  fn sync_dir<'a>(&'a self, dir: &'a Path) -> FsFuture<'a>;
+  fn move_file<'a>(&'a self, from: &'a Path, to: &'a Path) -> FsFuture<'a>;
+  fn remove_file<'a>(&'a self, path: &'a Path) -> FsFuture<'a>;
+}
+
+/// Performs the operations on the actual filesystem
+pub(crate) struct RealFs;
+
+#[cfg(feature = "test-hooks")]
+static REAL_FS: RealFs = RealFs;
+
+impl FsOps for RealFs {
+  fn write_all<'a>(&'a self, file: &'a mut File, data: &'a [u8]) -> FsFuture<'a> {
+    Box::pin(async move {
+      file.write_all(data).await?;
+      file.flush().await?;
+      Ok::<(), JsonlDBError>(())
+    })
+  }
+
+  fn sync_file<'a>(&'a self, file: &'a File) -> FsFuture<'a> {
+    Box::pin(async move { file.sync_all().await.map_err(JsonlDBError::from) })
+  }
+
+  fn sync_dir<'a>(&'a self, dir: &'a Path) -> FsFuture<'a> {
+    Box::pin(util::fsync_dir(dir))
+  }
+
+  fn move_file<'a>(&'a self, from: &'a Path, to: &'a Path) -> FsFuture<'a> {
+    Box::pin(util::move_file(from, to))
+  }
+
+  fn remove_file<'a>(&'a self, path: &'a Path) -> FsFuture<'a> {
+    Box::pin(async move { fs::remove_file(path).await.map_err(JsonlDBError::from) })
+  }
+}
+
+/// Simulates a crash: The first `crash_at - 1` operations are performed normally,
+/// the one after that and all following ones fail without touching the filesystem.
+#[cfg(feature = "test-hooks")]
+pub(crate) struct CrashingFs {
+  crash_at: usize,
+  steps: AtomicUsize,
+}
+
+#[cfg(feature = "test-hooks")]
+impl CrashingFs {
+  pub fn new(crash_at: usize) -> Self {
+    Self {
+      crash_at,
+      steps: AtomicUsize::new(0),
+    }
+  }
+
+  fn step(&self) -> Result<()> {
+    let step = self.steps.fetch_add(1, Ordering::SeqCst) + 1;
+    if step >= self.crash_at {
+      Err(JsonlDBError::SimulatedCrash)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+#[cfg(feature = "test-hooks")]
+fn failed<'a>(e: JsonlDBError) -> FsFuture<'a> {
+  Box::pin(std::future::ready(Err::<(), _>(e)))
+}
+
+#[cfg(feature = "test-hooks")]
+impl FsOps for CrashingFs {
+  fn write_all<'a>(&'a self, file: &'a mut File, data: &'a [u8]) -> FsFuture<'a> {
+    match self.step() {
+      Ok(()) => REAL_FS.write_all(file, data),
+      Err(e) => failed(e),
+    }
+  }
+
+  fn sync_file<'a>(&'a self, file: &'a File) -> FsFuture<'a> {
+    match self.step() {
+      Ok(()) => REAL_FS.sync_file(file),
+      Err(e) => failed(e),
+    }
+  }
+
+  fn sync_dir<'a>(&'a self, dir: &'a Path) -> FsFuture<'a> {
+    match self.step() {
+      Ok(()) => REAL_FS.sync_dir(dir),
+      Err(e) => failed(e),
+    }
+  }
+
+  fn move_file<'a>(&'a self, from: &'a Path, to: &'a Path) -> FsFuture<'a> {
+    match self.step() {
+      Ok(()) => REAL_FS.move_file(from, to),
+      Err(e) => failed(e),
+    }
+  }
+
+  fn remove_file<'a>(&'a self, path: &'a Path) -> FsFuture<'a> {
+    match self.step() {
+      Ok(()) => REAL_FS.remove_file(path),
+      Err(e) => failed(e),
+    }
+  }
+}
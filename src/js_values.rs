@@ -1,11 +1,24 @@
 use napi::{
   bindgen_prelude::{FromNapiValue, ToNapiValue},
-  JsObject, Result,
+  Env, JsBoolean, JsFunction, JsNumber, JsObject, JsString, JsUnknown, Result, ValueType,
 };
+use napi_derive::napi;
 use serde_json::Value;
 
+use crate::bg_thread::SnapshotInfo;
+use crate::db::{
+  JsonExportStats, JsonImportStats, QueryExplain, QueryMetrics, SelfCheckReport, SelfCheckViolation,
+};
+use crate::error::JsonlDBError;
+use crate::lockfile::LockStatus;
+use crate::memory_usage::MemoryUsage;
+use crate::storage::{
+  CompressEstimate, EntryMeta, LockSite, LockWaits, OpenDiagnostics, ParsedLine, WriteStats, HEADER_KEY,
+};
+
 pub enum JsValue {
-  Primitive(Value),
+  // Converted from a stored value while the storage is locked, see `primitive_to_js`
+  Primitive(JsUnknown),
   Object(JsObject),
 }
 
@@ -29,3 +42,685 @@ pub(crate) unsafe fn value_to_js_object(
   let js_object = FromNapiValue::from_napi_value(env, native)?;
   Ok(js_object)
 }
+
+/// Converts a primitive to a JS value without cloning it first, which matters for large strings.
+/// Arrays and objects are cloned, like `ToNapiValue` would need.
+pub(crate) fn primitive_to_js(env: Env, value: &Value) -> Result<JsUnknown> {
+  let ret = match value {
+    Value::Null => env.get_null()?.into_unknown(),
+    Value::Bool(b) => env.get_boolean(*b)?.into_unknown(),
+    // JS numbers are doubles anyways
+    Value::Number(n) => env.create_double(n.as_f64().unwrap_or(f64::NAN))?.into_unknown(),
+    Value::String(s) => env.create_string(s)?.into_unknown(),
+    Value::Array(_) | Value::Object(_) => unsafe { to_js_unknown(env.raw(), value.clone()) }?,
+  };
+  Ok(ret)
+}
+
+pub(crate) unsafe fn to_js_unknown<T: ToNapiValue>(
+  env: napi::sys::napi_env,
+  value: T,
+) -> Result<JsUnknown> {
+  let native = ToNapiValue::to_napi_value(env, value)?;
+  FromNapiValue::from_napi_value(env, native)
+}
+
+/// Converts a JS value to JSON for storing it under `key`. Unlike `Env::from_js_value`, this refuses
+/// numbers that JSON cannot represent instead of silently turning them into `null`, and values nested
+/// deeper than `max_depth` before the recursion can overflow the stack.
+/// Like `JSON.stringify`, `undefined`, functions and symbols are skipped in objects and become `null` in arrays.
+pub(crate) fn js_to_json(key: &str, value: JsUnknown, max_depth: usize) -> Result<Value> {
+  Ok(js_to_json_opt(key, value, 0, max_depth)?.unwrap_or(Value::Null))
+}
+
+fn js_to_json_opt(
+  key: &str,
+  value: JsUnknown,
+  depth: usize,
+  max_depth: usize,
+) -> Result<Option<Value>> {
+  let ret = match value.get_type()? {
+    ValueType::Null => Value::Null,
+    ValueType::Boolean => Value::Bool(unsafe { value.cast::<JsBoolean>() }.get_value()?),
+    ValueType::Number => {
+      let n = unsafe { value.cast::<JsNumber>() }.get_double()?;
+      match serde_json::Number::from_f64(n) {
+        Some(n) => Value::Number(n),
+        None => {
+          return Err(
+            JsonlDBError::NonFiniteNumber {
+              key: key.to_owned(),
+              value: n,
+            }
+            .into(),
+          )
+        }
+      }
+    }
+    ValueType::String => {
+      Value::String(unsafe { value.cast::<JsString>() }.into_utf8()?.into_owned()?)
+    }
+    ValueType::Object if depth >= max_depth => {
+      return Err(
+        JsonlDBError::TooDeep {
+          key: key.to_owned(),
+          max_depth,
+        }
+        .into(),
+      )
+    }
+    ValueType::Object => {
+      let obj = unsafe { value.cast::<JsObject>() };
+      if obj.is_array()? {
+        let len = obj.get_array_length()?;
+        let mut arr = Vec::with_capacity(len as usize);
+        for i in 0..len {
+          let item = js_to_json_opt(key, obj.get_element::<JsUnknown>(i)?, depth + 1, max_depth)?;
+          arr.push(item.unwrap_or(Value::Null));
+        }
+        Value::Array(arr)
+      } else {
+        let names = obj.get_property_names()?;
+        let len = names.get_array_length()?;
+        let mut map = serde_json::Map::new();
+        for i in 0..len {
+          let name = names.get_element::<JsString>(i)?.into_utf8()?.into_owned()?;
+          let prop = obj.get_named_property::<JsUnknown>(&name)?;
+          if let Some(prop) = js_to_json_opt(key, prop, depth + 1, max_depth)? {
+            map.insert(name, prop);
+          }
+        }
+        Value::Object(map)
+      }
+    }
+    _ => return Ok(None),
+  };
+  Ok(Some(ret))
+}
+
+/// Serializes a JS object with `JSON.stringify`, like the JS side does before storing it.
+/// Returns `None` if the object serializes to `undefined`.
+pub(crate) fn stringify_js_object(env: Env, obj: JsObject) -> Result<Option<String>> {
+  let json: JsObject = env.get_global()?.get_named_property("JSON")?;
+  let stringify: JsFunction = json.get_named_property("stringify")?;
+  let result = match stringify.call(Some(&json), &[obj]) {
+    Ok(result) => result,
+    Err(e) => {
+      // e.g. for circular objects. The exception must not stay pending, or all following calls fail
+      let mut exception = std::ptr::null_mut();
+      unsafe { napi::sys::napi_get_and_clear_last_exception(env.raw(), &mut exception) };
+      return Err(e);
+    }
+  };
+  match result.get_type()? {
+    ValueType::String => Ok(Some(result.coerce_to_string()?.into_utf8()?.into_owned()?)),
+    _ => Ok(None),
+  }
+}
+
+#[napi(object, js_name = "JsonlDBStats")]
+pub struct JsonlDBStats {
+  /// How many lines the DB file consists of
+  #[napi]
+  pub uncompressed_size: u32,
+  /// How many lines written since the last compress overwrote a key that was already in the file
+  #[napi]
+  pub overwrites: u32,
+  /// How many delete lines were written since the last compress
+  #[napi]
+  pub tombstones: u32,
+  /// When the DB file was last compressed (epoch ms). Not set if it wasn't since opening the DB
+  #[napi]
+  pub last_compress_at: Option<i64>,
+  /// How many lines were written since the last compress
+  #[napi]
+  pub changes_since_compress: u32,
+  /// When compressing by interval will be possible next (epoch ms), if enabled. This is
+  /// an estimate, compressing also requires `autoCompress.intervalMinChanges` changes.
+  #[napi]
+  pub next_compress_eligible_at: Option<i64>,
+}
+
+impl From<WriteStats> for JsonlDBStats {
+  fn from(stats: WriteStats) -> Self {
+    Self {
+      uncompressed_size: stats.uncompressed_size as u32,
+      overwrites: stats.overwrites as u32,
+      tombstones: stats.tombstones as u32,
+      last_compress_at: stats.last_compress_at.map(|t| t as i64),
+      changes_since_compress: stats.changes_since_compress as u32,
+      next_compress_eligible_at: stats.next_compress_eligible_at.map(|t| t as i64),
+    }
+  }
+}
+
+#[napi(object, js_name = "JsonlDBEntryMeta")]
+pub struct JsonlDBEntryMeta {
+  /// When the entry was first written (epoch ms), if known
+  #[napi]
+  pub created_at: Option<i64>,
+  /// When the entry was last written (epoch ms)
+  #[napi]
+  pub updated_at: i64,
+}
+
+impl From<EntryMeta> for JsonlDBEntryMeta {
+  fn from(meta: EntryMeta) -> Self {
+    Self {
+      created_at: meta.created_at.map(|t| t as i64),
+      updated_at: meta.updated_at as i64,
+    }
+  }
+}
+
+/// Estimated memory usage in bytes
+#[napi(object, js_name = "JsonlDBMemoryUsage")]
+pub struct JsonlDBMemoryUsage {
+  /// Keys and values of all entries
+  #[napi]
+  pub entries: i64,
+  /// Writes that are not yet persisted
+  #[napi]
+  pub journal: i64,
+  /// The lookup tables for `indexPaths`
+  #[napi]
+  pub index: i64,
+  #[napi]
+  pub total: i64,
+}
+
+impl From<MemoryUsage> for JsonlDBMemoryUsage {
+  fn from(usage: MemoryUsage) -> Self {
+    Self {
+      entries: usage.entries as i64,
+      journal: usage.journal as i64,
+      index: usage.index as i64,
+      total: usage.total() as i64,
+    }
+  }
+}
+
+/// What compressing the DB file would gain
+#[napi(object, js_name = "JsonlDBCompressEstimate")]
+pub struct JsonlDBCompressEstimate {
+  /// How many bytes the DB file currently occupies
+  #[napi]
+  pub current_file_bytes: i64,
+  /// How many bytes the DB file would occupy after compressing it
+  #[napi]
+  pub estimated_compact_bytes: i64,
+  /// How many bytes compressing would free up
+  #[napi]
+  pub wasted_bytes: i64,
+  /// How many entries the DB has
+  #[napi]
+  pub live_entries: u32,
+  /// How many lines the DB file currently consists of
+  #[napi]
+  pub file_lines: u32,
+}
+
+impl From<CompressEstimate> for JsonlDBCompressEstimate {
+  fn from(estimate: CompressEstimate) -> Self {
+    Self {
+      current_file_bytes: estimate.current_file_bytes as i64,
+      estimated_compact_bytes: estimate.estimated_compact_bytes as i64,
+      wasted_bytes: estimate.wasted_bytes() as i64,
+      live_entries: estimate.live_entries as u32,
+      file_lines: estimate.file_lines as u32,
+    }
+  }
+}
+
+/// Whether a DB file is locked by an open DB
+#[napi(object, js_name = "JsonlDBLockStatus")]
+pub struct JsonlDBLockStatus {
+  /// Whether the lockfile exists and was updated recently
+  #[napi]
+  pub locked: bool,
+  /// Whether the lockfile exists, but is so old that opening the DB would take it over
+  #[napi]
+  pub stale: bool,
+  /// When the lockfile was last updated (epoch ms), if it exists
+  #[napi]
+  pub mtime: Option<i64>,
+}
+
+impl From<LockStatus> for JsonlDBLockStatus {
+  fn from(status: LockStatus) -> Self {
+    Self {
+      locked: status.locked,
+      stale: status.stale,
+      mtime: status.mtime_ms.map(|mtime| mtime as i64),
+    }
+  }
+}
+
+#[napi(object, js_name = "JsonlDBStringifiedEntry")]
+pub struct JsonlDBStringifiedEntry {
+  #[napi]
+  pub key: String,
+  /// The value serialized as JSON
+  #[napi]
+  pub value_json_string: String,
+}
+
+/// A single line of a DB file
+#[napi(object, js_name = "JsonlDBParsedLine")]
+pub struct JsonlDBParsedLine {
+  #[napi]
+  pub key: String,
+  /// The value serialized as JSON, missing if the line deletes the entry.
+  /// For the header line, this is the application-defined header
+  #[napi]
+  pub value_json_string: Option<String>,
+  #[napi]
+  pub is_delete: bool,
+  /// Whether this is the line with the DB header, which is not an entry
+  #[napi]
+  pub is_header: bool,
+  /// When the entry was deleted (epoch ms), if the line is a soft-delete tombstone
+  #[napi]
+  pub deleted_at: Option<i64>,
+  /// Which migration was applied, only in the header line
+  #[napi]
+  pub migration_version: Option<u32>,
+}
+
+impl JsonlDBParsedLine {
+  /// Empty lines are skipped by opening the DB, so there is nothing to return for them
+  pub(crate) fn from_parsed(line: ParsedLine) -> Option<Self> {
+    let ret = match line {
+      ParsedLine::Empty => return None,
+      ParsedLine::Header {
+        header,
+        migration_version,
+      } => Self {
+        key: HEADER_KEY.to_owned(),
+        value_json_string: header,
+        is_delete: false,
+        is_header: true,
+        deleted_at: None,
+        migration_version,
+      },
+      ParsedLine::Set { key, value } => Self {
+        key,
+        value_json_string: Some(value),
+        is_delete: false,
+        is_header: false,
+        deleted_at: None,
+        migration_version: None,
+      },
+      ParsedLine::Delete { key, deleted_at } => Self {
+        key,
+        value_json_string: None,
+        is_delete: true,
+        is_header: false,
+        deleted_at: deleted_at.map(|t| t as i64),
+        migration_version: None,
+      },
+    };
+    Some(ret)
+  }
+}
+
+/// How far a call to `materialize` got
+#[napi(object, js_name = "JsonlDBMaterializeProgress")]
+pub struct JsonlDBMaterializeProgress {
+  /// The position of the entry to continue at
+  #[napi]
+  pub next: u32,
+  /// How many entries were converted to JS objects
+  #[napi]
+  pub converted: u32,
+  /// How many entries there are
+  #[napi]
+  pub total: u32,
+}
+
+/// The result of a call to `dump`
+#[napi(object, js_name = "JsonlDBDumpResult")]
+pub struct JsonlDBDumpResult {
+  /// How many bytes were written
+  #[napi]
+  pub bytes: u32,
+  /// Whether the DB was compressed while the dump was written
+  #[napi]
+  pub during_compress: bool,
+}
+
+/// The result of a call to `createConsistentSnapshotFile`
+#[napi(object, js_name = "JsonlDBSnapshotResult")]
+pub struct JsonlDBSnapshotResult {
+  /// How many bytes the snapshot occupies
+  #[napi]
+  pub bytes: i64,
+  /// How many entries the DB had when the snapshot was created
+  #[napi]
+  pub entries: u32,
+}
+
+impl From<SnapshotInfo> for JsonlDBSnapshotResult {
+  fn from(info: SnapshotInfo) -> Self {
+    Self {
+      bytes: info.bytes as i64,
+      entries: info.entries as u32,
+    }
+  }
+}
+
+/// Sizes of the DB file when it was opened, in bytes
+#[napi(object, js_name = "JsonlDBOpenDiagnostics")]
+pub struct JsonlDBOpenDiagnostics {
+  /// How many bytes the file occupied
+  #[napi]
+  pub file_bytes: i64,
+  /// How many bytes the file would occupy after compressing it
+  #[napi]
+  pub compacted_bytes: i64,
+  /// How many bytes compressing the file would free up
+  #[napi]
+  pub wasted_bytes: i64,
+  /// How many non-empty lines the file contained
+  #[napi]
+  pub total_lines: u32,
+  /// How many entries were left after reading all lines
+  #[napi]
+  pub live_entries: u32,
+  /// How many lines overwrote an existing entry
+  #[napi]
+  pub overwrites: u32,
+  /// How many lines deleted an entry, including tombstones
+  #[napi]
+  pub deletes: u32,
+  /// How many lines were skipped because they were longer than `maxLineLengthBytes`
+  #[napi]
+  pub oversized_lines: u32,
+  /// The byte offset of the first line that was too long
+  #[napi]
+  pub oversized_line_offset: Option<i64>,
+  /// How many invalid lines still contained a valid entry after some garbage, e.g. because
+  /// two processes wrote to the file at once. Only with `ignoreReadErrors`.
+  #[napi]
+  pub resynced_lines: u32,
+  /// How many bytes of invalid lines were skipped. Only with `ignoreReadErrors`.
+  #[napi]
+  pub skipped_bytes: i64,
+  /// How many lines were skipped because they are invalid, too long or nested too deeply.
+  /// Only with `ignoreReadErrors`.
+  #[napi]
+  pub read_error_count: u32,
+  /// Why the first 100 of these lines were skipped
+  #[napi]
+  pub read_errors: Vec<JsonlDBReadError>,
+}
+
+/// A line that was skipped while opening the DB with `ignoreReadErrors`
+#[napi(object, js_name = "JsonlDBReadError")]
+pub struct JsonlDBReadError {
+  /// The number of the line, starting at 1. With shards, this is relative to the shard file
+  #[napi]
+  pub line: u32,
+  /// The byte offset where the line starts. With shards, this is relative to the shard file
+  #[napi]
+  pub offset: i64,
+  /// Why the line was skipped, including an excerpt of it
+  #[napi]
+  pub reason: String,
+}
+
+impl From<OpenDiagnostics> for JsonlDBOpenDiagnostics {
+  fn from(diagnostics: OpenDiagnostics) -> Self {
+    Self {
+      file_bytes: diagnostics.file_bytes as i64,
+      compacted_bytes: diagnostics.compacted_bytes as i64,
+      wasted_bytes: diagnostics.wasted_bytes() as i64,
+      total_lines: diagnostics.total_lines as u32,
+      live_entries: diagnostics.live_entries as u32,
+      overwrites: diagnostics.overwrites as u32,
+      deletes: diagnostics.deletes as u32,
+      oversized_lines: diagnostics.oversized_lines as u32,
+      oversized_line_offset: diagnostics.oversized_line_offset.map(|offset| offset as i64),
+      resynced_lines: diagnostics.resynced_lines as u32,
+      skipped_bytes: diagnostics.skipped_bytes as i64,
+      read_error_count: diagnostics.read_errors.count as u32,
+      read_errors: diagnostics
+        .read_errors
+        .reported
+        .into_iter()
+        .map(|e| JsonlDBReadError {
+          line: e.line,
+          offset: e.offset as i64,
+          reason: e.reason,
+        })
+        .collect(),
+    }
+  }
+}
+
+#[napi(object, js_name = "JsonlDBQueryExplain")]
+pub struct JsonlDBQueryExplain {
+  /// Whether the object filter was resolved using the index
+  #[napi]
+  pub used_index: bool,
+  /// How many keys were considered before limiting them to the range
+  #[napi]
+  pub candidates: u32,
+  /// How many keys were within the range and looked up
+  #[napi]
+  pub scanned: u32,
+}
+
+impl From<QueryExplain> for JsonlDBQueryExplain {
+  fn from(explain: QueryExplain) -> Self {
+    Self {
+      used_index: explain.used_index,
+      candidates: explain.candidates as u32,
+      scanned: explain.scanned as u32,
+    }
+  }
+}
+
+/// What `exportJson` wrote
+#[napi(object, js_name = "JsonlDBJsonExportResult")]
+pub struct JsonlDBJsonExportResult {
+  /// How many entries were exported
+  #[napi]
+  pub entries: u32,
+  /// How many entries were left out, because their value is `null`
+  #[napi]
+  pub omitted_null_values: u32,
+}
+
+impl From<JsonExportStats> for JsonlDBJsonExportResult {
+  fn from(stats: JsonExportStats) -> Self {
+    Self {
+      entries: stats.entries as u32,
+      omitted_null_values: stats.omitted_null_values as u32,
+    }
+  }
+}
+
+/// What `importJson` changed
+#[napi(object, js_name = "JsonlDBJsonImportResult")]
+pub struct JsonlDBJsonImportResult {
+  /// How many entries were set
+  #[napi]
+  pub entries: u32,
+  /// How many existing entries were deleted, because their imported value is `null`
+  #[napi]
+  pub deleted_null_values: u32,
+}
+
+impl From<JsonImportStats> for JsonlDBJsonImportResult {
+  fn from(stats: JsonImportStats) -> Self {
+    Self {
+      entries: stats.entries as u32,
+      deleted_null_values: stats.deleted_null_values as u32,
+    }
+  }
+}
+
+/// A broken invariant that `selfCheck()` found
+#[napi(object, js_name = "JsonlDBSelfCheckViolation")]
+pub struct JsonlDBSelfCheckViolation {
+  /// Which check failed: `"journal"`, `"counters"`, `"values"`, `"index"` or `"file"`
+  #[napi]
+  pub check: String,
+  /// The affected entry, if the violation concerns a single one
+  #[napi]
+  pub key: Option<String>,
+  #[napi]
+  pub message: String,
+}
+
+impl From<SelfCheckViolation> for JsonlDBSelfCheckViolation {
+  fn from(violation: SelfCheckViolation) -> Self {
+    Self {
+      check: violation.check.to_owned(),
+      key: violation.key,
+      message: violation.message,
+    }
+  }
+}
+
+/// What `selfCheck()` found
+#[napi(object, js_name = "JsonlDBSelfCheckReport")]
+pub struct JsonlDBSelfCheckReport {
+  /// Whether no violations were found
+  #[napi]
+  pub ok: bool,
+  /// How many entries were checked
+  #[napi]
+  pub checked_entries: u32,
+  #[napi]
+  pub violations: Vec<JsonlDBSelfCheckViolation>,
+}
+
+impl From<SelfCheckReport> for JsonlDBSelfCheckReport {
+  fn from(report: SelfCheckReport) -> Self {
+    Self {
+      ok: report.violations.is_empty(),
+      checked_entries: report.checked_entries as u32,
+      violations: report.violations.into_iter().map(Into::into).collect(),
+    }
+  }
+}
+
+/// How often one part of the DB had to wait for another to access the entries
+#[napi(object, js_name = "JsonlDBLockWaits")]
+pub struct JsonlDBLockWaits {
+  /// How often the entries were accessed
+  #[napi]
+  pub acquisitions: i64,
+  /// How many of the accesses had to wait, because another thread was accessing the entries
+  #[napi]
+  pub contended: i64,
+  /// How long the accesses waited in total (ms)
+  #[napi]
+  pub total_wait_ms: f64,
+  /// The longest wait (ms)
+  #[napi]
+  pub max_wait_ms: f64,
+}
+
+impl From<LockWaits> for JsonlDBLockWaits {
+  fn from(waits: LockWaits) -> Self {
+    Self {
+      acquisitions: waits.acquisitions as i64,
+      contended: waits.contended as i64,
+      total_wait_ms: waits.total_wait_ns as f64 / 1e6,
+      max_wait_ms: waits.max_wait_ns as f64 / 1e6,
+    }
+  }
+}
+
+/// The waits for access to the entries, by who was waiting
+#[napi(object, js_name = "JsonlDBLockContention")]
+pub struct JsonlDBLockContention {
+  /// Calls from JS
+  #[napi]
+  pub main: JsonlDBLockWaits,
+  /// Writing changes to the DB file
+  #[napi]
+  pub persistence: JsonlDBLockWaits,
+  /// Writing the compressed DB file
+  #[napi]
+  pub compress: JsonlDBLockWaits,
+}
+
+/// Cumulative statistics about `getMany` calls and writes since the DB was opened
+#[napi(object, js_name = "JsonlDBMetrics")]
+pub struct JsonlDBMetrics {
+  #[napi]
+  pub get_many_calls: i64,
+  /// Calls with an object filter that was found in the index
+  #[napi]
+  pub index_hits: i64,
+  /// Calls with an object filter that was not found in the index
+  #[napi]
+  pub index_misses: i64,
+  /// How many keys were considered in total
+  #[napi]
+  pub candidates: i64,
+  /// How many keys were looked up in total
+  #[napi]
+  pub scanned: i64,
+  /// How many entries are currently held as JS objects, e.g. because they were read.
+  /// These can be released with `dematerialize` or `dematerializeAll`.
+  #[napi]
+  pub references: i64,
+  /// How many objects of cleared entries are not released yet. They are released in chunks,
+  /// so clearing a huge DB doesn't block the main thread.
+  #[napi]
+  pub pending_unrefs: i64,
+  /// How many lines were appended to the DB file, including tombstones. Compressing doesn't
+  /// reset this, and the lines it writes are not counted.
+  #[napi]
+  pub lines_written: i64,
+  /// How many of the lines written deleted an entry
+  #[napi]
+  pub tombstones_written: i64,
+  /// How often the DB file was truncated because the DB was cleared
+  #[napi]
+  pub truncations: i64,
+  /// How many bytes the lines written occupy
+  #[napi]
+  pub bytes_written: i64,
+  /// How often the DB file was synced to disk, e.g. by `fsyncIdleMs`, `flush()` or compressing
+  #[napi]
+  pub syncs: i64,
+  /// How often the persistence thread woke up to look for work. An idle DB only wakes up to refresh
+  /// its lockfile and for timed tasks like `autoCompress.intervalMs`.
+  #[napi]
+  pub persistence_wakeups: i64,
+  /// How long the main thread, the persistence thread and compressing waited for each other.
+  /// Only measured with the `measureLockContention` option.
+  #[napi]
+  pub lock_contention: Option<JsonlDBLockContention>,
+}
+
+impl From<QueryMetrics> for JsonlDBMetrics {
+  fn from(metrics: QueryMetrics) -> Self {
+    Self {
+      get_many_calls: metrics.get_many_calls as i64,
+      index_hits: metrics.index_hits as i64,
+      index_misses: metrics.index_misses as i64,
+      candidates: metrics.candidates as i64,
+      scanned: metrics.scanned as i64,
+      references: metrics.references as i64,
+      pending_unrefs: metrics.pending_unrefs as i64,
+      lines_written: metrics.lines_written as i64,
+      tombstones_written: metrics.tombstones_written as i64,
+      truncations: metrics.truncations as i64,
+      bytes_written: metrics.bytes_written as i64,
+      syncs: metrics.syncs as i64,
+      persistence_wakeups: metrics.persistence_wakeups as i64,
+      lock_contention: metrics.lock_contention.map(|waits| JsonlDBLockContention {
+        main: waits[LockSite::Main as usize].into(),
+        persistence: waits[LockSite::Persistence as usize].into(),
+        compress: waits[LockSite::Compress as usize].into(),
+      }),
+    }
+  }
+}
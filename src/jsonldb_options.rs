@@ -1,8 +1,15 @@
+use std::collections::HashSet;
+
 use napi_derive::napi;
 
 use crate::{
-  db_options::{AutoCompressOptionsBuilder, DBOptions, DBOptionsBuilder, ThrottleFSOptionsBuilder},
+  csv_io::{CsvImportOptions, CsvValueMode},
+  db_options::{
+    AutoCompressOptionsBuilder, AutoDumpOptions, DBOptions, DBOptionsBuilder, IntervalAnchor,
+    ThrottleFSOptionsBuilder, MAX_VALUE_DEPTH_LIMIT,
+  },
   error::JsonlDBError,
+  key_ordering::KeyOrdering,
 };
 
 #[napi(object, js_name = "JsonlDBOptions")]
@@ -16,15 +23,99 @@ pub struct JsonlDBOptions {
   #[napi]
   pub lockfile_directory: Option<String>,
   #[napi]
+  pub lock_lost_retries: Option<u32>,
+  #[napi]
   pub index_paths: Option<Vec<String>>,
+  #[napi]
+  pub dedicated_runtime: Option<JsonlDBOptionsDedicatedRuntime>,
+  #[napi]
+  pub file_mode: Option<u32>,
+  #[napi]
+  pub io_buffer_size_bytes: Option<u32>,
+  #[napi]
+  pub force_open: Option<bool>,
+  #[napi]
+  pub max_invalid_line_ratio: Option<f64>,
+  /// "lexicographic" (default), "numeric" or "natural"
+  #[napi]
+  pub key_ordering: Option<String>,
+  #[napi]
+  pub track_timestamps: Option<bool>,
+  #[napi]
+  pub soft_delete: Option<JsonlDBOptionsSoftDelete>,
+  #[napi]
+  pub auto_dump: Option<JsonlDBOptionsAutoDump>,
+  #[napi]
+  pub resnapshot_on_write: Option<bool>,
+  #[napi]
+  pub scratch_directory: Option<String>,
+  #[napi]
+  pub compress_dump_suffix: Option<String>,
+  #[napi]
+  pub compress_backup_suffix: Option<String>,
+  #[napi]
+  pub rebuild_index_after_import: Option<bool>,
+  #[napi]
+  pub max_line_length_bytes: Option<u32>,
+  #[napi]
+  pub shards: Option<u32>,
+  #[napi]
+  pub debug_consistency_checks: Option<bool>,
+  #[napi]
+  pub measure_lock_contention: Option<bool>,
+  #[napi]
+  pub prefix_counters: Option<Vec<String>>,
+  #[napi]
+  pub max_value_depth: Option<u32>,
+  #[napi]
+  pub undefined_is_delete: Option<bool>,
 }
 
 #[napi(object, js_name = "JsonlDBOptionsThrottleFS")]
 pub struct JsonlDBOptionsThrottleFS {
+  /// How long to wait before writing changes to the DB file (ms). Written changes are only handed
+  /// to the OS, which may lose them if the machine crashes, until they are synced to disk.
   #[napi]
   pub interval_ms: u32,
+  /// Write earlier if more changes than this are waiting
   #[napi]
   pub max_buffered_commands: Option<u32>,
+  /// Only write the last change of a key within this time (ms). Earlier changes are never written.
+  #[napi]
+  pub coalesce_window_ms: Option<u32>,
+  /// Sync the written changes to disk once nothing was written for this long (ms).
+  /// Bounds the changes a crash of the machine can lose while writing pauses. Disabled by default,
+  /// then changes are only synced by `close()`, compressing and the promises of durable writes.
+  #[napi]
+  pub fsync_idle_ms: Option<u32>,
+  /// Sync the written changes to disk at least this often (ms), even while writing continuously.
+  /// Bounds the changes a crash of the machine can lose under constant load. Disabled by default.
+  #[napi]
+  pub fsync_max_interval_ms: Option<u32>,
+}
+
+#[napi(object, js_name = "JsonlDBOptionsDedicatedRuntime")]
+pub struct JsonlDBOptionsDedicatedRuntime {
+  #[napi]
+  pub worker_threads: Option<u32>,
+}
+
+#[napi(object, js_name = "JsonlDBOptionsSoftDelete")]
+pub struct JsonlDBOptionsSoftDelete {
+  #[napi]
+  pub retain_ms: i64,
+}
+
+#[napi(object, js_name = "JsonlDBOptionsAutoDump")]
+pub struct JsonlDBOptionsAutoDump {
+  #[napi]
+  pub interval_ms: u32,
+  /// Where to write the dumps, relative to the DB file. `{timestamp}` is replaced with the current time.
+  #[napi]
+  pub filename_template: String,
+  /// How many automatic dumps to keep, at least 1
+  #[napi]
+  pub keep: u32,
 }
 
 #[napi(object, js_name = "JsonlDBOptionsAutoCompress")]
@@ -37,10 +128,19 @@ pub struct JsonlDBOptionsAutoCompress {
   pub interval_ms: Option<u32>,
   #[napi]
   pub interval_min_changes: Option<u32>,
+  /// "lastCompress" (default) or "lastWrite"
+  #[napi]
+  pub interval_anchor: Option<String>,
   #[napi]
   pub on_close: Option<bool>,
   #[napi]
   pub on_open: Option<bool>,
+  #[napi]
+  pub on_open_if_wasted_bytes_exceed: Option<i64>,
+  #[napi]
+  pub max_overwrite_ratio: Option<f64>,
+  #[napi]
+  pub max_tombstones: Option<u32>,
 }
 
 impl Default for JsonlDBOptions {
@@ -50,7 +150,29 @@ impl Default for JsonlDBOptions {
       throttle_fs: None,
       auto_compress: None,
       lockfile_directory: None,
+      lock_lost_retries: None,
       index_paths: None,
+      dedicated_runtime: None,
+      file_mode: None,
+      io_buffer_size_bytes: None,
+      force_open: None,
+      max_invalid_line_ratio: None,
+      key_ordering: None,
+      track_timestamps: None,
+      soft_delete: None,
+      auto_dump: None,
+      resnapshot_on_write: None,
+      scratch_directory: None,
+      compress_dump_suffix: None,
+      compress_backup_suffix: None,
+      rebuild_index_after_import: None,
+      max_line_length_bytes: None,
+      shards: None,
+      debug_consistency_checks: None,
+      measure_lock_contention: None,
+      prefix_counters: None,
+      max_value_depth: None,
+      undefined_is_delete: None,
     }
   }
 }
@@ -79,12 +201,25 @@ impl TryInto<DBOptions> for JsonlDBOptions {
       if let Some(interval_min_changes) = opts.interval_min_changes {
         compress.interval_min_changes(interval_min_changes);
       }
+      if let Some(interval_anchor) = opts.interval_anchor {
+        let interval_anchor: IntervalAnchor = interval_anchor.as_str().try_into()?;
+        compress.interval_anchor(interval_anchor);
+      }
       if let Some(on_close) = opts.on_close {
         compress.on_close(on_close);
       }
       if let Some(on_open) = opts.on_open {
         compress.on_open(on_open);
       }
+      if let Some(threshold) = opts.on_open_if_wasted_bytes_exceed {
+        compress.on_open_if_wasted_bytes_exceed(Some(threshold.max(0) as u64));
+      }
+      if let Some(max_overwrite_ratio) = opts.max_overwrite_ratio {
+        compress.max_overwrite_ratio(max_overwrite_ratio);
+      }
+      if let Some(max_tombstones) = opts.max_tombstones {
+        compress.max_tombstones(max_tombstones);
+      }
 
       ret.auto_compress(
         compress
@@ -99,6 +234,15 @@ impl TryInto<DBOptions> for JsonlDBOptions {
       if let Some(max_buf) = opts.max_buffered_commands {
         throttle.max_buffered_commands(max_buf as usize);
       }
+      if let Some(fsync_idle_ms) = opts.fsync_idle_ms {
+        throttle.fsync_idle_ms(fsync_idle_ms);
+      }
+      if let Some(fsync_max_interval_ms) = opts.fsync_max_interval_ms {
+        throttle.fsync_max_interval_ms(fsync_max_interval_ms);
+      }
+      if let Some(coalesce_window_ms) = opts.coalesce_window_ms {
+        throttle.coalesce_window_ms(coalesce_window_ms);
+      }
       ret.throttle_fs(
         throttle
           .build()
@@ -110,16 +254,146 @@ impl TryInto<DBOptions> for JsonlDBOptions {
       ret.lockfile_directory(lockfile_directory);
     }
 
+    if let Some(scratch_directory) = self.scratch_directory {
+      ret.scratch_directory(Some(scratch_directory));
+    }
+
+    if let Some(compress_dump_suffix) = self.compress_dump_suffix {
+      ret.compress_dump_suffix(compress_dump_suffix);
+    }
+
+    if let Some(compress_backup_suffix) = self.compress_backup_suffix {
+      ret.compress_backup_suffix(compress_backup_suffix);
+    }
+
+    if let Some(lock_lost_retries) = self.lock_lost_retries {
+      ret.lock_lost_retries(lock_lost_retries);
+    }
+
     if let Some(index_paths) = self.index_paths {
+      validate_index_paths(&index_paths)?;
       ret.index_paths(index_paths);
     }
 
+    if let Some(opts) = self.dedicated_runtime {
+      ret.dedicated_runtime(Some(opts.worker_threads.unwrap_or(1).max(1) as usize));
+    }
+
+    if let Some(file_mode) = self.file_mode {
+      ret.file_mode(Some(file_mode));
+    }
+
+    if let Some(io_buffer_size_bytes) = self.io_buffer_size_bytes {
+      ret.io_buffer_size(io_buffer_size_bytes as usize);
+    }
+
+    if let Some(max_line_length_bytes) = self.max_line_length_bytes {
+      ret.max_line_length(max_line_length_bytes as usize);
+    }
+
+    if let Some(shards) = self.shards {
+      ret.shards(shards.max(1) as usize);
+    }
+
+    if let Some(force_open) = self.force_open {
+      ret.force_open(force_open);
+    }
+
+    if let Some(max_invalid_line_ratio) = self.max_invalid_line_ratio {
+      ret.max_invalid_line_ratio(max_invalid_line_ratio);
+    }
+
+    if let Some(key_ordering) = self.key_ordering {
+      let key_ordering: KeyOrdering = key_ordering.as_str().try_into()?;
+      ret.key_ordering(key_ordering);
+    }
+
+    if let Some(track_timestamps) = self.track_timestamps {
+      ret.track_timestamps(track_timestamps);
+    }
+
+    if let Some(opts) = self.soft_delete {
+      ret.soft_delete_retain_ms(Some(opts.retain_ms.max(0) as u64));
+    }
+
+    if let Some(opts) = self.auto_dump {
+      ret.auto_dump(Some(AutoDumpOptions {
+        interval_ms: opts.interval_ms,
+        filename_template: opts.filename_template,
+        // Keeping no dumps would remove the one that was just written
+        keep: opts.keep.max(1),
+      }));
+    }
+
+    if let Some(resnapshot_on_write) = self.resnapshot_on_write {
+      ret.resnapshot_on_write(resnapshot_on_write);
+    }
+
+    if let Some(rebuild_index_after_import) = self.rebuild_index_after_import {
+      ret.rebuild_index_after_import(rebuild_index_after_import);
+    }
+
+    if let Some(debug_consistency_checks) = self.debug_consistency_checks {
+      ret.debug_consistency_checks(debug_consistency_checks);
+    }
+
+    if let Some(measure_lock_contention) = self.measure_lock_contention {
+      ret.measure_lock_contention(measure_lock_contention);
+    }
+
+    if let Some(prefix_counters) = self.prefix_counters {
+      ret.prefix_counters(prefix_counters);
+    }
+
+    if let Some(max_value_depth) = self.max_value_depth {
+      ret.max_value_depth((max_value_depth as usize).clamp(1, MAX_VALUE_DEPTH_LIMIT));
+    }
+
+    if let Some(undefined_is_delete) = self.undefined_is_delete {
+      ret.undefined_is_delete(undefined_is_delete);
+    }
+
     ret
       .build()
       .or_else(|e| Err(JsonlDBError::InvalidOptions { source: e.into() }))
   }
 }
 
+/// Checks that all index paths are valid JSON pointers and unique
+fn validate_index_paths(paths: &[String]) -> Result<(), JsonlDBError> {
+  let mut invalid: Vec<String> = Vec::new();
+  let mut seen = HashSet::new();
+  for path in paths {
+    if !is_json_pointer(path) {
+      invalid.push(format!("\"{path}\" is not a valid JSON pointer"));
+    } else if !seen.insert(path.as_str()) {
+      invalid.push(format!("\"{path}\" is contained more than once"));
+    }
+  }
+
+  if invalid.is_empty() {
+    Ok(())
+  } else {
+    Err(JsonlDBError::InvalidOptions {
+      source: anyhow::anyhow!("Invalid indexPaths: {}", invalid.join(", ")),
+    })
+  }
+}
+
+// See RFC 6901: Either empty or starting with a slash, and ~ must be escaped as ~0 or ~1
+fn is_json_pointer(path: &str) -> bool {
+  if !path.is_empty() && !path.starts_with('/') {
+    return false;
+  }
+  let mut chars = path.chars();
+  while let Some(c) = chars.next() {
+    if c == '~' && !matches!(chars.next(), Some('0') | Some('1')) {
+      return false;
+    }
+  }
+  true
+}
+
 impl TryInto<DBOptions> for Option<JsonlDBOptions> {
   type Error = JsonlDBError;
 
@@ -127,3 +401,66 @@ impl TryInto<DBOptions> for Option<JsonlDBOptions> {
     return self.unwrap_or_default().try_into();
   }
 }
+
+#[napi(object, js_name = "JsonlDBCsvExportOptions")]
+pub struct JsonlDBCsvExportOptions {
+  /// "json" (default) or "flatten"
+  #[napi]
+  pub value_mode: Option<String>,
+}
+
+/// Which entries an entry cursor iterates over. All given conditions must match.
+#[napi(object, js_name = "JsonlDBEntryCursorOptions")]
+pub struct JsonlDBEntryCursorOptions {
+  /// The first key to include
+  #[napi]
+  pub start: Option<String>,
+  /// The last key to include
+  #[napi]
+  pub end: Option<String>,
+  /// Only include keys that start with this
+  #[napi]
+  pub prefix: Option<String>,
+  /// How `start` and `end` are compared to the keys: "binary" (default) or "unicode"
+  #[napi]
+  pub collation: Option<String>,
+}
+
+#[napi(object, js_name = "JsonlDBCsvImportOptions")]
+pub struct JsonlDBCsvImportOptions {
+  #[napi]
+  pub key_column: Option<String>,
+  #[napi]
+  pub value_column: Option<String>,
+  #[napi]
+  pub value_is_json: Option<bool>,
+}
+
+impl TryInto<CsvValueMode> for Option<JsonlDBCsvExportOptions> {
+  type Error = JsonlDBError;
+
+  fn try_into(self) -> Result<CsvValueMode, Self::Error> {
+    match self.and_then(|o| o.value_mode) {
+      Some(mode) => mode.as_str().try_into(),
+      None => Ok(CsvValueMode::Json),
+    }
+  }
+}
+
+impl Into<CsvImportOptions> for Option<JsonlDBCsvImportOptions> {
+  fn into(self) -> CsvImportOptions {
+    let mut ret = CsvImportOptions::default();
+    if let Some(opts) = self {
+      if let Some(key_column) = opts.key_column {
+        ret.key_column = key_column;
+      }
+      if let Some(value_column) = opts.value_column {
+        ret.value_column = value_column;
+      }
+      if let Some(value_is_json) = opts.value_is_json {
+        ret.value_is_json = value_is_json;
+      }
+    }
+    ret
+  }
+}
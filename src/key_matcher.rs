@@ -0,0 +1,126 @@
+use regex::{Regex, RegexBuilder};
+
+use crate::error::{JsonlDBError, Result};
+
+// Limits the memory a compiled regex may use, which also bounds how long matching takes
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+const REGEX_MAX_PATTERN_LEN: usize = 4096;
+
+/// Matches keys against a glob or regex pattern
+pub(crate) enum KeyMatcher {
+  Glob(Vec<GlobToken>),
+  Regex(Regex),
+}
+
+pub(crate) enum GlobToken {
+  // Any single character
+  Any,
+  // Any sequence of characters, including the empty one
+  AnySequence,
+  Char(char),
+}
+
+impl KeyMatcher {
+  /// Compiles the pattern. `kind` is either "glob" or "regex".
+  pub fn new(pattern: &str, kind: &str) -> Result<Self> {
+    match kind {
+      "glob" => Ok(KeyMatcher::Glob(parse_glob(pattern)?)),
+      "regex" => {
+        if pattern.len() > REGEX_MAX_PATTERN_LEN {
+          return Err(JsonlDBError::other(&format!(
+            "Invalid regex: The pattern must not be longer than {REGEX_MAX_PATTERN_LEN} bytes"
+          )));
+        }
+        let regex = RegexBuilder::new(pattern)
+          .size_limit(REGEX_SIZE_LIMIT)
+          .dfa_size_limit(REGEX_SIZE_LIMIT)
+          .build()
+          .map_err(|e| JsonlDBError::other(&format!("Invalid regex: {e}")))?;
+        Ok(KeyMatcher::Regex(regex))
+      }
+      other => Err(JsonlDBError::other(&format!(
+        "Unsupported pattern kind \"{other}\", expected \"glob\" or \"regex\""
+      ))),
+    }
+  }
+
+  pub fn is_match(&self, key: &str) -> bool {
+    match self {
+      KeyMatcher::Glob(tokens) => glob_match(tokens, key),
+      KeyMatcher::Regex(regex) => regex.is_match(key),
+    }
+  }
+}
+
+/// Parses a glob pattern. `*` matches any sequence, `?` any single character
+/// and `\` escapes the next character.
+fn parse_glob(pattern: &str) -> Result<Vec<GlobToken>> {
+  let mut ret = Vec::new();
+  let mut chars = pattern.chars();
+  while let Some(c) = chars.next() {
+    let token = match c {
+      '*' => {
+        // Consecutive stars are equivalent to a single one
+        if let Some(GlobToken::AnySequence) = ret.last() {
+          continue;
+        }
+        GlobToken::AnySequence
+      }
+      '?' => GlobToken::Any,
+      '\\' => match chars.next() {
+        Some(c) => GlobToken::Char(c),
+        None => {
+          return Err(JsonlDBError::other(
+            "Invalid glob: The pattern must not end with an unescaped \\",
+          ))
+        }
+      },
+      c => GlobToken::Char(c),
+    };
+    ret.push(token);
+  }
+  Ok(ret)
+}
+
+// Iterative wildcard matching which backtracks only to the last star, so it runs in O(n*m) worst case
+fn glob_match(tokens: &[GlobToken], key: &str) -> bool {
+  let chars: Vec<char> = key.chars().collect();
+  let (mut t, mut c) = (0, 0);
+  // Where the last star was and which character it was matched up to
+  let mut backtrack: Option<(usize, usize)> = None;
+
+  while c < chars.len() {
+    match tokens.get(t) {
+      Some(GlobToken::AnySequence) => {
+        backtrack = Some((t, c));
+        t += 1;
+        continue;
+      }
+      Some(GlobToken::Any) => {
+        t += 1;
+        c += 1;
+        continue;
+      }
+      Some(GlobToken::Char(expected)) if *expected == chars[c] => {
+        t += 1;
+        c += 1;
+        continue;
+      }
+      _ => {}
+    }
+    // Mismatch, let the last star swallow one more character
+    match backtrack {
+      Some((star_t, star_c)) => {
+        backtrack = Some((star_t, star_c + 1));
+        t = star_t + 1;
+        c = star_c + 1;
+      }
+      None => return false,
+    }
+  }
+
+  // Only stars may remain
+  tokens[t..]
+    .iter()
+    .all(|token| matches!(token, GlobToken::AnySequence))
+}
@@ -0,0 +1,163 @@
+use std::{borrow::Cow, cmp::Ordering};
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::error::JsonlDBError;
+
+/// How keys are compared in range queries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyOrdering {
+  // Compare the UTF-8 bytes
+  Lexicographic,
+  // Numbers come first and are compared by value, everything else follows lexicographically
+  Numeric,
+  // Compare runs of digits by value and everything in between lexicographically
+  Natural,
+}
+
+impl Default for KeyOrdering {
+  fn default() -> Self {
+    KeyOrdering::Lexicographic
+  }
+}
+
+impl TryFrom<&str> for KeyOrdering {
+  type Error = JsonlDBError;
+
+  fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+    match value {
+      "lexicographic" => Ok(KeyOrdering::Lexicographic),
+      "numeric" => Ok(KeyOrdering::Numeric),
+      "natural" => Ok(KeyOrdering::Natural),
+      other => Err(JsonlDBError::InvalidOptions {
+        source: anyhow::anyhow!("Unsupported key ordering \"{other}\""),
+      }),
+    }
+  }
+}
+
+/// How the text of keys is compared in range queries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collation {
+  // Compare the UTF-8 bytes, which orders the characters by their code points
+  Binary,
+  // Normalize to NFC first, so composed and decomposed forms of a character are ordered alike
+  Unicode,
+}
+
+impl Default for Collation {
+  fn default() -> Self {
+    Collation::Binary
+  }
+}
+
+impl TryFrom<&str> for Collation {
+  type Error = JsonlDBError;
+
+  fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+    match value {
+      "binary" => Ok(Collation::Binary),
+      "unicode" => Ok(Collation::Unicode),
+      other => Err(JsonlDBError::InvalidOptions {
+        source: anyhow::anyhow!("Unsupported collation \"{other}\""),
+      }),
+    }
+  }
+}
+
+fn to_nfc(key: &str) -> Cow<'_, str> {
+  if is_nfc(key) {
+    Cow::Borrowed(key)
+  } else {
+    Cow::Owned(key.nfc().collect())
+  }
+}
+
+impl KeyOrdering {
+  /// Compares two keys. Keys that are equal according to the ordering (like "1" and "01") are
+  /// compared lexicographically, so only identical keys are considered equal.
+  pub fn compare(&self, a: &str, b: &str) -> Ordering {
+    let ret = match self {
+      KeyOrdering::Lexicographic => Ordering::Equal,
+      KeyOrdering::Numeric => compare_numeric(a, b),
+      KeyOrdering::Natural => compare_natural(a, b),
+    };
+    ret.then_with(|| a.cmp(b))
+  }
+
+  /// Like `compare`, but compares the text of the keys using the given collation.
+  /// With the unicode collation, keys that only differ in their normalization are equal.
+  pub fn compare_collated(&self, a: &str, b: &str, collation: Collation) -> Ordering {
+    match collation {
+      Collation::Binary => self.compare(a, b),
+      Collation::Unicode => self.compare(&to_nfc(a), &to_nfc(b)),
+    }
+  }
+
+  /// Tests whether start <= key <= end
+  pub fn in_range(&self, key: &str, start: &str, end: &str, collation: Collation) -> bool {
+    self.compare_collated(key, start, collation) != Ordering::Less
+      && self.compare_collated(key, end, collation) != Ordering::Greater
+  }
+}
+
+fn parse_number(key: &str) -> Option<f64> {
+  // Things like "inf" or "NaN" are not numbers in the sense of this ordering
+  key.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+// Comparing numbers by value and the other pairs lexicographically would not be transitive,
+// e.g. "2" < "10", "10" < "1a" and "1a" < "2". Sorting all numbers first avoids that.
+fn compare_numeric(a: &str, b: &str) -> Ordering {
+  match (parse_number(a), parse_number(b)) {
+    (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+    (Some(_), None) => Ordering::Less,
+    (None, Some(_)) => Ordering::Greater,
+    (None, None) => Ordering::Equal,
+  }
+}
+
+// Splits a key into runs of ASCII digits and runs of other characters
+fn segments(key: &str) -> impl Iterator<Item = &str> {
+  let mut rest = key;
+  std::iter::from_fn(move || {
+    let first = rest.chars().next()?;
+    let is_digit = first.is_ascii_digit();
+    let len = rest
+      .find(|c: char| c.is_ascii_digit() != is_digit)
+      .unwrap_or(rest.len());
+    let (segment, tail) = rest.split_at(len);
+    rest = tail;
+    Some(segment)
+  })
+}
+
+// Compares two runs of digits by value without parsing them, so they can be arbitrarily long
+fn compare_digits(a: &str, b: &str) -> Ordering {
+  let a = a.trim_start_matches('0');
+  let b = b.trim_start_matches('0');
+  a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+fn compare_natural(a: &str, b: &str) -> Ordering {
+  let mut a_segments = segments(a);
+  let mut b_segments = segments(b);
+  loop {
+    match (a_segments.next(), b_segments.next()) {
+      (None, None) => return Ordering::Equal,
+      (None, Some(_)) => return Ordering::Less,
+      (Some(_), None) => return Ordering::Greater,
+      (Some(a), Some(b)) => {
+        let both_digits = a.as_bytes()[0].is_ascii_digit() && b.as_bytes()[0].is_ascii_digit();
+        let ret = if both_digits {
+          compare_digits(a, b)
+        } else {
+          a.cmp(b)
+        };
+        if ret != Ordering::Equal {
+          return ret;
+        }
+      }
+    }
+  }
+}
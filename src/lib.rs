@@ -1,10 +1,32 @@
 #![deny(clippy::all)]
 
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use csv_io::{CsvImportOptions, CsvValueMode};
 use db_options::DBOptions;
 use error::JsonlDBError;
-use js_values::JsValue;
-use napi::{bindgen_prelude::*, JsObject};
+use js_values::{
+  js_to_json, to_js_unknown, JsValue, JsonlDBCompressEstimate, JsonlDBDumpResult, JsonlDBEntryMeta,
+  JsonlDBLockStatus, JsonlDBMaterializeProgress, JsonlDBMemoryUsage, JsonlDBMetrics,
+  JsonlDBJsonExportResult, JsonlDBJsonImportResult, JsonlDBOpenDiagnostics, JsonlDBParsedLine,
+  JsonlDBQueryExplain, JsonlDBSelfCheckReport, JsonlDBSnapshotResult, JsonlDBStats,
+  JsonlDBStringifiedEntry,
+};
+use bg_thread::{
+  CompressTrigger, DBEvent, EventCallback, FlushCallback, FlushInfo, MigrateCallback,
+  MigrationBatch, ReleaseCallback, ResnapshotCallback, ResnapshotRequest,
+};
+use napi::{
+  bindgen_prelude::*,
+  threadsafe_function::{ThreadSafeCallContext, ThreadsafeFunctionCallMode},
+  JsFunction, JsObject, JsUndefined, JsUnknown, ValueType,
+};
 use napi_derive::napi;
+use serde_json::{Map, Value};
+use storage::Key;
 
 #[macro_use]
 extern crate derive_builder;
@@ -19,19 +41,33 @@ extern crate derive_builder;
 static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 mod bg_thread;
+mod csv_io;
 mod db;
 mod db_options;
+mod fs_ops;
 mod js_values;
 mod jsonldb_options;
+mod key_matcher;
+mod key_ordering;
 mod lockfile;
+mod memory_usage;
 mod persistence;
+mod shards;
 mod storage;
 mod util;
 
 #[macro_use]
 mod error;
-use db::{Closed, HalfClosed, Opened, RsonlDB};
-use jsonldb_options::JsonlDBOptions;
+use db::{Closed, HalfClosed, Migration, Opened, RangeQuery, RsonlDB};
+use jsonldb_options::{
+  JsonlDBCsvExportOptions, JsonlDBCsvImportOptions, JsonlDBEntryCursorOptions, JsonlDBOptions,
+};
+use key_ordering::Collation;
+
+// The names of the states of the DB, as reported to JS
+const STATE_CLOSED: &str = "closed";
+const STATE_HALF_CLOSED: &str = "half-closed";
+const STATE_OPEN: &str = "open";
 
 enum DB {
   Closed(RsonlDB<Closed>),
@@ -47,56 +83,301 @@ impl DB {
     }
   }
 
-  fn as_opened_mut(&mut self) -> Option<&mut RsonlDB<Opened>> {
+  fn set_event_callback(&mut self, callback: Option<EventCallback>) {
+    match self {
+      DB::Closed(db) => db.set_event_callback(callback),
+      DB::HalfClosed(db) => db.set_event_callback(callback),
+      DB::Opened(db) => db.set_event_callback(callback),
+    }
+  }
+
+  /// The name of the current state, as used in error messages and by `getState()`
+  fn state_name(&self) -> &'static str {
+    match self {
+      DB::Closed(_) => STATE_CLOSED,
+      DB::HalfClosed(_) => STATE_HALF_CLOSED,
+      DB::Opened(_) => STATE_OPEN,
+    }
+  }
+
+  /// The error for calling `method` in the wrong state, including how to get to the required one
+  fn wrong_state(&self, method: &'static str, required: &'static str) -> JsonlDBError {
+    let current = self.state_name();
+    let hint = match (current, required) {
+      (STATE_CLOSED, STATE_OPEN) => "Call open() first",
+      (STATE_HALF_CLOSED, STATE_OPEN) => "Call close() and then open() first",
+      (STATE_OPEN, STATE_HALF_CLOSED) => "Call halfClose() first",
+      (STATE_CLOSED, STATE_HALF_CLOSED) => "Call open() and then halfClose() first",
+      (STATE_HALF_CLOSED, STATE_CLOSED) => "Call close() first",
+      _ => "Call halfClose() and then close() first",
+    };
+    JsonlDBError::WrongState {
+      method,
+      required,
+      current,
+      hint,
+    }
+  }
+
+  fn as_opened(
+    &self,
+    method: &'static str,
+  ) -> std::result::Result<&RsonlDB<Opened>, JsonlDBError> {
     match self {
-      DB::Opened(x) => Some(x),
-      _ => None,
+      DB::Opened(x) => Ok(x),
+      _ => Err(self.wrong_state(method, STATE_OPEN)),
     }
   }
 
-  fn as_closed_mut(&mut self) -> Option<&mut RsonlDB<Closed>> {
+  fn as_opened_mut(
+    &mut self,
+    method: &'static str,
+  ) -> std::result::Result<&mut RsonlDB<Opened>, JsonlDBError> {
     match self {
-      DB::Closed(x) => Some(x),
-      _ => None,
+      DB::Opened(x) => Ok(x),
+      _ => Err(self.wrong_state(method, STATE_OPEN)),
+    }
+  }
+
+  /// Like `as_opened_mut`, but fails if the DB became read-only because the lockfile was lost
+  fn as_writable_mut(
+    &mut self,
+    method: &'static str,
+  ) -> std::result::Result<&mut RsonlDB<Opened>, JsonlDBError> {
+    let db = self.as_opened_mut(method)?;
+    if db.is_lock_lost() {
+      return Err(JsonlDBError::LockLost);
     }
+    Ok(db)
   }
 
-  fn as_half_closed_mut(&mut self) -> Option<&mut RsonlDB<HalfClosed>> {
+  fn as_closed_mut(
+    &mut self,
+    method: &'static str,
+  ) -> std::result::Result<&mut RsonlDB<Closed>, JsonlDBError> {
     match self {
-      DB::HalfClosed(x) => Some(x),
-      _ => None,
+      DB::Closed(x) => Ok(x),
+      _ => Err(self.wrong_state(method, STATE_CLOSED)),
     }
   }
+
+  fn as_half_closed_mut(
+    &mut self,
+    method: &'static str,
+  ) -> std::result::Result<&mut RsonlDB<HalfClosed>, JsonlDBError> {
+    match self {
+      DB::HalfClosed(x) => Ok(x),
+      _ => Err(self.wrong_state(method, STATE_HALF_CLOSED)),
+    }
+  }
+}
+
+/// Lets the persistence thread have the JS objects in the journal serialized again on the main thread.
+/// The work happens while preparing the call, the JS function itself does nothing.
+fn create_resnapshot_callback(env: Env) -> Result<ResnapshotCallback> {
+  let noop = env.create_function_from_closure("resnapshot", |ctx| ctx.env.get_undefined())?;
+  let mut callback: ResnapshotCallback =
+    noop.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<ResnapshotRequest>| {
+      let ResnapshotRequest { storage, done } = ctx.value;
+      storage.lock().resnapshot_pending(ctx.env);
+      done.send(()).ok();
+      Ok(Vec::<JsUndefined>::new())
+    })?;
+  // Don't keep the process alive just because the DB exists
+  callback.unref(&env)?;
+  Ok(callback)
+}
+
+/// Lets the JS objects of a DB that was garbage-collected while half-closed be released on the
+/// main thread, where `close()` would have done that.
+fn create_release_callback(env: Env) -> Result<ReleaseCallback> {
+  let noop = env.create_function_from_closure("release", |ctx| ctx.env.get_undefined())?;
+  let mut callback: ReleaseCallback =
+    noop.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<HalfClosed>| {
+      ctx.value.release(ctx.env);
+      Ok(Vec::<JsUndefined>::new())
+    })?;
+  // Don't keep the process alive just because the DB exists
+  callback.unref(&env)?;
+  Ok(callback)
+}
+
+/// Calls the migration function of `openWithMigration()` with batches of entries. It gets the keys,
+/// the serialized values and a function to call with the changed keys and their new values, or with
+/// an error message.
+fn create_migrate_callback(env: Env, migrate: JsFunction) -> Result<MigrateCallback> {
+  let mut callback: MigrateCallback =
+    migrate.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<MigrationBatch>| {
+      let MigrationBatch { keys, values, done } = ctx.value;
+      let done = Cell::new(Some(done));
+      let done = ctx.env.create_function_from_closure("done", move |ctx| {
+        let error = ctx.get::<JsUnknown>(0)?;
+        let result = if error.get_type()? == ValueType::String {
+          Err(ctx.env.from_js_value(error)?)
+        } else {
+          let keys: Vec<String> = ctx.env.from_js_value(ctx.get::<JsUnknown>(1)?)?;
+          let values: Vec<Option<String>> = ctx.env.from_js_value(ctx.get::<JsUnknown>(2)?)?;
+          Ok(keys.into_iter().zip(values).collect())
+        };
+        if let Some(done) = done.take() {
+          done.send(result).ok();
+        }
+        ctx.env.get_undefined()
+      })?;
+      Ok(vec![
+        ctx.env.to_js_value(&keys)?,
+        ctx.env.to_js_value(&values)?,
+        done.into_unknown(),
+      ])
+    })?;
+  // Opening keeps the process alive anyways
+  callback.unref(&env)?;
+  Ok(callback)
+}
+
+/// Range queries compare the keys binary unless another collation is requested
+fn parse_collation(collation: Option<String>) -> Result<Collation> {
+  Ok(match collation {
+    Some(collation) => collation.as_str().try_into()?,
+    None => Collation::default(),
+  })
 }
 
 #[napi(js_name = "JsonlDB")]
 pub struct JsonlDB {
   r: DB,
+  release: ReleaseCallback,
+  // Async methods that only borrow the DB hold this for reading while they run. Closing holds it
+  // for writing, so the opened DB isn't replaced while they still use it.
+  transition: tokio::sync::RwLock<()>,
+  // The migration for the next call to `openWithMigration()`
+  migration: Option<Migration>,
+  // The referenced objects serialized by `prepareSelfCheck()` for the next call to `selfCheck()`
+  self_check_references: Mutex<Option<HashMap<Key, String>>>,
+  // The files read by `loadCsvImport()` and `loadJsonImport()` for `importLoaded()`, by their id
+  loaded_imports: Mutex<HashMap<u32, Map<String, Value>>>,
+  next_import_id: AtomicU32,
+}
+
+impl Drop for JsonlDB {
+  fn drop(&mut self) {
+    // Without the second call to `close()`, the JS objects would never be released
+    if let DB::HalfClosed(db) = &self.r {
+      self
+        .release
+        .call(db.abandon(), ThreadsafeFunctionCallMode::NonBlocking);
+    }
+  }
+}
+
+impl JsonlDB {
+  fn add_loaded_import(&self, map: Map<String, Value>) -> u32 {
+    let id = self.next_import_id.fetch_add(1, Ordering::Relaxed);
+    lock_field(&self.loaded_imports).insert(id, map);
+    id
+  }
+}
+
+/// Locks a field that async methods share with the others. They never panic while holding it.
+fn lock_field<T>(field: &Mutex<T>) -> MutexGuard<'_, T> {
+  field.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 #[napi(js_name = "JsonlDB")]
 impl JsonlDB {
   #[napi(constructor)]
-  pub fn new(filename: String, options: Option<JsonlDBOptions>) -> Result<Self> {
+  pub fn new(env: Env, filename: String, options: Option<JsonlDBOptions>) -> Result<Self> {
     let options: DBOptions = options.try_into()?;
+    let filename = util::normalize_db_filename(&filename)?;
+    let resnapshot_on_write = options.resnapshot_on_write;
+
+    let mut db = RsonlDB::new(filename, options);
+    if resnapshot_on_write {
+      db.set_resnapshot_callback(Some(create_resnapshot_callback(env)?));
+    }
 
     Ok(JsonlDB {
-      r: DB::Closed(RsonlDB::new(filename, options)),
+      r: DB::Closed(db),
+      release: create_release_callback(env)?,
+      transition: tokio::sync::RwLock::new(()),
+      migration: None,
+      self_check_references: Mutex::new(None),
+      loaded_imports: Mutex::new(HashMap::new()),
+      next_import_id: AtomicU32::new(1),
     })
   }
 
+  /// Checks whether the DB file is locked by an open DB, without opening it or acquiring the lock
+  #[napi]
+  pub fn is_locked(filename: String, options: Option<JsonlDBOptions>) -> Result<JsonlDBLockStatus> {
+    let options: DBOptions = options.try_into()?;
+    let filename = util::normalize_db_filename(&filename)?;
+    Ok(db::lock_status(&filename, &options)?.into())
+  }
+
+  /// Parses a single line of a DB file the same way opening the DB does.
+  /// Returns `null` for empty lines, which opening the DB skips
+  #[napi]
+  pub fn parse_line(line: String) -> Result<Option<JsonlDBParsedLine>> {
+    let parsed = storage::parse_line(&line)?;
+    Ok(JsonlDBParsedLine::from_parsed(parsed))
+  }
+
+  /// Renders the line that sets the key to the serialized value, the same way writing the DB does
+  #[napi]
+  pub fn format_line(key: String, value_json: String) -> Result<String> {
+    Ok(storage::format_value_line(&key, &value_json)?)
+  }
+
   #[napi]
   pub async fn open(&mut self) -> Result<()> {
-    let db = self.r.as_closed_mut().ok_or(JsonlDBError::AlreadyOpen)?;
+    let db = self.r.as_closed_mut("open()")?;
     let db = db.open().await?;
     self.r = DB::Opened(db);
 
     Ok(())
   }
 
+  /// Sets the migration for the next call to `openWithMigration()`
+  #[napi(
+    ts_args_type = "migrate: (keys: string[], valueJsonStrings: string[], done: (error: string | null, changedKeys?: string[], newValueJsonStrings?: (string | null)[]) => void) => void, version: number"
+  )]
+  pub fn set_migration(&mut self, env: Env, migrate: JsFunction, version: u32) -> Result<()> {
+    self.r.as_closed_mut("openWithMigration()")?;
+    self.migration = Some(Migration {
+      callback: create_migrate_callback(env, migrate)?,
+      version,
+    });
+    Ok(())
+  }
+
+  /// Opens the DB, but passes all entries through the migration function first, unless the DB
+  /// already has its version. Resolves to whether the migration ran.
+  #[napi]
+  pub async fn open_with_migration(&mut self) -> Result<bool> {
+    let migration = self.migration.take().ok_or_else(|| {
+      JsonlDBError::other("openWithMigration() requires calling setMigration() first")
+    })?;
+    let db = self.r.as_closed_mut("openWithMigration()")?;
+    let (db, migrated) = db.open_with_migration(Some(migration)).await?;
+    self.r = DB::Opened(db);
+
+    Ok(migrated)
+  }
+
+  #[napi]
+  pub async fn reshard(&mut self, shards: u32) -> Result<()> {
+    let db = self.r.as_closed_mut("reshard()")?;
+    db.reshard(shards.max(1) as usize).await?;
+
+    Ok(())
+  }
+
   #[napi]
   pub async fn half_close(&mut self) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+    // Wait for a running compress() etc. to finish
+    let _transition = self.transition.write().await;
+    let db = self.r.as_opened_mut("halfClose()")?;
     let db = db.close().await?;
     self.r = DB::HalfClosed(db);
 
@@ -105,10 +386,7 @@ impl JsonlDB {
 
   #[napi]
   pub fn close(&mut self, env: Env) -> Result<()> {
-    let db = self
-      .r
-      .as_half_closed_mut()
-      .ok_or(JsonlDBError::NotStopped)?;
+    let db = self.r.as_half_closed_mut("close()")?;
     let db = db.close(env)?;
     self.r = DB::Closed(db);
 
@@ -116,38 +394,142 @@ impl JsonlDB {
   }
 
   #[napi]
-  pub async fn dump(&mut self, filename: String) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    db.dump(&filename).await?;
+  pub async fn dump(&self, filename: String, gzip: Option<bool>) -> Result<JsonlDBDumpResult> {
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("dump()")?;
+    let (bytes, during_compress) = db.dump(&filename, gzip.unwrap_or(false)).await?;
+
+    Ok(JsonlDBDumpResult {
+      bytes: bytes as u32,
+      during_compress,
+    })
+  }
+
+  #[napi]
+  pub async fn compress(&self) -> Result<()> {
+    // Compressing does nothing while the DB is being closed
+    let _transition = match self.transition.try_read() {
+      Ok(guard) => guard,
+      Err(_) => return Ok(()),
+    };
+    let db = self.r.as_opened("compress()")?;
+    db.compress(CompressTrigger::Manual).await?;
 
     Ok(())
   }
 
   #[napi]
-  pub async fn compress(&mut self) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    db.compress().await?;
+  pub async fn create_consistent_snapshot_file(
+    &self,
+    filename: String,
+  ) -> Result<JsonlDBSnapshotResult> {
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("createConsistentSnapshotFile()")?;
+    Ok(db.create_snapshot_file(&filename).await?.into())
+  }
+
+  #[napi]
+  pub async fn retry_persistence(&self) -> Result<()> {
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("retryPersistence()")?;
+    db.retry_persistence().await?;
+
+    Ok(())
+  }
+
+  #[napi(
+    ts_args_type = "callback: (info: { entries: number; bytes: number; durationMs: number }) => void"
+  )]
+  pub fn on_flush(&mut self, callback: JsFunction) -> Result<()> {
+    let db = self.r.as_opened_mut("onFlush()")?;
+
+    let callback: FlushCallback = callback.create_threadsafe_function(
+      0,
+      |ctx: ThreadSafeCallContext<FlushInfo>| Ok(vec![ctx.env.to_js_value(&ctx.value)?]),
+    )?;
+    db.set_flush_callback(Some(callback));
 
     Ok(())
   }
 
+  #[napi]
+  pub fn off_flush(&mut self) -> Result<()> {
+    let db = self.r.as_opened_mut("offFlush()")?;
+    db.set_flush_callback(None);
+
+    Ok(())
+  }
+
+  /// Registers the callback for lifecycle events. It is released when the DB is closed.
+  #[napi(ts_args_type = "callback: (event: string, payload: any) => void")]
+  pub fn set_event_callback(&mut self, env: Env, callback: JsFunction) -> Result<()> {
+    let mut callback: EventCallback =
+      callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<DBEvent>| {
+        Ok(vec![
+          ctx.env.create_string(ctx.value.name)?.into_unknown(),
+          ctx.env.to_js_value(&ctx.value.payload)?,
+        ])
+      })?;
+    // It is kept after halfClose() to warn about a missing close(), which must not keep the
+    // process alive
+    callback.unref(&env)?;
+    self.r.set_event_callback(Some(callback));
+
+    Ok(())
+  }
+
+  #[napi]
+  pub fn clear_event_callback(&mut self) {
+    self.r.set_event_callback(None);
+  }
+
+  /// The current state of the DB: `"closed"`, `"half-closed"` or `"open"`
+  #[napi(ts_return_type = "\"closed\" | \"half-closed\" | \"open\"")]
+  pub fn get_state(&self) -> String {
+    self.r.state_name().to_owned()
+  }
+
   #[napi]
   pub fn is_open(&self) -> bool {
     self.r.is_opened()
   }
 
-  #[napi]
-  pub fn set_primitive(&mut self, env: Env, key: String, value: serde_json::Value) -> Result<()> {
+  /// `undefined` is not converted to `null`, but deletes the key with the `undefinedIsDelete`
+  /// option and is refused otherwise
+  #[napi(ts_args_type = "key: string, value: any")]
+  pub fn set_primitive(&mut self, env: Env, key: String, value: JsUnknown) -> Result<()> {
+    let db = self.r.as_writable_mut("setPrimitive()")?;
+    if value.get_type()? == ValueType::Undefined {
+      db.set_undefined(env, key)?;
+      return Ok(());
+    }
+
+    let value = js_to_json(&key, value, db.max_value_depth())?;
     if !(value.is_null() || value.is_number() || value.is_string() || value.is_boolean()) {
       return Err(JsonlDBError::NotPrimitive(value).into());
     }
-
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    db.set_native(env, key, value);
+    db.set_native(env, key, value)?;
 
     Ok(())
   }
 
+  #[napi(ts_args_type = "key: string, value: any", ts_return_type = "Promise<void>")]
+  pub fn set_primitive_durable(
+    &mut self,
+    env: Env,
+    key: String,
+    value: JsUnknown,
+  ) -> Result<JsObject> {
+    self.set_primitive(env, key, value)?;
+
+    let db = self.r.as_writable_mut("setPrimitiveDurable()")?;
+    let flushed = db.flush();
+    env.execute_tokio_future(
+      async move { flushed.await.map_err(napi::Error::from) },
+      |_, _| Ok(()),
+    )
+  }
+
   #[napi]
   pub fn set_object(
     &mut self,
@@ -155,68 +537,483 @@ impl JsonlDB {
     key: String,
     value: JsObject,
     stringified: String,
-    index_keys: Vec<String>,
+    index_keys: Option<Vec<String>>,
   ) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+    let db = self.r.as_writable_mut("setObject()")?;
+    db.check_value_depth(&key, &stringified)?;
 
     let reference = env.create_reference(value)?;
-    db.set_reference(env, key, reference, stringified, index_keys);
+    db.set_reference(env, key, reference, stringified, index_keys)?;
+
+    Ok(())
+  }
+
+  #[napi(ts_return_type = "Promise<void>")]
+  pub fn set_object_durable(
+    &mut self,
+    env: Env,
+    key: String,
+    value: JsObject,
+    stringified: String,
+    index_keys: Option<Vec<String>>,
+  ) -> Result<JsObject> {
+    self.set_object(env, key, value, stringified, index_keys)?;
+
+    let db = self.r.as_writable_mut("setObjectDurable()")?;
+    let flushed = db.flush();
+    env.execute_tokio_future(
+      async move { flushed.await.map_err(napi::Error::from) },
+      |_, _| Ok(()),
+    )
+  }
+
+  /// Stores any JSON value without keeping a reference to the JS object.
+  /// The index keys are derived from the value.
+  #[napi(ts_args_type = "key: string, value: any")]
+  pub fn set_native(&mut self, env: Env, key: String, value: JsUnknown) -> Result<()> {
+    let db = self.r.as_writable_mut("setNative()")?;
+    let value = js_to_json(&key, value, db.max_value_depth())?;
+    db.set_native(env, key, value)?;
+
+    Ok(())
+  }
+
+  /// Like `setNative()`, but an absent or `undefined` value deletes the key with the
+  /// `undefinedIsDelete` option. Returns whether a value was stored.
+  #[napi(ts_args_type = "key: string, value?: any")]
+  pub fn set_maybe(&mut self, env: Env, key: String, value: JsUnknown) -> Result<bool> {
+    let db = self.r.as_writable_mut("setMaybe()")?;
+    if value.get_type()? == ValueType::Undefined {
+      db.set_undefined(env, key)?;
+      return Ok(false);
+    }
+
+    let value = js_to_json(&key, value, db.max_value_depth())?;
+    db.set_native(env, key, value)?;
+
+    Ok(true)
+  }
+
+  #[napi]
+  pub fn set_object_detached(
+    &mut self,
+    env: Env,
+    key: String,
+    stringified: String,
+    index_keys: Option<Vec<String>>,
+  ) -> Result<()> {
+    let db = self.r.as_writable_mut("setObjectDetached()")?;
+    db.set_stringified(env, key, stringified, index_keys)?;
 
     Ok(())
   }
 
   #[napi]
   pub fn delete(&mut self, env: Env, key: String) -> Result<bool> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+    let db = self.r.as_writable_mut("delete()")?;
     Ok(db.delete(env, key))
   }
 
+  #[napi(ts_args_type = "pattern: string, kind: \"glob\" | \"regex\"")]
+  pub fn delete_matching(&mut self, env: Env, pattern: String, kind: String) -> Result<u32> {
+    let db = self.r.as_writable_mut("deleteMatching()")?;
+    Ok(db.delete_matching(env, &pattern, &kind)?)
+  }
+
   #[napi]
-  pub fn has(&mut self, key: String) -> Result<bool> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+  pub fn has(&self, key: String) -> Result<bool> {
+    let db = self.r.as_opened("has()")?;
     Ok(db.has(&key))
   }
 
   #[napi(ts_return_type = "unknown")]
-  pub fn get(&mut self, env: Env, key: String) -> Result<Option<JsValue>> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+  pub fn get(&self, env: Env, key: String) -> Result<Option<JsValue>> {
+    let db = self.r.as_opened("get()")?;
     let ret = db.get(env, &key)?;
     Ok(ret)
   }
 
+  /// Returns the serialized value of a large object that is not held as a JS object yet, so it can
+  /// be parsed without blocking the main thread for long. `null` for other entries.
+  #[napi]
+  pub async fn get_large_stringified(
+    &self,
+    key: String,
+    min_bytes: u32,
+  ) -> Result<Option<String>> {
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("getLargeStringified()")?;
+    Ok(db.get_large_stringified(&key, min_bytes as usize)?)
+  }
+
+  /// Keeps the object parsed from the result of `getLargeStringified` like `get` does and returns
+  /// the entry, which is another value if it was changed in the meantime
+  #[napi(ts_return_type = "unknown")]
+  pub fn adopt_parsed(
+    &self,
+    env: Env,
+    key: String,
+    value: JsObject,
+    stringified: String,
+  ) -> Result<Option<JsValue>> {
+    let db = self.r.as_opened("adoptParsed()")?;
+    let ret = db.adopt_parsed(env, &key, value, stringified)?;
+    Ok(ret)
+  }
+
   #[napi(ts_return_type = "unknown[]")]
+  pub fn get_batch(&self, env: Env, keys: Vec<String>) -> Result<Vec<JsUnknown>> {
+    let db = self.r.as_opened("getBatch()")?;
+    // Missing keys are returned as undefined like get() does. A `None` in a Vec would become null,
+    // which could not be told apart from a stored null.
+    db.get_batch(env, &keys)?
+      .into_iter()
+      .map(|value| match value {
+        Some(value) => unsafe { to_js_unknown(env.raw(), value) },
+        None => Ok(env.get_undefined()?.into_unknown()),
+      })
+      .collect()
+  }
+
+  #[napi(ts_return_type = "{ key: string; value: unknown }[]")]
+  pub fn get_batch_entries(&self, env: Env, keys: Vec<String>) -> Result<Vec<JsObject>> {
+    let db = self.r.as_opened("getBatchEntries()")?;
+    db.get_batch_entries(env, keys)?
+      .into_iter()
+      .map(|(key, value)| {
+        let mut obj = env.create_object()?;
+        obj.set_named_property("key", env.create_string_from_std(key)?)?;
+        obj.set_named_property("value", unsafe { to_js_unknown(env.raw(), value)? })?;
+        Ok(obj)
+      })
+      .collect()
+  }
+
+  #[napi]
+  pub fn get_batch_stringified(&self, keys: Vec<String>) -> Result<Vec<JsonlDBStringifiedEntry>> {
+    let db = self.r.as_opened("getBatchStringified()")?;
+    let ret = db
+      .get_batch_stringified(keys)
+      .into_iter()
+      .map(|(key, value_json_string)| JsonlDBStringifiedEntry {
+        key,
+        value_json_string,
+      })
+      .collect();
+    Ok(ret)
+  }
+
+  /// Takes a snapshot of the matching keys, whose entries can then be read in batches
+  #[napi]
+  pub fn create_entry_cursor(&mut self, options: Option<JsonlDBEntryCursorOptions>) -> Result<u32> {
+    let db = self.r.as_opened_mut("createEntryCursor()")?;
+    let (start, end, prefix, collation) = match options {
+      Some(options) => (options.start, options.end, options.prefix, options.collation),
+      None => (None, None, None, None),
+    };
+    let collation = parse_collation(collation)?;
+    let ret = db.create_entry_cursor(
+      start.as_deref(),
+      end.as_deref(),
+      prefix.as_deref(),
+      collation,
+    );
+    Ok(ret)
+  }
+
+  #[napi]
+  pub fn read_entry_cursor(
+    &mut self,
+    id: u32,
+    batch_size: u32,
+  ) -> Result<Vec<JsonlDBStringifiedEntry>> {
+    let db = self.r.as_opened_mut("readEntryCursor()")?;
+    let ret = db
+      .read_entry_cursor(id, batch_size as usize)?
+      .into_iter()
+      .map(|(key, value_json_string)| JsonlDBStringifiedEntry {
+        key,
+        value_json_string,
+      })
+      .collect();
+    Ok(ret)
+  }
+
+  #[napi]
+  pub fn close_entry_cursor(&mut self, id: u32) -> Result<bool> {
+    let db = self.r.as_opened_mut("closeEntryCursor()")?;
+    Ok(db.close_entry_cursor(id))
+  }
+
+  #[napi(
+    ts_args_type = "startKey: string, endKey: string, objFilter?: string | { path: string; value: string | number | boolean } | undefined | null, sortKeys?: ((keys: string[]) => string[]) | undefined | null, collation?: string | undefined | null",
+    ts_return_type = "unknown[]"
+  )]
   pub fn get_many(
     &mut self,
     env: Env,
     start_key: String,
     end_key: String,
-    obj_filter: Option<String>,
+    obj_filter: Option<serde_json::Value>,
+    sort_keys: Option<JsFunction>,
+    collation: Option<String>,
   ) -> Result<Vec<JsValue>> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    let ret = db.get_many(env, &start_key, &end_key, obj_filter)?;
+    let db = self.r.as_opened_mut("getMany()")?;
+    let query = RangeQuery {
+      start_key: &start_key,
+      end_key: &end_key,
+      obj_filter,
+      sort_keys: sort_keys.as_ref(),
+      collation: parse_collation(collation)?,
+    };
+    let (ret, _) = db.get_many(env, &query)?;
+    Ok(ret)
+  }
+
+  /// Like `get_many`, but returns the results as a single JSON string, which is faster to transfer
+  /// than many small objects. The string is an object keyed by the entry keys if `keyed` is true.
+  #[napi(
+    ts_args_type = "startKey: string, endKey: string, objFilter?: string | { path: string; value: string | number | boolean } | undefined | null, sortKeys?: ((keys: string[]) => string[]) | undefined | null, collation?: string | undefined | null, keyed?: boolean | undefined | null"
+  )]
+  #[allow(clippy::too_many_arguments)]
+  pub fn get_many_stringified(
+    &mut self,
+    env: Env,
+    start_key: String,
+    end_key: String,
+    obj_filter: Option<serde_json::Value>,
+    sort_keys: Option<JsFunction>,
+    collation: Option<String>,
+    keyed: Option<bool>,
+  ) -> Result<String> {
+    let db = self.r.as_opened_mut("getManyStringified()")?;
+    let query = RangeQuery {
+      start_key: &start_key,
+      end_key: &end_key,
+      obj_filter,
+      sort_keys: sort_keys.as_ref(),
+      collation: parse_collation(collation)?,
+    };
+    let ret = db.get_many_stringified(env, &query, keyed.unwrap_or(false))?;
     Ok(ret)
   }
 
+  /// Like `get_many`, but also returns how the query was answered
+  #[napi(
+    ts_args_type = "startKey: string, endKey: string, objFilter?: string | { path: string; value: string | number | boolean } | undefined | null, sortKeys?: ((keys: string[]) => string[]) | undefined | null, collation?: string | undefined | null",
+    ts_return_type = "{ results: unknown[]; explain: JsonlDBQueryExplain }"
+  )]
+  pub fn get_many_explain(
+    &mut self,
+    env: Env,
+    start_key: String,
+    end_key: String,
+    obj_filter: Option<serde_json::Value>,
+    sort_keys: Option<JsFunction>,
+    collation: Option<String>,
+  ) -> Result<JsObject> {
+    let db = self.r.as_opened_mut("getManyExplain()")?;
+    let query = RangeQuery {
+      start_key: &start_key,
+      end_key: &end_key,
+      obj_filter,
+      sort_keys: sort_keys.as_ref(),
+      collation: parse_collation(collation)?,
+    };
+    let (ret, explain) = db.get_many(env, &query)?;
+
+    let mut obj = env.create_object()?;
+    obj.set_named_property("results", unsafe { to_js_unknown(env.raw(), ret)? })?;
+    let explain: JsonlDBQueryExplain = explain.into();
+    obj.set_named_property("explain", unsafe { to_js_unknown(env.raw(), explain)? })?;
+    Ok(obj)
+  }
+
+  #[napi(ts_return_type = "Promise<void>")]
+  pub fn clear_durable(&mut self, env: Env) -> Result<JsObject> {
+    self.clear(env)?;
+
+    let db = self.r.as_writable_mut("clearDurable()")?;
+    let flushed = db.flush();
+    env.execute_tokio_future(
+      async move { flushed.await.map_err(napi::Error::from) },
+      |_, _| Ok(()),
+    )
+  }
+
   #[napi]
   pub fn clear(&mut self, env: Env) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    db.clear(env);
+    let db = self.r.as_writable_mut("clear()")?;
+    db.clear(env)?;
     Ok(())
   }
 
   #[napi(getter)]
-  pub fn size(&mut self) -> Result<u32> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+  pub fn size(&self) -> Result<u32> {
+    let db = self.r.as_opened("size")?;
     Ok(db.size() as u32)
   }
 
+  #[napi(getter)]
+  pub fn key_count(&self) -> Result<u32> {
+    let db = self.r.as_opened("keyCount")?;
+    Ok(db.size() as u32)
+  }
+
+  #[napi(getter)]
+  pub fn path(&self) -> Result<String> {
+    let path = match &self.r {
+      DB::Closed(db) => db.path(),
+      DB::HalfClosed(db) => db.path(),
+      DB::Opened(db) => db.path(),
+    }?;
+    Ok(path.to_string_lossy().into_owned())
+  }
+
+  #[napi(getter)]
+  pub fn file_size_bytes(&mut self) -> Result<i64> {
+    let db = self.r.as_opened_mut("fileSizeBytes")?;
+    Ok(db.file_size()? as i64)
+  }
+
+  #[napi(getter)]
+  pub fn lockfile_path(&mut self) -> Result<String> {
+    let db = self.r.as_opened_mut("lockfilePath")?;
+    Ok(db.lockfile_path().to_string_lossy().into_owned())
+  }
+
+  #[napi(getter)]
+  pub fn is_lock_lost(&self) -> Result<bool> {
+    let db = self.r.as_opened("isLockLost")?;
+    Ok(db.is_lock_lost())
+  }
+
+  #[napi]
+  pub fn get_meta(&self, key: String) -> Result<Option<JsonlDBEntryMeta>> {
+    let db = self.r.as_opened("getMeta()")?;
+    Ok(db.meta(&key).map(|meta| meta.into()))
+  }
+
+  #[napi]
+  pub fn set_header(&mut self, header: Option<String>) -> Result<()> {
+    let db = self.r.as_writable_mut("setHeader()")?;
+    db.set_header(header)?;
+    Ok(())
+  }
+
+  #[napi]
+  pub fn get_header(&self) -> Result<Option<String>> {
+    let db = self.r.as_opened("getHeader()")?;
+    Ok(db.header())
+  }
+
+  #[napi]
+  pub fn get_deleted_keys(&self, since: Option<i64>) -> Result<Vec<String>> {
+    let db = self.r.as_opened("getDeletedKeys()")?;
+    Ok(db.deleted_keys(since.map(|since| since.max(0) as u64)))
+  }
+
+  #[napi]
+  pub fn get_index_paths(&self) -> Result<Vec<String>> {
+    let db = self.r.as_opened("getIndexPaths()")?;
+    Ok(db.index_paths())
+  }
+
+  #[napi]
+  pub fn is_indexed(&self, path: String) -> Result<bool> {
+    let db = self.r.as_opened("isIndexed()")?;
+    Ok(db.is_indexed(&path))
+  }
+
+  #[napi]
+  pub fn get_index_size(&mut self, path: String) -> Result<u32> {
+    let db = self.r.as_opened_mut("getIndexSize()")?;
+    Ok(db.index_size(&path) as u32)
+  }
+
+  #[napi]
+  pub fn register_prefix_counter(&self, prefix: String) -> Result<bool> {
+    let db = self.r.as_opened("registerPrefixCounter()")?;
+    Ok(db.register_prefix_counter(&prefix))
+  }
+
+  #[napi]
+  pub fn unregister_prefix_counter(&self, prefix: String) -> Result<bool> {
+    let db = self.r.as_opened("unregisterPrefixCounter()")?;
+    Ok(db.unregister_prefix_counter(&prefix))
+  }
+
+  #[napi]
+  pub fn get_prefix_count(&self, prefix: String) -> Result<Option<u32>> {
+    let db = self.r.as_opened("getPrefixCount()")?;
+    Ok(db.prefix_count(&prefix).map(|count| count as u32))
+  }
+
+  /// Updates the serialized value of an entry after its object was changed, so the change is persisted
+  #[napi]
+  pub fn refresh_snapshot(&mut self, key: String, stringified: String) -> Result<bool> {
+    let db = self.r.as_writable_mut("refreshSnapshot()")?;
+    let ret = db.refresh_snapshot(&key, stringified)?;
+    Ok(ret)
+  }
+
+  #[napi]
+  pub fn dematerialize(&mut self, env: Env, key: String) -> Result<bool> {
+    let db = self.r.as_opened_mut("dematerialize()")?;
+    let ret = db.dematerialize(env, &key)?;
+    Ok(ret)
+  }
+
+  #[napi]
+  pub fn materialize(
+    &mut self,
+    env: Env,
+    prefix: Option<String>,
+    start: u32,
+    budget_ms: u32,
+  ) -> Result<JsonlDBMaterializeProgress> {
+    let db = self.r.as_opened_mut("materialize()")?;
+    let (next, converted) = db.materialize(env, prefix.as_deref(), start as usize, budget_ms)?;
+    Ok(JsonlDBMaterializeProgress {
+      next: next as u32,
+      converted: converted as u32,
+      total: db.size() as u32,
+    })
+  }
+
+  #[napi]
+  pub fn dematerialize_all(&mut self, env: Env) -> Result<u32> {
+    let db = self.r.as_opened_mut("dematerializeAll()")?;
+    let ret = db.dematerialize_all(env)?;
+    Ok(ret)
+  }
+
+  #[napi]
+  pub fn get_metrics(&self) -> Result<JsonlDBMetrics> {
+    let db = self.r.as_opened("getMetrics()")?;
+    Ok(db.metrics().into())
+  }
+
+  #[napi]
+  pub fn reset_metrics(&mut self) -> Result<()> {
+    let db = self.r.as_opened_mut("resetMetrics()")?;
+    db.reset_metrics();
+    Ok(())
+  }
+
+  #[napi]
+  pub fn get_stats(&self) -> Result<JsonlDBStats> {
+    let db = self.r.as_opened("getStats()")?;
+    Ok(db.stats().into())
+  }
+
   #[napi(ts_args_type = "callback: (value: any, key: string) => void")]
   pub fn for_each<T: Fn(JsValue, String) -> Result<()>>(
     &mut self,
     env: Env,
     callback: T,
   ) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+    let db = self.r.as_opened_mut("forEach()")?;
 
     for k in db.all_keys() {
       let v = db.get(env, &k)?;
@@ -229,37 +1026,203 @@ impl JsonlDB {
   }
 
   #[napi]
-  pub fn get_keys(&mut self) -> Result<Vec<String>> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+  pub fn get_keys(&self) -> Result<Vec<String>> {
+    let db = self.r.as_opened("getKeys()")?;
     Ok(db.all_keys())
   }
 
   #[napi]
-  pub fn get_keys_stringified(&mut self) -> Result<String> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
+  pub fn get_keys_page(&self, offset: u32, limit: u32) -> Result<Vec<String>> {
+    let db = self.r.as_opened("getKeysPage()")?;
+    Ok(db.keys_page(offset as usize, limit as usize))
+  }
+
+  #[napi]
+  pub fn get_keys_stringified_page(&self, offset: u32, limit: u32) -> Result<String> {
+    let db = self.r.as_opened("getKeysStringifiedPage()")?;
+    Ok(db.keys_page_stringified(offset as usize, limit as usize)?)
+  }
+
+  #[napi(ts_args_type = "sortKeys?: ((keys: string[]) => string[]) | undefined | null")]
+  pub fn get_keys_sorted(
+    &mut self,
+    env: Env,
+    sort_keys: Option<JsFunction>,
+  ) -> Result<Vec<String>> {
+    let db = self.r.as_opened_mut("getKeysSorted()")?;
+    let ret = db.sorted_keys(env, sort_keys.as_ref())?;
+    Ok(ret)
+  }
+
+  #[napi]
+  pub fn get_keys_stringified(&self) -> Result<String> {
+    let db = self.r.as_opened("getKeysStringified()")?;
     let ret = db.all_keys();
     let ret = serde_json::to_string(&ret)?;
     Ok(ret)
   }
 
   #[napi]
-  pub async fn export_json(&mut self, filename: String, pretty: bool) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    db.export_json(&filename, pretty).await?;
+  pub fn get_open_diagnostics(&self) -> Result<JsonlDBOpenDiagnostics> {
+    let db = self.r.as_opened("getOpenDiagnostics()")?;
+    Ok(db.open_diagnostics().into())
+  }
+
+  #[napi]
+  pub async fn get_memory_usage(&self) -> Result<JsonlDBMemoryUsage> {
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("getMemoryUsage()")?;
+    Ok(db.memory_usage().await.into())
+  }
+
+  #[napi]
+  pub async fn estimate_compress(&self) -> Result<JsonlDBCompressEstimate> {
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("estimateCompress()")?;
+    Ok(db.estimate_compress().await?.into())
+  }
+
+  /// Serializes the referenced objects that are about to be written for the next call to
+  /// `selfCheck()`. This needs the main thread, which `selfCheck()` doesn't run on.
+  #[napi]
+  pub fn prepare_self_check(&mut self, env: Env) -> Result<()> {
+    let db = self.r.as_opened_mut("prepareSelfCheck()")?;
+    // `selfCheck()` only borrows the DB, so it cannot bring the index up to date itself
+    db.update_stale_index_keys();
+    let references = db.serialize_pending_references(env);
+    *lock_field(&self.self_check_references) = Some(references);
     Ok(())
   }
 
   #[napi]
-  pub async fn import_json_file(&mut self, filename: String) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    db.import_json_file(&filename).await?;
+  pub async fn self_check(&self) -> Result<JsonlDBSelfCheckReport> {
+    let _transition = self.transition.read().await;
+    let references = lock_field(&self.self_check_references)
+      .take()
+      .unwrap_or_default();
+    let db = self.r.as_opened("selfCheck()")?;
+    Ok(db.self_check(references).await?.into())
+  }
+
+  #[napi]
+  pub async fn export_json(
+    &self,
+    filename: String,
+    pretty: bool,
+    gzip: Option<bool>,
+    omit_null_values: Option<bool>,
+  ) -> Result<JsonlDBJsonExportResult> {
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("exportJson()")?;
+    let stats = db
+      .export_json(
+        &filename,
+        pretty,
+        gzip.unwrap_or(false),
+        omit_null_values.unwrap_or(false),
+      )
+      .await?;
+    Ok(stats.into())
+  }
+
+  #[napi]
+  pub async fn export_csv(
+    &self,
+    filename: String,
+    options: Option<JsonlDBCsvExportOptions>,
+  ) -> Result<()> {
+    let mode: CsvValueMode = options.try_into()?;
+    let _transition = self.transition.read().await;
+    let db = self.r.as_opened("exportCsv()")?;
+    db.export_csv(&filename, mode).await?;
     Ok(())
   }
 
+  /// Reads and parses a CSV file in the background for `importLoaded()`, which imports it on the
+  /// main thread. Resolves to the id to pass to `importLoaded()`.
+  #[napi]
+  pub async fn load_csv_import(
+    &self,
+    filename: String,
+    options: Option<JsonlDBCsvImportOptions>,
+  ) -> Result<u32> {
+    self.r.as_opened("loadCsvImport()")?;
+    let options: CsvImportOptions = options.into();
+    let map = db::read_csv_import(&filename, &options).await?;
+    Ok(self.add_loaded_import(map))
+  }
+
+  /// Like `loadCsvImport()`, but for a JSON file, which may be gzipped
+  #[napi]
+  pub async fn load_json_import(&self, filename: String) -> Result<u32> {
+    self.r.as_opened("loadJsonImport()")?;
+    let map = db::read_json_import(&filename).await?;
+    Ok(self.add_loaded_import(map))
+  }
+
+  /// Imports a file that was read by `loadCsvImport()` or `loadJsonImport()`
+  #[napi]
+  pub fn import_loaded(
+    &mut self,
+    id: u32,
+    via_compress: Option<bool>,
+    null_means_delete: Option<bool>,
+  ) -> Result<JsonlDBJsonImportResult> {
+    // Taken out first, so it isn't kept around if the DB was closed in the meantime
+    let map = lock_field(&self.loaded_imports).remove(&id);
+    let db = self.r.as_writable_mut("importLoaded()")?;
+    let map =
+      map.ok_or_else(|| JsonlDBError::other(&format!("There is no loaded import with id {id}")))?;
+    let stats = db.import_json_map(
+      map,
+      via_compress.unwrap_or(false),
+      null_means_delete.unwrap_or(false),
+    )?;
+    Ok(stats.into())
+  }
+
   #[napi]
-  pub fn import_json_string(&mut self, json: String) -> Result<()> {
-    let db = self.r.as_opened_mut().ok_or(JsonlDBError::NotOpen)?;
-    db.import_json_string(&json)?;
+  pub fn import_json_string(
+    &mut self,
+    json: String,
+    via_compress: Option<bool>,
+    null_means_delete: Option<bool>,
+  ) -> Result<JsonlDBJsonImportResult> {
+    let db = self.r.as_writable_mut("importJsonString()")?;
+    let stats = db.import_json_string(
+      &json,
+      via_compress.unwrap_or(false),
+      null_means_delete.unwrap_or(false),
+    )?;
+    Ok(stats.into())
+  }
+}
+
+/// Hooks for the tests to simulate failures. They only exist in builds with the `test-hooks`
+/// feature, which releases don't have.
+#[cfg(feature = "test-hooks")]
+#[napi(js_name = "JsonlDB")]
+impl JsonlDB {
+  /// Only meant for tests: Lets the critical filesystem operations fail from the given step on,
+  /// as if the process crashed there. The steps are counted from the next call to `open()`.
+  #[napi(js_name = "__simulateCrashAt")]
+  pub fn simulate_crash_at(&mut self, step: u32) -> Result<()> {
+    let db = self.r.as_closed_mut("__simulateCrashAt()")?;
+    db.simulate_crash_at(step as usize);
     Ok(())
   }
+
+  /// Only meant for tests: Kills the persistence thread, as if it panicked
+  #[napi(js_name = "__abortPersistence")]
+  pub fn abort_persistence(&mut self) -> Result<()> {
+    let db = self.r.as_opened_mut("__abortPersistence()")?;
+    db.abort_persistence();
+    Ok(())
+  }
+}
+
+/// Returns how many background tasks are currently alive. Only meant for debugging.
+#[napi]
+pub fn get_live_task_count() -> u32 {
+  bg_thread::live_tasks() as u32
 }
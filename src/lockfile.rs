@@ -1,23 +1,48 @@
 use filetime::FileTime;
 use std::{
   fs,
+  io::ErrorKind,
   path::{Path, PathBuf},
-  time::SystemTime,
+  sync::{Arc, Mutex, MutexGuard},
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use tokio::time;
+
 use crate::error::{JsonlDBError, Result};
+use crate::util;
+
+// Some filesystems (e.g. FAT32) only store modification times with a resolution of 2 seconds
+const MTIME_GRANULARITY_MS: u128 = 2000;
+// How far a modification time may be in the future, e.g. because the clock was set back by NTP
+const MAX_CLOCK_SKEW_MS: u128 = 5000;
+// How often creating the lockfile is retried when it races with other processes
+const LOCK_RETRIES: u32 = 5;
+const LOCK_RETRY_BASE_DELAY_MS: u32 = 10;
+const LOCK_RETRY_JITTER_MS: u32 = 20;
 
 pub(crate) struct Lockfile {
   path: PathBuf,
   stale_interval_ms: u128,
   mtime: Option<FileTime>,
+  file_mode: Option<u32>,
 }
 
 pub(crate) enum CheckResult {
   NoLock,
-  Stale,
+  Stale(FileTime),
   Active(FileTime),
-  Unknown,
+  // Contains the reason why the lockfile could not be checked
+  Unknown(String),
+}
+
+/// Whether a lockfile is held, as seen by someone who does not hold it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LockStatus {
+  pub locked: bool,
+  pub stale: bool,
+  /// When the lockfile was last updated (epoch ms), if it exists
+  pub mtime_ms: Option<u64>,
 }
 
 impl Drop for Lockfile {
@@ -27,11 +52,12 @@ impl Drop for Lockfile {
 }
 
 impl Lockfile {
-  pub fn new(path: impl AsRef<Path>, stale_interval_ms: u128) -> Self {
+  pub fn new(path: impl AsRef<Path>, stale_interval_ms: u128, file_mode: Option<u32>) -> Self {
     Self {
       path: path.as_ref().to_owned(),
       stale_interval_ms,
       mtime: None,
+      file_mode,
     }
   }
 
@@ -39,14 +65,48 @@ impl Lockfile {
     self.stale_interval_ms
   }
 
-  pub fn lock(&mut self) -> Result<()> {
+  /// Acquires the lock. Races with other processes that create or remove lockfiles at the same
+  /// time are retried after a short delay.
+  pub async fn lock(&mut self) -> Result<()> {
+    let mut attempt: u32 = 0;
+    loop {
+      match self.try_lock() {
+        // Another process may have created or removed the lockfile (or its directory) in the
+        // meantime, e.g. when many processes start at once. Check it again after a short delay.
+        Err(JsonlDBError::IoError(e)) if is_transient_error(&e) => {
+          attempt += 1;
+          if attempt > LOCK_RETRIES {
+            return Err(
+              std::io::Error::new(
+                e.kind(),
+                format!(
+                  "Could not acquire lockfile after {attempt} attempts ({:?}): {e}",
+                  e.kind()
+                ),
+              )
+              .into(),
+            );
+          }
+          time::sleep(retry_delay(attempt)).await;
+        }
+        result => return result,
+      }
+    }
+  }
+
+  /// Tries to acquire the lock once
+  fn try_lock(&mut self) -> Result<()> {
     match self.check() {
       CheckResult::NoLock => self.create_lock(),
-      CheckResult::Stale => self.update_lock(),
-      CheckResult::Active(_) => Err(JsonlDBError::io_error_from_reason("Lockfile is in use")),
-      CheckResult::Unknown => Err(JsonlDBError::io_error_from_reason(
-        "Could not acquire lockfile",
-      )),
+      CheckResult::Stale(_) => self.update_lock(),
+      CheckResult::Active(mtime) => Err(JsonlDBError::io_error_from_reason(format!(
+        "Lockfile is in use (modified at {} ms, now: {} ms)",
+        filetime_ms(mtime),
+        epoch_ms(SystemTime::now())
+      ))),
+      CheckResult::Unknown(reason) => Err(JsonlDBError::io_error_from_reason(format!(
+        "Could not acquire lockfile: {reason}"
+      ))),
     }
   }
 
@@ -55,15 +115,29 @@ impl Lockfile {
       // File/Directory exists, check mtime
       let mtime = match meta.modified() {
         Ok(f) => f,
-        _ => return CheckResult::Unknown,
+        Err(e) => {
+          return CheckResult::Unknown(format!("Failed to read its modification time: {e}"))
+        }
       };
-      let elapsed = match SystemTime::now().duration_since(mtime) {
+      let now = SystemTime::now();
+      let elapsed = match now.duration_since(mtime) {
         Ok(d) => d,
-        _ => return CheckResult::Unknown,
+        // A slightly newer mtime is most likely caused by clock skew, the lock is in use
+        Err(e) if e.duration().as_millis() <= MAX_CLOCK_SKEW_MS => {
+          return CheckResult::Active(FileTime::from(mtime))
+        }
+        Err(_) => {
+          return CheckResult::Unknown(format!(
+            "Its modification time ({} ms) is in the future (now: {} ms)",
+            epoch_ms(mtime),
+            epoch_ms(now)
+          ))
+        }
       };
-      if elapsed.as_millis() > self.stale_interval_ms {
+      // Don't consider locks stale early because their mtime was rounded down
+      if elapsed.as_millis() > self.stale_interval_ms + MTIME_GRANULARITY_MS {
         // stale, we can re-acquire it
-        CheckResult::Stale
+        CheckResult::Stale(FileTime::from(mtime))
       } else {
         CheckResult::Active(FileTime::from(mtime))
       }
@@ -72,8 +146,37 @@ impl Lockfile {
     }
   }
 
+  /// Checks whether someone holds the lock, without acquiring it
+  pub fn status(&mut self) -> Result<LockStatus> {
+    match self.check() {
+      CheckResult::NoLock => Ok(LockStatus {
+        locked: false,
+        stale: false,
+        mtime_ms: None,
+      }),
+      CheckResult::Stale(mtime) => Ok(LockStatus {
+        locked: false,
+        stale: true,
+        mtime_ms: Some(filetime_ms(mtime) as u64),
+      }),
+      CheckResult::Active(mtime) => Ok(LockStatus {
+        locked: true,
+        stale: false,
+        mtime_ms: Some(filetime_ms(mtime) as u64),
+      }),
+      CheckResult::Unknown(reason) => Err(JsonlDBError::io_error_from_reason(format!(
+        "Could not check lockfile: {reason}"
+      ))),
+    }
+  }
+
   fn create_lock(&mut self) -> Result<()> {
-    fs::create_dir_all(&self.path)?;
+    if let Some(parent) = self.path.parent() {
+      util::create_dir_all(parent, self.file_mode)?;
+    }
+    // Only one process can create the directory. For the others, this fails with AlreadyExists,
+    // so they check the lock again.
+    util::create_dir(&self.path, self.file_mode)?;
     // And remember the timestamp
     let meta = fs::metadata(&self.path)?;
     let mtime = meta.modified()?;
@@ -84,10 +187,19 @@ impl Lockfile {
   fn update_lock(&mut self) -> Result<()> {
     let now = FileTime::now();
     filetime::set_file_times(&self.path, now, now)?;
-    self.mtime = Some(now.into());
+    // Remember the timestamp as it was stored, which may be rounded by the filesystem
+    let mtime = fs::metadata(&self.path)?.modified()?;
+    self.mtime = Some(mtime.into());
     Ok(())
   }
 
+  /// Tries to acquire the lock again after it was lost. Fails if another process holds it.
+  /// The caller retries, so this only makes a single attempt.
+  pub fn reacquire(&mut self) -> Result<()> {
+    self.mtime = None;
+    self.try_lock()
+  }
+
   pub fn release(&mut self) {
     if let Some(self_mtime) = self.mtime {
       if let Ok(meta) = fs::metadata(&self.path) {
@@ -106,7 +218,7 @@ impl Lockfile {
   pub fn update(&mut self) -> Result<()> {
     match self.check() {
       CheckResult::NoLock => self.create_lock(),
-      CheckResult::Stale => self.update_lock(),
+      CheckResult::Stale(_) => self.update_lock(),
       CheckResult::Active(mtime) => {
         if let Some(self_time) = self.mtime {
           if self_time != mtime {
@@ -117,9 +229,172 @@ impl Lockfile {
         }
         self.update_lock()
       }
-      CheckResult::Unknown => Err(JsonlDBError::io_error_from_reason(
-        "Could not update lockfile",
-      )),
+      CheckResult::Unknown(reason) => Err(JsonlDBError::io_error_from_reason(format!(
+        "Could not update lockfile: {reason}"
+      ))),
     }
   }
 }
+
+/// A lockfile that is used by the persistence thread, but can also be released by the DB itself,
+/// e.g. if the persistence thread died
+#[derive(Clone)]
+pub(crate) struct SharedLockfile(Arc<Mutex<Lockfile>>);
+
+impl SharedLockfile {
+  pub fn new(lock: Lockfile) -> Self {
+    Self(Arc::new(Mutex::new(lock)))
+  }
+
+  pub fn lock(&self) -> MutexGuard<'_, Lockfile> {
+    // Nothing can be done with a poisoned lock, but it still needs to be released
+    match self.0.lock() {
+      Ok(guard) => guard,
+      Err(poisoned) => poisoned.into_inner(),
+    }
+  }
+}
+
+/// Errors that are most likely caused by other processes creating or removing lockfiles concurrently
+fn is_transient_error(e: &std::io::Error) -> bool {
+  matches!(e.kind(), ErrorKind::NotFound | ErrorKind::AlreadyExists)
+}
+
+/// A delay that grows with each attempt. The jitter keeps processes that collided once from
+/// colliding again.
+fn retry_delay(attempt: u32) -> Duration {
+  let seed = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.subsec_nanos())
+    ^ std::process::id();
+  Duration::from_millis((LOCK_RETRY_BASE_DELAY_MS * attempt + seed % LOCK_RETRY_JITTER_MS) as u64)
+}
+
+fn epoch_ms(time: SystemTime) -> u128 {
+  time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis())
+}
+
+fn filetime_ms(time: FileTime) -> i128 {
+  time.unix_seconds() as i128 * 1000 + time.nanoseconds() as i128 / 1_000_000
+}
+
+// The canonical paths of all DB files that are currently opened by this process
+static OPENED_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Prevents opening the same DB file twice within this process, which the lockfile cannot detect
+/// reliably. The file is released when this is dropped.
+pub(crate) struct ProcessLock {
+  path: PathBuf,
+}
+
+impl ProcessLock {
+  pub fn acquire(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref().to_owned();
+    let mut opened = OPENED_FILES
+      .lock()
+      .map_err(|_| JsonlDBError::other("Failed to acquire lock on the list of opened files"))?;
+    if opened.contains(&path) {
+      return Err(JsonlDBError::OpenedTwice(
+        path.to_string_lossy().into_owned(),
+      ));
+    }
+    opened.push(path.clone());
+    Ok(Self { path })
+  }
+}
+
+impl Drop for ProcessLock {
+  fn drop(&mut self) {
+    if let Ok(mut opened) = OPENED_FILES.lock() {
+      opened.retain(|p| p != &self.path);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Barrier;
+  use std::thread;
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rsonl-db-{name}-{}", std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    dir
+  }
+
+  fn lock_blocking(lock: &mut Lockfile) -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+      .enable_time()
+      .build()
+      .unwrap()
+      .block_on(lock.lock())
+  }
+
+  #[test]
+  fn only_one_of_many_concurrent_creators_acquires_the_lock() {
+    let dir = test_dir("concurrent-lock");
+    // The lock directory's parent doesn't exist yet, so it is created concurrently too
+    let path = dir.join("locks").join("db.jsonl.lock");
+    let threads = 16;
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+      .map(|_| {
+        let path = path.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+          let mut lock = Lockfile::new(&path, 10000, None);
+          barrier.wait();
+          let result = lock_blocking(&mut lock);
+          (result, lock)
+        })
+      })
+      .collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+    let acquired = results.iter().filter(|(result, _)| result.is_ok()).count();
+    assert_eq!(acquired, 1);
+    for (result, _) in &results {
+      if let Err(e) = result {
+        assert!(e.to_string().contains("in use"), "unexpected error: {e}");
+      }
+    }
+
+    // Only the owner removes the lock when releasing it
+    drop(results);
+    assert!(!path.exists());
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn does_not_take_over_a_lock_that_was_created_after_checking() {
+    let dir = test_dir("racing-lock");
+    let path = dir.join("db.jsonl.lock");
+    let mut lock = Lockfile::new(&path, 10000, None);
+    assert!(matches!(lock.check(), CheckResult::NoLock));
+
+    // Another process acquires the lock between checking and creating it
+    let other = {
+      let path = path.clone();
+      thread::spawn(move || {
+        let mut other = Lockfile::new(&path, 10000, None);
+        lock_blocking(&mut other).unwrap();
+        other
+      })
+      .join()
+      .unwrap()
+    };
+    match lock.create_lock() {
+      Err(JsonlDBError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::AlreadyExists),
+      _ => panic!("the lock was created twice"),
+    }
+    // Checking again shows that it is in use
+    let e = lock_blocking(&mut lock).unwrap_err();
+    assert!(e.to_string().contains("in use"), "unexpected error: {e}");
+
+    drop(other);
+    lock_blocking(&mut lock).unwrap();
+    lock.release();
+    fs::remove_dir_all(&dir).ok();
+  }
+}
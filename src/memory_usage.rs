@@ -0,0 +1,80 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+
+use serde_json::Value;
+
+use crate::storage::{DBEntry, JournalEntry, Key};
+
+/// An estimate of the memory used by the DB in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MemoryUsage {
+  pub entries: usize,
+  pub journal: usize,
+  pub index: usize,
+}
+
+impl MemoryUsage {
+  pub fn total(&self) -> usize {
+    self.entries + self.journal + self.index
+  }
+}
+
+pub(crate) fn string_size(str: &str) -> usize {
+  size_of::<String>() + str.len()
+}
+
+/// Estimates the size of a key including the reference counts of its allocation.
+/// Further references to the same key only cost `size_of::<Key>()`.
+pub(crate) fn key_size(key: &str) -> usize {
+  size_of::<Key>() + 2 * size_of::<usize>() + key.len()
+}
+
+/// Estimates the size of a JSON value including all of its children
+pub(crate) fn value_size(value: &Value) -> usize {
+  size_of::<Value>()
+    + match value {
+      Value::Null | Value::Bool(_) | Value::Number(_) => 0,
+      Value::String(str) => str.len(),
+      Value::Array(arr) => arr.iter().map(value_size).sum(),
+      Value::Object(obj) => obj
+        .iter()
+        .map(|(key, val)| string_size(key) + value_size(val))
+        .sum(),
+    }
+}
+
+/// Estimates the size of an entry in the storage, including its key
+pub(crate) fn entry_size(key: &str, entry: &DBEntry) -> usize {
+  // IndexMap stores the hash next to each key-value pair, plus an index into the entries
+  let overhead = 2 * size_of::<usize>() + size_of::<DBEntry>();
+  let value = match entry {
+    DBEntry::Native(val) => value_size(val),
+    DBEntry::Reference(str, _) | DBEntry::Stringified(str) => str.len(),
+  };
+  overhead + key_size(key) + value
+}
+
+pub(crate) fn journal_size(journal: &[JournalEntry]) -> usize {
+  // The keys are shared with the entries
+  journal.len() * size_of::<JournalEntry>()
+}
+
+pub(crate) fn index_size(map: &HashMap<String, HashSet<Key>>) -> usize {
+  map
+    .iter()
+    .map(|(index_key, keys)| {
+      string_size(index_key) + size_of::<HashSet<Key>>() + keys.len() * size_of::<Key>()
+    })
+    .sum()
+}
+
+pub(crate) fn reverse_index_size(map: &HashMap<Key, Vec<String>>) -> usize {
+  map
+    .values()
+    .map(|index_keys| {
+      size_of::<Key>()
+        + size_of::<Vec<String>>()
+        + index_keys.iter().map(|index_key| string_size(index_key)).sum::<usize>()
+    })
+    .sum()
+}
@@ -1,28 +1,84 @@
-use std::{io::SeekFrom, path::Path, time::Duration};
+use std::{
+  collections::HashSet,
+  io::{self, SeekFrom},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
 
+use serde_json::json;
 use tokio::{
-  fs::{self, File, OpenOptions},
+  fs::{self, File},
   io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
-  sync::mpsc::Receiver,
+  sync::{mpsc::Receiver, oneshot, watch},
+  task,
   time::{self, error::Elapsed, Instant},
 };
 
 use crate::{
-  bg_thread::Command,
-  db_options::{AutoCompressOptions, DBOptions},
-  error::Result,
-  lockfile::Lockfile,
-  storage::{format_line, SharedStorage},
-  util::{file_needs_lf, fsync_dir, parent_dir},
+  bg_thread::{
+    Callback, Command, CompressTrigger, FlushInfo, ResnapshotRequest, SharedEventCallback,
+    SharedFlushCallback, SharedResnapshotCallback, SnapshotInfo,
+  },
+  db_options::{
+    AutoCompressOptions, AutoDumpOptions, DBOptions, IntervalAnchor, ThrottleFSOptions,
+    DEFAULT_COMPRESS_BACKUP_SUFFIX, DEFAULT_COMPRESS_DUMP_SUFFIX,
+  },
+  error::{JsonlDBError, Result},
+  fs_ops::FsOps,
+  lockfile::SharedLockfile,
+  shards::ShardLayout,
+  storage::{
+    format_line, JournalEntry, JournalLine, Key, LockSite, SharedStorage, WriteStats, HEADER_KEY,
+  },
+  util::{self, file_needs_lf, parent_dir, replace_dirname},
 };
 
-fn is_stop_cmd(cmd: std::result::Result<Option<Command>, Elapsed>) -> bool {
+// How many journal entries are written at once before other tasks get a chance to run
+const WRITE_CHUNK_SIZE: usize = 5000;
+// How many entries are rendered at once while dumping, so the storage is never locked for long
+const DUMP_CHUNK_SIZE: usize = 5000;
+// Replaced with the current time in the filenames of automatic dumps
+const TIMESTAMP_PLACEHOLDER: &str = "{timestamp}";
+// How long to wait before trying to re-acquire a lost lockfile
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+// How long to wait for the main thread to serialize the JS objects in the journal again
+const RESNAPSHOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn is_stop_cmd(cmd: &std::result::Result<Option<Command>, Elapsed>) -> bool {
   match cmd {
     Ok(Some(Command::Stop)) => true,
     _ => false,
   }
 }
 
+/// The callback of a flush command, or the target and callback of a snapshot command
+fn get_flush_callback(
+  cmd: std::result::Result<Option<Command>, Elapsed>,
+) -> (Option<Callback>, Option<(String, SnapshotCallback)>) {
+  match cmd {
+    Ok(Some(Command::Flush { done })) => (Some(done), None),
+    Ok(Some(Command::Snapshot { target, done })) => (None, Some((target, done))),
+    _ => (None, None),
+  }
+}
+
+type SnapshotCallback = oneshot::Sender<Result<SnapshotInfo>>;
+
+/// Why the given action cannot be done while nothing is written to the DB file
+fn persisting_unavailable(lock_lost: bool, action: &str) -> JsonlDBError {
+  if lock_lost {
+    JsonlDBError::LockLost
+  } else {
+    JsonlDBError::io_error_from_reason(format!(
+      "Cannot {action} while persisting is paused, call retryPersistence() first"
+    ))
+  }
+}
+
 fn need_to_compress_by_size(opts: &AutoCompressOptions, size: u32, uncompressed_size: u32) -> bool {
   if opts.size_factor == 0 {
     return false;
@@ -32,258 +88,1568 @@ fn need_to_compress_by_size(opts: &AutoCompressOptions, size: u32, uncompressed_
     && uncompressed_size as u32 >= opts.size_factor * size;
 }
 
-fn need_to_compress_by_time(
+fn need_to_compress_by_time(opts: &AutoCompressOptions, compress: &CompressTracker) -> bool {
+  if opts.interval_ms == 0 {
+    return false;
+  }
+
+  return compress.changes as u32 >= opts.interval_min_changes
+    && Instant::now()
+      .duration_since(compress.interval_anchor(opts))
+      .as_millis()
+      > opts.interval_ms as u128;
+}
+
+fn need_to_compress_by_overwrites(
   opts: &AutoCompressOptions,
-  last_compress: Instant,
-  changes_since_compress: u32,
+  uncompressed_size: u32,
+  overwrites: u32,
 ) -> bool {
-  if opts.interval_ms == 0 {
+  if opts.max_overwrite_ratio <= 0.0 || uncompressed_size == 0 {
     return false;
   }
 
-  return changes_since_compress >= opts.interval_min_changes
-    && Instant::now().duration_since(last_compress).as_millis() > opts.interval_ms as u128;
+  return uncompressed_size >= opts.size_factor_min_size
+    && overwrites as f64 / uncompressed_size as f64 >= opts.max_overwrite_ratio;
+}
+
+fn need_to_compress_by_tombstones(opts: &AutoCompressOptions, tombstones: u32) -> bool {
+  if opts.max_tombstones == 0 {
+    return false;
+  }
+
+  return tombstones >= opts.max_tombstones;
+}
+
+/// Returns why the DB file should be compressed automatically, if it should
+fn auto_compress_trigger(
+  opts: &AutoCompressOptions,
+  just_opened: bool,
+  size: u32,
+  uncompressed_size: u32,
+  compress: &CompressTracker,
+  lines: &LineTracker,
+) -> Option<CompressTrigger> {
+  if just_opened && opts.on_open {
+    Some(CompressTrigger::OnOpen)
+  } else if need_to_compress_by_size(opts, size, uncompressed_size) {
+    Some(CompressTrigger::Size)
+  } else if need_to_compress_by_time(opts, compress) {
+    Some(CompressTrigger::Interval)
+  } else if need_to_compress_by_overwrites(opts, uncompressed_size, lines.overwrites as u32) {
+    Some(CompressTrigger::Overwrites)
+  } else if need_to_compress_by_tombstones(opts, lines.tombstones as u32) {
+    Some(CompressTrigger::Tombstones)
+  } else {
+    None
+  }
+}
+
+/// Keeps track of when the DB file was written and compressed and how many lines were written since
+struct CompressTracker {
+  last_write: Instant,
+  last_compress: Instant,
+  // When the last compress finished (epoch ms), None if there was none since opening the DB
+  last_compress_at: Option<u64>,
+  changes: usize,
+}
+
+impl CompressTracker {
+  fn new() -> Self {
+    Self {
+      last_write: Instant::now(),
+      last_compress: Instant::now(),
+      last_compress_at: None,
+      changes: 0,
+    }
+  }
+
+  fn compressed(&mut self) {
+    self.last_compress = Instant::now();
+    self.last_compress_at = Some(util::now_ms());
+    self.changes = 0;
+  }
+
+  /// What the compress interval is measured from
+  fn interval_anchor(&self, opts: &AutoCompressOptions) -> Instant {
+    match opts.interval_anchor {
+      IntervalAnchor::LastCompress => self.last_compress,
+      IntervalAnchor::LastWrite => self.last_write,
+    }
+  }
+
+  /// When the persistence thread has to check again whether to compress by interval. None if there
+  /// are not enough changes yet, because only writing adds changes.
+  fn next_interval_check(&self, opts: &AutoCompressOptions) -> Option<Instant> {
+    if opts.interval_ms == 0 || (self.changes as u32) < opts.interval_min_changes {
+      return None;
+    }
+    // The interval has to be exceeded, not just reached
+    Some(self.interval_anchor(opts) + Duration::from_millis(opts.interval_ms as u64 + 1))
+  }
+
+  /// When compressing by interval will be possible next (epoch ms). This is only an estimate,
+  /// because it also depends on the number of changes.
+  fn next_eligible_at(&self, opts: &AutoCompressOptions) -> Option<u64> {
+    if opts.interval_ms == 0 {
+      return None;
+    }
+    let anchor_at =
+      util::now_ms().saturating_sub(self.interval_anchor(opts).elapsed().as_millis() as u64);
+    Some(anchor_at + opts.interval_ms as u64)
+  }
+}
+
+/// How much was written to the DB file since it was opened. Unlike the statistics that decide when
+/// to compress, these are not reset by compressing. Shared with the main thread.
+#[derive(Default)]
+pub(crate) struct WriteCounters {
+  pub lines: AtomicUsize,
+  // Lines that delete an entry
+  pub tombstones: AtomicUsize,
+  // How often the file was truncated by clear()
+  pub truncations: AtomicUsize,
+  pub bytes: AtomicUsize,
+  // How often the DB file was synced to disk
+  pub syncs: AtomicUsize,
+  // How often the persistence thread woke up to look for work
+  pub wakeups: AtomicUsize,
+}
+
+impl WriteCounters {
+  fn track(&self, entry: &JournalEntry, line: &JournalLine) {
+    match line {
+      JournalLine::Truncate => {
+        self.truncations.fetch_add(1, Ordering::Relaxed);
+      }
+      JournalLine::Append(str) => {
+        if let JournalEntry::Delete(_) = entry {
+          self.tombstones.fetch_add(1, Ordering::Relaxed);
+        }
+        self.lines.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(str.len() + 1, Ordering::Relaxed);
+      }
+    }
+  }
+
+  pub fn reset(&self) {
+    self.lines.store(0, Ordering::Relaxed);
+    self.tombstones.store(0, Ordering::Relaxed);
+    self.truncations.store(0, Ordering::Relaxed);
+    self.bytes.store(0, Ordering::Relaxed);
+    self.syncs.store(0, Ordering::Relaxed);
+    self.wakeups.store(0, Ordering::Relaxed);
+  }
+
+  fn synced(&self) {
+    self.syncs.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn woke_up(&self) {
+    self.wakeups.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+/// Keeps track of the keys that are contained in the DB file to detect overwrites and tombstones
+struct LineTracker {
+  keys_in_file: HashSet<Key>,
+  overwrites: usize,
+  tombstones: usize,
+}
+
+impl LineTracker {
+  fn new(storage: &mut SharedStorage) -> Self {
+    let mut ret = Self {
+      keys_in_file: HashSet::new(),
+      overwrites: 0,
+      tombstones: 0,
+    };
+    ret.reset(storage);
+    ret
+  }
+
+  fn track(&mut self, entry: &JournalEntry) {
+    match entry {
+      JournalEntry::Set(key) => {
+        if self.keys_in_file.contains(key) {
+          self.overwrites += 1;
+        } else {
+          self.keys_in_file.insert(key.clone());
+        }
+      }
+      JournalEntry::Delete(key) => {
+        self.keys_in_file.remove(key);
+        self.tombstones += 1;
+      }
+      JournalEntry::Clear => {
+        self.keys_in_file.clear();
+        self.overwrites = 0;
+        self.tombstones = 0;
+      }
+      // Only the last header line is needed, like for entries
+      JournalEntry::Header => {
+        if !self.keys_in_file.insert(HEADER_KEY.into()) {
+          self.overwrites += 1;
+        }
+      }
+    }
+  }
+
+  /// Resets the counters and remembers the keys that are currently in the DB
+  fn reset(&mut self, storage: &mut SharedStorage) {
+    let storage = storage.lock();
+    self.keys_in_file = storage.entries.keys().cloned().collect();
+    if storage.has_header_line() {
+      self.keys_in_file.insert(HEADER_KEY.into());
+    }
+    self.overwrites = 0;
+    self.tombstones = 0;
+  }
+
+  fn to_stats(
+    &self,
+    uncompressed_size: usize,
+    compress: &CompressTracker,
+    opts: &AutoCompressOptions,
+  ) -> WriteStats {
+    WriteStats {
+      uncompressed_size,
+      overwrites: self.overwrites,
+      tombstones: self.tombstones,
+      last_compress_at: compress.last_compress_at,
+      changes_since_compress: compress.changes,
+      next_compress_eligible_at: compress.next_eligible_at(opts),
+    }
+  }
+}
+
+/// The files of the shards after opening the DB and how many lines each of them consists of.
+/// Without sharding, this is only the DB file.
+pub(crate) struct OpenedShards {
+  pub layout: ShardLayout,
+  pub files: Vec<(File, usize)>,
+}
+
+/// Appends the journal to the files of the shards, each line to the shard its key belongs to
+struct ShardWriters {
+  layout: ShardLayout,
+  writers: Vec<BufWriter<File>>,
+  // How many lines each shard file consists of
+  lines: Vec<usize>,
+  buffer_size: usize,
+}
+
+impl ShardWriters {
+  /// Takes over the opened files and makes sure they end with LF
+  async fn new(shards: OpenedShards, buffer_size: usize, fs_ops: &dyn FsOps) -> Result<Self> {
+    let mut writers = Vec::with_capacity(shards.files.len());
+    let mut lines = Vec::with_capacity(shards.files.len());
+    for (mut file, file_lines) in shards.files {
+      // The last line was cut off, e.g. by a crash while writing it
+      if file_needs_lf(&mut file).await? {
+        fs_ops.write_all(&mut file, b"\n").await?;
+      }
+      writers.push(BufWriter::with_capacity(buffer_size, file));
+      lines.push(file_lines);
+    }
+    Ok(Self {
+      layout: shards.layout,
+      writers,
+      lines,
+      buffer_size,
+    })
+  }
+
+  /// Writes a line of the journal to the shard it belongs to. Truncating affects all shards.
+  async fn write(&mut self, entry: &JournalEntry, line: &JournalLine) -> Result<()> {
+    match line {
+      JournalLine::Truncate => {
+        for (writer, lines) in self.writers.iter_mut().zip(self.lines.iter_mut()) {
+          writer.rewind().await?;
+          writer.get_ref().set_len(0).await?;
+          *lines = 0;
+        }
+      }
+      JournalLine::Append(str) => {
+        let shard = self.layout.shard_of_entry(entry).unwrap_or(0);
+        let writer = &mut self.writers[shard];
+        writer.write(str.as_bytes()).await?;
+        writer.write(b"\n").await?;
+        self.lines[shard] += 1;
+      }
+    }
+    Ok(())
+  }
+
+  async fn flush(&mut self) -> Result<()> {
+    for writer in self.writers.iter_mut() {
+      writer.flush().await?;
+    }
+    Ok(())
+  }
+
+  /// Makes sure everything is on disk
+  async fn sync(&mut self, fs_ops: &dyn FsOps) -> Result<()> {
+    for writer in self.writers.iter_mut() {
+      writer.flush().await?;
+      fs_ops.sync_file(writer.get_ref()).await?;
+    }
+    Ok(())
+  }
+
+  async fn sync_all(&mut self) -> Result<()> {
+    for writer in self.writers.iter_mut() {
+      writer.flush().await?;
+      writer.get_ref().sync_all().await?;
+    }
+    Ok(())
+  }
+
+  /// Like `sync_all`, but skips the metadata that isn't needed to read the data back
+  async fn sync_data(&mut self) -> Result<()> {
+    for writer in self.writers.iter_mut() {
+      writer.flush().await?;
+      writer.get_ref().sync_data().await?;
+    }
+    Ok(())
+  }
+
+  /// Returns the first shard file that no longer exists, if any
+  async fn missing_file(&self) -> Result<Option<String>> {
+    for filename in self.layout.filenames() {
+      if !fs::try_exists(&filename).await? {
+        return Ok(Some(filename));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Closes the files, so they can be replaced
+  fn close(&mut self) {
+    self.writers.clear();
+  }
+
+  /// Opens the files again after they were closed or replaced, for appending
+  async fn reopen(&mut self, file_mode: Option<u32>) -> Result<()> {
+    self.writers.clear();
+    for filename in self.layout.filenames() {
+      let file = util::open_options(file_mode)
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(&filename)
+        .await?;
+      let mut writer = BufWriter::with_capacity(self.buffer_size, file);
+      writer.seek(SeekFrom::End(0)).await?;
+      self.writers.push(writer);
+    }
+    Ok(())
+  }
+
+  fn total_lines(&self) -> usize {
+    self.lines.iter().sum()
+  }
+}
+
+/// What the persistence thread shares with the DB
+pub(crate) struct PersistenceContext {
+  pub filename: String,
+  pub opts: DBOptions,
+  pub storage: SharedStorage,
+  pub lock: SharedLockfile,
+  pub fs_ops: Arc<dyn FsOps>,
+  pub on_flush: SharedFlushCallback,
+  pub events: SharedEventCallback,
+  pub resnapshot: SharedResnapshotCallback,
+  // Set when the lockfile was lost, which makes the DB read-only
+  pub lock_lost: Arc<AtomicBool>,
+  pub write_counters: Arc<WriteCounters>,
+}
+
+/// The result of a compress that others can wait for. `None` while it is running, the error is a string
+/// because every waiter gets a copy.
+pub(crate) type CompressOutcome = Option<std::result::Result<(), String>>;
+
+/// How the persistence thread communicates with the main thread
+pub(crate) struct PersistenceChannels {
+  pub rx: Receiver<Command>,
+  // Tells the main thread when the compress on open is done and whether it failed
+  pub compressed_on_open: watch::Sender<CompressOutcome>,
+  // Tells open() whether the thread is ready to persist changes
+  pub started: oneshot::Sender<Result<()>>,
 }
 
 pub(crate) async fn persistence_thread(
-  filename: &str,
-  mut file: File,
-  mut storage: SharedStorage,
-  mut lock: Lockfile,
-  mut rx: Receiver<Command>,
-  opts: &DBOptions,
+  ctx: PersistenceContext,
+  shards: OpenedShards,
+  channels: PersistenceChannels,
+) -> Result<()> {
+  let PersistenceChannels {
+    mut rx,
+    compressed_on_open,
+    started,
+  } = channels;
+  let events = ctx.events.clone();
+  // The callback of the compress or dump that is currently running
+  let mut in_flight: Option<Callback> = None;
+  let mut started = Some(started);
+  let result = run_persistence(
+    ctx,
+    shards,
+    &mut rx,
+    &mut in_flight,
+    &compressed_on_open,
+    &mut started,
+  )
+  .await;
+
+  if let Err(e) = &result {
+    // Whoever waits for the compress on open must not take this as success
+    compressed_on_open.send_if_modified(|outcome| {
+      let pending = outcome.is_none();
+      if pending {
+        *outcome = Some(Err(e.to_string()));
+      }
+      pending
+    });
+  }
+
+  if let Err(e) = result {
+    // The thread failed to start, so open() returns the error and no commands will follow
+    if let Some(started) = started.take() {
+      started.send(Err(e)).ok();
+      return Ok(());
+    }
+
+    // Nothing can be persisted anymore. Report that and settle all commands until the DB is closed,
+    // so nobody waits forever.
+    if let Some(done) = in_flight {
+      done.send(Err(JsonlDBError::other(&e.to_string()))).ok();
+    }
+    events.emit(
+      "error",
+      json!({ "message": e.to_string(), "source": "persistence" }),
+    );
+    while let Some(cmd) = rx.recv().await {
+      if !settle_command(cmd) {
+        break;
+      }
+    }
+    rx.close();
+    while let Ok(cmd) = rx.try_recv() {
+      settle_command(cmd);
+    }
+  }
+  Ok(())
+}
+
+/// Resolves a command without executing it. Returns false for the Stop command.
+fn settle_command(cmd: Command) -> bool {
+  match cmd {
+    Command::Compress {
+      done: Some(done), ..
+    }
+    | Command::Flush { done }
+    | Command::RetryPersistence { done } => {
+      done.send(Err(JsonlDBError::other("Persisting the DB failed"))).ok();
+    }
+    Command::Snapshot { done, .. } => {
+      done.send(Err(JsonlDBError::other("Persisting the DB failed"))).ok();
+    }
+    Command::Compress { done: None, .. } => {}
+    Command::Stop => return false,
+  }
+  true
+}
+
+/// Lets the main thread serialize the JS objects in the journal again, so changes that were made
+/// to them after `set()` are written too. If the main thread is busy for too long, the previous
+/// serialized values are written instead.
+async fn resnapshot_journal(storage: &SharedStorage, resnapshot: &SharedResnapshotCallback) {
+  let (done, rx) = oneshot::channel();
+  resnapshot.call(ResnapshotRequest {
+    // The request is handled on the main thread
+    storage: storage.at_site(LockSite::Main),
+    done,
+  });
+  // Resolves immediately if the request was dropped, e.g. because no callback is registered
+  time::timeout(RESNAPSHOT_TIMEOUT, rx).await.ok();
+}
+
+async fn run_persistence(
+  ctx: PersistenceContext,
+  shards: OpenedShards,
+  rx: &mut Receiver<Command>,
+  in_flight: &mut Option<Callback>,
+  compressed_on_open: &watch::Sender<CompressOutcome>,
+  started: &mut Option<oneshot::Sender<Result<()>>>,
 ) -> Result<()> {
+  let PersistenceContext {
+    filename,
+    opts,
+    mut storage,
+    lock,
+    fs_ops,
+    on_flush,
+    events,
+    resnapshot,
+    lock_lost,
+    write_counters,
+  } = ctx;
+  let (filename, opts, fs_ops, write_counters) = (
+    filename.as_str(),
+    &opts,
+    fs_ops.as_ref(),
+    write_counters.as_ref(),
+  );
+
   // Keep track of the write accesses
-  let mut last_write = Instant::now();
   let throttle_interval = opts.throttle_fs.interval_ms as u128;
   let max_buffered_commands = opts.throttle_fs.max_buffered_commands;
   let mut last_lockfile_refresh = Instant::now();
 
   // And compression attempts
-  let mut last_compress = Instant::now();
-  let mut uncompressed_size: usize = storage.len();
-  let mut changes_since_compress: usize = 0;
+  let mut compress_tracker = CompressTracker::new();
+  let mut uncompressed_size: usize = storage.stats().uncompressed_size;
+  let mut tracker = LineTracker::new(&mut storage);
+  storage.set_stats(tracker.to_stats(uncompressed_size, &compress_tracker, &opts.auto_compress));
 
-  // Open writer and make sure the file ends with LF
-  let mut writer = {
-    let needs_lf = file_needs_lf(&mut file).await?;
-    let mut ret = BufWriter::new(file);
-    if needs_lf {
-      ret.write(b"\n").await?;
-    }
-    ret
-  };
+  // And automatic dumps
+  let mut last_auto_dump = Instant::now();
+  let mut changes_since_auto_dump = false;
+
+  // Open writers and make sure the files end with LF
+  let mut shards = ShardWriters::new(shards, opts.io_buffer_size, fs_ops).await?;
+
+  // From now on, failures are reported as events and the DB stays open until it is closed
+  if let Some(started) = started.take() {
+    started.send(Ok(())).ok();
+  }
 
   let mut just_opened: bool = true;
+  // Whether nothing was written since the last compress, so compressing again would not change
+  // the files
+  let mut unchanged_since_compress = false;
+  // A command that was received while writing the journal
+  let mut pending_command: Option<Command> = None;
+  // Whether there were writes that are not guaranteed to be on disk yet
+  let mut needs_sync = false;
+  // Since when the oldest of these writes is waiting to be synced
+  let mut unsynced_since = Instant::now();
+  // Whether the DB file disappeared, e.g. because its drive was removed. Changes are kept in memory
+  // until persisting is retried.
+  let mut paused = false;
 
-  let idle_duration = Duration::from_millis(20);
+  // How long changes are collected before they are written, so bursts end up in the same write
+  let write_delay = Duration::from_millis(20);
+  // Since when the journal is not empty
+  let mut pending_since: Option<Instant> = None;
+  // Signaled when more writes are pending than may be buffered
+  let journal_full = storage.lock().journal_full.clone();
+  // Signaled on the first change while the journal is empty
+  let changed = storage.lock().changed.clone();
   loop {
     // Refresh lockfile if necessary
     if Instant::now()
       .duration_since(last_lockfile_refresh)
       .as_millis()
-      >= lock.get_stale_interval_ms()
+      >= lock.lock().get_stale_interval_ms()
+      && !lock_lost.load(Ordering::SeqCst)
+      // The lockfile may have disappeared too. It is restored when persisting is retried
+      && !paused
     {
-      lock.update()?;
+      let updated = lock.lock().update();
+      if let Err(e) = updated {
+        if !reacquire_lock(&lock, opts.lock_lost_retries).await {
+          // Another process may be writing to the file now. Stop writing, but keep the journal
+          // in memory, so the unsaved changes can still be dumped elsewhere.
+          lock_lost.store(true, Ordering::SeqCst);
+          events.emit("lockLost", json!({ "message": e.to_string() }));
+        }
+      }
       last_lockfile_refresh = Instant::now();
     }
 
+    if lock_lost.load(Ordering::SeqCst) || paused {
+      match rx.recv().await {
+        // Nothing may be written to the DB file anymore, so the changes are only in memory
+        Some(Command::Compress {
+          done: Some(done), ..
+        }) => {
+          let lock_lost = lock_lost.load(Ordering::SeqCst);
+          done.send(Err(persisting_unavailable(lock_lost, "compress the DB"))).ok();
+        }
+        Some(Command::Flush { done }) => {
+          let lock_lost = lock_lost.load(Ordering::SeqCst);
+          done.send(Err(persisting_unavailable(lock_lost, "write changes to disk"))).ok();
+        }
+        Some(Command::Compress { done: None, .. }) => {}
+        Some(Command::Snapshot { done, .. }) => {
+          let lock_lost = lock_lost.load(Ordering::SeqCst);
+          done.send(Err(persisting_unavailable(lock_lost, "create a snapshot"))).ok();
+        }
+        Some(Command::RetryPersistence { done }) => {
+          if lock_lost.load(Ordering::SeqCst) {
+            done.send(Err(JsonlDBError::LockLost)).ok();
+            continue;
+          }
+          match restore_db_files(&shards.layout, &mut storage, &lock, opts, fs_ops).await {
+            Ok(()) => {
+              shards.reopen(opts.file_mode).await?;
+              paused = false;
+              needs_sync = false;
+              last_lockfile_refresh = Instant::now();
+
+              // The files now contain exactly the current entries, like after compressing
+              shards.lines = storage.lock().compacted_lines_per_shard(&shards.layout);
+              uncompressed_size = storage.len();
+              tracker.reset(&mut storage);
+              storage.set_stats(tracker.to_stats(
+                uncompressed_size,
+                &compress_tracker,
+                &opts.auto_compress,
+              ));
+              done.send(Ok(())).ok();
+            }
+            Err(e) => {
+              done.send(Err(e)).ok();
+            }
+          }
+        }
+        Some(Command::Stop) | None => break,
+      }
+      continue;
+    }
+
     // Figure out what to do
-    let command = if (just_opened && opts.auto_compress.on_open)
-      || need_to_compress_by_size(
-        &opts.auto_compress,
-        storage.len() as u32,
-        uncompressed_size as u32,
-      )
-      || need_to_compress_by_time(
-        &opts.auto_compress,
-        last_compress,
-        changes_since_compress as u32,
-      ) {
+    let command = if let Some(cmd) = pending_command.take() {
+      // Handle commands that were received while writing first
+      Ok(Some(cmd))
+    } else if let Some(trigger) = auto_compress_trigger(
+      &opts.auto_compress,
+      just_opened,
+      storage.len() as u32,
+      uncompressed_size as u32,
+      &compress_tracker,
+      &tracker,
+    ) {
       // We need to compress, do it now!
-      Ok(Some(Command::Compress { done: None }))
+      Ok(Some(Command::Compress {
+        done: None,
+        trigger,
+      }))
     } else {
-      // If we don't have to compress, wait for a command
-      time::timeout(idle_duration, rx.recv()).await
+      // If we don't have to compress, sleep until a command arrives, something is changed or a
+      // timed task is due. An idle DB does not wake up periodically.
+      let (journal_len, needs_rewrite) = {
+        let mut storage = storage.lock();
+        storage.wake_on_change = storage.journal.is_empty();
+        (storage.journal.len(), storage.needs_rewrite)
+      };
+      if journal_len == 0 {
+        pending_since = None;
+      } else if pending_since.is_none() {
+        pending_since = Some(Instant::now());
+      }
+      let write_at = pending_since.map(|since| {
+        (since + write_delay)
+          .max(compress_tracker.last_write + Duration::from_millis(throttle_interval as u64))
+      });
+      let sync_at = if needs_sync {
+        fsync_deadline(&opts.throttle_fs, compress_tracker.last_write, unsynced_since, journal_len)
+      } else {
+        None
+      };
+      let auto_dump_at = match opts.auto_dump.as_ref() {
+        Some(auto_dump) if changes_since_auto_dump => {
+          Some(last_auto_dump + Duration::from_millis(auto_dump.interval_ms as u64))
+        }
+        _ => None,
+      };
+      let stale_interval_ms = lock.lock().get_stale_interval_ms() as u64;
+      let deadline = [
+        write_at,
+        needs_rewrite.then(Instant::now),
+        sync_at,
+        auto_dump_at,
+        compress_tracker.next_interval_check(&opts.auto_compress),
+      ]
+      .into_iter()
+      .flatten()
+      // The lockfile has to be refreshed regularly anyways
+      .fold(last_lockfile_refresh + Duration::from_millis(stale_interval_ms), Instant::min);
+
+      let command = tokio::select! {
+        command = time::timeout_at(deadline, rx.recv()) => {
+          // A closed channel means that nobody can ask to stop anymore
+          command.map(|cmd| Some(cmd.unwrap_or(Command::Stop)))
+        }
+        // Too many writes are pending, write them without waiting for the write delay
+        _ = journal_full.notified() => Ok(None),
+        // The first change only starts the write delay
+        _ = changed.notified(), if journal_len == 0 => {
+          write_counters.woke_up();
+          continue;
+        }
+      };
+      write_counters.woke_up();
+      command
     };
 
     just_opened = false;
 
+    // A pending rewrite contains everything in the journal, so it has to happen before writing
+    let command = match command {
+      Ok(Some(Command::Flush { .. }))
+      | Ok(Some(Command::Snapshot { .. }))
+      | Ok(Some(Command::Stop))
+      | Ok(None)
+      | Err(_)
+        if storage.lock().needs_rewrite =>
+      {
+        pending_command = command.ok().flatten();
+        Ok(Some(Command::Compress {
+          done: None,
+          trigger: CompressTrigger::Import,
+        }))
+      }
+      command => command,
+    };
+
     // Figure out if there is something to do
     match command {
-      Ok(Some(Command::Stop)) | Ok(None) | Err(_) => {
-        // No command or we were asked to stop
-        let stop = is_stop_cmd(command);
+      Ok(Some(Command::Stop))
+      | Ok(Some(Command::Flush { .. }))
+      | Ok(Some(Command::Snapshot { .. }))
+      | Ok(None)
+      | Err(_) => {
+        // No command or we were asked to stop, flush or create a snapshot
+        let stop = is_stop_cmd(&command);
+        let (flush_done, snapshot) = get_flush_callback(command);
+        let must_write = stop || flush_done.is_some() || snapshot.is_some();
+        *in_flight = flush_done;
 
         // Write to disk if necessary
         let journal_len = storage.journal_len();
         let should_write = journal_len > 0
-          && (stop
-            || Instant::now().duration_since(last_write).as_millis() >= throttle_interval
+          && (must_write
+            || Instant::now()
+              .duration_since(compress_tracker.last_write)
+              .as_millis()
+              >= throttle_interval
             || journal_len > max_buffered_commands);
 
         if should_write {
-          let journal = storage.drain_journal();
+          if opts.resnapshot_on_write {
+            resnapshot_journal(&storage, &resnapshot).await;
+          }
+          let write_start = Instant::now();
+          let mut lines_written: usize = 0;
+          let mut bytes_written: usize = 0;
 
-          for str in journal {
-            if str == "" {
-              // Truncate the file
-              writer.rewind().await?;
-              writer.get_ref().set_len(0).await?;
-              // Now the DB size is effectively 0 and we have no "uncompressed" changes pending
-              uncompressed_size = 0;
-              changes_since_compress = 0;
-            } else {
-              writer.write(str.as_bytes()).await?;
-              writer.write(b"\n").await?;
-              uncompressed_size += 1;
-              changes_since_compress += 1;
+          let written = async {
+            // A file that was deleted along with its directory can still be written to,
+            // but everything written to it is lost
+            if let Some(missing) = shards.missing_file().await? {
+              return Err(JsonlDBError::from(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("The DB file \"{}\" no longer exists", missing),
+              )));
             }
+
+            // Write the journal in chunks, so huge journals don't block the other tasks
+            loop {
+              let mut mismatches = Vec::new();
+              let checks = opts.debug_consistency_checks.then_some(&mut mismatches);
+              let journal = storage.drain_journal_chunk(WRITE_CHUNK_SIZE, checks);
+              for (key, message) in mismatches {
+                events.emit(
+                  "error",
+                  json!({ "message": message, "source": "consistencyCheck", "key": &*key }),
+                );
+              }
+
+              for (entry, line) in journal {
+                write_counters.track(&entry, &line);
+                shards.write(&entry, &line).await?;
+                match line {
+                  JournalLine::Truncate => {
+                    // Now the DB size is effectively 0 and we have no "uncompressed" changes
+                    // pending
+                    uncompressed_size = 0;
+                    compress_tracker.changes = 0;
+                  }
+                  JournalLine::Append(str) => {
+                    uncompressed_size += 1;
+                    compress_tracker.changes += 1;
+                    lines_written += 1;
+                    bytes_written += str.len() + 1;
+                  }
+                }
+                tracker.track(&entry);
+                unchanged_since_compress = false;
+              }
+
+              if storage.journal_len() == 0 {
+                break;
+              }
+
+              task::yield_now().await;
+
+              // When stopping or flushing, everything needs to be written anyways. Otherwise,
+              // handle new commands after this chunk. The remaining journal will be written
+              // afterwards.
+              if !must_write {
+                if let Ok(cmd) = rx.try_recv() {
+                  pending_command = Some(cmd);
+                  break;
+                }
+              }
+            }
+
+            // Make sure everything is on disk
+            shards.flush().await?;
+            Ok::<(), JsonlDBError>(())
           }
+          .await;
+          pause_if_storage_gone(written, &mut paused, &events)?;
+          if !paused {
+            compress_tracker.last_write = Instant::now();
+            if !needs_sync {
+              unsynced_since = compress_tracker.last_write;
+            }
+            needs_sync = true;
+            changes_since_auto_dump = true;
+            storage.set_stats(tracker.to_stats(
+              uncompressed_size,
+              &compress_tracker,
+              &opts.auto_compress,
+            ));
 
-          // Make sure everything is on disk
-          writer.flush().await?;
-          last_write = Instant::now();
+            let info = FlushInfo {
+              entries: lines_written as u32,
+              bytes: bytes_written as u32,
+              duration_ms: write_start.elapsed().as_millis() as u32,
+            };
+            events.emit("flush", json!(info));
+            on_flush.call(info);
+          }
         }
 
-        if stop {
+        if !paused && (stop || ((in_flight.is_some() || snapshot.is_some()) && needs_sync)) {
           // Make sure everything is on disk
-          writer.flush().await?;
-          writer.get_ref().sync_all().await?;
+          let synced = shards.sync_all().await;
+          pause_if_storage_gone(synced, &mut paused, &events)?;
+          write_counters.synced();
+          needs_sync = false;
+        } else if !paused
+          && needs_sync
+          && fsync_due(
+            &opts.throttle_fs,
+            compress_tracker.last_write,
+            unsynced_since,
+            storage.journal_len(),
+          )
+        {
+          // Limit how much a crash of the machine can lose, without syncing after every write
+          let synced = shards.sync_data().await;
+          pause_if_storage_gone(synced, &mut paused, &events)?;
+          write_counters.synced();
+          needs_sync = false;
+        }
+
+        // Nothing was written since the journal was drained, so compressing cannot get in between
+        if let Some((target, done)) = snapshot {
+          let result = if paused {
+            Err(persisting_unavailable(false, "create a snapshot"))
+          } else {
+            let entries = storage.len();
+            snapshot_files(&shards.layout, &target, opts.file_mode, fs_ops)
+              .await
+              .map(|bytes| SnapshotInfo { bytes, entries })
+          };
+          done.send(result).ok();
+        }
 
+        // Multiple flush commands may be queued. All but the first one are resolved
+        // immediately, because there is nothing left to write. If the DB file disappeared while
+        // writing, the changes are only in memory.
+        if let Some(done) = in_flight.take() {
+          let result = if paused {
+            Err(persisting_unavailable(false, "write changes to disk"))
+          } else {
+            Ok(())
+          };
+          done.send(result).ok();
+        }
+
+        if stop {
           break;
         }
+
+        // Create a rolling backup if the interval has elapsed and something changed
+        if let Some(auto_dump_opts) = opts.auto_dump.as_ref() {
+          if changes_since_auto_dump
+            && !paused
+            && last_auto_dump.elapsed().as_millis() >= auto_dump_opts.interval_ms as u128
+          {
+            let dump_start = Instant::now();
+            match auto_dump(filename, &mut storage, auto_dump_opts, opts.file_mode, fs_ops).await {
+              Ok((target, dump_size)) => events.emit(
+                "dumpDone",
+                json!({
+                  "filename": target,
+                  "bytes": dump_size,
+                  "durationMs": dump_start.elapsed().as_millis() as u32,
+                }),
+              ),
+              // Failing to create a backup is no reason to stop persisting the DB
+              Err(e) => events.emit(
+                "error",
+                json!({ "message": e.to_string(), "source": "autoDump" }),
+              ),
+            }
+            last_auto_dump = Instant::now();
+            changes_since_auto_dump = false;
+          }
+        }
       }
 
-      Ok(Some(Command::Compress { done })) => {
-        // Compress the database
-        let filename = filename.to_owned();
-        let dump_filename = format!("{}.dump", &filename);
-        let backup_filename = format!("{}.bak", &filename);
-        let dirname = parent_dir(Path::new(&filename))?;
+      Ok(Some(Command::Compress { done, .. }))
+        if unchanged_since_compress
+          && storage.journal_len() == 0
+          && !storage.lock().needs_rewrite
+          // Expired tombstones are only removed by compressing
+          && opts.soft_delete_retain_ms.is_none() =>
+      {
+        // E.g. compress() right after the compress on open. This would only do the same work again.
+        if let Some(done) = done {
+          done.send(Ok(())).ok();
+        }
+      }
 
+      Ok(Some(Command::Compress { done, trigger })) => {
+        *in_flight = done;
+        // Compress the database
+        let compress_start = Instant::now();
+        events.emit("compressStart", json!({}));
+        // Dumps can tell from this whether they were rendered while compressing
+        storage.lock().compress_epoch += 1;
         // 1. Ensure the backup contains everything in the DB and journal
+        if opts.resnapshot_on_write {
+          resnapshot_journal(&storage, &resnapshot).await;
+        }
         let write_journal = storage.drain_journal();
-        for str in write_journal.iter() {
-          if str == "" {
-            // Truncate the file
-            writer.seek(SeekFrom::Start(0)).await?;
-            writer.get_ref().set_len(0).await?;
-            // Now the DB size is effectively 0 and we have no "uncompressed" changes pending
-            uncompressed_size = 0;
-            changes_since_compress = 0;
-          } else {
-            writer.write(str.as_bytes()).await?;
-            writer.write(b"\n").await?;
-            uncompressed_size += 1;
-            changes_since_compress += 1;
+        if !write_journal.is_empty() {
+          changes_since_auto_dump = true;
+        }
+        for (entry, line) in write_journal.iter() {
+          write_counters.track(entry, line);
+          shards.write(entry, line).await?;
+          match line {
+            JournalLine::Truncate => {
+              // Now the DB size is effectively 0 and we have no "uncompressed" changes pending
+              uncompressed_size = 0;
+              compress_tracker.changes = 0;
+            }
+            JournalLine::Append(_) => {
+              uncompressed_size += 1;
+              compress_tracker.changes += 1;
+            }
           }
         }
         // Make sure everything is on disk
-        writer.flush().await?;
-        writer.get_ref().sync_all().await?;
-
-        // Close the file
-        drop(writer);
-
-        // 2. Create a dump, draining the journal to avoid duplicate writes
-        dump(&dump_filename, &mut storage, true).await?;
-
-        // 3. Ensure there are no pending rename operations or file creations
-        fsync_dir(&dirname).await?;
-
-        // 4. Swap files around, then ensure the directory entries are written to disk
-        fs::rename(&filename, &backup_filename).await?;
-        fs::rename(&dump_filename, &filename).await?;
-        fsync_dir(&dirname).await?;
-
-        // 5. Delete backup
-        fs::remove_file(&backup_filename).await?;
-
-        // 6. open the main DB file again
-        file = OpenOptions::new()
-          .create(true)
-          .read(true)
-          .write(true)
-          .open(&filename)
-          .await?;
-        writer = BufWriter::new(file);
-        writer.seek(SeekFrom::End(0)).await?;
+        shards.sync(fs_ops).await?;
+        write_counters.synced();
+
+        // Close the files
+        shards.close();
+
+        // Expired tombstones are left out, which deletes them for good
+        storage.prune_tombstones();
+        let dump_size = if shards.layout.is_sharded() {
+          // Only rewrite the shards that contain redundant lines, unless a rewrite is pending.
+          // Each dump only drains the pending writes of its own shard from the journal.
+          let bloated: Vec<usize> = {
+            let mut storage = storage.lock();
+            let rewrite_all = std::mem::take(&mut storage.needs_rewrite);
+            let compacted_lines = storage.compacted_lines_per_shard(&shards.layout);
+            (0..shards.layout.count())
+              .filter(|&shard| rewrite_all || shards.lines[shard] > compacted_lines[shard])
+              .collect()
+          };
+          let mut dump_size = 0;
+          for shard in bloated {
+            let shard_filename = shards.layout.filename(shard);
+            let target = Some((&shards.layout, shard));
+            let (size, lines) =
+              compress_file(&shard_filename, &mut storage, target, opts, fs_ops).await?;
+            dump_size += size;
+            shards.lines[shard] = lines;
+          }
+          dump_size
+        } else {
+          let (dump_size, lines) = compress_file(filename, &mut storage, None, opts, fs_ops).await?;
+          shards.lines[0] = lines;
+          dump_size
+        };
+
+        // 6. open the DB files again
+        shards.reopen(opts.file_mode).await?;
         // Any "new" data in the journal will be written in the next iteration
 
         // Remember the new statistics
-        uncompressed_size = storage.len();
-        changes_since_compress = 0;
-        last_compress = Instant::now();
+        needs_sync = false;
+        uncompressed_size = shards.total_lines();
+        compress_tracker.compressed();
+        tracker.reset(&mut storage);
+        storage.set_stats(tracker.to_stats(
+          uncompressed_size,
+          &compress_tracker,
+          &opts.auto_compress,
+        ));
+
+        let info = FlushInfo {
+          entries: uncompressed_size as u32,
+          bytes: dump_size as u32,
+          duration_ms: compress_start.elapsed().as_millis() as u32,
+        };
+        storage.lock().compress_epoch += 1;
+        unchanged_since_compress = true;
+        let mut payload = json!(info);
+        payload["trigger"] = json!(trigger.as_str());
+        events.emit("compressDone", payload);
+        events.emit("flush", json!(info));
+        on_flush.call(info);
+        if matches!(trigger, CompressTrigger::OnOpen) {
+          compressed_on_open.send(Some(Ok(()))).ok();
+        }
 
         // invoke the callback
-        if let Some(done) = done {
-          done.notify_waiters();
+        if let Some(done) = in_flight.take() {
+          done.send(Ok(())).ok();
         }
       }
 
-      Ok(Some(Command::Dump { filename, done })) => {
-        // Create a backup
-        dump(&filename, &mut storage, false).await?;
+      // Persisting is not paused, so there is nothing to retry
+      Ok(Some(Command::RetryPersistence { done })) => {
+        done.send(Ok(())).ok();
+      }
+    }
+  }
+
+  if paused {
+    events.emit(
+      "error",
+      json!({
+        "message": "The DB was closed while persisting was paused, recent changes were not written",
+        "source": "persistence",
+      }),
+    );
+  }
 
-        // invoke the callback
-        done.notify_waiters();
+  // Settle the commands that were queued after the Stop command, so nobody waits forever.
+  // Compressing is a no-op while the DB is being closed.
+  rx.close();
+  while let Ok(cmd) = rx.try_recv() {
+    match cmd {
+      Command::Compress {
+        done: Some(done), ..
+      } => {
+        done.send(Ok(())).ok();
+      }
+      // Everything was written when stopping, unless persisting was paused
+      Command::Flush { done } => {
+        let lock_lost = lock_lost.load(Ordering::SeqCst);
+        let result = if paused || lock_lost {
+          Err(persisting_unavailable(lock_lost, "write changes to disk"))
+        } else {
+          Ok(())
+        };
+        done.send(result).ok();
       }
+      Command::RetryPersistence { done } => {
+        done.send(Err(JsonlDBError::NotOpen)).ok();
+      }
+      Command::Snapshot { done, .. } => {
+        done.send(Err(JsonlDBError::NotOpen)).ok();
+      }
+      Command::Compress { done: None, .. } | Command::Stop => {}
     }
   }
 
   Ok(())
 }
 
-async fn dump(filename: &str, storage: &mut SharedStorage, drain_journal: bool) -> Result<()> {
-  let dump_file = OpenOptions::new()
-    .create(true)
-    .write(true)
-    .truncate(true)
-    .open(filename)
+/// Replaces a DB or shard file with a compressed dump of its entries. Returns the size of the dump
+/// and the number of lines in it.
+async fn compress_file(
+  filename: &str,
+  storage: &mut SharedStorage,
+  shard: Option<(&ShardLayout, usize)>,
+  opts: &DBOptions,
+  fs_ops: &dyn FsOps,
+) -> Result<(usize, usize)> {
+  let mut storage = storage.at_site(LockSite::Compress);
+  let dump_filename = compress_dump_filename(filename, opts)?;
+  let backup_filename = compress_backup_filename(filename, opts);
+  let dirname = parent_dir(Path::new(filename))?;
+  let dump_dirname = parent_dir(Path::new(&dump_filename))?;
+
+  // 2. Create a dump. The journal is drained to avoid duplicate writes, for a shard only its part.
+  // The dump becomes the main file, so it needs the same permissions
+  let (dump_size, lines) = dump(
+    &dump_filename,
+    &mut storage,
+    true,
+    false,
+    opts.file_mode,
+    fs_ops,
+    shard,
+  )
+  .await?;
+
+  // 3. Ensure there are no pending rename operations or file creations
+  if dump_dirname != dirname {
+    fs_ops.sync_dir(&dump_dirname).await?;
+  }
+  fs_ops.sync_dir(&dirname).await?;
+
+  // 4. Swap files around, then ensure the directory entries are written to disk
+  fs_ops
+    .move_file(Path::new(filename), Path::new(&backup_filename))
+    .await?;
+  fs_ops
+    .move_file(Path::new(&dump_filename), Path::new(filename))
     .await?;
+  fs_ops.sync_dir(&dirname).await?;
 
-  let mut writer = BufWriter::new(dump_file);
+  // 5. Delete backup
+  fs_ops.remove_file(Path::new(&backup_filename)).await?;
 
-  // Render the compressed file in memory so we only need to lock the storage very shortly
-  // Also, remember how many entries were in the journal. These are already part of
-  // the map, so we don't need to append them later
-  // and keep a consistent state
-  let (dump, journal_len) = {
-    let storage = storage.lock();
-    let journal = &storage.journal;
+  Ok((dump_size, lines))
+}
 
-    let dump: Vec<u8> = storage
-      .entries
-      .iter()
-      .flat_map(|(key, val)| [format_line(key, val).as_bytes(), b"\n"].concat())
-      .collect();
-    (dump, journal.len())
-  };
+/// Writes the entries into the files of the given layout, e.g. to change the number of shards.
+/// Each file is written next to its target first and moved into place once all are complete.
+pub(crate) async fn write_shards(
+  layout: &ShardLayout,
+  storage: &mut SharedStorage,
+  opts: &DBOptions,
+  fs_ops: &dyn FsOps,
+) -> Result<()> {
+  let dirname = parent_dir(layout.filename(0))?;
+  for shard in 0..layout.count() {
+    let dump_filename = format!("{}{}", layout.filename(shard), opts.compress_dump_suffix);
+    let target = Some((layout, shard));
+    dump(&dump_filename, storage, false, false, opts.file_mode, fs_ops, target).await?;
+  }
+  fs_ops.sync_dir(&dirname).await?;
+
+  for filename in layout.filenames() {
+    let dump_filename = format!("{}{}", filename, opts.compress_dump_suffix);
+    fs_ops
+      .move_file(Path::new(&dump_filename), Path::new(&filename))
+      .await?;
+  }
+  fs_ops.sync_dir(&dirname).await?;
+  Ok(())
+}
+
+/// Whether written data should be synced to disk now, because writing paused for `fsync_idle_ms`
+/// or the oldest unsynced write is older than `fsync_max_interval_ms`
+fn fsync_due(
+  opts: &ThrottleFSOptions,
+  last_write: Instant,
+  unsynced_since: Instant,
+  journal_len: usize,
+) -> bool {
+  fsync_deadline(opts, last_write, unsynced_since, journal_len)
+    .map_or(false, |deadline| deadline <= Instant::now())
+}
 
-  // Print all items
-  writer.write_all(dump.as_slice()).await?;
+/// When written data has to be synced to disk, if nothing is written before. None if it is only
+/// synced by flushing, compressing or closing.
+fn fsync_deadline(
+  opts: &ThrottleFSOptions,
+  last_write: Instant,
+  unsynced_since: Instant,
+  journal_len: usize,
+) -> Option<Instant> {
+  let idle = (opts.fsync_idle_ms > 0 && journal_len == 0)
+    .then(|| last_write + Duration::from_millis(opts.fsync_idle_ms as u64));
+  let overdue = (opts.fsync_max_interval_ms > 0)
+    .then(|| unsynced_since + Duration::from_millis(opts.fsync_max_interval_ms as u64));
+  idle.into_iter().chain(overdue).min()
+}
+
+/// Pauses persisting if the error means that the DB file is gone, e.g. because its drive was
+/// removed. Other errors are returned.
+fn pause_if_storage_gone(
+  result: Result<()>,
+  paused: &mut bool,
+  events: &SharedEventCallback,
+) -> Result<()> {
+  match result {
+    Err(JsonlDBError::IoError(e)) if util::is_storage_gone_error(&e) => {
+      *paused = true;
+      events.emit(
+        "error",
+        json!({ "message": e.to_string(), "source": "persistence", "paused": true }),
+      );
+      Ok(())
+    }
+    ret => ret,
+  }
+}
 
-  // And append any new entries in the journal
-  let journal = if drain_journal {
-    storage.drain_journal()
+/// Recreates the DB files after they disappeared. Because the previous content is gone, all entries
+/// are written, not just the journal.
+async fn restore_db_files(
+  layout: &ShardLayout,
+  storage: &mut SharedStorage,
+  lock: &SharedLockfile,
+  opts: &DBOptions,
+  fs_ops: &dyn FsOps,
+) -> Result<()> {
+  let dirname = parent_dir(layout.filename(0))?;
+  util::create_dir_all(&dirname, opts.file_mode)?;
+  // The lockfile may have been next to the DB file
+  lock.lock().update()?;
+
+  if layout.is_sharded() {
+    // All shards are rewritten, so the pending writes are part of the dumps
+    {
+      let mut storage = storage.lock();
+      storage.needs_rewrite = false;
+      storage.discard_journal();
+    }
+    for shard in 0..layout.count() {
+      let shard_filename = layout.filename(shard);
+      let target = Some((layout, shard));
+      dump(&shard_filename, storage, false, false, opts.file_mode, fs_ops, target).await?;
+    }
   } else {
-    storage.clone_journal()
+    dump(&layout.filename(0), storage, true, false, opts.file_mode, fs_ops, None).await?;
+  }
+  fs_ops.sync_dir(&dirname).await?;
+  Ok(())
+}
+
+/// Tries to acquire a lost lock again. Returns whether that succeeded.
+async fn reacquire_lock(lock: &SharedLockfile, retries: u32) -> bool {
+  for _ in 0..retries {
+    time::sleep(LOCK_RETRY_INTERVAL).await;
+    if lock.lock().reacquire().is_ok() {
+      return true;
+    }
+  }
+  false
+}
+
+/// Where compressing writes the dump that replaces the DB file. Unless a scratch directory is
+/// configured, that is next to the DB file. Otherwise the dump is copied over if necessary.
+fn compress_dump_filename(filename: &str, opts: &DBOptions) -> Result<String> {
+  scratch_filename(filename, &opts.compress_dump_suffix, opts)
+}
+
+/// Where compressing moves the DB file until the dump has replaced it
+fn compress_backup_filename(filename: &str, opts: &DBOptions) -> String {
+  format!("{}{}", filename, opts.compress_backup_suffix)
+}
+
+/// The files an interrupted compress may have left behind for the given DB file. Backups come
+/// first, because they have complete data, while a dump may have been written partially.
+/// Dumps may also be next to the DB file from before a scratch directory was configured, and the
+/// default suffixes are checked in case the configured ones were changed since.
+pub(crate) fn compress_leftover_filenames(
+  filename: &str,
+  opts: &DBOptions,
+) -> Result<Vec<String>> {
+  let suffixes = [
+    (opts.compress_backup_suffix.as_str(), opts.compress_dump_suffix.as_str()),
+    // TODO: Stop looking for the default suffixes in the next major release
+    (DEFAULT_COMPRESS_BACKUP_SUFFIX, DEFAULT_COMPRESS_DUMP_SUFFIX),
+  ];
+  let mut backups: Vec<String> = Vec::new();
+  let mut dumps: Vec<String> = Vec::new();
+  for (backup_suffix, dump_suffix) in suffixes {
+    // A name that is already used for the other kind of file keeps its first meaning
+    let backup_filename = format!("{}{}", filename, backup_suffix);
+    if !backups.contains(&backup_filename) && !dumps.contains(&backup_filename) {
+      backups.push(backup_filename);
+    }
+    for dump_filename in [
+      format!("{}{}", filename, dump_suffix),
+      scratch_filename(filename, dump_suffix, opts)?,
+    ] {
+      if !backups.contains(&dump_filename) && !dumps.contains(&dump_filename) {
+        dumps.push(dump_filename);
+      }
+    }
+  }
+  backups.append(&mut dumps);
+  Ok(backups)
+}
+
+/// Appends the suffix to the filename and moves the result to the scratch directory, if one is
+/// configured
+fn scratch_filename(filename: &str, suffix: &str, opts: &DBOptions) -> Result<String> {
+  let dump_filename = format!("{}{}", filename, suffix);
+  match &opts.scratch_directory {
+    None => Ok(dump_filename),
+    Some(dir) => replace_dirname(&dump_filename, dir)
+      .map(|path| path.to_string_lossy().into_owned())
+      .ok_or_else(|| {
+        JsonlDBError::io_error_from_reason(format!(
+          "Could not determine dump file name for \"{}\"",
+          filename
+        ))
+      }),
+  }
+}
+
+/// Dumps the DB to the given target. The dump is first written next to the DB file, so the target
+/// never contains a partial dump. This does not touch the DB file, so it can happen at any time,
+/// e.g. while the persistence thread is compressing.
+pub(crate) async fn dump_to(
+  db_filename: &str,
+  target: &str,
+  storage: &mut SharedStorage,
+  gzip: bool,
+  file_mode: Option<u32>,
+  fs_ops: &dyn FsOps,
+) -> Result<usize> {
+  let new_dirs = util::create_parent_dirs(target, file_mode)?;
+
+  let _guard = storage.lock_dumps().await;
+  let tmp_filename = format!("{}.export", db_filename);
+  let (ret, _) = dump(&tmp_filename, storage, false, gzip, file_mode, fs_ops, None).await?;
+  fs_ops
+    .move_file(Path::new(&tmp_filename), Path::new(target))
+    .await?;
+  // The directory entries of new directories are not on disk yet
+  for dir in new_dirs {
+    fs_ops.sync_dir(&dir).await?;
+  }
+  Ok(ret)
+}
+
+/// Copies the DB files to the target, which must happen while nothing is written to them. The
+/// shards of a sharded DB are copied to the corresponding shard files of the target.
+/// Returns the combined size of the copies.
+async fn snapshot_files(
+  layout: &ShardLayout,
+  target: &str,
+  file_mode: Option<u32>,
+  fs_ops: &dyn FsOps,
+) -> Result<u64> {
+  let new_dirs = util::create_parent_dirs(target, file_mode)?;
+  let target_layout = ShardLayout::new(target, layout.count());
+  let mut bytes = 0;
+  for shard in 0..layout.count() {
+    let copy_filename = target_layout.filename(shard);
+    let tmp_filename = format!("{}.tmp", copy_filename);
+    // A hard link would share later appends with the DB file. Copying doesn't, and filesystems
+    // that support it clone the data instead of duplicating it.
+    bytes += fs::copy(layout.filename(shard), &tmp_filename).await?;
+    let file = util::open_options(None).write(true).open(&tmp_filename).await?;
+    fs_ops.sync_file(&file).await?;
+    drop(file);
+    // The target never contains a partial copy
+    fs_ops
+      .move_file(Path::new(&tmp_filename), Path::new(&copy_filename))
+      .await?;
+  }
+  for dir in new_dirs {
+    fs_ops.sync_dir(&dir).await?;
+  }
+  Ok(bytes)
+}
+
+/// Writes an automatic dump and removes the oldest ones beyond the configured number.
+/// Returns the filename of the dump and its size.
+async fn auto_dump(
+  db_filename: &str,
+  storage: &mut SharedStorage,
+  opts: &AutoDumpOptions,
+  file_mode: Option<u32>,
+  fs_ops: &dyn FsOps,
+) -> Result<(String, usize)> {
+  // Relative templates are relative to the DB file
+  let template = parent_dir(db_filename)?.join(&opts.filename_template);
+  let file_template = template
+    .file_name()
+    .map(|f| f.to_string_lossy().into_owned())
+    .ok_or_else(|| {
+      JsonlDBError::io_error_from_reason(format!(
+        "Invalid auto-dump filename template \"{}\"",
+        &opts.filename_template
+      ))
+    })?;
+  // The directory is created by dump_to
+  let dir = parent_dir(&template)?;
+  let file_name = file_template.replace(TIMESTAMP_PLACEHOLDER, &util::now_ms().to_string());
+  let target = dir.join(file_name).to_string_lossy().into_owned();
+  let gzip = util::is_gzip_filename(&target);
+  let dump_size = dump_to(db_filename, &target, storage, gzip, file_mode, fs_ops).await?;
+
+  prune_auto_dumps(&dir, &file_template, opts.keep).await?;
+  Ok((target, dump_size))
+}
+
+/// Removes all but the `keep` newest files in `dir` that match the filename template
+async fn prune_auto_dumps(dir: &Path, file_template: &str, keep: u32) -> Result<()> {
+  let (prefix, suffix) = match file_template.split_once(TIMESTAMP_PLACEHOLDER) {
+    Some(parts) => parts,
+    // Without a timestamp, every dump overwrites the previous one
+    None => return Ok(()),
   };
-  for str in journal.iter().skip(journal_len) {
-    if str == "" {
-      // Truncate the file
-      writer.seek(SeekFrom::Start(0)).await?;
-      writer.get_ref().set_len(0).await?;
+
+  let mut dumps: Vec<(u64, PathBuf)> = Vec::new();
+  let mut read_dir = fs::read_dir(dir).await?;
+  while let Some(entry) = read_dir.next_entry().await? {
+    let name = entry.file_name();
+    let timestamp = name
+      .to_string_lossy()
+      .strip_prefix(prefix)
+      .and_then(|name| name.strip_suffix(suffix))
+      .and_then(|timestamp| timestamp.parse::<u64>().ok());
+    if let Some(timestamp) = timestamp {
+      dumps.push((timestamp, entry.path()));
+    }
+  }
+
+  // Newest first
+  dumps.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+  for (_, path) in dumps.into_iter().skip(keep as usize) {
+    fs::remove_file(path).await?;
+  }
+  Ok(())
+}
+
+/// Writes the entries to the given file like compressing does. If a shard is given, only its
+/// entries are written. Returns the size of the dump and the number of lines in it.
+async fn dump(
+  filename: &str,
+  storage: &mut SharedStorage,
+  drain_journal: bool,
+  gzip: bool,
+  file_mode: Option<u32>,
+  fs_ops: &dyn FsOps,
+  shard: Option<(&ShardLayout, usize)>,
+) -> Result<(usize, usize)> {
+  let in_shard = |key: &str| shard.map_or(true, |(layout, shard)| layout.shard_of(key) == shard);
+
+  // Render the compressed file in memory, a chunk of entries at a time so the storage is only
+  // locked very shortly. Entries that change while rendering end up in the journal, which is
+  // appended afterwards. Since later lines win, the result is consistent even if a changed entry
+  // was already rendered.
+  let mut dump: Vec<u8> = Vec::new();
+  let mut lines = 0;
+  let mut tap_start = 0;
+  let keys: Vec<Key> = {
+    let mut storage = storage.lock();
+    if drain_journal {
+      // The pending writes are part of the dump, only later changes need to be appended
+      match shard {
+        // The other shards still need their pending writes
+        Some((layout, shard)) => {
+          storage.take_shard_journal(layout, shard);
+        }
+        None => {
+          // A dump that replaces the DB file is the rewrite that may be pending
+          storage.needs_rewrite = false;
+          storage.discard_journal();
+        }
+      }
     } else {
-      writer.write(str.as_bytes()).await?;
-      writer.write(b"\n").await?;
+      // The persistence thread may take changes from the journal while this dump is rendered
+      tap_start = storage.open_dump();
+    }
+    // The DB header comes first, so it is easy to find
+    if let Some(line) = storage.header_line().filter(|_| in_shard(HEADER_KEY)) {
+      dump.extend_from_slice(line.as_bytes());
+      dump.push(b'\n');
+      lines += 1;
+    }
+    storage
+      .entries
+      .keys()
+      .filter(|key| in_shard(key))
+      .cloned()
+      .collect()
+  };
+  for chunk in keys.chunks(DUMP_CHUNK_SIZE) {
+    {
+      let storage = storage.lock();
+      for key in chunk {
+        // Entries that were deleted in the meantime have a pending write in the journal
+        if let Some(val) = storage.entries.get(key) {
+          dump.extend_from_slice(format_line(key, val, storage.meta(key)).as_bytes());
+          dump.push(b'\n');
+          lines += 1;
+        }
+      }
+    }
+    tokio::task::yield_now().await;
+  }
+  drop(keys);
+  {
+    let storage = storage.lock();
+    if let Some(tombstones) = storage.tombstones.as_ref() {
+      for key in tombstones.deleted.keys().filter(|key| in_shard(key)) {
+        let line = storage.tombstone_line(key).unwrap_or_default();
+        dump.extend_from_slice(line.as_bytes());
+        dump.push(b'\n');
+        lines += 1;
+      }
+    }
+  }
+
+  // And append the changes in the journal. Without draining, the pending writes from before
+  // rendering are appended too, because the persistence thread may already have taken them.
+  let journal = match (drain_journal, shard) {
+    (true, Some((layout, shard))) => storage.drain_shard_journal(layout, shard),
+    (true, None) => storage.drain_journal(),
+    (false, _) => storage.close_dump(tap_start),
+  };
+  for (entry, line) in journal.iter() {
+    match line {
+      // Truncate the output
+      JournalLine::Truncate => {
+        dump.clear();
+        lines = 0;
+      }
+      JournalLine::Append(str) => {
+        if let Some((layout, shard)) = shard {
+          if layout.shard_of_entry(entry) != Some(shard) {
+            continue;
+          }
+        }
+        dump.extend_from_slice(str.as_bytes());
+        dump.push(b'\n');
+        lines += 1;
+      }
     }
   }
 
-  // Make sure everything is on disk
-  writer.flush().await?;
-  writer.get_ref().sync_all().await?;
+  if gzip {
+    dump = util::gzip(&dump)?;
+  }
 
-  Ok(())
+  let mut dump_file = util::open_options(file_mode)
+    .create(true)
+    .write(true)
+    .truncate(true)
+    .open(filename)
+    .await?;
+  // An existing file keeps its permissions when it is truncated
+  util::set_file_mode(&dump_file, file_mode).await?;
+
+  // Print all items and make sure everything is on disk
+  fs_ops.write_all(&mut dump_file, dump.as_slice()).await?;
+  fs_ops.sync_file(&dump_file).await?;
+
+  Ok((dump.len(), lines))
 }
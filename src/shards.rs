@@ -0,0 +1,66 @@
+use crate::storage::{JournalEntry, HEADER_KEY};
+
+// FNV-1a parameters. The hash must never change, otherwise the keys end up in the wrong shards.
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Which files the entries of a DB are stored in. Each key belongs to exactly one shard, determined
+/// by a stable hash. Without sharding, the DB file is the only shard.
+#[derive(Debug, Clone)]
+pub(crate) struct ShardLayout {
+  filename: String,
+  count: usize,
+}
+
+impl ShardLayout {
+  pub fn new(filename: &str, count: usize) -> Self {
+    Self {
+      filename: filename.to_owned(),
+      count: count.max(1),
+    }
+  }
+
+  pub fn is_sharded(&self) -> bool {
+    self.count > 1
+  }
+
+  pub fn count(&self) -> usize {
+    self.count
+  }
+
+  /// The file of the given shard, `<filename>.<shard>.jsonl`
+  pub fn filename(&self, shard: usize) -> String {
+    if self.is_sharded() {
+      format!("{}.{}.jsonl", self.filename, shard)
+    } else {
+      self.filename.clone()
+    }
+  }
+
+  pub fn filenames(&self) -> Vec<String> {
+    (0..self.count).map(|shard| self.filename(shard)).collect()
+  }
+
+  /// Which shard the given key belongs to
+  pub fn shard_of(&self, key: &str) -> usize {
+    if !self.is_sharded() {
+      return 0;
+    }
+    (fnv1a(key.as_bytes()) % self.count as u64) as usize
+  }
+
+  /// Which shard a journal entry is written to. `None` if it affects all shards.
+  pub fn shard_of_entry(&self, entry: &JournalEntry) -> Option<usize> {
+    match entry {
+      JournalEntry::Set(key) | JournalEntry::Delete(key) => Some(self.shard_of(key)),
+      JournalEntry::Header => Some(self.shard_of(HEADER_KEY)),
+      JournalEntry::Clear => None,
+    }
+  }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+  })
+}
@@ -1,27 +1,61 @@
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::Instant;
 
+use crate::db_options::DBOptions;
 use crate::error::{JsonlDBError, Result};
+use crate::js_values::stringify_js_object;
+use crate::memory_usage;
+use crate::shards::ShardLayout;
+use crate::util;
 
 use indexmap::IndexMap;
-use napi::{Env, Ref};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use napi::{Env, JsObject, Ref};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, value::RawValue};
 use tokio::{
   fs::File,
   io::{AsyncBufReadExt, BufReader},
+  sync::{Mutex as AsyncMutex, Notify, OwnedMutexGuard},
 };
 
+/// Keys are shared between the entries, the journal and the index instead of being copied
+pub(crate) type Key = Arc<str>;
+
+/// The key of the line that holds the application-defined DB header and which migration was applied.
+/// It is not an entry, but older versions that don't know about it just load it as one.
+pub(crate) const HEADER_KEY: &str = "$jsonl-db-meta";
+
+/// Refuses the key of the DB header. An entry with it would be loaded as the header when opening.
+pub(crate) fn check_key(key: &str) -> Result<()> {
+  if key == HEADER_KEY {
+    return Err(JsonlDBError::ReservedKey(key.to_owned()));
+  }
+  Ok(())
+}
+
 pub(crate) enum DBEntry {
   Reference(String, Ref<()>),
   Native(serde_json::Value),
+  // A serialized value which is only parsed when it is read
+  Stringified(String),
 }
 
 #[derive(Clone)]
 pub(crate) enum JournalEntry {
-  Set(String),
-  Delete(String),
+  Set(Key),
+  Delete(Key),
   Clear,
+  // The DB header was changed or removed
+  Header,
+}
+
+/// What needs to be written to the DB file for a journal entry. Truncating is a separate variant,
+/// so it can never be confused with a line, whatever the key or value is.
+pub(crate) enum JournalLine {
+  Append(String),
+  Truncate,
 }
 
 impl TryFrom<&DBEntry> for serde_json::Value {
@@ -29,7 +63,7 @@ impl TryFrom<&DBEntry> for serde_json::Value {
 
   fn try_from(value: &DBEntry) -> std::result::Result<Self, Self::Error> {
     match value {
-      DBEntry::Reference(str, _) => {
+      DBEntry::Reference(str, _) | DBEntry::Stringified(str) => {
         serde_json::from_str(str).map_err(|e| JsonlDBError::SerializeError {
           reason: format!("Could not convert stringified entry {str}"),
           source: e,
@@ -43,7 +77,7 @@ impl TryFrom<&DBEntry> for serde_json::Value {
 impl Into<String> for DBEntry {
   fn into(self) -> String {
     match self {
-      DBEntry::Reference(str, _) => str,
+      DBEntry::Reference(str, _) | DBEntry::Stringified(str) => str,
       DBEntry::Native(v) => serde_json::to_string(&v).unwrap(),
     }
   }
@@ -52,7 +86,7 @@ impl Into<String> for DBEntry {
 impl Into<String> for &DBEntry {
   fn into(self) -> String {
     match self {
-      DBEntry::Reference(str, _) => str.to_owned(),
+      DBEntry::Reference(str, _) | DBEntry::Stringified(str) => str.to_owned(),
       DBEntry::Native(v) => serde_json::to_string(v).unwrap(),
     }
   }
@@ -69,63 +103,944 @@ pub(crate) fn drop_safe(env: Env, entry: Option<DBEntry>) {
       DBEntry::Native(v) => {
         drop(v);
       }
+      DBEntry::Stringified(str) => {
+        drop(str);
+      }
+    }
+  }
+}
+
+/// References of removed entries which are released in chunks, because releasing many of them at
+/// once blocks the main thread
+#[derive(Clone, Default)]
+pub(crate) struct DeferredUnrefs(Arc<Mutex<Vec<Ref<()>>>>);
+
+impl DeferredUnrefs {
+  fn lock(&self) -> MutexGuard<'_, Vec<Ref<()>>> {
+    self
+      .0
+      .lock()
+      .map_err(|_| JsonlDBError::other("Failed to acquire lock on deferred references"))
+      .unwrap()
+  }
+
+  /// Queues the references of the given entries. Returns whether the queue was empty before.
+  pub fn push(&self, entries: Vec<DBEntry>) -> bool {
+    let mut queue = self.lock();
+    let was_empty = queue.is_empty();
+    for e in entries {
+      if let DBEntry::Reference(_, r) = e {
+        queue.push(r);
+      }
+    }
+    was_empty
+  }
+
+  /// Releases up to `max_count` references and returns how many are left
+  pub fn release(&self, env: Env, max_count: usize) -> usize {
+    let mut queue = self.lock();
+    let start = queue.len().saturating_sub(max_count);
+    for mut r in queue.drain(start..) {
+      r.unref(env).ok();
+    }
+    queue.len()
+  }
+
+  pub fn len(&self) -> usize {
+    self.lock().len()
+  }
+}
+
+pub(crate) fn format_line(
+  key: &str,
+  val: impl Into<String>,
+  meta: Option<&EntryMeta>,
+) -> String {
+  match meta {
+    Some(meta) if stores_created_at(meta) => format!(
+      "{{\"k\":{},\"v\":{},\"t\":{},\"c\":{}}}",
+      serde_json::to_string(key).unwrap(),
+      val.into(),
+      meta.updated_at,
+      serde_json::to_string(&meta.created_at).unwrap()
+    ),
+    Some(meta) => format!(
+      "{{\"k\":{},\"v\":{},\"t\":{}}}",
+      serde_json::to_string(key).unwrap(),
+      val.into(),
+      meta.updated_at
+    ),
+    None => format!(
+      "{{\"k\":{},\"v\":{}}}",
+      serde_json::to_string(key).unwrap(),
+      val.into()
+    ),
+  }
+}
+
+/// The creation time is only needed once the line that created the entry is compressed away.
+/// An unknown one is stored as null, so it isn't taken from the last write then.
+fn stores_created_at(meta: &EntryMeta) -> bool {
+  meta.created_at != Some(meta.updated_at)
+}
+
+/// Counts the bytes written to it instead of storing them
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0 += buf.len();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+/// How many bytes the value serializes to, without allocating the serialized string
+fn json_len<T: serde::Serialize + ?Sized>(value: &T) -> usize {
+  let mut counter = ByteCounter(0);
+  serde_json::to_writer(&mut counter, value).unwrap();
+  counter.0
+}
+
+/// Whether the serialized value nests arrays and objects deeper than `max_depth`. This only scans
+/// the bytes, so it is safe for values that would overflow the stack when they are converted.
+pub(crate) fn json_exceeds_depth(json: &str, max_depth: usize) -> bool {
+  let mut depth: usize = 0;
+  let mut in_string = false;
+  let mut escaped = false;
+  for b in json.bytes() {
+    if in_string {
+      if escaped {
+        escaped = false;
+      } else if b == b'\\' {
+        escaped = true;
+      } else if b == b'"' {
+        in_string = false;
+      }
+      continue;
+    }
+    match b {
+      b'"' => in_string = true,
+      b'[' | b'{' => {
+        depth += 1;
+        if depth > max_depth {
+          return true;
+        }
+      }
+      b']' | b'}' => depth = depth.saturating_sub(1),
+      _ => {}
+    }
+  }
+  false
+}
+
+/// Whether the value nests arrays and objects deeper than `max_depth`. Doesn't recurse itself.
+pub(crate) fn value_exceeds_depth(value: &serde_json::Value, max_depth: usize) -> bool {
+  let mut stack = vec![(value, 0)];
+  while let Some((value, depth)) = stack.pop() {
+    match value {
+      serde_json::Value::Array(items) if depth < max_depth => {
+        stack.extend(items.iter().map(|item| (item, depth + 1)))
+      }
+      serde_json::Value::Object(map) if depth < max_depth => {
+        stack.extend(map.values().map(|item| (item, depth + 1)))
+      }
+      serde_json::Value::Array(_) | serde_json::Value::Object(_) => return true,
+      _ => {}
     }
   }
+  false
+}
+
+/// Checks that the serialized value is valid JSON and removes its line breaks, so it fits in a line
+pub(crate) fn normalize_value_json(value_json: &str) -> Result<String> {
+  let value = serde_json::from_str::<&RawValue>(value_json).map_err(|e| {
+    JsonlDBError::SerializeError {
+      reason: "The value is not valid JSON".to_owned(),
+      source: e,
+    }
+  })?;
+  // Outside of strings, line breaks are only whitespace. Inside, they are always escaped.
+  Ok(value.get().chars().filter(|c| !matches!(c, '\n' | '\r')).collect())
+}
+
+/// Renders the line that sets the key to the serialized value, like writing the DB does
+pub(crate) fn format_value_line(key: &str, value_json: &str) -> Result<String> {
+  Ok(format_line(key, normalize_value_json(value_json)?, None))
+}
+
+/// When an entry was written (epoch ms). Only maintained if timestamps are tracked.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EntryMeta {
+  // Best-effort, unknown for entries that were first written without timestamps
+  pub created_at: Option<u64>,
+  pub updated_at: u64,
 }
 
-pub(crate) fn format_line(key: &str, val: impl Into<String>) -> String {
+pub(crate) type EntryMetaMap = HashMap<Key, EntryMeta>;
+
+/// Serializes the tombstone of an entry that was deleted in soft-delete mode. The deletion time
+/// is stored next to the key instead of a value, so no value can be mistaken for a tombstone.
+fn format_tombstone(key: &str, deleted_at: u64) -> String {
   format!(
-    "{{\"k\":{},\"v\":{}}}",
-    serde_json::to_string(key).unwrap(),
-    val.into()
+    "{{\"k\":{},\"d\":{deleted_at}}}",
+    serde_json::to_string(key).unwrap()
   )
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(untagged)]
-pub(crate) enum Entry {
-  Value { k: String, v: serde_json::Value },
-  Delete { k: String },
+/// Escapes a string value for use in an index key, so it can contain "=", backslashes and
+/// control characters
+fn escape_index_value(value: &str) -> String {
+  let mut ret = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '\\' => ret.push_str("\\\\"),
+      '=' => ret.push_str("\\="),
+      c if c.is_control() => ret.push_str(&format!("\\u{:04x}", c as u32)),
+      c => ret.push(c),
+    }
+  }
+  ret
+}
+
+/// Returns the index key for the value at an indexed path. Only strings, numbers and booleans are
+/// indexed, other values return `None`.
+pub(crate) fn index_key(path: &str, value: &serde_json::Value) -> Option<String> {
+  match value {
+    serde_json::Value::String(str) => Some(format!("{path}={}", escape_index_value(str))),
+    // Escaped strings never start with "=", so numbers and booleans cannot be confused with them.
+    // Numbers are compared as f64 like in JS, so 1 and 1.0 are the same.
+    serde_json::Value::Number(num) => Some(format!("{path}=={}", num.as_f64()?)),
+    serde_json::Value::Bool(b) => Some(format!("{path}=={b}")),
+    _ => None,
+  }
+}
+
+/// Converts an object filter for `get_many` to an index key. Filters are either objects like
+/// `{ "path": "/type", "value": "foo" }` or legacy strings like `"/type=foo"`, whose value is
+/// always a string.
+pub(crate) fn filter_to_index_key(filter: &serde_json::Value) -> Result<Option<String>> {
+  match filter {
+    serde_json::Value::String(filter) => {
+      let ret = filter
+        .split_once('=')
+        .and_then(|(path, value)| index_key(path, &serde_json::Value::String(value.to_owned())));
+      Ok(ret)
+    }
+    serde_json::Value::Object(filter) => {
+      let path = filter.get("path").and_then(|path| path.as_str());
+      let value = filter.get("value").filter(|value| {
+        value.is_string() || value.is_number() || value.is_boolean()
+      });
+      match (path, value) {
+        (Some(path), Some(value)) => Ok(index_key(path, value)),
+        _ => Err(JsonlDBError::other(
+          "An object filter must have a string path and a string, number or boolean value",
+        )),
+      }
+    }
+    _ => Err(JsonlDBError::other("The object filter must be a string or an object")),
+  }
+}
+
+/// Entries that were deleted in soft-delete mode
+#[derive(Debug, Clone)]
+pub(crate) struct Tombstones {
+  pub retain_ms: u64,
+  // When each entry was deleted (epoch ms)
+  pub deleted: IndexMap<Key, u64>,
+}
+
+impl Tombstones {
+  pub fn new(retain_ms: u64) -> Self {
+    Self {
+      retain_ms,
+      deleted: IndexMap::new(),
+    }
+  }
+
+  /// Forgets tombstones that are older than the retention time
+  pub fn prune(&mut self) {
+    let min_time = util::now_ms().saturating_sub(self.retain_ms);
+    self.deleted.retain(|_, deleted_at| *deleted_at >= min_time);
+  }
+}
+
+/// The contents of a DB file
+pub(crate) struct ParsedEntries {
+  pub entries: IndexMap<Key, DBEntry>,
+  pub meta: Option<EntryMetaMap>,
+  pub tombstones: Option<Tombstones>,
+  pub header: Option<String>,
+  pub migration_version: Option<u32>,
+  /// How many lines the file consists of, including overwritten and deleted entries
+  pub lines: usize,
+  pub diagnostics: OpenDiagnostics,
+}
+
+impl ParsedEntries {
+  /// Adds the entries read from another shard. Each key is only stored in one shard.
+  /// They are appended, because the files don't tell in which order the keys of different shards were set.
+  pub fn merge(&mut self, other: ParsedEntries) {
+    self.entries.extend(other.entries);
+    if let (Some(meta), Some(other)) = (self.meta.as_mut(), other.meta) {
+      meta.extend(other);
+    }
+    if let (Some(tombstones), Some(other)) = (self.tombstones.as_mut(), other.tombstones) {
+      tombstones.deleted.extend(other.deleted);
+    }
+    // Both are stored in the same line
+    if other.header.is_some() || other.migration_version.is_some() {
+      self.header = other.header;
+      self.migration_version = other.migration_version;
+    }
+    self.lines += other.lines;
+    self.diagnostics.merge(&other.diagnostics);
+  }
+}
+
+/// A line that was skipped while reading the DB file with `ignore_read_errors`
+#[derive(Debug, Clone)]
+pub(crate) struct ReadError {
+  /// The number of the line in its file, starting at 1
+  pub line: u32,
+  /// Where the line starts in its file
+  pub offset: u64,
+  /// Why the line was skipped, including an excerpt of it
+  pub reason: String,
+}
+
+/// How many skipped lines are described in the diagnostics. Later ones are only counted, so a
+/// broken file doesn't take up lots of memory.
+const MAX_REPORTED_READ_ERRORS: usize = 100;
+
+/// The lines that were skipped while reading the DB file
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReadErrors {
+  /// The first `MAX_REPORTED_READ_ERRORS` of them
+  pub reported: Vec<ReadError>,
+  pub count: usize,
+}
+
+impl ReadErrors {
+  /// The reason is only rendered if the line is reported
+  fn push(&mut self, line: u32, offset: u64, reason: impl FnOnce() -> String) {
+    self.count += 1;
+    if self.reported.len() < MAX_REPORTED_READ_ERRORS {
+      self.reported.push(ReadError {
+        line,
+        offset,
+        reason: reason(),
+      });
+    }
+  }
+
+  fn merge(&mut self, other: &ReadErrors) {
+    self.count += other.count;
+    let free = MAX_REPORTED_READ_ERRORS - self.reported.len();
+    self
+      .reported
+      .extend(other.reported.iter().take(free).cloned());
+  }
+}
+
+/// What reading the DB file revealed about its size and how redundant it is
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OpenDiagnostics {
+  /// How many bytes the file occupies
+  pub file_bytes: u64,
+  /// How many bytes the file would occupy after compressing it
+  pub compacted_bytes: u64,
+  /// How many non-empty lines the file contains
+  pub total_lines: usize,
+  /// How many entries are left after reading all lines
+  pub live_entries: usize,
+  /// How many lines overwrote an existing entry
+  pub overwrites: usize,
+  /// How many lines deleted an entry, including tombstones
+  pub deletes: usize,
+  /// How many lines were skipped because they were too long
+  pub oversized_lines: usize,
+  /// Where the first line that was too long starts
+  pub oversized_line_offset: Option<u64>,
+  /// How many invalid lines still contained a valid entry after some garbage
+  pub resynced_lines: usize,
+  /// How many bytes of invalid lines were skipped
+  pub skipped_bytes: u64,
+  /// The lines that were skipped because they are invalid, too long or nested too deeply
+  pub read_errors: ReadErrors,
+}
+
+impl OpenDiagnostics {
+  /// Adds up the diagnostics of multiple shards
+  pub fn merge(&mut self, other: &OpenDiagnostics) {
+    self.file_bytes += other.file_bytes;
+    self.compacted_bytes += other.compacted_bytes;
+    self.total_lines += other.total_lines;
+    self.live_entries += other.live_entries;
+    self.overwrites += other.overwrites;
+    self.deletes += other.deletes;
+    self.oversized_lines += other.oversized_lines;
+    // The offsets are relative to each shard file, so only the first one is kept
+    self.oversized_line_offset = self.oversized_line_offset.or(other.oversized_line_offset);
+    self.resynced_lines += other.resynced_lines;
+    self.skipped_bytes += other.skipped_bytes;
+    // Like the offsets, the line numbers are relative to each shard file
+    self.read_errors.merge(&other.read_errors);
+  }
+
+  /// How many bytes compressing the file would free up
+  pub fn wasted_bytes(&self) -> u64 {
+    self.file_bytes.saturating_sub(self.compacted_bytes)
+  }
+}
+
+/// What compressing the DB file would gain, estimated from the current entries
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CompressEstimate {
+  /// How many bytes the file currently occupies
+  pub current_file_bytes: u64,
+  /// How many bytes the file would occupy after compressing it
+  pub estimated_compact_bytes: u64,
+  /// How many entries the DB currently has
+  pub live_entries: usize,
+  /// How many lines the file currently consists of
+  pub file_lines: usize,
+}
+
+impl CompressEstimate {
+  /// How many bytes compressing the file would free up
+  pub fn wasted_bytes(&self) -> u64 {
+    self
+      .current_file_bytes
+      .saturating_sub(self.estimated_compact_bytes)
+  }
+}
+
+/// How many bytes an entry occupies in a compressed DB file, including the line break. This is computed
+/// without building the line like `format_line` does
+pub(crate) fn compacted_line_len(key: &str, entry: &DBEntry, meta: Option<&EntryMeta>) -> usize {
+  let value_len = match entry {
+    DBEntry::Reference(str, _) | DBEntry::Stringified(str) => str.len(),
+    DBEntry::Native(v) => json_len(v),
+  };
+  let meta_len = match meta {
+    Some(meta) if stores_created_at(meta) => {
+      r#","t":,"c":"#.len() + json_len(&meta.updated_at) + json_len(&meta.created_at)
+    }
+    Some(meta) => r#","t":"#.len() + json_len(&meta.updated_at),
+    None => 0,
+  };
+  r#"{"k":,"v":}"#.len() + json_len(key) + value_len + meta_len + 1
+}
+
+/// Serializes the DB header and the migration version. Without either, the line removes them.
+fn header_line(header: Option<&str>, migration_version: Option<u32>) -> String {
+  let mut ret = format!("{{\"k\":{}", serde_json::to_string(HEADER_KEY).unwrap());
+  if let Some(header) = header {
+    ret.push_str(&format!(",\"v\":{header}"));
+  }
+  if let Some(version) = migration_version {
+    ret.push_str(&format!(",\"m\":{version}"));
+  }
+  ret.push('}');
+  ret
+}
+
+/// How many bytes the tombstones occupy in a compressed DB file, including the line breaks
+fn compacted_tombstones_len(tombstones: Option<&Tombstones>) -> usize {
+  tombstones.map_or(0, |tombstones| {
+    tombstones
+      .deleted
+      .iter()
+      .map(|(key, deleted_at)| r#"{"k":,"d":}"#.len() + json_len(&**key) + json_len(deleted_at) + 1)
+      .sum()
+  })
+}
+
+/// Remembers that an entry was written at the given time
+fn touch_meta(meta: &mut EntryMetaMap, key: &Key, existed: bool, time: u64) {
+  match meta.get_mut(key) {
+    Some(m) => m.updated_at = time,
+    None => {
+      meta.insert(
+        key.clone(),
+        EntryMeta {
+          // Entries without metadata were created at an unknown time
+          created_at: if existed { None } else { Some(time) },
+          updated_at: time,
+        },
+      );
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+struct RawEntry<'a> {
+  k: String,
+  // Lines without a value are deletes. An explicit null must not be mistaken for that
+  #[serde(borrow, default, deserialize_with = "deserialize_present")]
+  v: Option<&'a RawValue>,
+  // When the entry was written, if timestamps are tracked
+  #[serde(default)]
+  t: Option<u64>,
+  // When the entry was created, if it differs from `t`. Null if it is unknown
+  #[serde(default, deserialize_with = "deserialize_present")]
+  c: Option<Option<u64>>,
+  // When the entry was deleted, if this line is a soft-delete tombstone
+  #[serde(default)]
+  d: Option<u64>,
+  // Which migration was applied, only in the header line
+  #[serde(default)]
+  m: Option<u32>,
+}
+
+fn deserialize_present<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+  D: Deserializer<'de>,
+  T: Deserialize<'de>,
+{
+  T::deserialize(deserializer).map(Some)
+}
+
+// How many characters of an invalid line are included in error messages
+const MAX_EXCERPT_CHARS: usize = 200;
+// How many lines are checked to decide whether a file looks like a DB file
+const SANITY_CHECK_LINES: u32 = 100;
+
+/// Returns the start of a line for use in error messages, with control characters escaped
+fn error_excerpt(line: &str) -> String {
+  let mut ret = String::new();
+  for c in line.chars().take(MAX_EXCERPT_CHARS) {
+    if c.is_control() {
+      ret.extend(c.escape_default());
+    } else {
+      ret.push(c);
+    }
+  }
+  let excerpt_len = line
+    .char_indices()
+    .nth(MAX_EXCERPT_CHARS)
+    .map_or(line.len(), |(i, _)| i);
+  if excerpt_len < line.len() {
+    ret.push_str(&format!("... ({} more bytes)", line.len() - excerpt_len));
+  }
+  ret
+}
+
+/// Reads a line including its line break into `buf`, but keeps at most `max_len` bytes of it in
+/// memory. The rest of a longer line is skipped and `buf` is left empty.
+/// Returns how many bytes were read and whether the line was too long.
+async fn read_line_capped(
+  reader: &mut BufReader<&mut File>,
+  buf: &mut Vec<u8>,
+  max_len: usize,
+) -> Result<(usize, bool)> {
+  let mut bytes_read: usize = 0;
+  let mut too_long = false;
+  loop {
+    let available = reader.fill_buf().await?;
+    if available.is_empty() {
+      break;
+    }
+    let (len, done) = match available.iter().position(|&b| b == b'\n') {
+      Some(i) => (i + 1, true),
+      None => (available.len(), false),
+    };
+    if !too_long {
+      buf.extend_from_slice(&available[..len]);
+      let line_len = if done {
+        trim_line_break(buf).len()
+      } else {
+        buf.len()
+      };
+      if line_len > max_len {
+        too_long = true;
+        buf.clear();
+      }
+    }
+    reader.consume(len);
+    bytes_read += len;
+    if done {
+      break;
+    }
+  }
+  Ok((bytes_read, too_long))
+}
+
+fn trim_line_break(line: &[u8]) -> &[u8] {
+  let line = line.strip_suffix(b"\n").unwrap_or(line);
+  line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// How every line written by the DB starts
+const LINE_START: &str = "{\"k\":";
+
+/// Looks for valid entries in an invalid line. When two processes append to the same file, a line
+/// of one can be torn apart by a line of the other, or lines can be glued together. Then the entries
+/// that were written in between are still intact. Every place where a line could start is tried,
+/// but only entries that end where the line or another entry ends are accepted, so the values of
+/// torn entries aren't mistaken for entries. Returns the entries and how many bytes were skipped.
+fn resync_line(line: &str) -> (Vec<RawEntry<'_>>, usize) {
+  let mut entries = Vec::new();
+  let mut skipped: usize = 0;
+  // Where the part of the line starts that was not recovered yet
+  let mut pos: usize = 0;
+  for (start, _) in line.match_indices(LINE_START) {
+    // Inside an entry that was already recovered
+    if start < pos {
+      continue;
+    }
+    let mut stream = serde_json::Deserializer::from_str(&line[start..]).into_iter::<RawEntry>();
+    let entry = match stream.next() {
+      Some(Ok(entry)) => entry,
+      _ => continue,
+    };
+    let end = start + stream.byte_offset();
+    if end < line.len() && !line[end..].starts_with(LINE_START) {
+      continue;
+    }
+    skipped += start - pos;
+    pos = end;
+    entries.push(entry);
+  }
+  skipped += line.len() - pos;
+  (entries, skipped)
+}
+
+/// Fails if too many of the checked lines are invalid to assume that this is a DB file
+fn check_invalid_ratio(checked_lines: u32, invalid_lines: u32, max_ratio: f64) -> Result<()> {
+  if checked_lines > 0 && invalid_lines as f64 / checked_lines as f64 > max_ratio {
+    return Err(JsonlDBError::NotADBFile(format!(
+      "{invalid_lines} of the first {checked_lines} lines are invalid"
+    )));
+  }
+  Ok(())
+}
+
+/// What a single line of a DB file means when opening the DB
+#[derive(Debug, PartialEq)]
+pub(crate) enum ParsedLine {
+  /// Empty lines are skipped
+  Empty,
+  /// The line with the application-defined header and the migration version, which is not an entry
+  Header {
+    header: Option<String>,
+    migration_version: Option<u32>,
+  },
+  Set { key: String, value: String },
+  /// Deletes the entry. Tombstones also tell when, which only soft-delete mode keeps
+  Delete { key: String, deleted_at: Option<u64> },
+}
+
+/// Parses a single line of a DB file and classifies it like opening the DB does
+pub(crate) fn parse_line(line: &str) -> Result<ParsedLine> {
+  let line = line.strip_suffix('\n').unwrap_or(line);
+  let line = line.strip_suffix('\r').unwrap_or(line);
+  if line.is_empty() {
+    return Ok(ParsedLine::Empty);
+  }
+  let RawEntry { k, v, d, m, .. } =
+    serde_json::from_str::<RawEntry>(line).map_err(|e| JsonlDBError::SerializeError {
+      reason: format!("Invalid line: {}", error_excerpt(line)),
+      source: e,
+    })?;
+  let v = v.map(|v| v.get().to_owned());
+  Ok(match v {
+    _ if k == HEADER_KEY => ParsedLine::Header {
+      header: v,
+      migration_version: m,
+    },
+    Some(value) => ParsedLine::Set { key: k, value },
+    None => ParsedLine::Delete {
+      key: k,
+      deleted_at: d,
+    },
+  })
+}
+
+/// Keeps track of the order in which the entries of a DB file were set. Entries are deleted with
+/// `swap_remove` while reading, which is fast, but moves the last entry into the gap. Afterwards,
+/// the entries are put back into the order in which they were set, like the DB keeps them.
+#[derive(Default)]
+struct ReadOrder {
+  // The position of each entry. Only needed once an entry was deleted, the order is correct before
+  positions: Option<HashMap<Key, usize>>,
+  next_position: usize,
+}
+
+impl ReadOrder {
+  /// Must be called after inserting a new entry at the given index
+  fn inserted(&mut self, entries: &IndexMap<Key, DBEntry>, index: usize) {
+    if let Some(positions) = self.positions.as_mut() {
+      if let Some((key, _)) = entries.get_index(index) {
+        positions.insert(key.clone(), self.next_position);
+      }
+    }
+    self.next_position += 1;
+  }
+
+  fn remove(&mut self, entries: &mut IndexMap<Key, DBEntry>, key: &str) {
+    if !entries.contains_key(key) {
+      return;
+    }
+    let positions = self.positions.get_or_insert_with(|| {
+      entries
+        .keys()
+        .enumerate()
+        .map(|(position, key)| (key.clone(), position))
+        .collect()
+    });
+    positions.remove(key);
+    entries.swap_remove(key);
+  }
+
+  fn restore(self, entries: &mut IndexMap<Key, DBEntry>) {
+    if let Some(positions) = self.positions {
+      entries.sort_by(|k1, _, k2, _| positions[k1].cmp(&positions[k2]));
+    }
+  }
 }
 
-pub(crate) async fn parse_entries(
-  file: &mut File,
-  ignore_read_errors: bool,
-) -> Result<IndexMap<String, DBEntry>> {
-  let mut entries = IndexMap::<String, DBEntry>::new();
+pub(crate) async fn parse_entries(file: &mut File, opts: &DBOptions) -> Result<ParsedEntries> {
+  let mut entries = IndexMap::<Key, DBEntry>::new();
+  let mut order = ReadOrder::default();
+  let mut meta = if opts.track_timestamps {
+    Some(EntryMetaMap::new())
+  } else {
+    None
+  };
+  let mut tombstones = opts.soft_delete_retain_ms.map(Tombstones::new);
+  let mut header: Option<String> = None;
+  let mut migration_version: Option<u32> = None;
 
-  let mut lines = BufReader::new(file).lines();
+  let mut reader = BufReader::with_capacity(opts.io_buffer_size, file);
+
+  // Binary files are certainly not DB files
+  if !opts.force_open && reader.fill_buf().await?.contains(&0) {
+    return Err(JsonlDBError::NotADBFile(
+      "it contains binary data".to_owned(),
+    ));
+  }
+  // Count how many of the first lines are invalid to detect files in other formats
+  let mut checked_lines: u32 = 0;
+  let mut invalid_lines: u32 = 0;
+
+  let mut buf: Vec<u8> = Vec::new();
   let mut line_no: u32 = 0;
-  while let Some(line) = lines.next_line().await? {
-    // Count source lines for the error message
+  let mut offset: u64 = 0;
+  // Non-empty lines, which is what compressing by size compares against the number of entries
+  let mut lines: usize = 0;
+  let mut overwrites: usize = 0;
+  let mut deletes: usize = 0;
+  let mut oversized_lines: usize = 0;
+  let mut oversized_line_offset: Option<u64> = None;
+  let mut resynced_lines: usize = 0;
+  let mut skipped_bytes: u64 = 0;
+  let mut read_errors = ReadErrors::default();
+  loop {
+    buf.clear();
+    let (bytes_read, too_long) =
+      read_line_capped(&mut reader, &mut buf, opts.max_line_length).await?;
+    if bytes_read == 0 {
+      break;
+    }
+    // Count source lines and bytes for the error message
     line_no += 1;
+    let line_offset = offset;
+    offset += bytes_read as u64;
+
+    let line = std::str::from_utf8(trim_line_break(&buf)).map_err(|_| {
+      std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+      )
+    })?;
     // Skip empty lines
-    if line.len() == 0 {
+    if line.len() == 0 && !too_long {
       continue;
     }
+    lines += 1;
+
+    // Keep the serialized value around, it is only parsed when needed.
+    // Lines that are too long were not kept in memory, so they cannot be parsed.
+    let entry = (!too_long).then(|| serde_json::from_str::<RawEntry>(line));
 
-    let entry = serde_json::from_str::<Entry>(&line);
-    match entry {
-      Ok(Entry::Value { k, v }) => {
-        entries.insert(k, DBEntry::Native(v));
+    if !opts.force_open && checked_lines < SANITY_CHECK_LINES {
+      checked_lines += 1;
+      if !matches!(entry, Some(Ok(_))) {
+        invalid_lines += 1;
       }
-      Ok(Entry::Delete { k }) => {
-        entries.remove(&k);
+      if checked_lines == SANITY_CHECK_LINES {
+        check_invalid_ratio(checked_lines, invalid_lines, opts.max_invalid_line_ratio)?;
       }
-      Err(e) => {
-        if ignore_read_errors {
-          // ignore read errors
-        } else {
-          return Err(JsonlDBError::SerializeError {
-            reason: format!("Cannot open DB file: Invalid data in line {line_no}"),
-            source: e,
-          });
+    }
+
+    // Try to recover the entries of torn lines instead of discarding them completely.
+    // A line can contain several of them if lines of other writers were glued together.
+    let mut line_entries = vec![entry];
+    if opts.ignore_read_errors && matches!(line_entries[0], Some(Err(_))) {
+      let (recovered, skipped) = resync_line(line);
+      if !recovered.is_empty() {
+        resynced_lines += 1;
+        line_entries = recovered.into_iter().map(|entry| Some(Ok(entry))).collect();
+      }
+      skipped_bytes += skipped as u64;
+    }
+
+    for entry in line_entries {
+      match entry {
+        // The DB header is kept separately from the entries
+        Some(Ok(RawEntry { k, v, m, .. })) if k == HEADER_KEY => {
+          header = v.map(|v| v.get().to_owned());
+          migration_version = m;
+        }
+        // Without soft-delete mode, tombstones are regular deletes
+        Some(Ok(RawEntry {
+          k,
+          v: None,
+          d: Some(deleted_at),
+          ..
+        })) if tombstones.is_some() => {
+          let k: Key = k.into();
+          if let Some(meta) = meta.as_mut() {
+            meta.remove(&k);
+          }
+          order.remove(&mut entries, &k);
+          if let Some(tombstones) = tombstones.as_mut() {
+            tombstones.deleted.insert(k, deleted_at);
+          }
+          deletes += 1;
+        }
+        Some(Ok(RawEntry { k, v: Some(v), t, c, .. })) => {
+          // Converting such a value later would overflow the stack
+          if json_exceeds_depth(v.get(), opts.max_value_depth) {
+            if opts.ignore_read_errors {
+              skipped_bytes += line.len() as u64;
+              read_errors.push(line_no, line_offset, || {
+                format!(
+                  "The value of \"{k}\" is nested deeper than maxValueDepth ({} levels)",
+                  opts.max_value_depth
+                )
+              });
+              continue;
+            }
+            return Err(JsonlDBError::io_error_from_reason(format!(
+              "Cannot open DB file: The value in line {line_no} (byte offset {line_offset}) is nested deeper than maxValueDepth ({} levels)",
+              opts.max_value_depth
+            )));
+          }
+          let k: Key = k.into();
+          if let Some(tombstones) = tombstones.as_mut() {
+            tombstones.deleted.remove(&k);
+          }
+          if let Some(meta) = meta.as_mut() {
+            match t {
+              Some(t) => {
+                touch_meta(meta, &k, entries.contains_key(&k), t);
+                // Compressing keeps only the last line of an entry, which then tells when it was created
+                if let (Some(c), Some(m)) = (c, meta.get_mut(&k)) {
+                  m.created_at = c;
+                }
+              }
+              // Written by an older version, so the timestamps are unknown
+              None => {
+                meta.remove(&k);
+              }
+            }
+          }
+          match entries.insert_full(k, DBEntry::Stringified(v.get().to_owned())) {
+            (_, Some(_)) => overwrites += 1,
+            (index, None) => order.inserted(&entries, index),
+          }
+        }
+        Some(Ok(RawEntry { k, v: None, .. })) => {
+          let k = k.as_str();
+          if let Some(meta) = meta.as_mut() {
+            meta.remove(k);
+          }
+          if let Some(tombstones) = tombstones.as_mut() {
+            tombstones.deleted.remove(k);
+          }
+          order.remove(&mut entries, k);
+          deletes += 1;
+        }
+        Some(Err(e)) => {
+          if opts.ignore_read_errors {
+            read_errors.push(line_no, line_offset, || {
+              format!("Invalid data: {} ({e})", error_excerpt(line))
+            });
+          } else {
+            return Err(JsonlDBError::SerializeError {
+              reason: format!(
+                "Cannot open DB file: Invalid data in line {line_no} (byte offset {line_offset}): {}",
+                error_excerpt(line)
+              ),
+              source: e,
+            });
+          }
+        }
+        None => {
+          if opts.ignore_read_errors {
+            oversized_lines += 1;
+            oversized_line_offset.get_or_insert(line_offset);
+            read_errors.push(line_no, line_offset, || {
+              format!("The line is longer than {} bytes", opts.max_line_length)
+            });
+          } else {
+            return Err(JsonlDBError::io_error_from_reason(format!(
+              "Cannot open DB file: Line {line_no} (byte offset {line_offset}) is longer than {} bytes",
+              opts.max_line_length
+            )));
+          }
         }
       }
     }
   }
 
-  Ok(entries)
+  // Also check short files
+  if !opts.force_open && checked_lines < SANITY_CHECK_LINES {
+    check_invalid_ratio(checked_lines, invalid_lines, opts.max_invalid_line_ratio)?;
+  }
+  order.restore(&mut entries);
+
+  // The lines that compressing would write, including the line breaks
+  let compacted_bytes = entries
+    .iter()
+    .map(|(key, val)| compacted_line_len(key, val, meta.as_ref().and_then(|meta| meta.get(key))))
+    .sum::<usize>()
+    + compacted_tombstones_len(tombstones.as_ref())
+    + if header.is_some() || migration_version.is_some() {
+      header_line(header.as_deref(), migration_version).len() + 1
+    } else {
+      0
+    };
+  let live_entries = entries.len();
+
+  Ok(ParsedEntries {
+    entries,
+    meta,
+    tombstones,
+    header,
+    migration_version,
+    lines,
+    diagnostics: OpenDiagnostics {
+      file_bytes: offset,
+      compacted_bytes: compacted_bytes as u64,
+      total_lines: lines,
+      live_entries,
+      overwrites,
+      deletes,
+      oversized_lines,
+      oversized_line_offset,
+      resynced_lines,
+      skipped_bytes,
+      read_errors,
+    },
+  })
 }
 
 pub(crate) type Journal = Vec<JournalEntry>;
@@ -133,53 +1048,106 @@ pub(crate) type Journal = Vec<JournalEntry>;
 pub(crate) struct Index {
   paths: Vec<String>,
   // (Map: "path=value" => (object keys[]))
-  map: HashMap<String, HashSet<String>>,
+  map: HashMap<String, HashSet<Key>>,
+  // (Map: object key => "path=value"[]), so outdated index keys can be removed
+  by_key: HashMap<Key, Vec<String>>,
 }
 
 impl Index {
   pub fn new(paths: Vec<String>) -> Self {
     Self {
       map: HashMap::new(),
+      by_key: HashMap::new(),
       paths,
     }
   }
 
-  pub fn add_entries_checked(&mut self, entries: &IndexMap<String, DBEntry>) {
-    let paths = { self.paths.clone() };
+  pub fn estimated_size(&self) -> usize {
+    memory_usage::index_size(&self.map) + memory_usage::reverse_index_size(&self.by_key)
+  }
+
+  pub fn add_entries_checked(&mut self, entries: &IndexMap<Key, DBEntry>) {
+    if self.paths.is_empty() {
+      return;
+    }
+
     for (key, val) in entries {
-      for path in &paths {
-        if let DBEntry::Native(val) = val {
-          // ... create a new index entry
-          if let Some(index_val) = val.pointer(path).map_or(None, |v| v.as_str()) {
-            let index_key = format!("{}={}", path, &index_val);
-            self.add_one(&index_key, &key);
-          }
+      match val {
+        DBEntry::Native(val) => self.add_value_checked(key, val),
+        DBEntry::Stringified(str) | DBEntry::Reference(str, _) => {
+          self.add_stringified_checked(key, str)
         }
       }
     }
   }
 
-  pub fn add_value_checked(&mut self, key: &str, val: &serde_json::Value) {
-    let paths = { self.paths.clone() };
-    for path in paths {
-      if let Some(index_val) = val.pointer(&path).map_or(None, |v| v.as_str()) {
-        let index_key = format!("{}={}", &path, &index_val);
-        self.add_one(&index_key, &key);
+  /// Returns the index keys of the given value
+  pub fn index_keys(&self, val: &serde_json::Value) -> Vec<String> {
+    self
+      .paths
+      .iter()
+      .filter_map(|path| val.pointer(path).and_then(|v| index_key(path, v)))
+      .collect()
+  }
+
+  /// Indexes the given value, replacing the previous index keys of the entry
+  pub fn add_value_checked(&mut self, key: &Key, val: &serde_json::Value) {
+    let index_keys = self.index_keys(val);
+    self.add_many(key, index_keys);
+  }
+
+  /// Like `add_value_checked`, but for a serialized value
+  pub fn add_stringified_checked(&mut self, key: &Key, str: &str) {
+    // Stringified values only need to be parsed if they can contain the indexed paths
+    if !self.paths.is_empty() && (str.starts_with('{') || str.starts_with('[')) {
+      if let Ok(val) = serde_json::from_str::<serde_json::Value>(str) {
+        self.add_value_checked(key, &val);
+        return;
       }
     }
+    self.remove(key);
   }
 
-  pub fn add_one(&mut self, index_key: &str, key: &str) {
+  fn add_one(&mut self, index_key: &str, key: &Key) {
     let value_set = self
       .map
       .entry(index_key.to_owned())
       .or_insert_with(|| HashSet::new());
-    value_set.insert(key.to_owned());
+    value_set.insert(key.clone());
+    self
+      .by_key
+      .entry(key.clone())
+      .or_default()
+      .push(index_key.to_owned());
   }
 
-  pub fn add_many(&mut self, key: &str, index_keys: Vec<String>) {
+  /// Replaces the index keys of the given entry
+  pub fn add_many(&mut self, key: &Key, index_keys: Vec<String>) {
+    self.remove(key);
     for index_key in index_keys {
-      self.add_one(&index_key, &key);
+      self.add_one(&index_key, key);
+    }
+  }
+
+  /// Replaces the index keys of many entries at once. Each index key is only looked up once per
+  /// batch, which is much faster than calling `add_many` for each entry.
+  pub fn add_batch(&mut self, batch: Vec<(Key, Vec<String>)>) {
+    let mut keys_by_index_key: HashMap<String, Vec<Key>> = HashMap::new();
+    for (key, index_keys) in batch {
+      self.remove(&key);
+      if index_keys.is_empty() {
+        continue;
+      }
+      for index_key in index_keys.iter() {
+        keys_by_index_key
+          .entry(index_key.clone())
+          .or_default()
+          .push(key.clone());
+      }
+      self.by_key.insert(key, index_keys);
+    }
+    for (index_key, keys) in keys_by_index_key {
+      self.map.entry(index_key).or_default().extend(keys);
     }
   }
 
@@ -187,20 +1155,87 @@ impl Index {
   //   self.map.len()
   // }
 
+  pub fn paths(&self) -> &[String] {
+    &self.paths
+  }
+
+  pub fn is_indexed(&self, path: &str) -> bool {
+    self.paths.iter().any(|p| p == path)
+  }
+
+  /// Counts the distinct values of the given path among the current entries
+  pub fn distinct_values(&self, path: &str) -> usize {
+    let prefix = format!("{}=", path);
+    self
+      .map
+      .iter()
+      .filter(|(index_key, keys)| index_key.starts_with(&prefix) && !keys.is_empty())
+      .count()
+  }
+
+  /// Returns the index paths that don't match any entry
+  pub fn unused_paths(&self) -> Vec<&String> {
+    self
+      .paths
+      .iter()
+      .filter(|path| self.distinct_values(path) == 0)
+      .collect()
+  }
+
   pub fn clear(&mut self) {
     self.map.clear();
+    self.by_key.clear();
   }
 
   pub fn remove(&mut self, key: &str) {
-    for keys in self.map.values_mut() {
-      keys.remove(key);
+    let index_keys = match self.by_key.remove(key) {
+      Some(index_keys) => index_keys,
+      None => return,
+    };
+    for index_key in index_keys {
+      if let Some(keys) = self.map.get_mut(&index_key) {
+        keys.remove(key);
+        if keys.is_empty() {
+          self.map.remove(&index_key);
+        }
+      }
     }
   }
 
+  /// The keys of all indexed entries
+  pub fn keys(&self) -> Vec<Key> {
+    self.by_key.keys().cloned().collect()
+  }
+
+  pub fn contains_key(&self, key: &Key) -> bool {
+    self.by_key.contains_key(key)
+  }
+
+  /// Returns the keys that are listed under an index key that the entry does not know about.
+  /// Those would not be removed from the index when the entry changes.
+  pub fn reverse_mismatches(&self) -> Vec<(Key, String)> {
+    let mut ret = Vec::new();
+    for (index_key, keys) in self.map.iter() {
+      for key in keys {
+        let known = self
+          .by_key
+          .get(key)
+          .map_or(false, |index_keys| index_keys.contains(index_key));
+        if !known {
+          ret.push((
+            key.clone(),
+            format!("The index lists \"{key}\" under \"{index_key}\" only in one direction"),
+          ));
+        }
+      }
+    }
+    ret
+  }
+
   pub fn get_keys(&self, index_key: &str) -> Option<Vec<String>> {
     match self.map.get(index_key) {
       Some(keys) => {
-        let keys = keys.iter().cloned().collect();
+        let keys = keys.iter().map(|key| key.to_string()).collect();
         Some(keys)
       }
       None => None,
@@ -208,112 +1243,947 @@ impl Index {
   }
 }
 
+/// Statistics about the DB file, maintained by the persistence thread
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WriteStats {
+  /// How many lines the DB file consists of
+  pub uncompressed_size: usize,
+  /// How many lines written since the last compress overwrote a key that was already in the file
+  pub overwrites: usize,
+  /// How many delete lines were written since the last compress
+  pub tombstones: usize,
+  /// When the DB file was last compressed (epoch ms). None if it wasn't since opening the DB
+  pub last_compress_at: Option<u64>,
+  /// How many lines were written since the last compress
+  pub changes_since_compress: usize,
+  /// When compressing by interval will be possible next (epoch ms), if that is enabled
+  pub next_compress_eligible_at: Option<u64>,
+}
+
+/// How many entries there are for each registered key prefix. Kept up to date while the entries
+/// change, so reading a count doesn't need to look at all keys.
+#[derive(Default)]
+pub(crate) struct PrefixCounters {
+  counts: HashMap<String, usize>,
+}
+
+impl PrefixCounters {
+  pub fn new(prefixes: &[String], entries: &IndexMap<Key, DBEntry>) -> Self {
+    let mut ret = Self::default();
+    for prefix in prefixes {
+      ret.register(prefix, entries);
+    }
+    ret
+  }
+
+  /// Starts counting the entries with the given prefix. Returns false if it is already counted.
+  pub fn register(&mut self, prefix: &str, entries: &IndexMap<Key, DBEntry>) -> bool {
+    if self.counts.contains_key(prefix) {
+      return false;
+    }
+    let count = entries.keys().filter(|k| k.starts_with(prefix)).count();
+    self.counts.insert(prefix.to_owned(), count);
+    true
+  }
+
+  /// Stops counting the entries with the given prefix. Returns false if it wasn't counted.
+  pub fn unregister(&mut self, prefix: &str) -> bool {
+    self.counts.remove(prefix).is_some()
+  }
+
+  pub fn get(&self, prefix: &str) -> Option<usize> {
+    self.counts.get(prefix).copied()
+  }
+
+  /// Must be called when an entry is added, but not when an existing one is overwritten
+  pub fn added(&mut self, key: &str) {
+    for (prefix, count) in self.counts.iter_mut() {
+      if key.starts_with(prefix.as_str()) {
+        *count += 1;
+      }
+    }
+  }
+
+  /// Must be called when an existing entry is removed
+  pub fn removed(&mut self, key: &str) {
+    for (prefix, count) in self.counts.iter_mut() {
+      if key.starts_with(prefix.as_str()) {
+        *count = count.saturating_sub(1);
+      }
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.counts.values_mut().for_each(|count| *count = 0);
+  }
+}
+
 pub(crate) struct Storage {
-  pub entries: IndexMap<String, DBEntry>,
+  pub entries: IndexMap<Key, DBEntry>,
   pub journal: Journal,
+  pub stats: WriteStats,
+  // Updates to keys with a pending journal entry younger than this are coalesced
+  pub coalesce_window_ms: u32,
+  // When the journal grows longer than this, the persistence thread is woken up to write it
+  pub max_buffered_commands: usize,
+  pub journal_full: Arc<Notify>,
+  // Wakes up the persistence thread when it is waiting for the first change
+  pub changed: Arc<Notify>,
+  // Whether the persistence thread is waiting for the first change. Set by the thread itself.
+  pub wake_on_change: bool,
+  // When the pending journal entries for each key were created
+  pub pending_sets: HashMap<Key, Instant>,
+  // When each entry was written. None if timestamps aren't tracked
+  pub meta: Option<EntryMetaMap>,
+  // Deleted entries. None if soft-delete mode is disabled
+  pub tombstones: Option<Tombstones>,
+  // The application-defined DB header, a serialized JSON value
+  pub header: Option<String>,
+  // Which migration was applied by openWithMigration(). Stored in the same line as the header
+  pub migration_version: Option<u32>,
+  // Whether the DB file will be rewritten with all entries like when compressing,
+  // which makes the journal redundant
+  pub needs_rewrite: bool,
+  // How many dumps are being rendered. While there are any, the entries taken from the journal
+  // are kept in `dump_tap`, so the dumps can include the changes made in the meantime
+  pub open_dumps: usize,
+  pub dump_tap: Vec<JournalEntry>,
+  // Incremented when compressing starts and when it ends, so it is odd while compressing
+  pub compress_epoch: u32,
+  pub prefix_counters: PrefixCounters,
+  // Entries whose serialized value was changed by `resnapshot_pending`. The index is not shared
+  // with the storage, so their index keys are updated the next time the main thread uses it.
+  pub stale_index_keys: Vec<Key>,
+}
+
+impl Storage {
+  /// Returns the key of an existing entry, so it is not stored twice. New keys are converted.
+  pub fn intern(&self, key: String) -> Key {
+    match self.entries.get_key_value(key.as_str()) {
+      Some((existing, _)) => existing.clone(),
+      None => key.into(),
+    }
+  }
+
+  /// Removes all pending writes for the given key from the journal
+  fn dedupe_journal(&mut self, key: &str) {
+    self.journal.retain(|e| match e {
+      JournalEntry::Set(k) if &**k == key => false,
+      JournalEntry::Delete(k) if &**k == key => false,
+      _ => true,
+    });
+  }
+
+  /// Whether the file will be truncated by the next write
+  pub fn has_pending_clear(&self) -> bool {
+    // Clear is always the first journal entry, because clear() discards all previous ones
+    matches!(self.journal.first(), Some(JournalEntry::Clear))
+  }
+
+  /// Serializes the tombstone for a deleted entry, if there is one
+  pub fn tombstone_line(&self, key: &str) -> Option<String> {
+    let deleted_at = self.tombstones.as_ref()?.deleted.get(key)?;
+    Some(format_tombstone(key, *deleted_at))
+  }
+
+  /// Whether the DB file contains a header line after compressing
+  pub fn has_header_line(&self) -> bool {
+    self.header.is_some() || self.migration_version.is_some()
+  }
+
+  /// Serializes the DB header, if there is one
+  pub fn header_line(&self) -> Option<String> {
+    self
+      .has_header_line()
+      .then(|| header_line(self.header.as_deref(), self.migration_version))
+  }
+
+  /// How many lines each shard file would consist of after compressing it
+  pub fn compacted_lines_per_shard(&self, layout: &ShardLayout) -> Vec<usize> {
+    let mut ret = vec![0; layout.count()];
+    for key in self.entries.keys() {
+      ret[layout.shard_of(key)] += 1;
+    }
+    if let Some(tombstones) = self.tombstones.as_ref() {
+      for key in tombstones.deleted.keys() {
+        ret[layout.shard_of(key)] += 1;
+      }
+    }
+    if self.has_header_line() {
+      ret[layout.shard_of(HEADER_KEY)] += 1;
+    }
+    ret
+  }
+
+  /// Returns the deleted keys, optionally only those deleted at or after `since`
+  pub fn deleted_keys(&self, since: Option<u64>) -> Vec<String> {
+    match self.tombstones.as_ref() {
+      Some(tombstones) => tombstones
+        .deleted
+        .iter()
+        .filter(|(_, deleted_at)| since.map_or(true, |since| **deleted_at >= since))
+        .map(|(key, _)| key.to_string())
+        .collect(),
+      None => Vec::new(),
+    }
+  }
+
+  pub fn meta(&self, key: &str) -> Option<&EntryMeta> {
+    self.meta.as_ref().and_then(|meta| meta.get(key))
+  }
+
+  /// How many bytes up to `limit` entries starting at `offset` occupy in a compressed DB file.
+  /// Also returns how many entries were looked at.
+  pub fn compacted_entries_len(&self, offset: usize, limit: usize) -> (usize, usize) {
+    self
+      .entries
+      .iter()
+      .skip(offset)
+      .take(limit)
+      .fold((0, 0), |(size, count), (key, entry)| {
+        (size + compacted_line_len(key, entry, self.meta(key)), count + 1)
+      })
+  }
+
+  /// How many bytes the tombstones occupy in a compressed DB file
+  pub fn compacted_tombstones_len(&self) -> usize {
+    compacted_tombstones_len(self.tombstones.as_ref())
+  }
+
+  /// Records the write time of an entry if timestamps are tracked. Must be called before inserting it.
+  pub fn touch(&mut self, key: &Key) {
+    if self.meta.is_some() {
+      let existed = self.entries.contains_key(key);
+      if let Some(meta) = self.meta.as_mut() {
+        touch_meta(meta, key, existed, util::now_ms());
+      }
+    }
+  }
+
+  /// Removes the tombstone of an entry that is set again, so it isn't written after the entry
+  pub fn forget_tombstone(&mut self, key: &Key) {
+    if let Some(tombstones) = self.tombstones.as_mut() {
+      tombstones.deleted.remove(key);
+    }
+  }
+
+  /// Schedules writing an entry, unless a recent pending write for it will serialize it anyways
+  fn schedule_set(&mut self, key: Key) {
+    self.forget_tombstone(&key);
+
+    if self.coalesce_window_ms > 0 {
+      if let Some(since) = self.pending_sets.get(&key) {
+        if since.elapsed().as_millis() < self.coalesce_window_ms as u128 {
+          return;
+        }
+      }
+      self.pending_sets.insert(key.clone(), Instant::now());
+    }
+
+    // Deduplicate while inserting, removing all previous pending writes for this key
+    self.dedupe_journal(&key);
+    self.journal.push(JournalEntry::Set(key));
+    self.notify_persistence();
+  }
+
+  /// Wakes up the persistence thread if it waits for the first change, or if more writes are
+  /// pending than it may buffer, so it doesn't wait for the write delay to write them
+  pub fn notify_persistence(&mut self) {
+    if std::mem::take(&mut self.wake_on_change) {
+      self.changed.notify_one();
+    }
+    if self.journal.len() > self.max_buffered_commands {
+      self.journal_full.notify_one();
+    }
+  }
+
+  /// Replaces the pending writes with a full rewrite of the DB file, e.g. before importing so many
+  /// entries that journaling each of them would be wasteful
+  pub fn schedule_rewrite(&mut self) {
+    let journal = std::mem::take(&mut self.journal);
+    self.tap_journal(&journal);
+    self.pending_sets.clear();
+    self.needs_rewrite = true;
+    self.notify_persistence();
+  }
+
+  /// Serializes the JS objects of the entries that are about to be written again,
+  /// so changes that were made to them after `set()` are persisted too
+  pub fn resnapshot_pending(&mut self, env: Env) {
+    let Storage {
+      entries,
+      journal,
+      stale_index_keys,
+      ..
+    } = self;
+    for entry in journal.iter() {
+      if let JournalEntry::Set(key) = entry {
+        if let Some(DBEntry::Reference(str, r)) = entries.get_mut(key) {
+          // Keep the old value if the object cannot be serialized anymore
+          if let Ok(Some(fresh)) = env
+            .get_reference_value::<JsObject>(r)
+            .and_then(|obj| stringify_js_object(env, obj))
+          {
+            if *str != fresh {
+              *str = fresh;
+              stale_index_keys.push(key.clone());
+            }
+          }
+        }
+      }
+    }
+  }
+
+  /// Serializes the referenced objects with pending writes again without changing the entries, so
+  /// `check_journal` can tell whether the lines about to be written still match them
+  pub fn serialize_pending_references(&self, env: Env) -> HashMap<Key, String> {
+    let mut ret = HashMap::new();
+    for entry in self.journal.iter() {
+      if let JournalEntry::Set(key) = entry {
+        if let Some(DBEntry::Reference(_, r)) = self.entries.get(key) {
+          if let Ok(Some(fresh)) = env
+            .get_reference_value::<JsObject>(r)
+            .and_then(|obj| stringify_js_object(env, obj))
+          {
+            ret.insert(key.clone(), fresh);
+          }
+        }
+      }
+    }
+    ret
+  }
+
+  /// Discards the pending writes, because the caller persists the current state of all entries
+  pub fn discard_journal(&mut self) {
+    let journal: Vec<JournalEntry> = self.journal.drain(..).collect();
+    self.forget_pending(&journal);
+    self.tap_journal(&journal);
+  }
+
+  /// Like `discard_journal`, but only takes the pending writes of the given shard out of the
+  /// journal and returns them. The others are still needed for the other shards. A pending clear
+  /// truncates every shard, so nothing is taken before it was written.
+  pub fn take_shard_journal(&mut self, layout: &ShardLayout, shard: usize) -> Vec<JournalEntry> {
+    if self.has_pending_clear() {
+      return Vec::new();
+    }
+    let (taken, kept): (Vec<JournalEntry>, Vec<JournalEntry>) = std::mem::take(&mut self.journal)
+      .into_iter()
+      .partition(|j| layout.shard_of_entry(j) == Some(shard));
+    self.journal = kept;
+    self.forget_pending(&taken);
+    self.tap_journal(&taken);
+    taken
+  }
+
+  /// Keeps the entries taken from the journal for the dumps that are being rendered
+  fn tap_journal(&mut self, taken: &[JournalEntry]) {
+    if self.open_dumps > 0 {
+      self.dump_tap.extend_from_slice(taken);
+    }
+  }
+
+  /// Registers a dump that is about to be rendered. Returns where its part of the tap starts.
+  pub fn open_dump(&mut self) -> usize {
+    self.open_dumps += 1;
+    self.dump_tap.len()
+  }
+
+  /// Checks that the journal can be written as it is: a clear only comes first, each key has at
+  /// most one pending write and each line matches the entry in memory. Referenced objects are
+  /// compared in the form `serialize_pending_references` returned. Returns what is wrong.
+  pub fn check_journal(&self, references: &HashMap<Key, String>) -> Vec<(Option<Key>, String)> {
+    let mut ret = Vec::new();
+    let mut seen: HashSet<&Key> = HashSet::new();
+    for (position, j) in self.journal.iter().enumerate() {
+      let key = match j {
+        JournalEntry::Clear if position > 0 => {
+          ret.push((
+            None,
+            format!("The journal contains a clear at position {position} instead of the start"),
+          ));
+          continue;
+        }
+        JournalEntry::Set(key) | JournalEntry::Delete(key) => key,
+        _ => continue,
+      };
+      if !seen.insert(key) {
+        ret.push((
+          Some(key.clone()),
+          format!("The journal contains more than one write for \"{key}\""),
+        ));
+      }
+      match journal_entry_to_line(self, j) {
+        Some(line) => {
+          if let Some((key, reason)) = check_journal_line(self, j, &line, Some(references)) {
+            ret.push((Some(key), reason));
+          }
+        }
+        None => ret.push((
+          Some(key.clone()),
+          format!("The journal has a write for \"{key}\", which neither exists nor was deleted"),
+        )),
+      }
+    }
+    ret
+  }
+
+  /// Checks the counters that are kept up to date while the entries change by counting again.
+  /// Like registering a prefix counter, this looks at all keys at once. Returns what is wrong.
+  pub fn check_counters(&self) -> Vec<(Option<Key>, String)> {
+    let mut ret = Vec::new();
+    for (prefix, count) in self.prefix_counters.counts.iter() {
+      let actual = self
+        .entries
+        .keys()
+        .filter(|key| key.starts_with(prefix.as_str()))
+        .count();
+      if actual != *count {
+        ret.push((
+          None,
+          format!("The prefix counter for \"{prefix}\" is {count}, but {actual} keys match"),
+        ));
+      }
+    }
+    if let Some(meta) = self.meta.as_ref() {
+      for key in meta.keys().filter(|key| !self.entries.contains_key(*key)) {
+        ret.push((
+          Some(key.clone()),
+          format!("There are timestamps for \"{key}\", which is not an entry"),
+        ));
+      }
+    }
+    ret
+  }
+
+  /// Forgets about pending writes that were taken from the journal
+  fn forget_pending(&mut self, drained: &[JournalEntry]) {
+    if self.pending_sets.is_empty() {
+      return;
+    }
+    for entry in drained {
+      if let JournalEntry::Set(key) = entry {
+        self.pending_sets.remove(key);
+      }
+    }
+  }
+}
+
+/// Where the storage is locked from, so the time spent waiting for the lock can be attributed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LockSite {
+  // Calls from JS
+  Main = 0,
+  // The persistence thread writing the journal to the DB file
+  Persistence = 1,
+  // Rendering the dump that replaces the DB file
+  Compress = 2,
+}
+
+/// How often a site had to wait for the storage lock and for how long
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct LockWaits {
+  pub acquisitions: u64,
+  // Acquisitions where the lock was held by someone else
+  pub contended: u64,
+  pub total_wait_ns: u64,
+  pub max_wait_ns: u64,
+}
+
+#[derive(Default)]
+struct AtomicLockWaits {
+  acquisitions: AtomicU64,
+  contended: AtomicU64,
+  total_wait_ns: AtomicU64,
+  max_wait_ns: AtomicU64,
+}
+
+/// Measures the waits for the storage lock per site. Only exists with `measure_lock_contention`.
+#[derive(Default)]
+pub(crate) struct LockContention([AtomicLockWaits; 3]);
+
+impl LockContention {
+  fn record(&self, site: LockSite, wait_ns: Option<u64>) {
+    let waits = &self.0[site as usize];
+    waits.acquisitions.fetch_add(1, Ordering::Relaxed);
+    if let Some(wait_ns) = wait_ns {
+      waits.contended.fetch_add(1, Ordering::Relaxed);
+      waits.total_wait_ns.fetch_add(wait_ns, Ordering::Relaxed);
+      waits.max_wait_ns.fetch_max(wait_ns, Ordering::Relaxed);
+    }
+  }
+
+  pub fn get(&self, site: LockSite) -> LockWaits {
+    let waits = &self.0[site as usize];
+    LockWaits {
+      acquisitions: waits.acquisitions.load(Ordering::Relaxed),
+      contended: waits.contended.load(Ordering::Relaxed),
+      total_wait_ns: waits.total_wait_ns.load(Ordering::Relaxed),
+      max_wait_ns: waits.max_wait_ns.load(Ordering::Relaxed),
+    }
+  }
+
+  pub fn reset(&self) {
+    for waits in self.0.iter() {
+      waits.acquisitions.store(0, Ordering::Relaxed);
+      waits.contended.store(0, Ordering::Relaxed);
+      waits.total_wait_ns.store(0, Ordering::Relaxed);
+      waits.max_wait_ns.store(0, Ordering::Relaxed);
+    }
+  }
 }
 
 #[derive(Clone)]
-pub(crate) struct SharedStorage(Arc<Mutex<Storage>>);
+pub(crate) struct SharedStorage {
+  storage: Arc<Mutex<Storage>>,
+  dumps: Arc<AsyncMutex<()>>,
+  // None unless lock contention is measured, so locking is not slowed down otherwise
+  contention: Option<Arc<LockContention>>,
+  // Which site the waits of this handle are attributed to
+  site: LockSite,
+}
 
 impl SharedStorage {
-  pub fn new(s: Storage) -> Self {
-    Self(Arc::new(Mutex::new(s)))
+  pub fn new(s: Storage, contention: Option<Arc<LockContention>>) -> Self {
+    Self {
+      storage: Arc::new(Mutex::new(s)),
+      dumps: Arc::new(AsyncMutex::new(())),
+      contention,
+      site: LockSite::Main,
+    }
+  }
+
+  /// Returns a handle to the same storage whose waits for the lock are attributed to the given site
+  pub fn at_site(&self, site: LockSite) -> Self {
+    Self {
+      site,
+      ..self.clone()
+    }
+  }
+
+  pub fn lock(&self) -> MutexGuard<'_, Storage> {
+    let contention = match &self.contention {
+      None => return self.lock_blocking(),
+      Some(contention) => contention,
+    };
+    // Only acquisitions that have to wait are timed
+    match self.storage.try_lock() {
+      Ok(guard) => {
+        contention.record(self.site, None);
+        guard
+      }
+      Err(TryLockError::WouldBlock) => {
+        let start = Instant::now();
+        let guard = self.lock_blocking();
+        contention.record(self.site, Some(start.elapsed().as_nanos() as u64));
+        guard
+      }
+      Err(TryLockError::Poisoned(_)) => self.lock_blocking(),
+    }
   }
 
-  pub fn lock(&mut self) -> MutexGuard<'_, Storage> {
+  fn lock_blocking(&self) -> MutexGuard<'_, Storage> {
     // If we cannot lock the mutex, crashing doesn't seem like the worst option.
     self
-      .0
+      .storage
       .lock()
       .map_err(|_| JsonlDBError::other("Failed to acquire lock on storage"))
       .unwrap()
   }
 
-  pub fn len(&mut self) -> usize {
+  pub fn len(&self) -> usize {
     let storage = self.lock();
     let entries = &storage.entries;
     entries.len()
   }
 
-  pub fn journal_len(&mut self) -> usize {
+  pub fn journal_len(&self) -> usize {
     let storage = self.lock();
     storage.journal.len()
   }
 
-  pub fn insert(&mut self, key: String, value: DBEntry) -> Option<DBEntry> {
+  /// Returns the key of an existing entry, so it is not stored twice. New keys are converted.
+  pub fn intern(&self, key: String) -> Key {
+    self.lock().intern(key)
+  }
+
+  pub fn insert(&mut self, key: Key, value: DBEntry) -> Option<DBEntry> {
     let mut storage = self.lock();
+    storage.touch(&key);
     let old = storage.entries.insert(key.clone(), value);
-    // Deduplicate while inserting, removing all previous pending writes for this key
-    storage.journal.retain(|e| match e {
-      JournalEntry::Set(k) if k == &key => false,
-      JournalEntry::Delete(k) if k == &key => false,
-      _ => true,
-    });
-    storage.journal.push(JournalEntry::Set(key));
+    if old.is_none() {
+      storage.prefix_counters.added(&key);
+    }
+    storage.schedule_set(key);
     old
   }
 
-  pub fn remove(&mut self, key: String) -> Option<DBEntry> {
+  /// Replaces the serialized value of an entry that is held as a JS object and schedules writing it.
+  /// Returns the key of the entry, or `None` if it is not held as a JS object.
+  pub fn refresh_snapshot(&mut self, key: &str, stringified: String) -> Option<Key> {
+    let mut storage = self.lock();
+    let key = match storage.entries.get_key_value(key) {
+      Some((key, DBEntry::Reference(..))) => key.clone(),
+      _ => return None,
+    };
+    storage.touch(&key);
+    if let Some(DBEntry::Reference(str, _)) = storage.entries.get_mut(&key) {
+      *str = stringified;
+    }
+    storage.schedule_set(key.clone());
+    Some(key)
+  }
+
+  pub fn remove(&mut self, key: &str) -> Option<DBEntry> {
     let mut storage = self.lock();
-    let ret = storage.entries.remove(&key);
+    // Reuse the key of the removed entry for the journal. Shifting the later entries keeps the
+    // insertion order, like a JS Map does. This is linear in the number of entries, which is why
+    // deleting many entries goes through `remove_many`.
+    let (key, ret) = match storage.entries.shift_remove_entry(key) {
+      Some((key, entry)) => {
+        storage.prefix_counters.removed(&key);
+        (key, Some(entry))
+      }
+      None => (Key::from(key), None),
+    };
+    storage.pending_sets.remove(&key);
+    if let Some(meta) = storage.meta.as_mut() {
+      meta.remove(&key);
+    }
+    if let Some(tombstones) = storage.tombstones.as_mut() {
+      tombstones.deleted.remove(&key);
+    }
     // Deduplicate while inserting, removing all previous pending writes for this key
+    storage.dedupe_journal(&key);
+    // clear() replaces the journal with a single Clear entry, so if that hasn't been written yet,
+    // the file will be truncated and cannot contain this key. A tombstone would be redundant.
+    if !storage.has_pending_clear() {
+      storage.journal.push(JournalEntry::Delete(key));
+      storage.notify_persistence();
+    }
+    ret
+  }
+
+  /// Replaces an entry with a tombstone, which is persisted like a normal entry.
+  /// Returns `None` if soft-delete mode is disabled or the entry does not exist.
+  pub fn soft_remove(&mut self, key: &str) -> Option<DBEntry> {
+    let mut storage = self.lock();
+    storage.tombstones.as_ref()?;
+    let (key, ret) = storage.entries.shift_remove_entry(key)?;
+    storage.prefix_counters.removed(&key);
+    storage.pending_sets.remove(&key);
+    if let Some(meta) = storage.meta.as_mut() {
+      meta.remove(&key);
+    }
+    if let Some(tombstones) = storage.tombstones.as_mut() {
+      tombstones.deleted.insert(key.clone(), util::now_ms());
+    }
+    storage.dedupe_journal(&key);
+    storage.journal.push(JournalEntry::Set(key));
+    storage.notify_persistence();
+    Some(ret)
+  }
+
+  /// Removes many entries at once, like calling `remove` or `soft_remove` for each of them.
+  /// The entries and the journal are only scanned once instead of once per key.
+  pub fn remove_many(&mut self, keys: &HashSet<&str>, soft: bool) -> Vec<DBEntry> {
+    if keys.is_empty() {
+      return Vec::new();
+    }
+    let mut storage = self.lock();
+    let soft = soft && storage.tombstones.is_some();
+
+    // Retaining keeps the insertion order without shifting the later entries once per key
+    let mut removed: Vec<(Key, DBEntry)> = Vec::new();
+    storage.entries.retain(|key, entry| {
+      if !keys.contains(&**key) {
+        return true;
+      }
+      let entry = std::mem::replace(entry, DBEntry::Stringified(String::new()));
+      removed.push((key.clone(), entry));
+      false
+    });
+    if removed.is_empty() {
+      return Vec::new();
+    }
+
+    let removed_keys: HashSet<Key> = removed.iter().map(|(key, _)| key.clone()).collect();
     storage.journal.retain(|e| match e {
-      JournalEntry::Set(k) if k == &key => false,
-      JournalEntry::Delete(k) if k == &key => false,
+      JournalEntry::Set(k) | JournalEntry::Delete(k) => !removed_keys.contains(k),
       _ => true,
     });
-    storage.journal.push(JournalEntry::Delete(key));
+
+    let pending_clear = storage.has_pending_clear();
+    let now = util::now_ms();
+    let mut ret = Vec::with_capacity(removed.len());
+    for (key, entry) in removed {
+      storage.prefix_counters.removed(&key);
+      storage.pending_sets.remove(&key);
+      if let Some(meta) = storage.meta.as_mut() {
+        meta.remove(&key);
+      }
+      if let Some(tombstones) = storage.tombstones.as_mut() {
+        if soft {
+          tombstones.deleted.insert(key.clone(), now);
+        } else {
+          tombstones.deleted.remove(&key);
+        }
+      }
+      if soft {
+        storage.journal.push(JournalEntry::Set(key));
+      } else if !pending_clear {
+        storage.journal.push(JournalEntry::Delete(key));
+      }
+      ret.push(entry);
+    }
+    storage.notify_persistence();
     ret
   }
 
   pub fn clear(&mut self) -> Vec<DBEntry> {
     let mut storage = self.lock();
     let ret = storage.entries.drain(..).map(|(_, e)| e).collect();
+    storage.prefix_counters.clear();
     // All pending writes are obsolete, remove them from the journal
     storage.journal.clear();
+    storage.pending_sets.clear();
+    if let Some(meta) = storage.meta.as_mut() {
+      meta.clear();
+    }
+    if let Some(tombstones) = storage.tombstones.as_mut() {
+      tombstones.deleted.clear();
+    }
     storage.journal.push(JournalEntry::Clear);
+    // The DB header is not an entry, so it survives truncating the file
+    if storage.has_header_line() {
+      storage.journal.push(JournalEntry::Header);
+    }
+    storage.notify_persistence();
     ret
   }
 
-  pub fn drain_journal(&mut self) -> Vec<String> {
+  /// Replaces or removes the DB header and schedules writing it
+  pub fn set_header(&mut self, header: Option<String>) {
+    let mut storage = self.lock();
+    storage.header = header;
+    // Only the last header line matters
+    storage.journal.retain(|e| !matches!(e, JournalEntry::Header));
+    storage.journal.push(JournalEntry::Header);
+    storage.notify_persistence();
+  }
+
+  pub fn header(&self) -> Option<String> {
+    self.lock().header.clone()
+  }
+
+  pub fn drain_journal(&mut self) -> Vec<(JournalEntry, JournalLine)> {
     let mut storage = self.lock();
 
     let journal: Vec<JournalEntry> = storage.journal.splice(.., []).collect();
+    storage.forget_pending(&journal);
+    storage.tap_journal(&journal);
 
     journal
       .into_iter()
-      .filter_map(|j| journal_entry_to_string(&storage.entries, &j))
+      .filter_map(|j| journal_entry_to_line(&storage, &j).map(|line| (j, line)))
       .collect()
   }
 
-  pub fn clone_journal(&mut self) -> Vec<String> {
-    let storage = self.lock();
-    storage
-      .journal
-      .clone()
+  /// Like drain_journal, but only drains the pending writes of the given shard
+  pub fn drain_shard_journal(
+    &mut self,
+    layout: &ShardLayout,
+    shard: usize,
+  ) -> Vec<(JournalEntry, JournalLine)> {
+    let mut storage = self.lock();
+    let journal = storage.take_shard_journal(layout, shard);
+    journal
       .into_iter()
-      .filter_map(|j| journal_entry_to_string(&storage.entries, &j))
+      .filter_map(|j| journal_entry_to_line(&storage, &j).map(|line| (j, line)))
       .collect()
   }
+
+  /// Like drain_journal, but only drains up to `max_len` entries. If `mismatches` is given, each
+  /// line is checked against the entry in memory and the mismatches are added to it.
+  pub fn drain_journal_chunk(
+    &mut self,
+    max_len: usize,
+    mut mismatches: Option<&mut Vec<(Key, String)>>,
+  ) -> Vec<(JournalEntry, JournalLine)> {
+    let mut storage = self.lock();
+
+    let len = max_len.min(storage.journal.len());
+    let journal: Vec<JournalEntry> = storage.journal.drain(..len).collect();
+    storage.forget_pending(&journal);
+    storage.tap_journal(&journal);
+
+    journal
+      .into_iter()
+      .filter_map(|j| {
+        let line = journal_entry_to_line(&storage, &j)?;
+        if let Some(mismatches) = mismatches.as_deref_mut() {
+          // The referenced objects can only be serialized on the main thread
+          if let Some(mismatch) = check_journal_line(&storage, &j, &line, None) {
+            mismatches.push(mismatch);
+          }
+        }
+        Some((j, line))
+      })
+      .collect()
+  }
+
+  /// Finishes a dump that was registered with `open_dump`. Returns the changes that were made
+  /// since then, including those that were taken from the journal in the meantime.
+  pub fn close_dump(&self, tap_start: usize) -> Vec<(JournalEntry, JournalLine)> {
+    let mut storage = self.lock();
+    let mut journal = storage.dump_tap[tap_start..].to_vec();
+    journal.extend_from_slice(&storage.journal);
+    storage.open_dumps -= 1;
+    if storage.open_dumps == 0 {
+      storage.dump_tap = Vec::new();
+    }
+    journal
+      .into_iter()
+      .filter_map(|j| journal_entry_to_line(&storage, &j).map(|line| (j, line)))
+      .collect()
+  }
+
+  /// Dumps share a temporary file next to the DB file, so only one may be written at a time
+  pub async fn lock_dumps(&self) -> OwnedMutexGuard<()> {
+    self.dumps.clone().lock_owned().await
+  }
+
+  pub fn prune_tombstones(&mut self) {
+    if let Some(tombstones) = self.lock().tombstones.as_mut() {
+      tombstones.prune();
+    }
+  }
+
+  pub fn stats(&self) -> WriteStats {
+    self.lock().stats.clone()
+  }
+
+  pub fn set_stats(&mut self, stats: WriteStats) {
+    self.lock().stats = stats;
+  }
 }
 
-fn journal_entry_to_string(
-  entries: &IndexMap<String, DBEntry>,
+/// Parses a line that is about to be written and compares it with the entry in memory it was
+/// created from. Returns the key and what does not match.
+/// The line of a referenced object is rendered from the string that was serialized when it was set,
+/// so comparing with that string only checks the rendering. If `references` is given, the line is
+/// compared with a fresh serialization of the object instead, which also reveals changes to the
+/// object that were not set again.
+fn check_journal_line(
+  storage: &Storage,
   j: &JournalEntry,
-) -> Option<String> {
+  line: &JournalLine,
+  references: Option<&HashMap<Key, String>>,
+) -> Option<(Key, String)> {
+  let (key, line) = match (j, line) {
+    (JournalEntry::Set(key) | JournalEntry::Delete(key), JournalLine::Append(line)) => (key, line),
+    _ => return None,
+  };
+  let mismatch = |reason: String| Some((key.clone(), reason));
+
+  let raw: RawEntry = match serde_json::from_str(line) {
+    Ok(raw) => raw,
+    Err(e) => return mismatch(format!("The line for \"{key}\" is not valid JSON: {e}")),
+  };
+  if raw.k != **key {
+    return mismatch(format!("The line for \"{key}\" has the key \"{}\"", raw.k));
+  }
+  let written: Option<serde_json::Value> = raw.v.and_then(|v| serde_json::from_str(v.get()).ok());
+
+  let matches = match (j, storage.entries.get(key)) {
+    (JournalEntry::Delete(_), entry) => entry.is_none() && written.is_none(),
+    (_, Some(DBEntry::Native(value))) => written.as_ref() == Some(value),
+    (_, Some(DBEntry::Reference(str, _))) => {
+      let expected = references.and_then(|r| r.get(key)).unwrap_or(str);
+      written.is_some() && written == serde_json::from_str(expected).ok()
+    }
+    (_, Some(DBEntry::Stringified(str))) => {
+      written.is_some() && written == serde_json::from_str(str).ok()
+    }
+    // Deleted entries are only written as tombstones
+    (_, None) => match storage.tombstones.as_ref().and_then(|t| t.deleted.get(key)) {
+      Some(deleted_at) => written.is_none() && raw.d == Some(*deleted_at),
+      None => false,
+    },
+  };
+  if matches {
+    None
+  } else {
+    mismatch(format!("The line for \"{key}\" does not match the entry in memory: {line}"))
+  }
+}
+
+fn journal_entry_to_line(storage: &Storage, j: &JournalEntry) -> Option<JournalLine> {
   match j {
-    JournalEntry::Set(key) => match entries.get(key) {
-      Some(DBEntry::Native(v)) => Some(json!({ "k": key, "v": v }).to_string()),
-      Some(DBEntry::Reference(str, _)) => Some(format!(
-        "{{\"k\":{},\"v\":{}}}",
-        serde_json::to_string(key).unwrap(),
-        str
-      )),
+    JournalEntry::Set(key) => {
       // Skip entries that no longer exist
-      None => None,
-    },
-    JournalEntry::Delete(key) => Some(json!({ "k": key }).to_string()),
-    JournalEntry::Clear => Some("".to_string()),
+      let line = match storage.entries.get(key) {
+        Some(entry) => Some(format_line(key, entry, storage.meta(key))),
+        None => storage.tombstone_line(key),
+      };
+      line.map(JournalLine::Append)
+    }
+    JournalEntry::Delete(key) => Some(JournalLine::Append(json!({ "k": &**key }).to_string())),
+    JournalEntry::Clear => Some(JournalLine::Truncate),
+    JournalEntry::Header => Some(JournalLine::Append(header_line(
+      storage.header.as_deref(),
+      storage.migration_version,
+    ))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn compacted_line_len_matches_the_formatted_line() {
+    let entries = [
+      DBEntry::Stringified(r#"{"a":"\u00e4"}"#.to_owned()),
+      DBEntry::Native(json!({ "a": [1, 2.5, null], "b": "\"ä\n" })),
+    ];
+    let metas = [
+      None,
+      Some(EntryMeta {
+        created_at: Some(1700000000000),
+        updated_at: 1700000000000,
+      }),
+      Some(EntryMeta {
+        created_at: Some(1),
+        updated_at: 1700000000000,
+      }),
+      Some(EntryMeta {
+        created_at: None,
+        updated_at: 12,
+      }),
+    ];
+    for key in ["key", "\"quoted\"\tä😀"] {
+      for entry in &entries {
+        for meta in &metas {
+          assert_eq!(
+            compacted_line_len(key, entry, meta.as_ref()),
+            format_line(key, entry, meta.as_ref()).len() + 1
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn compacted_tombstones_len_matches_the_formatted_lines() {
+    let mut tombstones = Tombstones::new(0);
+    tombstones.deleted.insert(Key::from("a\"ä"), 1700000000000);
+    tombstones.deleted.insert(Key::from("b"), 0);
+    let expected: usize = tombstones
+      .deleted
+      .iter()
+      .map(|(key, deleted_at)| format_tombstone(key, *deleted_at).len() + 1)
+      .sum();
+    assert_eq!(compacted_tombstones_len(Some(&tombstones)), expected);
   }
 }
@@ -1,7 +1,9 @@
 use crate::error::{JsonlDBError, Result};
-use std::io::SeekFrom;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::io::{Read, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use tokio::fs::File;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 pub(crate) async fn file_needs_lf(file: &mut File) -> Result<bool> {
@@ -14,6 +16,39 @@ pub(crate) async fn file_needs_lf(file: &mut File) -> Result<bool> {
   }
 }
 
+/// Reads the last line of a file without its line break. Returns an empty line for empty files.
+pub(crate) async fn read_last_line(file: &mut File) -> Result<Vec<u8>> {
+  const CHUNK_SIZE: u64 = 4096;
+
+  let mut start = file.metadata().await?.len();
+  if start > 0 {
+    file.seek(SeekFrom::End(-1)).await?;
+    if file.read_u8().await? == b'\n' {
+      start -= 1;
+    }
+  }
+
+  // Read backwards until the previous line break
+  let mut line: Vec<u8> = Vec::new();
+  while start > 0 {
+    let chunk_len = start.min(CHUNK_SIZE);
+    start -= chunk_len;
+    let mut chunk = vec![0; chunk_len as usize];
+    file.seek(SeekFrom::Start(start)).await?;
+    file.read_exact(&mut chunk).await?;
+    let line_break = chunk.iter().rposition(|&b| b == b'\n');
+    if let Some(pos) = line_break {
+      chunk.drain(..=pos);
+    }
+    chunk.extend_from_slice(&line);
+    line = chunk;
+    if line_break.is_some() {
+      break;
+    }
+  }
+  Ok(line)
+}
+
 pub(crate) async fn fsync_dir(
   #[cfg_attr(target_os = "windows", allow(unused_variables))] dir: &Path,
 ) -> Result<()> {
@@ -25,8 +60,171 @@ pub(crate) async fn fsync_dir(
   Ok(())
 }
 
+/// Creates options for opening a file, which gets the given permissions if it is created (unix only)
+pub(crate) fn open_options(
+  #[cfg_attr(not(unix), allow(unused_variables))] file_mode: Option<u32>,
+) -> OpenOptions {
+  let mut ret = OpenOptions::new();
+  #[cfg(unix)]
+  if let Some(mode) = file_mode {
+    ret.mode(mode);
+  }
+  ret
+}
+
+/// Changes the permissions of an opened file (unix only)
+pub(crate) async fn set_file_mode(
+  #[cfg_attr(not(unix), allow(unused_variables))] file: &File,
+  #[cfg_attr(not(unix), allow(unused_variables))] file_mode: Option<u32>,
+) -> Result<()> {
+  #[cfg(unix)]
+  if let Some(mode) = file_mode {
+    use std::os::unix::fs::PermissionsExt;
+    file
+      .set_permissions(std::fs::Permissions::from_mode(mode))
+      .await?;
+  }
+  Ok(())
+}
+
+/// Recursively creates a directory. New directories get the given file mode, plus the execute bit
+/// wherever it allows reading, so they can be entered (unix only)
+pub(crate) fn create_dir_all(path: impl AsRef<Path>, file_mode: Option<u32>) -> Result<()> {
+  dir_builder(file_mode).recursive(true).create(path)?;
+  Ok(())
+}
+
+/// Like `create_dir_all`, but fails if the directory already exists or its parent is missing
+pub(crate) fn create_dir(path: impl AsRef<Path>, file_mode: Option<u32>) -> Result<()> {
+  dir_builder(file_mode).create(path)?;
+  Ok(())
+}
+
+fn dir_builder(
+  #[cfg_attr(not(unix), allow(unused_variables))] file_mode: Option<u32>,
+) -> std::fs::DirBuilder {
+  #[cfg_attr(not(unix), allow(unused_mut))]
+  let mut builder = std::fs::DirBuilder::new();
+  #[cfg(unix)]
+  if let Some(mode) = file_mode {
+    use std::os::unix::fs::DirBuilderExt;
+    builder.mode(mode | ((mode & 0o444) >> 2));
+  }
+  builder
+}
+
+/// Creates the missing parent directories of the given file. Returns the directories that got new
+/// entries, which have to be synced to disk once the file is written, so it survives a crash.
+pub(crate) fn create_parent_dirs(
+  filename: impl AsRef<Path>,
+  file_mode: Option<u32>,
+) -> Result<Vec<PathBuf>> {
+  let dir = parent_dir(&filename)?;
+  let mut new_dirs = Vec::new();
+  let mut ancestor = dir.as_path();
+  while !ancestor.as_os_str().is_empty() && !ancestor.exists() {
+    new_dirs.push(ancestor.to_owned());
+    match ancestor.parent() {
+      Some(parent) => ancestor = parent,
+      None => break,
+    }
+  }
+  if new_dirs.is_empty() {
+    return Ok(new_dirs);
+  }
+
+  create_dir_all(&dir, file_mode).map_err(|e| match e {
+    JsonlDBError::IoError(e) => std::io::Error::new(
+      e.kind(),
+      format!(
+        "Cannot create the directory \"{}\" for \"{}\": {}",
+        dir.display(),
+        filename.as_ref().display(),
+        e
+      ),
+    )
+    .into(),
+    e => e,
+  })?;
+  // The topmost new directory is an entry of an existing one
+  new_dirs.push(if ancestor.as_os_str().is_empty() {
+    Path::new(".").to_owned()
+  } else {
+    ancestor.to_owned()
+  });
+  Ok(new_dirs)
+}
+
+/// Where a file that is moved across devices is copied to before it replaces the target
+pub(crate) fn move_temp_filename(to: impl AsRef<Path>) -> PathBuf {
+  let mut filename = to.as_ref().as_os_str().to_owned();
+  filename.push(".tmp");
+  PathBuf::from(filename)
+}
+
+/// Renames a file. If the target is on a different device, the file is copied next to the target
+/// and synced to disk first. Renaming the copy replaces the target atomically, so a crash never
+/// leaves a partial target behind. The original is removed afterwards.
+pub(crate) async fn move_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+  let (from, to) = (from.as_ref(), to.as_ref());
+  match tokio::fs::rename(from, to).await {
+    Err(e) if is_cross_device_error(&e) => copy_across_devices(from, to).await,
+    ret => Ok(ret?),
+  }
+}
+
+/// The fallback of [move_file] when the source and the target are on different devices
+async fn copy_across_devices(from: &Path, to: &Path) -> Result<()> {
+  let temp = move_temp_filename(to);
+  let copied = async {
+    tokio::fs::copy(from, &temp).await?;
+    let file = OpenOptions::new().write(true).open(&temp).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temp, to).await
+  }
+  .await;
+  if let Err(e) = copied {
+    tokio::fs::remove_file(&temp).await.ok();
+    return Err(e.into());
+  }
+  tokio::fs::remove_file(from).await?;
+  Ok(())
+}
+
+fn is_cross_device_error(e: &std::io::Error) -> bool {
+  // EXDEV on unix, ERROR_NOT_SAME_DEVICE on Windows
+  #[cfg(not(windows))]
+  const CROSS_DEVICE: i32 = 18;
+  #[cfg(windows)]
+  const CROSS_DEVICE: i32 = 17;
+  e.raw_os_error() == Some(CROSS_DEVICE)
+}
+
+/// Whether an error means that a file or the device it is on is gone, e.g. because its directory
+/// was deleted or the drive was removed
+pub(crate) fn is_storage_gone_error(e: &std::io::Error) -> bool {
+  // EIO, ENXIO and ENODEV on unix, ERROR_NOT_READY and ERROR_DEV_NOT_EXIST on Windows
+  #[cfg(not(windows))]
+  const STORAGE_GONE: [i32; 3] = [5, 6, 19];
+  #[cfg(windows)]
+  const STORAGE_GONE: [i32; 2] = [21, 55];
+  e.kind() == std::io::ErrorKind::NotFound
+    || e
+      .raw_os_error()
+      .map_or(false, |code| STORAGE_GONE.contains(&code))
+}
+
+/// The current time in milliseconds since the UNIX epoch
+pub(crate) fn now_ms() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_millis() as u64)
+}
+
 pub(crate) fn parent_dir(p: impl AsRef<Path>) -> Result<PathBuf> {
   match p.as_ref().parent() {
+    // The root is its own parent, e.g. when walking up from a file directly in it
+    None if p.as_ref().has_root() => Ok(p.as_ref().to_owned()),
     None => Err(JsonlDBError::io_error_from_reason(format!(
       "\"{}\" does not have a parent directory",
       &p.as_ref().to_str().unwrap_or("unknown dir")
@@ -41,6 +239,42 @@ pub(crate) fn parent_dir(p: impl AsRef<Path>) -> Result<PathBuf> {
   }
 }
 
+/// Checks that the given DB filename names a file and removes trailing separators, which would make
+/// it look like a directory
+pub(crate) fn normalize_db_filename(filename: &str) -> Result<String> {
+  if filename.is_empty() {
+    return Err(JsonlDBError::InvalidFilename("The filename must not be empty".to_owned()));
+  }
+  let normalized = filename.trim_end_matches(std::path::is_separator);
+  if normalized.is_empty() || Path::new(normalized).file_name().is_none() {
+    return Err(JsonlDBError::InvalidFilename(format!("\"{}\" does not name a file", filename)));
+  }
+  ensure_not_directory(normalized)?;
+  Ok(normalized.to_owned())
+}
+
+/// Fails if the given DB file is a directory, which cannot be opened as a file
+pub(crate) fn ensure_not_directory(filename: &str) -> Result<()> {
+  if Path::new(filename).is_dir() {
+    return Err(JsonlDBError::InvalidFilename(format!("\"{}\" is a directory", filename)));
+  }
+  Ok(())
+}
+
+/// Returns the absolute path of the given file, with symlinks in its directory resolved if the
+/// directory exists.
+pub(crate) fn resolve_path(filename: &str) -> Result<PathBuf> {
+  let path = Path::new(filename);
+  let file_name = path.file_name().ok_or_else(|| {
+    JsonlDBError::io_error_from_reason(format!("\"{}\" is not a file name", filename))
+  })?;
+  match std::fs::canonicalize(parent_dir(path)?) {
+    Ok(dir) => Ok(dir.join(file_name)),
+    // The directory does not exist yet, so there is nothing to resolve
+    Err(_) => Ok(std::env::current_dir()?.join(path)),
+  }
+}
+
 pub(crate) fn replace_dirname(
   path: impl AsRef<Path>,
   dirname: impl AsRef<Path>,
@@ -50,3 +284,85 @@ pub(crate) fn replace_dirname(
   let ret: PathBuf = [basename, dirname.as_ref(), filename].iter().collect();
   Some(ret)
 }
+
+pub(crate) fn is_gzip_filename(filename: &str) -> bool {
+  filename.ends_with(".gz")
+}
+
+pub(crate) fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+  let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(data)?;
+  Ok(encoder.finish()?)
+}
+
+/// Decompresses the given data if it starts with the gzip magic bytes, otherwise returns it unchanged
+pub(crate) fn gunzip_if_needed(data: Vec<u8>) -> Result<Vec<u8>> {
+  if !data.starts_with(&[0x1f, 0x8b]) {
+    return Ok(data);
+  }
+  let mut ret = Vec::new();
+  GzDecoder::new(data.as_slice()).read_to_end(&mut ret)?;
+  Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rsonl-db-{name}-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .unwrap()
+      .block_on(f)
+  }
+
+  #[test]
+  fn recognizes_cross_device_errors() {
+    #[cfg(not(windows))]
+    let cross_device = std::io::Error::from_raw_os_error(18);
+    #[cfg(windows)]
+    let cross_device = std::io::Error::from_raw_os_error(17);
+    assert!(is_cross_device_error(&cross_device));
+    assert!(!is_cross_device_error(&std::io::Error::from(
+      std::io::ErrorKind::NotFound
+    )));
+  }
+
+  #[test]
+  fn copying_across_devices_replaces_the_target_and_removes_the_source() {
+    let dir = test_dir("cross-device-move");
+    let from = dir.join("db.jsonl.export");
+    let to = dir.join("dump.jsonl");
+    std::fs::write(&from, "new\n").unwrap();
+    std::fs::write(&to, "old\n").unwrap();
+
+    block_on(copy_across_devices(&from, &to)).unwrap();
+
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "new\n");
+    assert!(!from.exists());
+    assert!(!move_temp_filename(&to).exists());
+    std::fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn copying_across_devices_leaves_the_target_alone_when_it_fails() {
+    let dir = test_dir("cross-device-move-failed");
+    let from = dir.join("missing.jsonl");
+    let to = dir.join("dump.jsonl");
+    std::fs::write(&to, "old\n").unwrap();
+
+    assert!(block_on(copy_across_devices(&from, &to)).is_err());
+
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "old\n");
+    assert!(!move_temp_filename(&to).exists());
+    std::fs::remove_dir_all(&dir).ok();
+  }
+}
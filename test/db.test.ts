@@ -1,9 +1,24 @@
 import { wait } from "alcalzone-shared/async";
+import { spawnSync } from "child_process";
 import * as fs from "fs-extra";
+import os from "os";
 import path from "path";
-import { JsonlDB } from "../index";
+import zlib from "zlib";
+import {
+	getLiveTaskCount,
+	JsonlDB,
+	JsonlDBDumpResult,
+	JsonlDBOptions,
+	JsonlDBStringifiedEntry,
+} from "../index";
+import { JsonlDB as NativeJsonlDB } from "../lib";
 import { TestFS } from "./helper/testFs";
 
+// The hooks to simulate failures only exist in builds with the `test-hooks` feature
+// (yarn build:test)
+const hasTestHooks = "__simulateCrashAt" in NativeJsonlDB.prototype;
+const itWithTestHooks = hasTestHooks ? it : it.skip;
+
 // let mockAppendFileThrottle = 0;
 // let mockMoveFileThrottle = 0;
 
@@ -97,6 +112,17 @@ describe("lib/db", () => {
 				).toThrowError("intervalMs");
 			});
 
+			it("maxOverwriteRatio outside of (0, 1)", () => {
+				expect(
+					() =>
+						new JsonlDB("foo", {
+							autoCompress: {
+								maxOverwriteRatio: 1,
+							},
+						}),
+				).toThrowError("maxOverwriteRatio");
+			});
+
 			it("intervalMinChanges < 10", () => {
 				expect(
 					() =>
@@ -107,6 +133,17 @@ describe("lib/db", () => {
 						}),
 				).toThrowError("intervalMinChanges");
 			});
+
+			it("intervalAnchor is not supported", () => {
+				expect(
+					() =>
+						new JsonlDB("foo", {
+							autoCompress: {
+								intervalAnchor: "lastRead" as any,
+							},
+						}),
+				).toThrowError("intervalAnchor");
+			});
 		});
 
 		describe("validates throttleFS options", () => {
@@ -132,6 +169,78 @@ describe("lib/db", () => {
 						}),
 				).toThrowError("maxBufferedCommands");
 			});
+
+			it("fsyncIdleMs < 0 or fsyncMaxIntervalMs < 0", () => {
+				expect(
+					() =>
+						new JsonlDB("foo", {
+							throttleFS: {
+								intervalMs: 0,
+								fsyncIdleMs: -1,
+							},
+						}),
+				).toThrowError("fsyncIdleMs");
+				expect(
+					() =>
+						new JsonlDB("foo", {
+							throttleFS: {
+								intervalMs: 0,
+								fsyncMaxIntervalMs: -1,
+							},
+						}),
+				).toThrowError("fsyncMaxIntervalMs");
+			});
+		});
+
+		it("validates the ioBufferSizeBytes option", () => {
+			expect(
+				() => new JsonlDB("foo", { ioBufferSizeBytes: 1024 }),
+			).toThrowError("ioBufferSizeBytes");
+		});
+
+		it("validates the maxInvalidLineRatio option", () => {
+			expect(
+				() => new JsonlDB("foo", { maxInvalidLineRatio: 1.5 }),
+			).toThrowError("maxInvalidLineRatio");
+		});
+
+		it("validates the fileMode option", () => {
+			expect(
+				() => new JsonlDB("foo", { fileMode: 0o1000 }),
+			).toThrowError("fileMode");
+			expect(() => new JsonlDB("foo", { fileMode: 1.5 })).toThrowError(
+				"fileMode",
+			);
+		});
+
+		it("validates the indexPaths option", () => {
+			expect(
+				() => new JsonlDB("foo", { indexPaths: ["", "/a/b~0c~1d"] }),
+			).not.toThrow();
+			let error: Error | undefined;
+			try {
+				new JsonlDB("foo", {
+					indexPaths: ["type", "/ok", "/a~2", "/ok"],
+				});
+			} catch (e: any) {
+				error = e;
+			}
+			expect(error?.message).toMatch("indexPaths");
+			expect(error?.message).toMatch('"type" is not a valid JSON pointer');
+			expect(error?.message).toMatch('"/a~2" is not a valid JSON pointer');
+			expect(error?.message).toMatch('"/ok" is contained more than once');
+		});
+
+		it("rejects filenames that do not name a file", () => {
+			expect(() => new JsonlDB("")).toThrowError(
+				"The filename must not be empty",
+			);
+			expect(() => new JsonlDB(".")).toThrowError(
+				'"." does not name a file',
+			);
+			expect(() => new JsonlDB("foo/..")).toThrowError(
+				'"foo/.." does not name a file',
+			);
 		});
 	});
 
@@ -151,6 +260,11 @@ describe("lib/db", () => {
 				broken: `{"k":"key1","v":1}\n{"k":,"v":1}\n`,
 				broken2: `{"k":"key1","v":1}\n{"k":"key2","v":}\n`,
 				broken3: `{"k":"key1"\n`,
+				garbage: "this is\nnot a\njsonl file\n",
+				binary: "SQLite format 3\0\0\0",
+				broken4: `{"k":"key1","v":1}\n{"k":"key2","v":"${"x".repeat(
+					10000,
+				)}\n`,
 				reviver: `
 {"k":"key1","v":1}
 {"k":"key2","v":"2"}
@@ -175,6 +289,74 @@ describe("lib/db", () => {
 			await db.close();
 		});
 
+		it("rejects paths that are directories", async () => {
+			const dirname = path.join(testFSRoot, "dir");
+			await fs.ensureDir(dirname);
+			expect(() => new JsonlDB(dirname)).toThrowError(
+				`"${dirname}" is a directory`,
+			);
+
+			// The directory may also be created after constructing the DB
+			const db = new JsonlDB(path.join(testFSRoot, "later"));
+			await fs.ensureDir(path.join(testFSRoot, "later"));
+			await expect(db.open()).rejects.toThrow("is a directory");
+			expect(db.isOpen).toBeFalse();
+			await expect(
+				fs.pathExists(path.join(testFSRoot, "later.lock")),
+			).resolves.toBeFalse();
+		});
+
+		it("releases the lockfile when opening fails, so the DB can be reopened right away", async () => {
+			const expectNoLockfile = (filename: string) =>
+				expect(
+					fs.pathExists(path.join(testFSRoot, `${filename}.lock`)),
+				).resolves.toBeFalse();
+
+			// Reading the file fails
+			let db = new JsonlDB(path.join(testFSRoot, "broken"));
+			await expect(db.open()).rejects.toThrow();
+			expect(db.isOpen).toBeFalse();
+			await expectNoLockfile("broken");
+			db = new JsonlDB(path.join(testFSRoot, "broken"), {
+				ignoreReadErrors: true,
+			});
+			await db.open();
+			await db.close();
+
+			// Starting the persistence thread fails, because the missing final newline cannot be written
+			const runtimes = hasTestHooks ? [false, true] : [];
+			for (const dedicatedRuntime of runtimes) {
+				db = new JsonlDB(path.join(testFSRoot, "yes"), {
+					dedicatedRuntime,
+				});
+				(db as any).db.__simulateCrashAt(1);
+				await expect(db.open()).rejects.toThrow("Simulated a crash");
+				expect(db.isOpen).toBeFalse();
+				await expectNoLockfile("yes");
+			}
+			db = new JsonlDB(path.join(testFSRoot, "yes"));
+			await db.open();
+			expect([...db.entries()]).toEqual([["key2", "2"]]);
+			await db.close();
+			await expect(
+				fs.readFile(path.join(testFSRoot, "yes"), "utf8"),
+			).resolves.toEndWith("\n");
+		});
+
+		it("ignores trailing path separators", async () => {
+			const filename = path.relative(
+				process.cwd(),
+				path.join(testFSRoot, "trailing"),
+			);
+			const db = new JsonlDB(filename + path.sep);
+			await db.open();
+			db.set("key", 1);
+			await db.close();
+			await expect(
+				fs.readFile(path.join(testFSRoot, "trailing"), "utf8"),
+			).resolves.toBe('{"k":"key","v":1}\n');
+		});
+
 		it("also creates leading directories if they don't exist", async () => {
 			const db = new JsonlDB(
 				path.join(testFSRoot, "this/path/does/not/exist"),
@@ -197,6 +379,81 @@ describe("lib/db", () => {
 			await expect(fs.pathExists(lockfileDirectory)).resolves.toBeTrue();
 		});
 
+		it("isLocked() checks the lockfile without acquiring it", async () => {
+			const filename = path.join(testFSRoot, "islocked");
+			const lockfileDirectory = path.join(testFSRoot, "locks");
+			expect(JsonlDB.isLocked(filename)).toMatchObject({
+				locked: false,
+				stale: false,
+			});
+
+			const db = new JsonlDB(filename, { lockfileDirectory });
+			await db.open();
+			const status = JsonlDB.isLocked(filename, { lockfileDirectory });
+			expect(status).toMatchObject({ locked: true, stale: false });
+			expect(status.mtime).toBeNumber();
+			// Without the lockfileDirectory, the lockfile is looked for next to the DB file
+			expect(JsonlDB.isLocked(filename).locked).toBeFalse();
+			await db.close();
+
+			expect(
+				JsonlDB.isLocked(filename, { lockfileDirectory }).locked,
+			).toBeFalse();
+
+			// A lockfile that was not updated for a long time is stale
+			const lockfile = `${filename}.lock`;
+			await fs.mkdir(lockfile);
+			const longAgo = new Date(Date.now() - 60000);
+			await fs.utimes(lockfile, longAgo, longAgo);
+			expect(JsonlDB.isLocked(filename)).toMatchObject({
+				locked: false,
+				stale: true,
+			});
+			// Checking does not take over the lockfile
+			expect((await fs.stat(lockfile)).mtimeMs).toBeLessThan(
+				Date.now() - 30000,
+			);
+		});
+
+		it("treats lockfiles with a slightly newer mtime as in use", async () => {
+			const filename = path.join(testFSRoot, "skewed");
+			const lockfile = `${filename}.lock`;
+			await fs.mkdir(lockfile);
+			// e.g. after NTP set the clock back
+			const future = new Date(Date.now() + 2000);
+			await fs.utimes(lockfile, future, future);
+
+			const db = new JsonlDB(filename);
+			await expect(db.open()).rejects.toThrow("Lockfile is in use");
+		});
+
+		it("reports the timestamps if the lockfile mtime is far in the future", async () => {
+			const filename = path.join(testFSRoot, "future");
+			const lockfile = `${filename}.lock`;
+			await fs.mkdir(lockfile);
+			const future = new Date(Date.now() + 3600_000);
+			await fs.utimes(lockfile, future, future);
+
+			const db = new JsonlDB(filename);
+			await expect(db.open()).rejects.toThrow(
+				/modification time \(\d+ ms\) is in the future \(now: \d+ ms\)/,
+			);
+		});
+
+		it("works with a dedicated runtime", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "yes"), {
+				dedicatedRuntime: { workerThreads: 2 },
+			});
+			await db.open();
+			expect(db.get("key2")).toBe("2");
+			db.set("key3", 3);
+			await db.close();
+
+			await expect(
+				fs.readFile(path.join(testFSRoot, "yes"), "utf8"),
+			).resolves.toEndWith('{"k":"key3","v":3}\n');
+		});
+
 		it("reads the file if it exists", async () => {
 			const db = new JsonlDB(path.join(testFSRoot, "yes"));
 			await db.open();
@@ -212,7 +469,9 @@ describe("lib/db", () => {
 				await db2.open();
 				throw new Error("it did not throw");
 			} catch (e: any) {
-				expect(e.message).toMatch(/lockfile is in use/i);
+				expect(e.message).toMatch(
+					/already opened by another instance in this process/i,
+				);
 			}
 
 			await db1.close();
@@ -221,6 +480,18 @@ describe("lib/db", () => {
 			await db2.close();
 		});
 
+		it("throws if another DB in this process has opened the same file with a different lockfile directory", async () => {
+			const db1 = new JsonlDB(path.join(testFSRoot, "yes"));
+			await db1.open();
+
+			const db2 = new JsonlDB(path.join(testFSRoot, "yes"), {
+				lockfileDirectory: path.join(testFSRoot, "locks"),
+			});
+			await expect(db2.open()).rejects.toThrow(/already opened/);
+
+			await db1.close();
+		});
+
 		it("should contain the correct data", async () => {
 			const db = new JsonlDB(path.join(testFSRoot, "yes"));
 			await db.open();
@@ -258,6 +529,41 @@ describe("lib/db", () => {
 			} catch (e: any) {
 				expect(e.message).toMatch(/invalid data/i);
 				expect(e.message).toMatch("line 2");
+				expect(e.message).toMatch("byte offset 19");
+				expect(e.message).toMatch('{"k":,"v":1}');
+			}
+		});
+
+		it("refuses to open files that don't look like DB files, even if `ignoreReadErrors` is true", async () => {
+			for (const file of ["garbage", "binary"]) {
+				const db = new JsonlDB(path.join(testFSRoot, file), {
+					ignoreReadErrors: true,
+				});
+				await expect(db.open()).rejects.toThrow(
+					/does not look like a jsonl-db file/,
+				);
+			}
+		});
+
+		it("opens files that don't look like DB files if `forceOpen` is true", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "garbage"), {
+				ignoreReadErrors: true,
+				forceOpen: true,
+			});
+			await expect(db.open()).toResolve();
+			expect(db.size).toBe(0);
+			await db.close();
+		});
+
+		it("truncates long lines in the error message", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "broken4"));
+			try {
+				await db.open();
+				throw new Error("it did not throw");
+			} catch (e: any) {
+				expect(e.message).toMatch("line 2");
+				expect(e.message).toMatch("more bytes");
+				expect(e.message.length).toBeLessThan(500);
 			}
 		});
 
@@ -299,6 +605,195 @@ describe("lib/db", () => {
 			await db.close();
 		});
 
+		it("recovers the entries of lines that were torn apart by another writer", async () => {
+			// Two processes appended to the same file.
+			// Each torn line contains a complete line of the other.
+			await testFS.create({
+				torn:
+					'{"k":"a1","v":1}\n' +
+					'{"k":"a2","v":{"x{"k":"b1","v":{"k":"nested"}}\n' +
+					':1}}\n' +
+					'{"k":"a3","v":3}\n' +
+					'{"k":"b2","v":[1,{"k":"a4","v":4}\n' +
+					"2]}\n" +
+					'{"k":"a5","v":5}\n',
+			});
+			const db = new JsonlDB(path.join(testFSRoot, "torn"), {
+				ignoreReadErrors: true,
+			});
+			await db.open();
+			const keys = [...db.keys()].sort();
+			expect(keys).toEqual(["a1", "a3", "a4", "a5", "b1"]);
+			expect(db.get("b1")).toEqual({ k: "nested" });
+			expect(db.get("a4")).toBe(4);
+			expect(db.getOpenDiagnostics()).toMatchObject({
+				resyncedLines: 2,
+				skippedBytes:
+					'{"k":"a2","v":{"x'.length +
+					":1}}".length +
+					'{"k":"b2","v":[1,'.length +
+					"2]}".length,
+			});
+			await db.close();
+		});
+
+		it("recovers entries that were glued together, but not entries in torn values", async () => {
+			const torn = '{"k":"c","v":{"k":"fake","v":0},"y';
+			await testFS.create({
+				glued:
+					'{"k":"x","v":0}\n' +
+					'{"k":"a","v":1}{"k":"b","v":2}\n' +
+					torn +
+					"\n",
+			});
+			const db = new JsonlDB(path.join(testFSRoot, "glued"), {
+				ignoreReadErrors: true,
+			});
+			await db.open();
+			expect([...db.keys()]).toEqual(["x", "a", "b"]);
+			expect(db.get("b")).toBe(2);
+			expect(db.getOpenDiagnostics()).toMatchObject({
+				resyncedLines: 1,
+				skippedBytes: torn.length,
+			});
+			await db.close();
+		});
+
+		it("reports every line it skipped with ignoreReadErrors", async () => {
+			const lines = [
+				'{"k":"a","v":1}',
+				"garbage",
+				'{"k":"b","v":2}',
+				'{"k":"c","v":',
+				'{"k":"d","v":4}',
+				'{"k":"e","v":5}',
+				'{"k":"f","v":6}',
+				'{"k":"g","v":7}',
+				'{"k":"h","v":8}',
+				'{"k":"i","v":9}',
+			];
+			await testFS.create({ skipped: lines.join("\n") + "\n" });
+			const offsetOf = (i: number) =>
+				lines.slice(0, i).join("\n").length + (i > 0 ? 1 : 0);
+
+			let db = new JsonlDB(path.join(testFSRoot, "skipped"));
+			// Without ignoreReadErrors, the first one fails opening
+			await expect(db.open()).rejects.toThrow(
+				"Invalid data in line 2 (byte offset 16): garbage",
+			);
+
+			db = new JsonlDB(path.join(testFSRoot, "skipped"), {
+				ignoreReadErrors: true,
+			});
+			await db.open();
+			expect(db.size).toBe(8);
+			const { readErrorCount, readErrors } = db.getOpenDiagnostics();
+			expect(readErrorCount).toBe(2);
+			expect(readErrors).toEqual([
+				{
+					line: 2,
+					offset: offsetOf(1),
+					reason: expect.stringMatching(/^Invalid data: garbage \(/),
+				},
+				{
+					line: 4,
+					offset: offsetOf(3),
+					reason: expect.stringMatching(/^Invalid data: \{"k":"c","v": \(/),
+				},
+			]);
+			await db.close();
+		});
+
+		it("reads the file correctly with a custom buffer size", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "emptyLines"), {
+				ioBufferSizeBytes: 4096,
+			});
+			await db.open();
+			expect(db.size).toBe(2);
+			expect(db.get("key1")).toBe(1);
+			expect(db.get("key2")).toBe("2");
+			await db.close();
+		});
+
+		it("skips lines longer than maxLineLengthBytes without reading them", async () => {
+			const filename = path.join(testFSRoot, "longLine");
+			const firstLine = '{"k":"key1","v":1}\n';
+			await fs.writeFile(
+				filename,
+				firstLine +
+					`{"k":"key2","v":"${"x".repeat(3 * 1024 * 1024)}"}\n` +
+					'{"k":"key3","v":3}\n',
+			);
+
+			let db = new JsonlDB(filename, { maxLineLengthBytes: 1000 });
+			await expect(db.open()).rejects.toThrow("longer than 1000 bytes");
+
+			db = new JsonlDB(filename, {
+				maxLineLengthBytes: 1000,
+				ignoreReadErrors: true,
+			});
+			await db.open();
+			expect(db.size).toBe(2);
+			expect(db.get("key1")).toBe(1);
+			expect(db.has("key2")).toBeFalse();
+			expect(db.get("key3")).toBe(3);
+			expect(db.getOpenDiagnostics()).toMatchObject({
+				oversizedLines: 1,
+				oversizedLineOffset: firstLine.length,
+				readErrorCount: 1,
+				readErrors: [
+					{
+						line: 2,
+						offset: firstLine.length,
+						reason: "The line is longer than 1000 bytes",
+					},
+				],
+			});
+			await db.close();
+		});
+
+		it("exposes the resolved paths of the DB file and the lockfile", async () => {
+			const realRoot = await fs.realpath(testFSRoot);
+			const db = new JsonlDB(path.join(testFSRoot, "yes"), {
+				lockfileDirectory: path.join(testFSRoot, "locks"),
+			});
+			// The path is also available while the DB is closed
+			expect(db.path).toBe(path.join(realRoot, "yes"));
+
+			await db.open();
+			expect(db.path).toBe(path.join(realRoot, "yes"));
+			expect(db.lockfilePath).toBe(
+				path.join(realRoot, "locks", "yes.lock"),
+			);
+			await db.close();
+		});
+
+		it("many DBs sharing a new lockfile directory can be opened at once", async () => {
+			const lockfileDirectory = path.join(testFSRoot, "shared/locks");
+			const dbs = Array.from(
+				{ length: 30 },
+				(_, i) =>
+					new JsonlDB(path.join(testFSRoot, `db${i}.jsonl`), {
+						lockfileDirectory,
+					}),
+			);
+			await Promise.all(dbs.map((db) => db.open()));
+			for (const db of dbs) {
+				expect(db.isOpen).toBeTrue();
+			}
+			await Promise.all(dbs.map((db) => db.close()));
+			await expect(fs.readdir(lockfileDirectory)).resolves.toEqual([]);
+		});
+
+		it("exposes the size of the DB file", async () => {
+			const filename = path.join(testFSRoot, "yes");
+			const db = new JsonlDB(filename);
+			await db.open();
+			expect(db.fileSizeBytes).toBe((await fs.stat(filename)).size);
+			await db.close();
+			expect(() => db.fileSizeBytes).toThrow(/not open/i);
+		});
+
 		// it("transforms each value using the valueReviver function if any is passed", async () => {
 		// 	const reviver = jest.fn().mockReturnValue("eeee");
 		// 	const db = new JsonlDB(path.join(testFSRoot, "reviver"), {
@@ -316,172 +811,405 @@ describe("lib/db", () => {
 		// });
 	});
 
-	describe("clear()", () => {
-		const testFilename = "clear.jsonl";
-		let testFilenameFull: string;
-		let db: JsonlDB;
+	describe("calling methods in the wrong state", () => {
 		let testFS: TestFS;
 		let testFSRoot: string;
+		let db: JsonlDB;
+		// The native DB, so the wrapper does not get in the way
+		let native: any;
 
 		beforeEach(async () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create({
-				[testFilename]:
-					'{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n{"k":"key1"}\n',
-			});
-			db = new JsonlDB(testFilenameFull);
-			await db.open();
+			db = new JsonlDB(path.join(testFSRoot, "state.jsonl"));
+			native = (db as any).db;
 		});
 		afterEach(async () => {
+			if (db.getState() === "half-closed") native.close();
+			await db.close();
 			await testFS.remove();
 		});
 
-		it("throws when the DB is not open", async () => {
-			await db.close();
-			expect(() => db.clear()).toThrowError("not open");
+		/** Returns the error a call throws or rejects with */
+		async function errorOf(call: () => unknown): Promise<Error> {
+			try {
+				await call();
+			} catch (e) {
+				return e as Error;
+			}
+			throw new Error("The call did not fail");
+		}
+
+		const file = "wrong-state.json";
+		const noop = () => {};
+		// The methods that require an open DB, with valid arguments for each
+		const requiresOpen: [string, unknown[]][] = [
+			["halfClose", []],
+			["dump", [file]],
+			["compress", []],
+			["createConsistentSnapshotFile", [file]],
+			["retryPersistence", []],
+			["onFlush", [noop]],
+			["offFlush", []],
+			["setPrimitive", ["key", 1]],
+			["setPrimitiveDurable", ["key", 1]],
+			["setObject", ["key", {}, "{}"]],
+			["setObjectDurable", ["key", {}, "{}"]],
+			["setNative", ["key", 1]],
+			["setMaybe", ["key", 1]],
+			["setObjectDetached", ["key", "{}"]],
+			["delete", ["key"]],
+			["deleteMatching", ["key*", "glob"]],
+			["has", ["key"]],
+			["get", ["key"]],
+			["getLargeStringified", ["key", 1]],
+			["adoptParsed", ["key", {}, "{}"]],
+			["getBatch", [["key"]]],
+			["getBatchEntries", [["key"]]],
+			["getBatchStringified", [["key"]]],
+			["createEntryCursor", []],
+			["readEntryCursor", [1, 1]],
+			["closeEntryCursor", [1]],
+			["getMany", ["a", "z"]],
+			["getManyStringified", ["a", "z"]],
+			["getManyExplain", ["a", "z"]],
+			["clearDurable", []],
+			["clear", []],
+			["getMeta", ["key"]],
+			["setHeader", [null]],
+			["getHeader", []],
+			["getDeletedKeys", []],
+			["getIndexPaths", []],
+			["isIndexed", ["/a"]],
+			["getIndexSize", ["/a"]],
+			["registerPrefixCounter", ["a"]],
+			["unregisterPrefixCounter", ["a"]],
+			["getPrefixCount", ["a"]],
+			["refreshSnapshot", ["key", "{}"]],
+			["dematerialize", ["key"]],
+			["materialize", [undefined, 0, 1]],
+			["dematerializeAll", []],
+			["getMetrics", []],
+			["resetMetrics", []],
+			["getStats", []],
+			["forEach", [noop]],
+			["getKeys", []],
+			["getKeysPage", [0, 1]],
+			["getKeysStringifiedPage", [0, 1]],
+			["getKeysSorted", []],
+			["getKeysStringified", []],
+			["getOpenDiagnostics", []],
+			["getMemoryUsage", []],
+			["estimateCompress", []],
+			["prepareSelfCheck", []],
+			["selfCheck", []],
+			["exportJson", [file, false]],
+			["exportCsv", [file]],
+			["loadCsvImport", [file]],
+			["loadJsonImport", [file]],
+			["importLoaded", [1]],
+			["importJsonString", ["{}"]],
+		];
+		if (hasTestHooks) {
+			requiresOpen.push(["__abortPersistence", []]);
+		}
+		const gettersRequiringOpen = [
+			"size",
+			"keyCount",
+			"fileSizeBytes",
+			"lockfilePath",
+			"isLockLost",
+		];
+
+		it.each(requiresOpen)(
+			"%s() explains that the DB must be opened first",
+			async (method, args) => {
+				const error = await errorOf(() => native[method](...args));
+				expect(error.message).toBe(
+					`${method}() requires the DB to be open, but the DB is not open (current state: closed). Call open() first`,
+				);
+			},
+		);
+
+		it.each(gettersRequiringOpen)(
+			"%s explains that the DB must be opened first",
+			async (getter) => {
+				const error = await errorOf(() => native[getter]);
+				expect(error.message).toMatch(
+					`${getter} requires the DB to be open`,
+				);
+				expect(error.message).toMatch("Call open() first");
+			},
+		);
+
+		it.each([
+			"open",
+			"reshard",
+			...(hasTestHooks ? ["__simulateCrashAt"] : []),
+		])(
+			"%s() explains that the DB must be closed first",
+			async (method) => {
+				await db.open();
+				const error = await errorOf(() => native[method](1));
+				expect(error.message).toBe(
+					`${method}() requires the DB to be closed, but the DB is not closed (current state: open). Call halfClose() and then close() first`,
+				);
+			},
+		);
+
+		it("close() explains how to get to the half-closed state", async () => {
+			let error = await errorOf(() => native.close());
+			expect(error.message).toMatch(
+				"(current state: closed). Call open() and then halfClose() first",
+			);
+
+			await db.open();
+			error = await errorOf(() => native.close());
+			expect(error.message).toMatch(
+				"(current state: open). Call halfClose() first",
+			);
 		});
 
-		it("removes all entries from the database and truncates the file", async () => {
-			db.clear();
-			expect(db.size).toBe(0);
-			expect(db.has("key1")).toBeFalse();
-			expect(db.has("key2")).toBeFalse();
+		it("reports the current state while half-closed", async () => {
+			await db.open();
+			await native.halfClose();
+			expect(db.getState()).toBe("half-closed");
 
-			// Force the stream to be flushed
-			await db.close();
+			let error = await errorOf(() => native.get("key"));
+			expect(error.message).toMatch(
+				"(current state: half-closed). Call close() and then open() first",
+			);
+			error = await errorOf(() => native.open());
+			expect(error.message).toMatch(
+				"(current state: half-closed). Call close() first",
+			);
 
-			await expect(fs.stat(testFilenameFull)).resolves.toMatchObject({
-				size: 0,
-			});
+			native.close();
+			expect(db.getState()).toBe("closed");
 		});
 	});
 
-	describe("delete()", () => {
-		const testFilename = "delete.jsonl";
-		let testFilenameFull: string;
-		let db: JsonlDB;
+	describe("openWithMigration()", () => {
 		let testFS: TestFS;
 		let testFSRoot: string;
-
+		let testFilenameFull: string;
 		beforeEach(async () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
+			testFilenameFull = path.join(testFSRoot, "migrate.jsonl");
 			await testFS.create({
-				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+				"migrate.jsonl":
+					'{"k":"a","v":{"name":"A"}}\n{"k":"b","v":{"name":"B"}}\n{"k":"c","v":1}\n',
 			});
-			db = new JsonlDB(testFilenameFull);
-			await db.open();
 		});
 		afterEach(async () => {
 			await testFS.remove();
 		});
 
-		it("throws when the DB is not open", async () => {
-			await db.close();
-			expect(() => db.delete("key1")).toThrowError("not open");
-		});
+		const renameField = (key: string, value: string): string | null => {
+			if (key === "b") return null;
+			const parsed = JSON.parse(value);
+			if (typeof parsed !== "object") return value;
+			return JSON.stringify({ label: parsed.name });
+		};
 
-		it("removes the given key from the database and writes a line with an undefined value", async () => {
-			expect(db.delete("key2")).toBeTrue();
-			expect(db.size).toBe(1);
-			expect(db.has("key1")).toBeTrue();
-			expect(db.has("key2")).toBeFalse();
+		it("migrates each entry once and persists the result", async () => {
+			const migrate = jest.fn(renameField);
+			const db = new JsonlDB(testFilenameFull);
+			await expect(
+				db.openWithMigration(migrate, { version: 1 }),
+			).resolves.toBeTrue();
+			expect(migrate).toBeCalledTimes(3);
+			expect(migrate).toBeCalledWith("a", '{"name":"A"}');
+			expect(db.get("a")).toEqual({ label: "A" });
+			expect(db.has("b")).toBeFalse();
+			expect(db.get("c")).toBe(1);
+			await db.close();
 
-			// Force the stream to be flushed
+			// The same version is not applied again
+			migrate.mockClear();
+			await expect(
+				db.openWithMigration(migrate, { version: 1 }),
+			).resolves.toBeFalse();
+			expect(migrate).not.toBeCalled();
+			expect(db.get("a")).toEqual({ label: "A" });
+			// The version is not an entry
+			expect([...db.keys()]).toEqual(["a", "c"]);
+			expect(db.size).toBe(2);
 			await db.close();
 
+			// But a newer one is
 			await expect(
-				fs.readFile(testFilenameFull, "utf8"),
-			).resolves.toEndWith(`{"k":"key2"}\n`);
+				db.openWithMigration(migrate, { version: 2 }),
+			).resolves.toBeTrue();
+			expect(migrate).toBeCalledTimes(2);
+			await db.compress();
+			await db.close();
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"$jsonl-db-meta","m":2}\n{"k":"a","v":{}}\n{"k":"c","v":1}\n',
+			);
 		});
 
-		it("removes multiple key from the database in the correct order", async () => {
-			expect(db.delete("key2")).toBeTrue();
-			expect(db.delete("key1")).toBeTrue();
-			expect(db.size).toBe(0);
-			expect(db.has("key1")).toBeFalse();
-			expect(db.has("key2")).toBeFalse();
+		it("stores the version next to the DB header", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			db.setHeader('{"schema":1}');
+			await db.close();
 
-			// Force the stream to be flushed
+			await db.openWithMigration(renameField, { version: 1 });
+			expect(db.getHeader()).toBe('{"schema":1}');
+			db.setHeader(null);
 			await db.close();
 
+			// Removing the header keeps the version
 			await expect(
-				fs.readFile(testFilenameFull, "utf8"),
-			).resolves.toEndWith(`{"k":"key2"}\n{"k":"key1"}\n`);
+				db.openWithMigration(renameField, { version: 1 }),
+			).resolves.toBeFalse();
+			expect(db.getHeader()).toBeNull();
+			await db.close();
 		});
 
-		it("deleting a key twice does not write to the log twice", async () => {
-			expect(db.delete("key2")).toBeTrue();
-			expect(db.delete("key2")).toBeFalse();
+		it("does not accept writes while migrating", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			const opening = db.openWithMigration(renameField, { version: 1 });
+			expect(() => db.set("a", 2)).toThrow();
+			await opening;
+			expect(db.get("a")).toEqual({ label: "A" });
+			await db.close();
+		});
 
-			// Force the stream to be flushed
+		it("applies queued writes after migrating", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				queueWritesBeforeOpen: true,
+			});
+			const opening = db.openWithMigration(renameField, { version: 1 });
+			db.set("a", 2);
+			await opening;
+			expect(db.get("a")).toBe(2);
 			await db.close();
 
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				`{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n{"k":"key2"}\n`,
+			await db.open();
+			expect(db.get("a")).toBe(2);
+			await db.close();
+		});
+
+		it("does not change anything if the migration fails", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			let calls = 0;
+			await expect(
+				db.openWithMigration(
+					(key, value) => {
+						if (++calls === 2) throw new Error("oops");
+						return renameField(key, value);
+					},
+					{ version: 1 },
+				),
+			).rejects.toThrow("oops");
+			expect(db.isOpen).toBeFalse();
+
+			await db.open();
+			expect(db.get("a")).toEqual({ name: "A" });
+			expect(db.has("b")).toBeTrue();
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.not.toMatch(
+				"$jsonl-db-meta",
 			);
 		});
+
+		it("rejects migrated values that are not JSON", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await expect(
+				db.openWithMigration(() => "{", { version: 1 }),
+			).rejects.toThrow("not valid JSON");
+			expect(db.isOpen).toBeFalse();
+		});
 	});
 
-	describe("set()", () => {
-		const testFilename = "set.jsonl";
-		let testFilenameFull: string;
-		let db: JsonlDB;
+	describe("setHeader() / getHeader()", () => {
 		let testFS: TestFS;
 		let testFSRoot: string;
-
+		let testFilenameFull: string;
 		beforeEach(async () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create();
-			db = new JsonlDB(testFilenameFull);
-			await db.open();
+			testFilenameFull = path.join(testFSRoot, "header.jsonl");
+			await testFS.create({
+				"header.jsonl":
+					'{"k":"$jsonl-db-meta","v":{"schema":1}}\n{"k":"a","v":1}\n',
+			});
 		});
-
 		afterEach(async () => {
 			await testFS.remove();
 		});
 
-		it("throws when the DB is not open", async () => {
+		it("loads the header separately from the entries", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			expect(db.getHeader()).toBe('{"schema":1}');
+			expect(db.size).toBe(1);
+			expect([...db.keys()]).toEqual(["a"]);
+			expect(db.has("$jsonl-db-meta")).toBeFalse();
 			await db.close();
-			expect(() => db.set("foo", 1)).toThrowError("not open");
 		});
 
-		it("adds the given key to the database and writes a line with the serialized value", async () => {
-			db.set("key", true);
-			expect(db.size).toBe(1);
-			expect(db.has("key")).toBeTrue();
+		it("persists changes and survives compress() and clear()", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			db.setHeader('{"schema":2}');
+			await db.compress();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"$jsonl-db-meta","v":{"schema":2}}\n{"k":"a","v":1}\n',
+			);
 
-			// Force the stream to be flushed
+			db.clear();
 			await db.close();
-
 			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				`{"k":"key","v":true}\n`,
+				'{"k":"$jsonl-db-meta","v":{"schema":2}}\n',
 			);
+
+			await db.open();
+			expect(db.getHeader()).toBe('{"schema":2}');
+			expect(db.size).toBe(0);
+			db.setHeader(null);
+			await db.close();
+
+			await db.open();
+			expect(db.getHeader()).toBeNull();
+			await db.close();
 		});
 
-		it("adds multiple keys to the database in the correct order", async () => {
-			db.set("key2", true);
-			db.set("key1", 1000);
-			db.set("key3", "");
+		it("rejects invalid JSON and the reserved key", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			expect(() => db.setHeader("{")).toThrow("not valid JSON");
+			expect(() => db.set("$jsonl-db-meta", 1)).toThrow("reserved");
+			await db.close();
+		});
 
-			// Force the stream to be flushed
+		it("imports refuse the reserved key, so it cannot replace the header on reopen", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			expect(() =>
+				db.importJson({ b: 2, "$jsonl-db-meta": { schema: 2 } }),
+			).toThrow("reserved");
+			expect(db.has("b")).toBeFalse();
+
+			const csvFilename = path.join(testFSRoot, "import.csv");
+			await fs.writeFile(csvFilename, 'key,value\n$jsonl-db-meta,"2"\n');
+			await expect(db.importCsv(csvFilename)).rejects.toThrow("reserved");
 			await db.close();
 
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				`{"k":"key2","v":true}\n{"k":"key1","v":1000}\n{"k":"key3","v":""}\n`,
-			);
+			await db.open();
+			expect(db.getHeader()).toBe('{"schema":1}');
+			expect([...db.keys()]).toEqual(["a"]);
+			await db.close();
 		});
 	});
 
-	describe("importJson()", () => {
-		const testFilename = "import.jsonl";
+	describe("clear()", () => {
+		const testFilename = "clear.jsonl";
 		let testFilenameFull: string;
-		let jsonFilenameFull: string;
 		let db: JsonlDB;
 		let testFS: TestFS;
 		let testFSRoot: string;
@@ -490,10 +1218,9 @@ describe("lib/db", () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
 			testFilenameFull = path.join(testFSRoot, testFilename);
-			jsonFilenameFull = path.join(testFSRoot, "jsonFile");
 			await testFS.create({
-				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
-				jsonFile: '{"key3": 1, "key4": true}',
+				[testFilename]:
+					'{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n{"k":"key1"}\n',
 			});
 			db = new JsonlDB(testFilenameFull);
 			await db.open();
@@ -502,106 +1229,142 @@ describe("lib/db", () => {
 			await testFS.remove();
 		});
 
-		it("both versions throw when the DB is not open", async () => {
+		it("throws when the DB is not open", async () => {
 			await db.close();
-			expect(() => db.importJson({})).toThrowError("not open");
-			await expect(db.importJson("")).toReject();
+			expect(() => db.clear()).toThrowError("not open");
 		});
 
-		it("the object version adds all keys and values to the database", async () => {
-			db.importJson({
-				foo: "bar",
-				baz: "inga",
-				"1": 1,
-			});
+		it("removes all entries from the database and truncates the file", async () => {
+			db.clear();
+			expect(db.size).toBe(0);
+			expect(db.has("key1")).toBeFalse();
+			expect(db.has("key2")).toBeFalse();
+
 			// Force the stream to be flushed
 			await db.close();
 
-			// The order changes because Object.entries reads the entries in a different order
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				`{"k":"key1","v":1}
-{"k":"key2","v":"2"}
-{"k":"1","v":1}
-{"k":"baz","v":"inga"}
-{"k":"foo","v":"bar"}
-`,
-			);
+			await expect(fs.stat(testFilenameFull)).resolves.toMatchObject({
+				size: 0,
+			});
 		});
 
-		it("the file version asynchronously adds all keys and values to the database", async () => {
-			await db.importJson(jsonFilenameFull);
-			// Force the stream to be flushed
+		it("does not write tombstones for keys deleted after clearing", async () => {
+			db.clear();
+			db.delete("x");
+			db.set("key3", 3);
+			db.delete("key3");
 			await db.close();
 
-			// The order changes because Object.entries reads the entries in a different order
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe("");
+		});
+
+		it("handles the empty key like any other key around clear()", async () => {
+			db.set("", 1);
+			await db.close();
+			await db.open();
+			db.delete("");
+			db.clear();
+			db.set("", 2);
+			await db.close();
 			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				`{"k":"key1","v":1}
-{"k":"key2","v":"2"}
-{"k":"key3","v":1}
-{"k":"key4","v":true}
-`,
+				'{"k":"","v":2}\n',
 			);
-		});
-	});
 
-	describe("exportJson()", () => {
-		const testFilename = "export.jsonl";
-		let testFilenameFull: string;
-		let jsonFilenameFull: string;
-		let db: JsonlDB;
-		let testFS: TestFS;
-		let testFSRoot: string;
+			await db.open();
+			expect(db.get("")).toBe(2);
+			db.clear();
+			db.set("", 3);
+			db.delete("");
+			db.set("key", 4);
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key","v":4}\n',
+			);
 
-		beforeEach(async () => {
-			testFS = new TestFS();
-			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
-			jsonFilenameFull = path.join(testFSRoot, "jsonFile");
-			await testFS.create({
-				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
-				jsonFile: '{"key3": 1, "key4": true}',
-			});
-			db = new JsonlDB(testFilenameFull);
 			await db.open();
-		});
-		afterEach(async () => {
-			if (db) await db.close();
-			await testFS.remove();
+			db.set("", 5);
+			db.delete("");
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key","v":4}\n{"k":""}\n',
+			);
+			await db.open();
+			expect(db.has("")).toBeFalse();
+			expect(db.get("key")).toBe(4);
+			await db.close();
 		});
 
-		it("throws when the DB is not open", async () => {
-			await db.close();
-			try {
-				await db.exportJson(jsonFilenameFull);
-				throw new Error("it did not throw");
-			} catch (e: any) {
-				expect(e.message).toMatch(/is not open/i);
-				true;
+		it("releases the objects of cleared entries in chunks without blocking the main thread", async () => {
+			const count = 100000;
+			for (let i = 0; i < count; i++) {
+				db.set(`obj${i}`, { i });
+			}
+
+			let start = Date.now();
+			db.clear();
+			const clearMs = Date.now() - start;
+			expect(db.size).toBe(0);
+			expect(db.getMetrics().pendingUnrefs).toBe(count);
+
+			// Other macrotasks get to run while the objects are released
+			let ticks = 0;
+			let longestTickMs = 0;
+			start = Date.now();
+			while (db.getMetrics().pendingUnrefs > 0 && ticks < 100000) {
+				await new Promise((resolve) => setImmediate(resolve));
+				longestTickMs = Math.max(longestTickMs, Date.now() - start);
+				start = Date.now();
+				ticks++;
 			}
+			expect(db.getMetrics().pendingUnrefs).toBe(0);
+			expect(ticks).toBeGreaterThan(1);
+			// Before, clear() blocked until all objects were released, which took several 100 ms
+			expect(Math.max(clearMs, longestTickMs)).toBeLessThan(500);
+
+			await db.close();
 		});
 
-		it("overwrites the given file with the DB contents as valid JSON", async () => {
-			await db.exportJson(jsonFilenameFull);
-			await expect(fs.readFile(jsonFilenameFull, "utf8")).resolves.toBe(
-				`{"key1":1,"key2":"2"}`,
-			);
+		it("releases the remaining objects of cleared entries when closing", async () => {
+			for (let i = 0; i < 10000; i++) {
+				db.set(`obj${i}`, { i });
+			}
+			db.clear();
+			expect(db.getMetrics().pendingUnrefs).toBe(10000);
+			await db.close();
+
+			// The DB can be opened again without leaking anything
+			await db.open();
+			expect(db.getMetrics().pendingUnrefs).toBe(0);
+			await db.close();
 		});
 
-		it("honors the JSON formatting options", async () => {
-			await db.exportJson(jsonFilenameFull, true);
-			await expect(fs.readFile(jsonFilenameFull, "utf8")).resolves.toBe(
-				`{
-  "key1": 1,
-  "key2": "2"
-}`,
-			);
+		it("with durable: true, the file is truncated when the promise resolves", async () => {
+			await db.close();
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: { intervalMs: 60000 },
+			});
+			await db.open();
+			await db.clear({ durable: true });
+			expect(db.size).toBe(0);
+
+			// Simulate a crash by opening a copy of the file while the DB is still open
+			await expect(fs.stat(testFilenameFull)).resolves.toMatchObject({
+				size: 0,
+			});
+			const copyFilename = path.join(testFSRoot, "clear-copy.jsonl");
+			await fs.copy(testFilenameFull, copyFilename);
+			const copy = new JsonlDB(copyFilename);
+			await copy.open();
+			expect(copy.size).toBe(0);
+			await copy.close();
+
+			await db.close();
 		});
 	});
 
-	describe("close()", () => {
-		const testFilename = "close.jsonl";
+	describe("delete()", () => {
+		const testFilename = "delete.jsonl";
 		let testFilenameFull: string;
-		// The basic functionality is tested in the other suites
 		let db: JsonlDB;
 		let testFS: TestFS;
 		let testFSRoot: string;
@@ -610,7 +1373,9 @@ describe("lib/db", () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
 			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create();
+			await testFS.create({
+				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+			});
 			db = new JsonlDB(testFilenameFull);
 			await db.open();
 		});
@@ -618,109 +1383,55 @@ describe("lib/db", () => {
 			await testFS.remove();
 		});
 
-		it("may be called twice", async () => {
-			await db.close();
-			await db.close();
-		});
-
-		it("sets the isOpen property to false", async () => {
+		it("throws when the DB is not open", async () => {
 			await db.close();
-			expect(db.isOpen).toBeFalse();
+			expect(() => db.delete("key1")).toThrowError("not open");
 		});
-	});
 
-	// describe("dump()", () => {
-	// 	const testFilename = "dump.jsonl";
-	// 	let testFilenameFull: string;
-	// 	let db: JsonlDB;
-	// 	let dumpdb: JsonlDB;
-	// 	let testFS: TestFS;
-	// 	let testFSRoot: string;
+		it("removes the given key from the database and writes a line with an undefined value", async () => {
+			expect(db.delete("key2")).toBeTrue();
+			expect(db.size).toBe(1);
+			expect(db.has("key1")).toBeTrue();
+			expect(db.has("key2")).toBeFalse();
 
-	// 	beforeEach(async () => {
-	// 		testFS = new TestFS();
-	// 		testFSRoot = await testFS.getRoot();
-	// 		testFilenameFull = path.join(testFSRoot, testFilename);
-	// 		await testFS.create({
-	// 			[testFilename]: "",
-	// 			[testFilename + ".dump"]: "",
-	// 		});
-	// 		db = new JsonlDB(testFilenameFull);
-	// 		dumpdb = new JsonlDB(testFilenameFull + ".dump");
-	// 		await db.open();
-	// 	});
-	// 	afterEach(async () => {
-	// 		await db.close();
-	// 		await dumpdb.close();
-	// 		await testFS.remove();
-	// 		mockAppendFileThrottle = 0;
-	// 	});
+			// Force the stream to be flushed
+			await db.close();
 
-	// 	it("writes a compressed version of the database", async () => {
-	// 		for (let i = 1; i < 20; i++) {
-	// 			if (i % 4 === 0) {
-	// 				db.delete(`${i - 1}`);
-	// 			} else {
-	// 				db.set(`${i}`, i);
-	// 			}
-	// 		}
-	// 		await db.dump();
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.toEndWith(`{"k":"key2"}\n`);
+		});
 
-	// 		await dumpdb.close();
-	// 		await dumpdb.open();
-	// 		assertEqual(db, dumpdb);
-	// 	});
+		it("removes multiple key from the database in the correct order", async () => {
+			expect(db.delete("key2")).toBeTrue();
+			expect(db.delete("key1")).toBeTrue();
+			expect(db.size).toBe(0);
+			expect(db.has("key1")).toBeFalse();
+			expect(db.has("key2")).toBeFalse();
 
-	// 	it("when additional data is written during the dump, it is also dumped", async () => {
-	// 		// simulate a slow FS
-	// 		mockAppendFileThrottle = 50;
-	// 		let dumpPromise: Promise<void>;
-	// 		// 10 entries are written before the dump
-	// 		// Afterwards: write 11, 20ms pause, delete 11, 20ms pause, write 13, dump done
-	// 		for (let i = 1; i <= 13; i++) {
-	// 			if (i % 4 === 0) {
-	// 				db.delete(`${i - 1}`);
-	// 			} else {
-	// 				db.set(`${i}`, i);
-	// 			}
-	// 			if (i === 10) dumpPromise = db.dump();
-	// 			if (i > 10) await wait(20);
-	// 		}
-	// 		await dumpPromise!;
+			// Force the stream to be flushed
+			await db.close();
 
-	// 		await dumpdb.close();
-	// 		await dumpdb.open();
-	// 		assertEqual(db, dumpdb);
-	// 	});
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.toEndWith(`{"k":"key2"}\n{"k":"key1"}\n`);
+		});
 
-	// 	it("blocks the close() call", async () => {
-	// 		for (let i = 1; i < 20; i++) {
-	// 			if (i % 4 === 0) {
-	// 				db.delete(`${i - 1}`);
-	// 			} else {
-	// 				db.set(`${i}`, i);
-	// 			}
-	// 		}
+		it("deleting a key twice does not write to the log twice", async () => {
+			expect(db.delete("key2")).toBeTrue();
+			expect(db.delete("key2")).toBeFalse();
 
-	// 		// simulate a slow FS
-	// 		mockAppendFileThrottle = 50;
-	// 		// dump without waiting
-	// 		db.dump();
-	// 		// wait a bit, so the files are being opened
-	// 		await wait(20);
-	// 		// and write something that will be put into the dump backlog
-	// 		db.set("21", 21);
+			// Force the stream to be flushed
+			await db.close();
 
-	// 		await db.close();
-	// 		// The dumpdb must be opened before the db, because the open call will delete the old dump
-	// 		await dumpdb.open();
-	// 		await db.open();
-	// 		assertEqual(db, dumpdb);
-	// 	});
-	// });
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n{"k":"key2"}\n`,
+			);
+		});
+	});
 
-	describe("compress()", () => {
-		const testFilename = "compress.jsonl";
+	describe("set()", () => {
+		const testFilename = "set.jsonl";
 		let testFilenameFull: string;
 		let db: JsonlDB;
 		let testFS: TestFS;
@@ -730,700 +1441,5190 @@ describe("lib/db", () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
 			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create({
-				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
-			});
+			await testFS.create();
 			db = new JsonlDB(testFilenameFull);
 			await db.open();
 		});
+
 		afterEach(async () => {
-			await db.close();
 			await testFS.remove();
 		});
 
-		it("overwrites the append-only file with a compressed version", async () => {
-			db.set("key3", 3);
-			db.delete("key2");
-			db.set("key3", 3.5);
+		it("throws when the DB is not open", async () => {
+			await db.close();
+			expect(() => db.set("foo", 1)).toThrowError("not open");
+		});
+
+		it("throws for keys with lone surrogates instead of mangling them", async () => {
+			for (const key of ["\uD800", "a\uDC00b", "\uDFFF\uD800"]) {
+				expect(() => db.set(key, 1)).toThrowError("lone surrogate");
+				expect(() => db.setDetached(key, { a: 1 })).toThrowError(
+					"lone surrogate",
+				);
+				await expect(db.setDurable(key, 1)).rejects.toThrowError(
+					"lone surrogate",
+				);
+				expect(() => db.get(key)).toThrowError("lone surrogate");
+				expect(() => db.has(key)).toThrowError("lone surrogate");
+				expect(() => db.delete(key)).toThrowError("lone surrogate");
+			}
+			expect(db.size).toBe(0);
+			await db.close();
+		});
+
+		it("preserves tricky keys across reopening", async () => {
+			const keys = [
+				"emoji \uD83D\uDE00",
+				"replacement char \uFFFD",
+				"nul \u0000 char",
+				"line\nbreak\r\n",
+				'"quotes" and \\backslashes\\',
+				"\u2028\u2029",
+				"日本語",
+			];
+			keys.forEach((key, i) => db.set(key, i));
+			await db.close();
+
+			await db.open();
+			expect(db.size).toBe(keys.length);
+			keys.forEach((key, i) => {
+				expect(db.has(key)).toBeTrue();
+				expect(db.get(key)).toBe(i);
+			});
+			expect([...db.keys()].sort()).toEqual([...keys].sort());
+			await db.close();
+		});
+
+		it("adds the given key to the database and writes a line with the serialized value", async () => {
+			db.set("key", true);
+			expect(db.size).toBe(1);
+			expect(db.has("key")).toBeTrue();
+
+			// Force the stream to be flushed
+			await db.close();
 
-			await db.compress();
 			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+				`{"k":"key","v":true}\n`,
 			);
-			await expect(
-				fs.pathExists(testFilenameFull + ".dump"),
-			).resolves.toBeFalse();
-			await expect(
-				fs.pathExists(testFilenameFull + ".bak"),
-			).resolves.toBeFalse();
 		});
 
-		it("after compresing, writing works as usual", async () => {
-			db.set("key3", 3);
-			db.delete("key2");
-			db.set("key3", 3.5);
-			await db.compress();
+		it("adds multiple keys to the database in the correct order", async () => {
+			db.set("key2", true);
+			db.set("key1", 1000);
+			db.set("key3", "");
 
-			db.set("key2", 1);
-			// Force flush
+			// Force the stream to be flushed
 			await db.close();
+
 			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n{"k":"key2","v":1}\n',
+				`{"k":"key2","v":true}\n{"k":"key1","v":1000}\n{"k":"key3","v":""}\n`,
 			);
 		});
 
-		it("does not do anything while the DB is being closed", async () => {
-			db.set("key3", 3);
-			await wait(30);
-			db.delete("key2");
-			db.set("key3", 3.5);
-			const closePromise = db.close();
-			await db.compress();
-			await closePromise;
+		it("setDurable() resolves after the value was written to disk", async () => {
+			await db.close();
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: { intervalMs: 60000 },
+			});
+			await db.open();
+
+			db.set("key1", 1);
+			await Promise.all([
+				db.setDurable("key2", { a: 1 }),
+				db.setDurable("key3", "3"),
+			]);
 
 			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n{"k":"key3","v":3}\n{"k":"key2"}\n{"k":"key3","v":3.5}\n',
+				`{"k":"key1","v":1}\n{"k":"key2","v":{"a":1}}\n{"k":"key3","v":"3"}\n`,
 			);
+			await db.close();
 		});
 
-		it("works correctly while the DB is being compressed already", async () => {
-			db.set("key3", 3);
-			db.delete("key2");
-			db.set("key3", 3.5);
+		it("onFlush() callbacks are invoked after data was written", async () => {
+			const onFlush = jest.fn();
+			db.onFlush(onFlush);
+			db.set("key", true);
+			await wait(50);
 
-			// // simulate slow FS
-			// mockMoveFileThrottle = 10;
-			// mockAppendFileThrottle = 10;
+			expect(onFlush).toBeCalledTimes(1);
+			expect(onFlush.mock.calls[0][0]).toMatchObject({
+				entries: 1,
+				bytes: `{"k":"key","v":true}\n`.length,
+			});
 
-			const compressPromise = db.compress();
-			await db.compress();
-			await compressPromise;
+			db.offFlush();
+			db.set("key", false);
+			await wait(50);
+			expect(onFlush).toBeCalledTimes(1);
+			await db.close();
+		});
+
+		it("returns primitives unchanged, also after reopening", async () => {
+			const primitives: Record<string, unknown> = {
+				string: "x".repeat(100000),
+				empty: "",
+				int: -42,
+				float: 1.5,
+				big: Number.MAX_SAFE_INTEGER,
+				true: true,
+				false: false,
+				null: null,
+			};
+			for (const [key, value] of Object.entries(primitives)) {
+				db.set(key, value);
+			}
+			for (const [key, value] of Object.entries(primitives)) {
+				expect(db.get(key)).toBe(value);
+			}
+			await db.close();
+
+			// The values are parsed from the file when reading them first
+			await db.open();
+			expect(db.getBatch(Object.keys(primitives))).toEqual(
+				Object.values(primitives),
+			);
+			expect(db.getMany("a", "z")).toHaveLength(8);
+			await db.close();
+		});
+
+		it("setDetached() stores objects without keeping a reference", async () => {
+			const value = { foo: "bar", baz: [1, 2] };
+			db.setDetached("key", value);
+			expect(db.has("key")).toBeTrue();
+
+			const read = db.get("key");
+			expect(read).toEqual(value);
+			expect(read).not.toBe(value);
+			// Subsequent reads return the same object
+			expect(db.get("key")).toBe(read);
+
+			// Force the stream to be flushed
+			await db.close();
 
 			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+				`{"k":"key","v":{"foo":"bar","baz":[1,2]}}\n`,
 			);
 		});
 
-		// it("when additional data is written while the files are moved, it is appended to the main file", async () => {
-		// 	// simulate a slow FS
-		// 	mockMoveFileThrottle = 50;
-		// 	let compressPromise: Promise<void>;
-		// 	const map = new Map<any, any>([
-		// 		["key1", 1],
-		// 		["key2", "2"],
-		// 	]);
-		// 	for (let i = 1; i < 20; i++) {
-		// 		if (i % 4 === 0) {
-		// 			db.delete(`${i - 1}`);
-		// 			map.delete(`${i - 1}`);
-		// 		} else {
-		// 			db.set(`${i}`, i);
-		// 			map.set(`${i}`, i);
-		// 		}
-		// 		if (i === 10) compressPromise = db.compress();
-		// 		if (i > 10) await wait(20);
-		// 	}
-		// 	await compressPromise!;
+		it("setNativeObject() stores JSON values without keeping a reference", async () => {
+			await db.close();
+			db = new JsonlDB(testFilenameFull, { indexPaths: ["/type"] });
+			await db.open();
 
-		// 	await db.close();
-		// 	await db.open();
+			const value = { type: "foo", baz: [1, 2] };
+			db.setNativeObject("key", value);
+			db.setNativeObject("arr", [1, "2", null]);
+			db.setNativeObject("num", 1);
+			value.baz.push(3);
+
+			expect(db.get("key")).toEqual({ type: "foo", baz: [1, 2] });
+			expect(db.get("arr")).toEqual([1, "2", null]);
+			expect(db.get("num")).toBe(1);
+			// The index keys are derived from the value
+			expect(db.getMany("a", "z", "/type=foo")).toEqual([
+				{ type: "foo", baz: [1, 2] },
+			]);
 
-		// 	assertEqual(db, map);
-		// });
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key","v":{"type":"foo","baz":[1,2]}}\n{"k":"arr","v":[1,"2",null]}\n{"k":"num","v":1}\n`,
+			);
+		});
 
-		it("blocks the close() call", async () => {
-			// // simulate a slow FS
-			// mockMoveFileThrottle = 50;
-			const map = new Map<any, any>([
-				["key1", 1],
-				["key2", "2"],
+		it("refuses undefined values instead of storing null", async () => {
+			db.set("foo", 1);
+			expect(() => db.set("foo", undefined)).toThrowError(
+				"undefinedIsDelete",
+			);
+			expect(() => db.setMaybe("foo", undefined)).toThrowError(
+				"undefinedIsDelete",
+			);
+			expect(() => db.setNativeObject("foo", undefined)).toThrowError(
+				"undefinedIsDelete",
+			);
+			await expect(db.setDurable("foo", undefined)).rejects.toThrowError(
+				"undefinedIsDelete",
+			);
+			expect(() =>
+				(db as any).db.setPrimitive("bar", undefined),
+			).toThrowError("undefinedIsDelete");
+			expect(db.get("foo")).toBe(1);
+			expect(db.has("bar")).toBeFalse();
+
+			// null is still stored
+			db.set("bar", null);
+			expect(db.has("bar")).toBeTrue();
+			expect(db.get("bar")).toBeNull();
+			await db.close();
+		});
+
+		it("deletes keys that are set to undefined with the undefinedIsDelete option", async () => {
+			await db.close();
+			db = new JsonlDB(testFilenameFull, { undefinedIsDelete: true });
+			await db.open();
+
+			for (const key of ["a", "b", "c", "d"]) db.set(key, { key });
+			expect([...db.keys()]).toEqual(["a", "b", "c", "d"]);
+			db.set("a", undefined);
+			db.setMaybe("b", undefined);
+			db.setNativeObject("c", undefined);
+			await db.setDurable("d", undefined);
+			// Deleting keys that don't exist is not an error
+			db.setMaybe("missing", undefined);
+			expect(db.size).toBe(0);
+			expect([...db.keys()]).toEqual([]);
+
+			db.setMaybe("e", { key: "e" });
+			expect(db.get("e")).toEqual({ key: "e" });
+			await db.close();
+
+			await db.open();
+			expect([...db.entries()]).toEqual([["e", { key: "e" }]]);
+			await db.close();
+		});
+	});
+
+	describe("changing objects after set()", () => {
+		const testFilename = "resnapshot.jsonl";
+		let testFilenameFull: string;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			await testFS.create();
+		});
+
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("writes the value from the time of set() by default", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			const value = { foo: 1 };
+			db.set("key", value);
+			value.foo = 2;
+			expect(db.get("key")).toEqual({ foo: 2 });
+
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key","v":{"foo":1}}\n`,
+			);
+		});
+
+		it("refreshSnapshot() writes the current state of the object", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			const value = { foo: 1 };
+			db.set("key", value);
+			await db.setDurable("primitive", 1);
+
+			value.foo = 2;
+			expect(db.refreshSnapshot("key")).toBeTrue();
+			expect(db.refreshSnapshot("primitive")).toBeFalse();
+			expect(db.refreshSnapshot("missing")).toBeFalse();
+			expect(() => db.refreshSnapshot("key", "{")).toThrow(
+				"not valid JSON",
+			);
+
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key","v":{"foo":1}}\n{"k":"primitive","v":1}\n{"k":"key","v":{"foo":2}}\n`,
+			);
+		});
+
+		it("with resnapshotOnWrite, objects are serialized again before they are written", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				resnapshotOnWrite: true,
+				throttleFS: { intervalMs: 60000 },
+			});
+			await db.open();
+			const value = { foo: 1 };
+			db.set("key", value);
+			value.foo = 2;
+
+			await db.setDurable("other", 1);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key","v":{"foo":2}}\n{"k":"other","v":1}\n`,
+			);
+
+			// Changes without a pending write are not persisted
+			value.foo = 3;
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key","v":{"foo":2}}\n{"k":"other","v":1}\n`,
+			);
+		});
+
+		it("with resnapshotOnWrite, the index follows the value that was written", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				resnapshotOnWrite: true,
+				throttleFS: { intervalMs: 60000 },
+				indexPaths: ["/type"],
+			});
+			await db.open();
+			db.set("unchanged", { type: "a" });
+			const value = { type: "a" };
+			db.set("key", value);
+			value.type = "b";
+
+			await db.setDurable("other", 1);
+			expect(db.getMany("a", "z", "/type=a")).toEqual([{ type: "a" }]);
+			expect(db.getMany("a", "z", "/type=b")).toEqual([{ type: "b" }]);
+			expect(db.getIndexSize("/type")).toBe(2);
+			await expect(db.selfCheck()).resolves.toMatchObject({
+				ok: true,
+				violations: [],
+			});
+			await db.close();
+		});
+	});
+
+	describe("getBatch()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("returns the values in the order of the keys", async () => {
+			await testFS.create({
+				"db.jsonl": `{"k":"a","v":1}
+{"k":"b","v":{"foo":"bar"}}
+`,
+			});
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			db.set("c", [1, 2]);
+
+			db.set("null", null);
+
+			const values = db.getBatch(["c", "missing", "a", "b", "null"]);
+			expect(values).toHaveLength(5);
+			expect(values[0]).toEqual([1, 2]);
+			// Like with get(), missing keys can be told apart from stored nulls
+			expect(values[1]).toBeUndefined();
+			expect(values[2]).toBe(1);
+			expect(values[3]).toEqual({ foo: "bar" });
+			expect(values[4]).toBeNull();
+			// Objects are the same as the ones returned by get()
+			expect(values[3]).toBe(db.get("b"));
+
+			expect(db.getBatchEntries(["b", "missing", "a"])).toEqual([
+				{ key: "b", value: { foo: "bar" } },
+				{ key: "a", value: 1 },
 			]);
-			for (let i = 1; i < 20; i++) {
-				if (i % 4 === 0) {
-					db.delete(`${i - 1}`);
-					map.delete(`${i - 1}`);
-				} else {
-					db.set(`${i}`, i);
-					map.set(`${i}`, i);
+			await db.close();
+		});
+	});
+
+	describe("getAsync()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let db: JsonlDB;
+
+		// Several MB when serialized
+		const large = Array.from({ length: 50000 }, (_, i) => ({
+			id: i,
+			name: `entry ${i}`.padEnd(50, "."),
+			tags: ["a", "b", "c"],
+			nested: { on: i % 2 === 0, level: i / 7 },
+		}));
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("returns small values like get()", async () => {
+			db.set("primitive", 1);
+			db.set("object", { foo: "bar" });
+			db.dematerialize("object");
+
+			await expect(db.getAsync("primitive")).resolves.toBe(1);
+			await expect(db.getAsync("missing")).resolves.toBeUndefined();
+			const obj = await db.getAsync("object");
+			expect(obj).toEqual({ foo: "bar" });
+			expect(db.get("object")).toBe(obj);
+		});
+
+		it("returns large values and keeps them like get()", async () => {
+			db.set("large", large);
+			db.dematerialize("large");
+
+			const value = await db.getAsync("large");
+			expect(value).toEqual(large);
+			expect(db.get("large")).toBe(value);
+			await expect(db.getAsync("large")).resolves.toBe(value);
+			expect(db.getMetrics().references).toBe(1);
+
+			// Also for values read from the DB file
+			await db.close();
+			await db.open();
+			await expect(db.getAsync("large")).resolves.toEqual(large);
+		});
+
+		it("returns the current value if the entry is changed while serializing", async () => {
+			db.set("large", large);
+			db.dematerialize("large");
+
+			const promise = db.getAsync("large");
+			db.set("large", { changed: true });
+			await expect(promise).resolves.toEqual({ changed: true });
+			expect(db.get("large")).toEqual({ changed: true });
+
+			const deleted = db.getAsync("large");
+			db.delete("large");
+			await expect(deleted).resolves.toBeUndefined();
+		});
+
+		it("blocks the event loop shorter than get() for large values", async () => {
+			db.set("large", large);
+			db.dematerialize("large");
+
+			const start = Date.now();
+			db.get("large");
+			const getDuration = Date.now() - start;
+			db.dematerialize("large");
+
+			// The longest time the timers could not run while getAsync() is pending
+			let maxLag = 0;
+			let last = Date.now();
+			const timer = setInterval(() => {
+				const now = Date.now();
+				maxLag = Math.max(maxLag, now - last);
+				last = now;
+			}, 1);
+			const value = await db.getAsync("large");
+			maxLag = Math.max(maxLag, Date.now() - last);
+			clearInterval(timer);
+
+			expect(value).toEqual(large);
+			expect(maxLag).toBeLessThan(getDuration);
+		}, 20000);
+	});
+
+	describe("streamEntries()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("passes all entries in batches and waits for the callback", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			for (let i = 0; i < 25; i++) {
+				db.set(`key${i}`, { i });
+			}
+
+			const batches: JsonlDBStringifiedEntry[][] = [];
+			let pending = false;
+			await db.streamEntries(
+				async (batch) => {
+					expect(pending).toBeFalse();
+					pending = true;
+					batches.push(batch);
+					// Changes during the iteration don't break it
+					db.delete("key24");
+					db.set("key0", "changed");
+					db.set("new", true);
+					await wait(5);
+					pending = false;
+				},
+				{ batchSize: 10 },
+			);
+
+			expect(batches.map((b) => b.length)).toEqual([10, 10, 4]);
+			expect(batches[0][0]).toEqual({
+				key: "key0",
+				valueJsonString: '{"i":0}',
+			});
+			expect(batches[1][0]).toEqual({
+				key: "key10",
+				valueJsonString: '{"i":10}',
+			});
+			await db.close();
+		});
+
+		it("rejects invalid batch sizes", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			await expect(
+				db.streamEntries(() => {}, { batchSize: 0 }),
+			).rejects.toThrowError("batchSize");
+			await db.close();
+		});
+	});
+
+	describe("iterateEntriesStringified() / createReadStream()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let db: JsonlDB;
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			for (const key of ["a1", "a2", "a3", "a4", "a5", "b1", "b2"]) {
+				db.set(key, { key });
+			}
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("iterates over the keys that existed when it was called", async () => {
+			const iterator = db.iterateEntriesStringified({
+				prefix: "a",
+				batchSize: 2,
+			});
+			// Changes made in the meantime don't break the iteration
+			db.delete("a5");
+			db.set("a0", { key: "a0" });
+			db.set("a1", "changed");
+
+			const entries: JsonlDBStringifiedEntry[] = [];
+			for await (const entry of iterator) {
+				entries.push(entry);
+			}
+			expect(entries).toEqual([
+				{ key: "a1", valueJsonString: '"changed"' },
+				{ key: "a2", valueJsonString: '{"key":"a2"}' },
+				{ key: "a3", valueJsonString: '{"key":"a3"}' },
+				{ key: "a4", valueJsonString: '{"key":"a4"}' },
+			]);
+		});
+
+		it("respects the range", async () => {
+			const keys: string[] = [];
+			for await (const entry of db.iterateEntriesStringified({
+				start: "a3",
+				end: "b1",
+			})) {
+				keys.push(entry.key);
+			}
+			expect(keys).toEqual(["a3", "a4", "a5", "b1"]);
+		});
+
+		it("createReadStream() returns the entries as an object stream", async () => {
+			const keys: string[] = [];
+			for await (const entry of db.createReadStream({ prefix: "b" })) {
+				keys.push((entry as JsonlDBStringifiedEntry).key);
+			}
+			expect(keys).toEqual(["b1", "b2"]);
+		});
+
+		it("rejects invalid batch sizes", async () => {
+			expect(() =>
+				db.iterateEntriesStringified({ batchSize: 0 }),
+			).toThrowError("batchSize");
+		});
+	});
+
+	describe("getMany()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		async function openWith(
+			keyOrdering: JsonlDBOptions["keyOrdering"],
+			keys: string[],
+		): Promise<JsonlDB<string>> {
+			const db = new JsonlDB<string>(path.join(testFSRoot, "db.jsonl"), {
+				keyOrdering,
+			});
+			await db.open();
+			for (const key of keys) db.set(key, key);
+			return db;
+		}
+
+		it("returns primitives and objects from the file in insertion order", async () => {
+			await testFS.create({
+				"db.jsonl": `{"k":"a","v":1}
+{"k":"b","v":{"foo":"bar"}}
+{"k":"c","v":"c"}
+{"k":"d","v":[1,2]}
+{"k":"e","v":null}
+`,
+			});
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			const expected = [1, { foo: "bar" }, "c", [1, 2], null];
+			expect(db.getMany("a", "e")).toEqual(expected);
+			// Repeated calls return the same objects
+			const [, b1] = db.getMany("a", "e");
+			expect(db.getMany("b", "b")[0]).toBe(b1);
+			expect(db.get("b")).toBe(b1);
+			await db.close();
+		});
+
+		it("getManyStringified() returns the same results as one JSON string", async () => {
+			await testFS.create({
+				"db.jsonl": `{"k":"a","v":1}
+{"k":"b","v":{"type":"foo"}}
+{"k":"c","v":"c"}
+{"k":"d","v":{"type":"bar"}}
+{"k":"e","v":{"type":"foo","x":[1,2]}}
+`,
+			});
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"), {
+				indexPaths: ["/type"],
+			});
+			await db.open();
+			db.set("f", { type: "foo" });
+
+			expect(JSON.parse(db.getManyStringified("a", "f"))).toEqual(
+				db.getMany("a", "f"),
+			);
+			expect(
+				JSON.parse(
+					db.getManyStringified(
+						"b",
+						"f",
+						{ path: "/type", value: "foo" },
+						{ keyed: true },
+					),
+				),
+			).toEqual({
+				b: { type: "foo" },
+				e: { type: "foo", x: [1, 2] },
+				f: { type: "foo" },
+			});
+			expect(
+				db.getManyStringified("x", "z", undefined, { keyed: true }),
+			).toBe("{}");
+			expect(db.getManyStringified("x", "z")).toBe("[]");
+			await db.close();
+		});
+
+		it("compares keys lexicographically by default", async () => {
+			const db = await openWith(undefined, ["1", "2", "5", "10", "50"]);
+			expect(db.getMany("1", "5").sort()).toEqual(["1", "10", "2", "5"]);
+			await db.close();
+		});
+
+		it("compares numeric keys by value with keyOrdering: numeric", async () => {
+			const db = await openWith("numeric", [
+				"1",
+				"2",
+				"5",
+				"10",
+				"1.5",
+				"1a",
+				"a",
+			]);
+			expect(db.getMany("1", "5").sort()).toEqual(["1", "1.5", "2", "5"]);
+			// Non-numeric keys come after all numbers and are compared lexicographically
+			expect(db.getMany("5", "b").sort()).toEqual(["10", "1a", "5", "a"]);
+			expect(db.getMany("1a", "a").sort()).toEqual(["1a", "a"]);
+			await db.close();
+		});
+
+		it("compares runs of digits by value with keyOrdering: natural", async () => {
+			const db = await openWith("natural", [
+				"node1",
+				"node2",
+				"node10",
+				"node11",
+				"node2.prop3",
+			]);
+			expect(db.getMany("node2", "node10").sort()).toEqual([
+				"node10",
+				"node2",
+				"node2.prop3",
+			]);
+			await db.close();
+		});
+
+		it("returns the values in the order defined by the key comparator", async () => {
+			const db = await openWith(undefined, ["b", "c", "a", "d"]);
+			const comparator = jest.fn((keys: string[]) =>
+				[...keys].sort().reverse(),
+			);
+			db.setKeyComparator(comparator);
+			expect(db.getMany("a", "c")).toEqual(["c", "b", "a"]);
+			// The comparator is called once per query
+			expect(comparator).toBeCalledTimes(1);
+
+			db.setKeyComparator(undefined);
+			expect(db.getMany("a", "c")).toEqual(["b", "c", "a"]);
+			await db.close();
+		});
+
+		it("throws if the key comparator does not return the keys it was given", async () => {
+			const db = await openWith(undefined, ["a", "b"]);
+			db.setKeyComparator((keys) => keys.slice(1));
+			expect(() => db.getMany("a", "b")).toThrowError("key comparator");
+			db.setKeyComparator(() => ["a", "x"]);
+			expect(() => db.getSortedKeys()).toThrowError("key comparator");
+			await db.close();
+		});
+
+		it("getSortedKeys() uses the key comparator or the key ordering", async () => {
+			const db = await openWith("natural", ["n10", "n2", "n1"]);
+			expect(db.getSortedKeys()).toEqual(["n1", "n2", "n10"]);
+			db.setKeyComparator((keys) => [...keys].sort());
+			expect(db.getSortedKeys()).toEqual(["n1", "n10", "n2"]);
+			await db.close();
+		});
+
+		it("rejects unknown orderings", () => {
+			expect(
+				() =>
+					new JsonlDB("foo", {
+						keyOrdering: "random" as any,
+					}),
+			).toThrowError("key ordering");
+		});
+
+		describe("is a total order", () => {
+			// Mixes numbers, digits in other keys and keys that are equal by value
+			const keys = [
+				"1",
+				"01",
+				"1.5",
+				"1a",
+				"2",
+				"10",
+				"-3",
+				"1e3",
+				"a",
+				"a1",
+				"a01",
+				"a10",
+				"a2",
+				"node2",
+				"node10",
+				"Node2",
+			];
+
+			for (const ordering of [
+				"lexicographic",
+				"numeric",
+				"natural",
+			] as const) {
+				it(ordering, async () => {
+					const db = await openWith(ordering, keys);
+					// a <= b exactly if a is in the range from a to b. This checks the comparator
+					// of the DB itself instead of a reference implementation.
+					const le = new Map<string, boolean>();
+					for (const a of keys) {
+						for (const b of keys) {
+							le.set(`${a}|${b}`, db.getMany(a, b).includes(a));
+						}
+					}
+					const lessOrEqual = (a: string, b: string) =>
+						le.get(`${a}|${b}`)!;
+
+					for (const a of keys) {
+						for (const b of keys) {
+							// Antisymmetry and totality
+							if (lessOrEqual(a, b) && lessOrEqual(b, a)) {
+								expect(a).toBe(b);
+							}
+							expect(lessOrEqual(a, b) || lessOrEqual(b, a)).toBeTrue();
+							// Transitivity
+							for (const c of keys) {
+								if (lessOrEqual(a, b) && lessOrEqual(b, c)) {
+									expect([a, b, c, lessOrEqual(a, c)]).toEqual([
+										a,
+										b,
+										c,
+										true,
+									]);
+								}
+							}
+						}
+					}
+
+					// Sorting uses the same comparator
+					const sorted = db.getSortedKeys();
+					for (let i = 0; i < sorted.length - 1; i++) {
+						expect(lessOrEqual(sorted[i], sorted[i + 1])).toBeTrue();
+					}
+					await db.close();
+				});
+			}
+		});
+
+		describe("matches a reference implementation for random keys", () => {
+			// Deterministic pseudo-random numbers
+			let seed = 1234;
+			const random = () => {
+				seed = (seed * 1103515245 + 12345) % 2 ** 31;
+				return seed / 2 ** 31;
+			};
+			const alphabet = "0123456789ab";
+			const randomKey = () => {
+				let ret = "";
+				const len = 1 + Math.floor(random() * 5);
+				for (let i = 0; i < len; i++) {
+					ret += alphabet[Math.floor(random() * alphabet.length)];
 				}
+				return ret;
+			};
+			const keys = [...new Set(Array.from({ length: 300 }, randomKey))];
+
+			const lexicographic = (a: string, b: string) =>
+				a < b ? -1 : a > b ? 1 : 0;
+			const compareDigits = (a: string, b: string) => {
+				a = a.replace(/^0+/, "");
+				b = b.replace(/^0+/, "");
+				return a.length - b.length || lexicographic(a, b);
+			};
+			const references: Record<
+				string,
+				(a: string, b: string) => number
+			> = {
+				numeric: (a, b) => {
+					const isNumber = (k: string) => /^\d+$/.test(k);
+					// Numbers come before all other keys
+					const ret =
+						isNumber(a) && isNumber(b)
+							? Number(a) - Number(b)
+							: Number(isNumber(b)) - Number(isNumber(a));
+					return Math.sign(ret) || lexicographic(a, b);
+				},
+				natural: (a, b) => {
+					const segA = a.match(/\d+|\D+/g)!;
+					const segB = b.match(/\d+|\D+/g)!;
+					for (let i = 0; i < Math.min(segA.length, segB.length); i++) {
+						const ret =
+							/\d/.test(segA[i][0]) && /\d/.test(segB[i][0])
+								? compareDigits(segA[i], segB[i])
+								: lexicographic(segA[i], segB[i]);
+						if (ret !== 0) return Math.sign(ret);
+					}
+					return (
+						Math.sign(segA.length - segB.length) || lexicographic(a, b)
+					);
+				},
+			};
+
+			for (const [ordering, compare] of Object.entries(references)) {
+				it(ordering, async () => {
+					const db = await openWith(ordering as any, keys);
+					for (let i = 0; i < 50; i++) {
+						let start = keys[Math.floor(random() * keys.length)];
+						let end = keys[Math.floor(random() * keys.length)];
+						if (compare(start, end) > 0) [start, end] = [end, start];
+
+						const expected = keys.filter(
+							(k) => compare(k, start) >= 0 && compare(k, end) <= 0,
+						);
+						expect(db.getMany(start, end).sort()).toEqual(
+							expected.sort(),
+						);
+					}
+					await db.close();
+				});
+			}
+		});
+
+		it("exposes the configured index paths", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"), {
+				indexPaths: ["/type", "/room"],
+			});
+			await db.open();
+			db.set("a", { type: "foo", room: "kitchen" });
+			db.set("b", { type: "bar", room: "kitchen" });
+			db.set("c", { type: "foo" });
+
+			expect(db.getIndexPaths()).toEqual(["/type", "/room"]);
+			expect(db.isIndexed("/type")).toBeTrue();
+			expect(db.isIndexed("/name")).toBeFalse();
+			expect(db.getIndexSize("/type")).toBe(2);
+			expect(db.getIndexSize("/room")).toBe(1);
+			expect(db.getIndexSize("/name")).toBe(0);
+
+			db.delete("b");
+			expect(db.getIndexSize("/type")).toBe(1);
+			await db.close();
+		});
+
+		it("keeps the index up to date when entries are overwritten", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"), {
+				indexPaths: ["/type", "/a~1b"],
+			});
+			await db.open();
+			db.set("a", { type: "foo", "a/b": "x" });
+			db.setDetached("b", { type: "foo" });
+			db.setNativeObject("c", { type: "foo" });
+			expect(db.getMany("a", "z", "/type=foo")).toHaveLength(3);
+			// Escaped JSON pointers are resolved like in Rust
+			expect(db.getMany("a", "z", "/a~1b=x")).toEqual([
+				{ type: "foo", "a/b": "x" },
+			]);
+
+			db.set("a", { type: "bar" });
+			db.setDetached("b", { type: "bar" });
+			db.set("c", 1);
+			expect(db.getMany("a", "z", "/type=foo")).toEqual([]);
+			expect(db.getIndexSize("/type")).toBe(1);
+			expect(db.getIndexSize("/a~1b")).toBe(0);
+			expect(db.getMany("a", "z", "/type=bar")).toHaveLength(2);
+			await db.close();
+		});
+
+		it("returns indexed results in a stable order", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"), {
+				indexPaths: ["/type"],
+			});
+			await db.open();
+			const expected: number[] = [];
+			for (let i = 0; i < 200; i++) {
+				const type = i % 2 === 0 ? "even" : "odd";
+				db.set(`key${199 - i}`, { type, i });
+				if (type === "even") expected.push(i);
+			}
+
+			const results = db.getMany("a", "z", "/type=even") as any[];
+			expect(results.map((r) => r.i)).toEqual(expected);
+			// The same order as without the index
+			expect(
+				(db.getMany("a", "z") as any[]).filter((r) => r.type === "even"),
+			).toEqual(results);
+			// And the same order on every call
+			for (let i = 0; i < 5; i++) {
+				expect(db.getMany("a", "z", "/type=even")).toEqual(results);
+			}
+			await db.close();
+		});
+
+		it("compares non-ASCII keys according to the collation", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			const decomposed = "a\u0308";
+			for (const key of ["a", decomposed, "b", "z", "ä", "😀"]) {
+				db.set(key, key);
+			}
+
+			// By default, keys are compared by their UTF-8 bytes
+			expect(db.getMany("a", "b")).toEqual(["a", decomposed, "b"]);
+			expect(db.getMany("ä", "😀")).toEqual(["ä", "😀"]);
+			expect(db.getMany("z", "😀")).toEqual(["z", "ä", "😀"]);
+
+			// Unicode collation treats composed and decomposed characters alike
+			const unicode = { collation: "unicode" } as const;
+			expect(db.getMany("a", "b", undefined, unicode)).toEqual(["a", "b"]);
+			expect(db.getMany("ä", "😀", undefined, unicode)).toEqual([
+				decomposed,
+				"ä",
+				"😀",
+			]);
+			expect(db.getMany(decomposed, decomposed, undefined, unicode)).toEqual(
+				[decomposed, "ä"],
+			);
+
+			const keys: string[] = [];
+			for await (const entry of db.iterateEntriesStringified({
+				start: "ä",
+				end: "ä",
+				...unicode,
+			})) {
+				keys.push(entry.key);
+			}
+			expect(keys).toEqual([decomposed, "ä"]);
+
+			expect(() =>
+				db.getMany("a", "b", undefined, { collation: "locale" as any }),
+			).toThrow("Unsupported collation");
+			await db.close();
+		});
+
+		it("explains whether the index was used", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"), {
+				indexPaths: ["/type"],
+			});
+			await db.open();
+			db.set("a", { type: "foo" });
+			db.set("b", { type: "bar" });
+			db.set("c", { type: "foo" });
+
+			expect(db.getMany("a", "b", "/type=foo", { explain: true })).toEqual({
+				results: [{ type: "foo" }],
+				explain: { usedIndex: true, candidates: 2, scanned: 1 },
+			});
+			expect(
+				db.getMany("a", "z", "/name=foo", { explain: true }).explain,
+			).toEqual({ usedIndex: false, candidates: 3, scanned: 3 });
+			expect(db.getMany("a", "z")).toHaveLength(3);
+
+			expect(db.getMetrics()).toEqual({
+				getManyCalls: 3,
+				indexHits: 1,
+				indexMisses: 1,
+				candidates: 8,
+				scanned: 7,
+				references: 3,
+				pendingUnrefs: 0,
+				// Depends on when the persistence thread got to write
+				linesWritten: expect.any(Number),
+				tombstonesWritten: expect.any(Number),
+				truncations: expect.any(Number),
+				bytesWritten: expect.any(Number),
+				syncs: expect.any(Number),
+				persistenceWakeups: expect.any(Number),
+			});
+			await db.close();
+		});
+
+		it("counts the lines written since opening, also across compress", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			await db.setDurable("a", 1);
+			await db.setDurable("b", 2);
+			db.delete("a");
+			await db.compress();
+
+			const lines = ['{"k":"a","v":1}', '{"k":"b","v":2}', '{"k":"a"}'];
+			expect(db.getMetrics()).toMatchObject({
+				linesWritten: 3,
+				tombstonesWritten: 1,
+				truncations: 0,
+				bytesWritten: lines.join("\n").length + 1,
+			});
+
+			db.clear();
+			await db.setDurable("c", 3);
+			expect(db.getMetrics()).toMatchObject({
+				linesWritten: 4,
+				tombstonesWritten: 1,
+				truncations: 1,
+			});
+
+			db.resetMetrics();
+			expect(db.getMetrics()).toMatchObject({
+				getManyCalls: 0,
+				linesWritten: 0,
+				tombstonesWritten: 0,
+				truncations: 0,
+				bytesWritten: 0,
+			});
+			await db.close();
+		});
+
+		it("measures lock contention only if enabled", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			db.set("a", 1);
+			expect(db.getMetrics().lockContention).toBeUndefined();
+			await db.close();
+		});
+
+		it("measures the waits for the lock while the threads compete for it", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"), {
+				measureLockContention: true,
+			});
+			await db.open();
+			const value = { text: "x".repeat(1000) };
+			for (let i = 0; i < 20000; i++) {
+				db.set(`key${i}`, value);
+			}
+
+			// Keep setting entries while the persistence thread compresses
+			const contended = () => {
+				const { main, persistence, compress } =
+					db.getMetrics().lockContention!;
+				return (
+					main.contended + persistence.contended + compress.contended
+				);
+			};
+			for (let round = 0; round < 20 && contended() === 0; round++) {
+				let done = false;
+				const compress = db.compress().then(() => (done = true));
+				while (!done) {
+					for (let i = 0; i < 1000; i++) {
+						db.set(`key${i}`, value);
+					}
+					await wait(0);
+				}
+				await compress;
+			}
+
+			const { main, persistence, compress } =
+				db.getMetrics().lockContention!;
+			expect(main.acquisitions).toBeGreaterThan(20000);
+			expect(persistence.acquisitions).toBeGreaterThan(0);
+			expect(compress.acquisitions).toBeGreaterThan(0);
+			expect(contended()).toBeGreaterThan(0);
+			for (const waits of [main, persistence, compress]) {
+				expect(waits.contended).toBeLessThanOrEqual(waits.acquisitions);
+				expect(waits.maxWaitMs).toBeLessThanOrEqual(waits.totalWaitMs);
+				if (waits.contended > 0) {
+					expect(waits.totalWaitMs).toBeGreaterThan(0);
+				}
+			}
+
+			db.resetMetrics();
+			// Only getMetrics() itself accessed the entries since
+			expect(
+				db.getMetrics().lockContention!.main.acquisitions,
+			).toBeLessThanOrEqual(1);
+			await db.close();
+		}, 60000);
+
+		it("does not wake up the persistence thread periodically while idle", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			await db.setDurable("a", 1);
+			db.resetMetrics();
+			await wait(500);
+			expect(db.getMetrics().persistenceWakeups).toBeLessThanOrEqual(1);
+
+			// Changes are still written shortly after they were made
+			db.resetMetrics();
+			db.set("b", 2);
+			db.set("c", 3);
+			await wait(200);
+			expect(db.getMetrics()).toMatchObject({ linesWritten: 2 });
+			expect(db.getMetrics().persistenceWakeups).toBeLessThanOrEqual(3);
+
+			await wait(500);
+			expect(db.getMetrics().persistenceWakeups).toBeLessThanOrEqual(3);
+			await db.close();
+		});
+
+		it("releases JS objects with dematerialize", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+			db.set("a", { foo: "a" });
+			db.set("b", { foo: "b" });
+			db.set("c", 1);
+			expect(db.getMetrics().references).toBe(2);
+
+			expect(db.dematerialize("a")).toBeTrue();
+			expect(db.dematerialize("a")).toBeFalse();
+			expect(db.dematerialize("c")).toBeFalse();
+			expect(db.dematerialize("missing")).toBeFalse();
+			expect(db.getMetrics().references).toBe(1);
+			expect(db.get("b")).toEqual({ foo: "b" });
+
+			expect(db.dematerializeAll()).toBe(1);
+			expect(db.getMetrics().references).toBe(0);
+
+			// Reading the entries converts them again
+			expect(db.get("a")).toEqual({ foo: "a" });
+			expect(db.get("b")).toEqual({ foo: "b" });
+			expect(db.getMetrics().references).toBe(2);
+			await db.close();
+		});
+
+		it("accepts structured object filters", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "db.jsonl"), {
+				indexPaths: ["/name", "/level", "/on"],
+			});
+			await db.open();
+			db.set("a", { name: "a=b", level: 1, on: true });
+			db.set("b", { name: "with spaces", level: 2, on: false });
+			db.set("c", { name: "ünï💩de", level: 1 });
+			db.set("d", { name: "back\\slash\n", level: "1" });
+
+			const query = (path: string, value: string | number | boolean) =>
+				db
+					.getMany("a", "z", { path, value }, { explain: true })
+					.results.map((v: any) => v.name)
+					.sort();
+			expect(query("/name", "a=b")).toEqual(["a=b"]);
+			expect(query("/name", "with spaces")).toEqual(["with spaces"]);
+			expect(query("/name", "ünï💩de")).toEqual(["ünï💩de"]);
+			expect(query("/name", "back\\slash\n")).toEqual(["back\\slash\n"]);
+			// Numbers and strings are not the same
+			expect(query("/level", 1)).toEqual(["a=b", "ünï💩de"]);
+			expect(query("/level", "1")).toEqual(["back\\slash\n"]);
+			expect(query("/on", false)).toEqual(["with spaces"]);
+
+			// The legacy string form only matches strings
+			expect(db.getMany("a", "z", "/name=a=b")).toEqual([
+				{ name: "a=b", level: 1, on: true },
+			]);
+
+			expect(() =>
+				db.getMany("a", "z", { path: "/name", value: null } as any),
+			).toThrowError("object filter");
+			await db.close();
+		});
+	});
+
+	describe("importJson()", () => {
+		const testFilename = "import.jsonl";
+		let testFilenameFull: string;
+		let jsonFilenameFull: string;
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			jsonFilenameFull = path.join(testFSRoot, "jsonFile");
+			await testFS.create({
+				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+				jsonFile: '{"key3": 1, "key4": true}',
+			});
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("both versions throw when the DB is not open", async () => {
+			await db.close();
+			expect(() => db.importJson({})).toThrowError("not open");
+			await expect(db.importJson("")).toReject();
+		});
+
+		it("the object version adds all keys and values to the database", async () => {
+			db.importJson({
+				foo: "bar",
+				baz: "inga",
+				"1": 1,
+			});
+			// Force the stream to be flushed
+			await db.close();
+
+			// The order changes because Object.entries reads the entries in a different order
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key1","v":1}
+{"k":"key2","v":"2"}
+{"k":"1","v":1}
+{"k":"baz","v":"inga"}
+{"k":"foo","v":"bar"}
+`,
+			);
+		});
+
+		it("the file version asynchronously adds all keys and values to the database", async () => {
+			await db.importJson(jsonFilenameFull);
+			// Force the stream to be flushed
+			await db.close();
+
+			// The order changes because Object.entries reads the entries in a different order
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key1","v":1}
+{"k":"key2","v":"2"}
+{"k":"key3","v":1}
+{"k":"key4","v":true}
+`,
+			);
+		});
+
+		it("with viaCompress, the DB file is rewritten instead of appending the entries", async () => {
+			const onCompressDone = jest.fn();
+			db.on("compressDone", onCompressDone);
+			db.set("key1", 5);
+			db.importJson({ key3: 3 }, { viaCompress: true });
+			await db.setDurable("key4", 4);
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key1","v":5}
+{"k":"key2","v":"2"}
+{"k":"key3","v":3}
+{"k":"key4","v":4}
+`,
+			);
+			await db.close();
+			expect(onCompressDone).toBeCalledWith(
+				expect.objectContaining({ trigger: "import" }),
+			);
+		});
+
+		it("the index is the same whether it is rebuilt after importing or not", async () => {
+			await db.close();
+			const json: Record<string, any> = {};
+			for (let i = 0; i < 25000; i++) {
+				json[`key${i}`] = { type: `type${i % 7}`, room: i % 3 };
+			}
+
+			const queryIndex = async (rebuildIndexAfterImport: boolean) => {
+				const db = new JsonlDB(
+					path.join(testFSRoot, `index-${rebuildIndexAfterImport}.jsonl`),
+					{ indexPaths: ["/type", "/room"], rebuildIndexAfterImport },
+				);
+				await db.open();
+				// Some of the imported entries overwrite indexed entries
+				db.set("key1", { type: "other", room: 5 });
+				db.set("key8", { name: "unindexed" });
+				db.set("other", { type: "type1" });
+				db.importJson(json);
+
+				const ret: Record<string, any> = {};
+				for (const filter of [
+					...[0, 1, 2, 3, 4, 5, 6, "x"].map((i) => ({
+						path: "/type",
+						value: `type${i}`,
+					})),
+					...[0, 1, 2, 5].map((value) => ({ path: "/room", value })),
+				]) {
+					const { results, explain } = db.getMany(
+						"",
+						"\uffff",
+						filter,
+						{ explain: true },
+					);
+					ret[`${filter.path}=${filter.value}`] = {
+						usedIndex: explain.usedIndex,
+						results: results.map((v: any) => JSON.stringify(v)).sort(),
+					};
+				}
+				ret.sizes = [db.getIndexSize("/type"), db.getIndexSize("/room")];
+				await db.close();
+				return ret;
+			};
+
+			const batched = await queryIndex(false);
+			const rebuilt = await queryIndex(true);
+			expect(rebuilt).toEqual(batched);
+			expect(batched["/type=type1"].usedIndex).toBeTrue();
+			expect(batched["/type=type1"].results).toHaveLength(3572 + 1);
+			expect(batched["/room=5"].results).toEqual([]);
+			expect(batched.sizes).toEqual([7, 3]);
+		});
+
+		it("a large import after clear() rewrites the DB file", async () => {
+			const onCompressDone = jest.fn();
+			db.on("compressDone", onCompressDone);
+			const json: Record<string, number> = {};
+			for (let i = 0; i < 10001; i++) {
+				json[`key${i}`] = i;
+			}
+
+			db.clear();
+			db.importJson(json);
+			await db.close();
+
+			const lines = (await fs.readFile(testFilenameFull, "utf8"))
+				.split("\n")
+				.filter(Boolean);
+			expect(lines).toHaveLength(10001);
+			expect(onCompressDone).toBeCalledWith(
+				expect.objectContaining({ trigger: "import" }),
+			);
+		});
+	});
+
+	describe("importJson() with null values", () => {
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			db = new JsonlDB(path.join(testFSRoot, "nulls.jsonl"));
+			await db.open();
+			db.set("key1", 1);
+			db.set("key2", 2);
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("stores null values by default", () => {
+			expect(db.importJson({ key1: null, key3: null })).toEqual({
+				entries: 2,
+				deletedNullValues: 0,
+			});
+			expect(db.has("key1")).toBeTrue();
+			expect(db.get("key1")).toBeNull();
+			expect(db.get("key3")).toBeNull();
+		});
+
+		it("deletes the entries with null values with nullMeansDelete", async () => {
+			expect(
+				db.importJson(
+					{ key1: null, key3: null, key4: 4 },
+					{ nullMeansDelete: true },
+				),
+			).toEqual({ entries: 1, deletedNullValues: 1 });
+			expect(db.has("key1")).toBeFalse();
+			expect(db.has("key3")).toBeFalse();
+			expect(db.get("key4")).toBe(4);
+
+			const jsonFilename = path.join(testFSRoot, "import.json");
+			await fs.writeFile(jsonFilename, '{"key2":null,"key5":null}');
+			await expect(
+				db.importJson(jsonFilename, { nullMeansDelete: true }),
+			).resolves.toEqual({ entries: 0, deletedNullValues: 1 });
+			expect([...db.keys()]).toEqual(["key4"]);
+
+			// The deletes are persisted
+			await db.close();
+			await db.open();
+			expect([...db.keys()]).toEqual(["key4"]);
+		});
+	});
+
+	describe("exportJson()", () => {
+		const testFilename = "export.jsonl";
+		let testFilenameFull: string;
+		let jsonFilenameFull: string;
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			jsonFilenameFull = path.join(testFSRoot, "jsonFile");
+			await testFS.create({
+				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+				jsonFile: '{"key3": 1, "key4": true}',
+			});
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			if (db) await db.close();
+			await testFS.remove();
+		});
+
+		it("throws when the DB is not open", async () => {
+			await db.close();
+			try {
+				await db.exportJson(jsonFilenameFull);
+				throw new Error("it did not throw");
+			} catch (e: any) {
+				expect(e.message).toMatch(/is not open/i);
+				true;
+			}
+		});
+
+		it("overwrites the given file with the DB contents as valid JSON", async () => {
+			await db.exportJson(jsonFilenameFull);
+			await expect(fs.readFile(jsonFilenameFull, "utf8")).resolves.toBe(
+				`{"key1":1,"key2":"2"}`,
+			);
+		});
+
+		it("honors the JSON formatting options", async () => {
+			await db.exportJson(jsonFilenameFull, true);
+			await expect(fs.readFile(jsonFilenameFull, "utf8")).resolves.toBe(
+				`{
+  "key1": 1,
+  "key2": "2"
+}`,
+			);
+		});
+
+		it("gzips the output when the filename ends with .gz", async () => {
+			const gzFilename = `${jsonFilenameFull}.gz`;
+			await db.exportJson(gzFilename);
+			const raw = await fs.readFile(gzFilename);
+			expect(zlib.gunzipSync(raw).toString("utf8")).toBe(
+				`{"key1":1,"key2":"2"}`,
+			);
+		});
+
+		it("gzipped exports can be imported again", async () => {
+			await db.exportJson(jsonFilenameFull, false, true);
+			db.clear();
+			await db.importJson(jsonFilenameFull);
+			expect(db.get("key1")).toBe(1);
+			expect(db.get("key2")).toBe("2");
+		});
+
+		it("keeps null values by default and counts the exported entries", async () => {
+			db.set("nothing", null);
+			await expect(db.exportJson(jsonFilenameFull)).resolves.toEqual({
+				entries: 3,
+				omittedNullValues: 0,
+			});
+			await expect(fs.readFile(jsonFilenameFull, "utf8")).resolves.toBe(
+				`{"key1":1,"key2":"2","nothing":null}`,
+			);
+		});
+
+		it("leaves out null values with omitNullValues", async () => {
+			db.set("nothing", null);
+			db.set("nested", { a: null });
+			await expect(
+				db.exportJson(jsonFilenameFull, { omitNullValues: true }),
+			).resolves.toEqual({ entries: 3, omittedNullValues: 1 });
+			await expect(fs.readFile(jsonFilenameFull, "utf8")).resolves.toBe(
+				`{"key1":1,"key2":"2","nested":{"a":null}}`,
+			);
+
+			await db.exportJson(jsonFilenameFull, {
+				pretty: true,
+				omitNullValues: true,
+			});
+			await expect(fs.readFile(jsonFilenameFull, "utf8")).resolves.toMatch(
+				/^{\n  "key1": 1,/,
+			);
+		});
+
+		it("dump() also supports gzipped output", async () => {
+			const gzFilename = path.join(testFSRoot, "dump.jsonl.gz");
+			await db.dump(gzFilename);
+			const raw = await fs.readFile(gzFilename);
+			expect(zlib.gunzipSync(raw).toString("utf8")).toBe(
+				`{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n`,
+			);
+		});
+
+		it("dump() creates the target directory if necessary", async () => {
+			const dumpFilename = path.join(testFSRoot, "new/dir/dump.jsonl");
+			await db.dump(dumpFilename);
+			await expect(fs.readFile(dumpFilename, "utf8")).resolves.toBe(
+				`{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n`,
+			);
+		});
+
+		it("exportJson() creates missing parent directories", async () => {
+			const exportFilename = path.join(
+				testFSRoot,
+				"backups/2024-06/nested/export.json",
+			);
+			await db.exportJson(exportFilename);
+			await expect(fs.readFile(exportFilename, "utf8")).resolves.toBe(
+				`{"key1":1,"key2":"2"}`,
+			);
+		});
+
+		it("explains why the target directory cannot be created", async () => {
+			// Permissions don't apply to root
+			if (process.getuid?.() === 0) return;
+
+			const readonlyDir = path.join(testFSRoot, "readonly");
+			await fs.mkdir(readonlyDir, 0o555);
+			try {
+				await expect(
+					db.dump(path.join(readonlyDir, "sub/dump.jsonl")),
+				).rejects.toThrow(/Cannot create the directory .*sub/);
+			} finally {
+				await fs.chmod(readonlyDir, 0o755);
+			}
+		});
+	});
+
+	describe("exportCsv() / importCsv()", () => {
+		const testFilename = "csv.jsonl";
+		let testFilenameFull: string;
+		let csvFilenameFull: string;
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			csvFilenameFull = path.join(testFSRoot, "export.csv");
+			await testFS.create({
+				[testFilename]: "",
+			});
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			if (db) await db.close();
+			await testFS.remove();
+		});
+
+		it("throws when the DB is not open", async () => {
+			await db.close();
+			await expect(db.exportCsv(csvFilenameFull)).rejects.toThrow(
+				/is not open/i,
+			);
+			await expect(db.importCsv(csvFilenameFull)).rejects.toThrow(
+				/is not open/i,
+			);
+		});
+
+		it("writes a key,value header and the values as compact JSON", async () => {
+			db.set("key1", 1);
+			db.set("key2", { a: "b" });
+			await db.exportCsv(csvFilenameFull);
+			await expect(fs.readFile(csvFilenameFull, "utf8")).resolves.toBe(
+				`key,value\nkey1,1\nkey2,"{""a"":""b""}"\n`,
+			);
+		});
+
+		it("creates missing parent directories", async () => {
+			db.set("key1", 1);
+			const nestedFilename = path.join(testFSRoot, "a/b/c/export.csv");
+			await db.exportCsv(nestedFilename);
+			await expect(fs.readFile(nestedFilename, "utf8")).resolves.toBe(
+				`key,value\nkey1,1\n`,
+			);
+		});
+
+		it("flattens shallow objects into columns", async () => {
+			db.set("key1", { a: "x", b: 1 });
+			db.set("key2", { b: true, c: "y,z" });
+			await db.exportCsv(csvFilenameFull, { valueMode: "flatten" });
+			await expect(fs.readFile(csvFilenameFull, "utf8")).resolves.toBe(
+				`key,a,b,c\nkey1,x,1,\nkey2,,true,"y,z"\n`,
+			);
+		});
+
+		it("rejects unknown value modes", async () => {
+			await expect(
+				db.exportCsv(csvFilenameFull, { valueMode: "nope" as any }),
+			).rejects.toThrow();
+		});
+
+		it("round-trips tricky values", async () => {
+			const values: Record<string, any> = {
+				"with,comma": "a,b",
+				'with"quote': 'say "hi"',
+				"with\nnewline": "line1\nline2\r\nline3",
+				empty: "",
+				number: 1.5,
+				nil: null,
+				object: { nested: ["x", { y: "z,\"" }] },
+			};
+			for (const [k, v] of Object.entries(values)) db.set(k, v);
+			await db.exportCsv(csvFilenameFull);
+
+			db.clear();
+			await db.importCsv(csvFilenameFull, { valueIsJson: true });
+			expect(db.size).toBe(Object.keys(values).length);
+			for (const [k, v] of Object.entries(values)) {
+				expect(db.get(k)).toEqual(v);
+			}
+		});
+
+		it("imports values as strings unless valueIsJson is set", async () => {
+			await fs.writeFile(
+				csvFilenameFull,
+				`id,data,other\nfoo,1,x\n"b,ar","""quoted""",y\n`,
+			);
+			await db.importCsv(csvFilenameFull, {
+				keyColumn: "id",
+				valueColumn: "data",
+			});
+			expect(db.get("foo")).toBe("1");
+			expect(db.get("b,ar")).toBe('"quoted"');
+		});
+
+		it("throws when a column is missing", async () => {
+			await fs.writeFile(csvFilenameFull, `key,data\nfoo,1\n`);
+			await expect(db.importCsv(csvFilenameFull)).rejects.toThrow(
+				/no column "value"/,
+			);
+		});
+	});
+
+	describe("close()", () => {
+		const testFilename = "close.jsonl";
+		let testFilenameFull: string;
+		// The basic functionality is tested in the other suites
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			await testFS.create();
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("may be called twice", async () => {
+			await db.close();
+			await db.close();
+		});
+
+		it("sets the isOpen property to false", async () => {
+			await db.close();
+			expect(db.isOpen).toBeFalse();
+		});
+
+		it("waits for running exports, dumps and self checks to finish", async () => {
+			for (let i = 0; i < 1000; i++) {
+				db.set(`key${i}`, { value: i });
+			}
+			const jsonFilename = path.join(testFSRoot, "export.json");
+			const dumpFilename = path.join(testFSRoot, "dump.jsonl");
+			const exporting = db.exportJson(jsonFilename);
+			const dumping = db.dump(dumpFilename);
+			const checking = db.selfCheck();
+			// Not awaiting them first must not pull the DB out from under them
+			await db.close();
+
+			await expect(exporting).resolves.toMatchObject({ entries: 1000 });
+			await expect(dumping).resolves.toMatchObject({
+				bytes: (await fs.stat(dumpFilename)).size,
+			});
+			await expect(checking).resolves.toMatchObject({ ok: true });
+			expect(Object.keys(await fs.readJSON(jsonFilename))).toHaveLength(
+				1000,
+			);
+		});
+
+		it("repeated open/close cycles don't leak background tasks or lockfiles", async () => {
+			await db.close();
+			const tasksBefore = getLiveTaskCount();
+
+			for (let i = 0; i < 100; i++) {
+				await db.open();
+				expect(getLiveTaskCount()).toBe(tasksBefore + 1);
+				db.set(`key${i}`, i);
+				await db.close();
+				expect(getLiveTaskCount()).toBe(tasksBefore);
+			}
+
+			await expect(
+				fs.pathExists(`${testFilenameFull}.lock`),
+			).resolves.toBeFalse();
+
+			await db.open();
+			expect(db.size).toBe(100);
+			await db.close();
+		});
+
+		itWithTestHooks(
+			"succeeds and releases the lockfile when the persistence thread died",
+			async () => {
+				const onError = jest.fn();
+				db.on("error", onError);
+				db.set("key1", 1);
+				await db.close();
+				await db.open();
+
+				(db as any).db.__abortPersistence();
+				await expect(db.close()).resolves.toBeUndefined();
+				expect(db.isOpen).toBeFalse();
+				await expect(
+					fs.pathExists(`${testFilenameFull}.lock`),
+				).resolves.toBeFalse();
+
+				// The DB can be opened again
+				await db.open();
+				expect(db.get("key1")).toBe(1);
+				expect(onError).toBeCalledWith(
+					expect.objectContaining({ source: "persistence" }),
+				);
+				await db.close();
+			},
+		);
+
+		it("releases the objects of a half-closed DB when it is garbage-collected", async () => {
+			await db.close();
+			// Needs a separate process with an exposed GC. Leaked references are reported on stderr.
+			const script = `
+const { JsonlDB } = require(${JSON.stringify(path.join(__dirname, "../lib.js"))});
+(async () => {
+	let db = new JsonlDB(${JSON.stringify(testFilenameFull)});
+	const warnings = [];
+	db.setEventCallback((event, payload) => {
+		if (event === "warning") warnings.push(payload.source);
+	});
+	await db.open();
+	for (let i = 0; i < 100; i++) {
+		const obj = { i };
+		db.setObject("obj" + i, obj, JSON.stringify(obj));
+	}
+	await db.halfClose();
+	db = undefined;
+	for (let i = 0; i < 50 && warnings.length === 0; i++) {
+		global.gc();
+		await new Promise((resolve) => setTimeout(resolve, 20));
+	}
+	console.log(JSON.stringify(warnings));
+})();
+`;
+			const result = spawnSync(
+				process.execPath,
+				["--expose-gc", "-e", script],
+				{ encoding: "utf8" },
+			);
+			expect(result.stderr).not.toMatch(/ref count|memory leak|panicked/i);
+			expect(result.status).toBe(0);
+			expect(result.stdout.trim()).toBe('["close"]');
+		});
+
+		it("a half-closed DB with event listeners neither keeps the process alive nor escapes the GC", async () => {
+			await db.close();
+			const script = `
+require("@swc-node/register");
+const { JsonlDB } = require(${JSON.stringify(path.join(__dirname, "../index.ts"))});
+(async () => {
+	const warnings = [];
+	let db = new JsonlDB(${JSON.stringify(testFilenameFull)});
+	db.on("warning", (payload) => warnings.push(payload.source));
+	await db.open();
+	db.set("obj", { a: 1 });
+	await db.db.halfClose();
+	db = undefined;
+	for (let i = 0; i < 50 && warnings.length === 0; i++) {
+		global.gc();
+		await new Promise((resolve) => setTimeout(resolve, 20));
+	}
+	console.log(JSON.stringify(warnings));
+})();
+`;
+			const result = spawnSync(
+				process.execPath,
+				["--expose-gc", "-e", script],
+				// The process must exit on its own
+				{ encoding: "utf8", timeout: 20000 },
+			);
+			expect(result.status).toBe(0);
+			expect(result.stdout.trim()).toBe('["close"]');
+		});
+	});
+
+	describe("retryPersistence()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let dbDir: string;
+		let testFilenameFull: string;
+		let db: JsonlDB;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			dbDir = path.join(testFSRoot, "removable");
+			testFilenameFull = path.join(dbDir, "retry.jsonl");
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("pauses persisting when the DB directory disappears until retryPersistence() is called", async () => {
+			const onError = jest.fn();
+			db.on("error", onError);
+			await db.setDurable("key1", 1);
+
+			await fs.remove(dbDir);
+			// Durable writes must not report success while the changes are only in memory
+			await expect(db.setDurable("key2", 2)).rejects.toThrow(
+				"persisting is paused",
+			);
+			db.set("key3", 3);
+			await expect(db.compress()).rejects.toThrow("persisting is paused");
+			await wait(50);
+			expect(onError).toBeCalledWith(
+				expect.objectContaining({ source: "persistence", paused: true }),
+			);
+			// The changes are kept in memory
+			expect(db.get("key2")).toBe(2);
+			await expect(fs.pathExists(testFilenameFull)).resolves.toBeFalse();
+
+			await db.retryPersistence();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key2","v":2}\n{"k":"key3","v":3}\n',
+			);
+
+			// Writing works as usual afterwards
+			db.set("key4", 4);
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key2","v":2}\n{"k":"key3","v":3}\n{"k":"key4","v":4}\n',
+			);
+		});
+
+		it("close() succeeds and reports the changes that were not persisted", async () => {
+			const onError = jest.fn();
+			db.on("error", onError);
+			await db.setDurable("key1", 1);
+
+			await fs.remove(dbDir);
+			await expect(db.setDurable("key2", 2)).rejects.toThrow();
+			await expect(db.close()).resolves.toBeUndefined();
+			await wait(50);
+			expect(onError).toBeCalledWith(
+				expect.objectContaining({
+					source: "persistence",
+					message: expect.stringContaining("not written"),
+				}),
+			);
+		});
+	});
+
+	// describe("dump()", () => {
+	// 	const testFilename = "dump.jsonl";
+	// 	let testFilenameFull: string;
+	// 	let db: JsonlDB;
+	// 	let dumpdb: JsonlDB;
+	// 	let testFS: TestFS;
+	// 	let testFSRoot: string;
+
+	// 	beforeEach(async () => {
+	// 		testFS = new TestFS();
+	// 		testFSRoot = await testFS.getRoot();
+	// 		testFilenameFull = path.join(testFSRoot, testFilename);
+	// 		await testFS.create({
+	// 			[testFilename]: "",
+	// 			[testFilename + ".dump"]: "",
+	// 		});
+	// 		db = new JsonlDB(testFilenameFull);
+	// 		dumpdb = new JsonlDB(testFilenameFull + ".dump");
+	// 		await db.open();
+	// 	});
+	// 	afterEach(async () => {
+	// 		await db.close();
+	// 		await dumpdb.close();
+	// 		await testFS.remove();
+	// 		mockAppendFileThrottle = 0;
+	// 	});
+
+	// 	it("writes a compressed version of the database", async () => {
+	// 		for (let i = 1; i < 20; i++) {
+	// 			if (i % 4 === 0) {
+	// 				db.delete(`${i - 1}`);
+	// 			} else {
+	// 				db.set(`${i}`, i);
+	// 			}
+	// 		}
+	// 		await db.dump();
+
+	// 		await dumpdb.close();
+	// 		await dumpdb.open();
+	// 		assertEqual(db, dumpdb);
+	// 	});
+
+	// 	it("when additional data is written during the dump, it is also dumped", async () => {
+	// 		// simulate a slow FS
+	// 		mockAppendFileThrottle = 50;
+	// 		let dumpPromise: Promise<void>;
+	// 		// 10 entries are written before the dump
+	// 		// Afterwards: write 11, 20ms pause, delete 11, 20ms pause, write 13, dump done
+	// 		for (let i = 1; i <= 13; i++) {
+	// 			if (i % 4 === 0) {
+	// 				db.delete(`${i - 1}`);
+	// 			} else {
+	// 				db.set(`${i}`, i);
+	// 			}
+	// 			if (i === 10) dumpPromise = db.dump();
+	// 			if (i > 10) await wait(20);
+	// 		}
+	// 		await dumpPromise!;
+
+	// 		await dumpdb.close();
+	// 		await dumpdb.open();
+	// 		assertEqual(db, dumpdb);
+	// 	});
+
+	// 	it("blocks the close() call", async () => {
+	// 		for (let i = 1; i < 20; i++) {
+	// 			if (i % 4 === 0) {
+	// 				db.delete(`${i - 1}`);
+	// 			} else {
+	// 				db.set(`${i}`, i);
+	// 			}
+	// 		}
+
+	// 		// simulate a slow FS
+	// 		mockAppendFileThrottle = 50;
+	// 		// dump without waiting
+	// 		db.dump();
+	// 		// wait a bit, so the files are being opened
+	// 		await wait(20);
+	// 		// and write something that will be put into the dump backlog
+	// 		db.set("21", 21);
+
+	// 		await db.close();
+	// 		// The dumpdb must be opened before the db, because the open call will delete the old dump
+	// 		await dumpdb.open();
+	// 		await db.open();
+	// 		assertEqual(db, dumpdb);
+	// 	});
+	// });
+
+	describe("createConsistentSnapshotFile()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+		let db: JsonlDB;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "db.jsonl");
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: { intervalMs: 60000 },
+			});
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("copies the DB file including the pending changes", async () => {
+			db.set("a", 1);
+			db.set("b", { c: 2 });
+			const snapshotFilename = path.join(testFSRoot, "snapshot.jsonl");
+
+			const expected = `{"k":"a","v":1}\n{"k":"b","v":{"c":2}}\n`;
+			await expect(
+				db.createConsistentSnapshotFile(snapshotFilename),
+			).resolves.toEqual({ bytes: expected.length, entries: 2 });
+			await expect(fs.readFile(snapshotFilename, "utf8")).resolves.toBe(
+				expected,
+			);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				expected,
+			);
+		});
+
+		it("is not affected by later changes or compressing", async () => {
+			db.set("a", 1);
+			const snapshotFilename = path.join(testFSRoot, "snapshot.jsonl");
+			await db.createConsistentSnapshotFile(snapshotFilename);
+
+			await db.setDurable("a", 2);
+			await db.setDurable("b", 3);
+			await db.compress();
+			await expect(fs.readFile(snapshotFilename, "utf8")).resolves.toBe(
+				`{"k":"a","v":1}\n`,
+			);
+		});
+
+		it("creates missing parent directories", async () => {
+			db.set("a", 1);
+			const snapshotFilename = path.join(testFSRoot, "a/b/snapshot.jsonl");
+			await db.createConsistentSnapshotFile(snapshotFilename);
+			await expect(fs.readFile(snapshotFilename, "utf8")).resolves.toBe(
+				`{"k":"a","v":1}\n`,
+			);
+		});
+	});
+
+	describe("dump() to another device", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		// tmpfs is usually a different device than the test directory. Where it isn't,
+		// the fallback is covered by the unit tests of the native move_file
+		const otherDevice = "/dev/shm";
+		const itCrossDevice =
+			fs.pathExistsSync(otherDevice) &&
+			fs.statSync(otherDevice).dev !== fs.statSync(os.tmpdir()).dev
+				? it
+				: it.skip;
+
+		itCrossDevice(
+			"works when the target is on a different device than the DB",
+			async () => {
+				const filename = path.join(testFSRoot, "db.jsonl");
+				const dumpFilename = path.join(
+					otherDevice,
+					`rsonl-db-dump-${process.pid}.jsonl`,
+				);
+				const db = new JsonlDB(filename);
+				await db.open();
+				db.set("key", "value");
+				try {
+					await db.dump(dumpFilename);
+					await expect(fs.readFile(dumpFilename, "utf8")).resolves.toBe(
+						'{"k":"key","v":"value"}\n',
+					);
+					// The temporary file must not be left behind
+					await expect(
+						fs.pathExists(`${filename}.export`),
+					).resolves.toBeFalse();
+				} finally {
+					await db.close();
+					await fs.remove(dumpFilename);
+				}
+			},
+		);
+	});
+
+	describe("dump() while compressing", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let db: JsonlDB;
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			db = new JsonlDB(path.join(testFSRoot, "db.jsonl"));
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("reports whether the dump ran during a compress", async () => {
+			db.set("key", "value");
+			await expect(
+				db.dump(path.join(testFSRoot, "idle.jsonl")),
+			).resolves.toEqual({ bytes: 24, duringCompress: false });
+		});
+
+		it("does not wait for the compress and contains all entries", async () => {
+			for (let i = 0; i < 50000; i++) {
+				db.set(`key${i}`, { i, padding: "x".repeat(50) });
+			}
+			const dumpFilename = path.join(testFSRoot, "dump.jsonl");
+			const dumped = new Promise<JsonlDBDumpResult>((resolve, reject) => {
+				db.on("compressStart", () => {
+					// Changes made while dumping must be included too
+					db.set("late", true);
+					db.dump(dumpFilename).then(resolve, reject);
+				});
+			});
+			await db.compress();
+			const result = await dumped;
+			expect(result.duringCompress).toBeTrue();
+
+			const dump = new JsonlDB(dumpFilename);
+			await dump.open();
+			expect(dump.size).toBe(50001);
+			expect(dump.get("late")).toBeTrue();
+			expect(dump.get("key49999")).toMatchObject({ i: 49999 });
+			await dump.close();
+		});
+	});
+
+	describe("compress()", () => {
+		const testFilename = "compress.jsonl";
+		let testFilenameFull: string;
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			await testFS.create({
+				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+			});
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("overwrites the append-only file with a compressed version", async () => {
+			db.set("key3", 3);
+			db.delete("key2");
+			db.set("key3", 3.5);
+
+			await db.compress();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+			);
+			await expect(
+				fs.pathExists(testFilenameFull + ".dump"),
+			).resolves.toBeFalse();
+			await expect(
+				fs.pathExists(testFilenameFull + ".bak"),
+			).resolves.toBeFalse();
+		});
+
+		it("after compresing, writing works as usual", async () => {
+			db.set("key3", 3);
+			db.delete("key2");
+			db.set("key3", 3.5);
+			await db.compress();
+
+			db.set("key2", 1);
+			// Force flush
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n{"k":"key2","v":1}\n',
+			);
+		});
+
+		it("estimateCompress() predicts the result without touching the file", async () => {
+			db.set("key3", 3);
+			db.delete("key2");
+			await db.setDurable("key3", 3.5);
+			const before = await fs.readFile(testFilenameFull, "utf8");
+
+			const estimate = await db.estimateCompress();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				before,
+			);
+			expect(estimate).toEqual({
+				currentFileBytes: Buffer.byteLength(before),
+				estimatedCompactBytes: Buffer.byteLength(
+					'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+				),
+				wastedBytes:
+					Buffer.byteLength(before) -
+					Buffer.byteLength(
+						'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+					),
+				liveEntries: 2,
+				fileLines: 5,
+			});
+
+			await db.compress();
+			expect((await fs.stat(testFilenameFull)).size).toBe(
+				estimate.estimatedCompactBytes,
+			);
+		});
+
+		it("writes the dump to the scratchDirectory if one is configured", async () => {
+			await db.close();
+			const scratchDirectory = path.join(testFSRoot, "scratch");
+			db = new JsonlDB(testFilenameFull, { scratchDirectory });
+			await db.open();
+			await expect(fs.pathExists(scratchDirectory)).resolves.toBeTrue();
+
+			db.set("key3", 3);
+			db.delete("key2");
+			db.set("key3", 3.5);
+
+			await db.compress();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+			);
+			await expect(fs.readdir(scratchDirectory)).resolves.toEqual([]);
+			await expect(
+				fs.pathExists(testFilenameFull + ".dump"),
+			).resolves.toBeFalse();
+		});
+
+		it("validates compressDumpSuffix and compressBackupSuffix", () => {
+			expect(
+				() => new JsonlDB(testFilenameFull, { compressDumpSuffix: "" }),
+			).toThrow("compressDumpSuffix must be a non-empty string");
+			expect(
+				() =>
+					new JsonlDB(testFilenameFull, {
+						compressBackupSuffix: "/bak",
+					}),
+			).toThrow("compressBackupSuffix must be a non-empty string");
+			expect(
+				() =>
+					new JsonlDB(testFilenameFull, {
+						compressDumpSuffix: ".bak",
+					}),
+			).toThrow("must be different");
+		});
+
+		it("does not do anything while the DB is being closed", async () => {
+			db.set("key3", 3);
+			await wait(30);
+			db.delete("key2");
+			db.set("key3", 3.5);
+			const closePromise = db.close();
+			await db.compress();
+			await closePromise;
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n{"k":"key3","v":3}\n{"k":"key2"}\n{"k":"key3","v":3.5}\n',
+			);
+		});
+
+		it("works correctly while the DB is being compressed already", async () => {
+			db.set("key3", 3);
+			db.delete("key2");
+			db.set("key3", 3.5);
+
+			// // simulate slow FS
+			// mockMoveFileThrottle = 10;
+			// mockAppendFileThrottle = 10;
+
+			const compressPromise = db.compress();
+			await db.compress();
+			await compressPromise;
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+			);
+		});
+
+		it("reads and writes are not blocked by a pending compress", async () => {
+			for (let i = 0; i < 50000; i++) {
+				db.set(`key${i}`, { value: i });
+			}
+			let compressed = false;
+			const compressPromise = db.compress().then(() => {
+				compressed = true;
+			});
+
+			// Keep reading and writing while the persistence thread is busy
+			let ops = 0;
+			while (!compressed && ops < 1000) {
+				expect(db.has(`key${ops}`)).toBeTrue();
+				expect(db.get(`key${ops}`)).toEqual({ value: ops });
+				db.set(`new${ops}`, ops);
+				expect(db.size).toBe(50000 + ops + 1);
+				ops++;
+				await wait(0);
+			}
+			expect(ops).toBeGreaterThan(0);
+			await compressPromise;
+
+			await db.close();
+			await db.open();
+			for (let i = 0; i < ops; i++) {
+				expect(db.get(`new${i}`)).toBe(i);
+			}
+		});
+
+		it("closing waits for a pending compress", async () => {
+			for (let i = 0; i < 50000; i++) {
+				db.set(`key${i}`, { value: i });
+			}
+			const compressPromise = db.compress();
+			// Written while the dump is rendered
+			db.set("late", 1);
+			await Promise.all([compressPromise, db.close()]);
+			await expect(db.compress()).rejects.toThrow("open");
+
+			await db.open();
+			expect(db.size).toBe(50001);
+			expect(db.get("late")).toBe(1);
+		});
+
+		it("getMany() is not blocked for long while a large DB is compressed", async () => {
+			const count = 200000;
+			for (let i = 0; i < count; i++) {
+				db.set(`key${i}`, i);
+			}
+			// Write everything first, so compressing only needs to render the dump
+			await db.compress();
+
+			let compressed = false;
+			const compressPromise = db.compress().then(() => {
+				compressed = true;
+			});
+
+			let longestGetMs = 0;
+			let i = 0;
+			while (!compressed) {
+				const start = Date.now();
+				const result = db.getMany("key150000", "key150000");
+				longestGetMs = Math.max(longestGetMs, Date.now() - start);
+				expect(result).toEqual([150000]);
+				// Changes made while the dump is rendered must end up in the DB file
+				db.set(`key${i}`, -i);
+				db.delete(`key${count - 1 - i}`);
+				i++;
+				await wait(0);
+			}
+			await compressPromise;
+			expect(longestGetMs).toBeLessThan(20);
+
+			await db.close();
+			const reopened = new JsonlDB(testFilenameFull);
+			await reopened.open();
+			expect(reopened.size).toBe(count - i);
+			for (let j = 0; j < i; j++) {
+				expect(reopened.get(`key${j}`)).toBe(-j);
+				expect(reopened.has(`key${count - 1 - j}`)).toBeFalse();
+			}
+			await reopened.close();
+		});
+
+		// it("when additional data is written while the files are moved, it is appended to the main file", async () => {
+		// 	// simulate a slow FS
+		// 	mockMoveFileThrottle = 50;
+		// 	let compressPromise: Promise<void>;
+		// 	const map = new Map<any, any>([
+		// 		["key1", 1],
+		// 		["key2", "2"],
+		// 	]);
+		// 	for (let i = 1; i < 20; i++) {
+		// 		if (i % 4 === 0) {
+		// 			db.delete(`${i - 1}`);
+		// 			map.delete(`${i - 1}`);
+		// 		} else {
+		// 			db.set(`${i}`, i);
+		// 			map.set(`${i}`, i);
+		// 		}
+		// 		if (i === 10) compressPromise = db.compress();
+		// 		if (i > 10) await wait(20);
+		// 	}
+		// 	await compressPromise!;
+
+		// 	await db.close();
+		// 	await db.open();
+
+		// 	assertEqual(db, map);
+		// });
+
+		it("compress() and close() called back-to-back both settle", async () => {
+			db.set("key3", 3);
+			const compressPromise = db.compress();
+			const closePromise = db.close();
+			await expect(
+				Promise.all([compressPromise, closePromise]),
+			).toResolve();
+			expect(db.isOpen).toBeFalse();
+		});
+
+		it("blocks the close() call", async () => {
+			// // simulate a slow FS
+			// mockMoveFileThrottle = 50;
+			const map = new Map<any, any>([
+				["key1", 1],
+				["key2", "2"],
+			]);
+			for (let i = 1; i < 20; i++) {
+				if (i % 4 === 0) {
+					db.delete(`${i - 1}`);
+					map.delete(`${i - 1}`);
+				} else {
+					db.set(`${i}`, i);
+					map.set(`${i}`, i);
+				}
+			}
+			db.compress();
+			await db.close();
+			await db.open();
+
+			assertEqual(db, map);
+		});
+	});
+
+	describe("fileMode", () => {
+		// File permissions are not supported on Windows
+		const itUnix = process.platform === "win32" ? it.skip : it;
+		const getMode = async (filename: string) =>
+			(await fs.stat(filename)).mode & 0o777;
+
+		let testFS: TestFS;
+		let testFSRoot: string;
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		itUnix("is applied to the DB file and the lockfile", async () => {
+			const filename = path.join(testFSRoot, "secret.jsonl");
+			const db = new JsonlDB(filename, { fileMode: 0o600 });
+			await db.open();
+			await expect(getMode(filename)).resolves.toBe(0o600);
+			// The lockfile is a directory, so it also needs to be executable
+			await expect(getMode(db.lockfilePath)).resolves.toBe(0o700);
+			await db.close();
+		});
+
+		itUnix("is preserved when the DB is compressed", async () => {
+			const filename = path.join(testFSRoot, "secret.jsonl");
+			const db = new JsonlDB(filename, { fileMode: 0o640 });
+			await db.open();
+			db.set("key", "value");
+			db.set("key", "value2");
+			await db.compress();
+			await expect(getMode(filename)).resolves.toBe(0o640);
+			await db.close();
+		});
+
+		itUnix("is applied to dumps and exports", async () => {
+			const db = new JsonlDB(path.join(testFSRoot, "secret.jsonl"), {
+				fileMode: 0o600,
+			});
+			await db.open();
+			db.set("key", "value");
+
+			const dumpFilename = path.join(testFSRoot, "dump.jsonl");
+			// Existing files must get the new permissions too
+			await fs.writeFile(dumpFilename, "");
+			await fs.chmod(dumpFilename, 0o644);
+			await db.dump(dumpFilename);
+			await expect(getMode(dumpFilename)).resolves.toBe(0o600);
+
+			const jsonFilename = path.join(testFSRoot, "export.json");
+			await db.exportJson(jsonFilename);
+			await expect(getMode(jsonFilename)).resolves.toBe(0o600);
+
+			const csvFilename = path.join(testFSRoot, "export.csv");
+			await db.exportCsv(csvFilename);
+			await expect(getMode(csvFilename)).resolves.toBe(0o600);
+
+			await db.close();
+		});
+	});
+
+	describe("compress() regression test: backup file exists", () => {
+		const testFilename = "compress-with-bak.jsonl";
+		let testFilenameFull: string;
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			await testFS.create({
+				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+				[`${testFilename}.bak`]:
+					'{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+			});
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("does not crash", async () => {
+			await db.compress();
+			await db.close();
+		});
+	});
+
+	// 	describe("uncompressedSize", () => {
+	// 		const testFilename = "uncompressedSize.jsonl";
+	// 		let testFilenameFull: string;
+	// 		let db: JsonlDB;
+	// 		let testFS: TestFS;
+	// 		let testFSRoot: string;
+
+	// 		beforeEach(async () => {
+	// 			testFS = new TestFS();
+	// 			testFSRoot = await testFS.getRoot();
+	// 			testFilenameFull = path.join(testFSRoot, testFilename);
+	// 			await testFS.create({
+	// 				[testFilename]: `
+	// {"k":"key1","v":1}
+	// {"k":"key2","v":"2"}
+	// {"k":"key1"}
+	// {"k":"key2"}
+	// {"k":"key2","v":"2"}
+	// {"k":"key3","v":3}
+	// {"k":"key3"}
+	// `,
+	// 			});
+	// 			db = new JsonlDB(testFilenameFull);
+	// 			await db.open();
+	// 		});
+	// 		afterEach(async () => {
+	// 			await db.close();
+	// 			await testFS.remove();
+	// 			mockMoveFileThrottle = 0;
+	// 		});
+
+	// 		it("throws when the DB is not open", async () => {
+	// 			await db.close();
+	// 			expect(() => db.uncompressedSize).toThrowError("not open");
+	// 		});
+
+	// 		it("returns the non-empty line count of the db file", async () => {
+	// 			expect(db.uncompressedSize).toBe(7);
+	// 		});
+
+	// 		it("increases by 1 for each set command", async () => {
+	// 			db.set("key4", 1);
+	// 			expect(db.uncompressedSize).toBe(8);
+	// 			db.set("key4", 1);
+	// 			expect(db.uncompressedSize).toBe(9);
+	// 			db.set("key4", 1);
+	// 			expect(db.uncompressedSize).toBe(10);
+	// 			db.set("key5", 2);
+	// 			expect(db.uncompressedSize).toBe(11);
+	// 		});
+
+	// 		it("increases by 1 for each non-noop delete", async () => {
+	// 			db.delete("key4");
+	// 			expect(db.uncompressedSize).toBe(7);
+	// 			db.delete("key2");
+	// 			expect(db.uncompressedSize).toBe(8);
+	// 			db.delete("key2");
+	// 			expect(db.uncompressedSize).toBe(8);
+	// 		});
+
+	// 		it("is reset to 0 after clear()", async () => {
+	// 			db.clear();
+	// 			expect(db.uncompressedSize).toBe(0);
+	// 		});
+
+	// 		it("is reset to the compressed size afer compress()", async () => {
+	// 			await db.compress();
+	// 			expect(db.uncompressedSize).toBe(1);
+	// 		});
+
+	// 		it("writes during compress are counted", async () => {
+	// 			// simulate a slow FS
+	// 			mockMoveFileThrottle = 50;
+	// 			const compressPromise = db.compress();
+	// 			await wait(20);
+
+	// 			db.set("key1", "value1");
+	// 			await compressPromise;
+
+	// 			expect(db.uncompressedSize).toBe(2);
+	// 		});
+	// 	});
+
+	describe("auto-compression", () => {
+		// Tests that check file contents after certain timeouts are really flaky on CI systems, so we don't test there
+		if (process.env.CI) {
+			test.todo("skipped on CI systems");
+			return;
+		}
+
+		const testFilename = "autoCompress.jsonl";
+		let testFilenameFull: string;
+		const uncompressed = `
+{"k":"key1","v":1}
+{"k":"key2","v":"2"}
+{"k":"key3","v":3}
+{"k":"key2"}
+{"k":"key3","v":3.5}`;
+
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			await testFS.create({
+				[testFilename]: `{"k":"key1","v":1}`,
+				openClose: uncompressed,
+			});
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("triggers when uncompressedSize >= size * sizeFactor", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					sizeFactor: 4,
+				},
+			});
+			await db.open();
+
+			db.set("key1", 2);
+			await wait(25);
+			db.set("key1", 3);
+			await wait(25);
+
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.not.toBe('{"k":"key1","v":3}\n');
+
+			db.set("key1", 4);
+			// compress is async, so give it some time
+			await wait(50);
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":4}\n',
+			);
+
+			await db.close();
+		});
+
+		it("..., but only above the minimum size", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					sizeFactor: 4,
+					sizeFactorMinimumSize: 6,
+				},
+			});
+			await db.open();
+
+			for (let i = 2; i <= 5; i++) {
+				db.set("key1", i);
+				await wait(25);
+			}
+
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.not.toBe('{"k":"key1","v":5}\n');
+
+			db.set("key1", 6);
+			// compress is async, so give it some time
+			await wait(50);
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":6}\n',
+			);
+
+			await db.close();
+		});
+
+		it("counts the lines that are already in the file", async () => {
+			// 1000 lines, but only 10 entries
+			const lines: string[] = [];
+			for (let i = 0; i < 1000; i++) {
+				lines.push(JSON.stringify({ k: `key${i % 10}`, v: i }));
+			}
+			await fs.writeFile(testFilenameFull, lines.join("\n") + "\n");
+
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					sizeFactor: 2,
+					sizeFactorMinimumSize: 20,
+				},
+			});
+			await db.open();
+
+			// compress is async, so give it some time
+			await wait(50);
+
+			const content = await fs.readFile(testFilenameFull, "utf8");
+			expect(content.trim().split("\n")).toHaveLength(10);
+			expect(db.getStats().uncompressedSize).toBe(10);
+
+			await db.close();
+		});
+
+		it("reports when and why the DB was compressed", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					intervalMs: 1000,
+					onClose: true,
+				},
+			});
+			const onDone = jest.fn();
+			db.on("compressDone", onDone);
+			const start = Date.now();
+			await db.open();
+
+			let stats = db.getStats();
+			expect(stats.lastCompressAt).toBeFalsy();
+			expect(stats.nextCompressEligibleAt).toBeGreaterThanOrEqual(
+				start + 990,
+			);
+
+			db.set("key1", 2);
+			db.set("key2", 2);
+			await wait(25);
+			expect(db.getStats().changesSinceCompress).toBe(2);
+
+			await db.compress();
+			stats = db.getStats();
+			expect(stats.lastCompressAt).toBeGreaterThanOrEqual(start);
+			expect(stats.changesSinceCompress).toBe(0);
+			expect(stats.nextCompressEligibleAt).toBeGreaterThanOrEqual(
+				stats.lastCompressAt! + 990,
+			);
+
+			await db.close();
+			await wait(10);
+			expect(onDone.mock.calls.map(([info]) => info.trigger)).toEqual([
+				"manual",
+				"onClose",
+			]);
+		});
+
+		it("doesn't trigger when different keys are added", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					sizeFactor: 4,
+				},
+			});
+			const compressSpy = jest.spyOn(db, "compress");
+			await db.open();
+
+			for (let i = 2; i <= 20; i++) {
+				db.set("key" + i, i);
+			}
+			await db.close();
+			expect(compressSpy).not.toBeCalled();
+		});
+
+		it("triggers when maxTombstones delete lines were written", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					maxTombstones: 2,
+				},
+			});
+			await db.open();
+
+			db.set("key2", 2);
+			db.set("key3", 3);
+			db.delete("key2");
+			await wait(25);
+
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.not.toBe('{"k":"key1","v":1}\n');
+
+			db.delete("key3");
+			// compress is async, so give it some time
+			await wait(50);
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n',
+			);
+		});
+
+		it("triggers when the ratio of overwritten lines exceeds maxOverwriteRatio", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					maxOverwriteRatio: 0.7,
+				},
+			});
+			await db.open();
+
+			db.set("key1", 2);
+			await wait(25);
+			// 1 of 2 lines is an overwrite
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.not.toBe('{"k":"key1","v":2}\n');
+
+			db.set("key1", 3);
+			db.set("key1", 4);
+			await wait(25);
+			db.set("key1", 5);
+			// compress is async, so give it some time
+			await wait(50);
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":5}\n',
+			);
+		});
+
+		it("triggers after intervalMs", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					intervalMs: 100,
+				},
+			});
+			await db.open();
+
+			db.set("key1", 2);
+			await wait(25);
+			db.set("key1", 3);
+			await wait(25);
+
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.not.toBe('{"k":"key1","v":3}\n');
+
+			await wait(75);
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":3}\n',
+			);
+
+			await db.close();
+		});
+
+		it("..., but only if there were at least intervalMinChanges changes", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					intervalMs: 100,
+					intervalMinChanges: 2,
+				},
+			});
+			await db.open();
+
+			db.set("key1", 2);
+			await wait(110);
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.not.toBe('{"k":"key1","v":2}\n');
+
+			db.set("key1", 3);
+			await wait(110);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":3}\n',
+			);
+
+			await db.close();
+		});
+
+		it("measures intervalMs from the last write when intervalAnchor is lastWrite", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					intervalMs: 100,
+					intervalAnchor: "lastWrite",
+				},
+			});
+			await db.open();
+
+			// Keep writing for longer than the interval
+			for (let i = 2; i <= 6; i++) {
+				db.set("key1", i);
+				await wait(40);
+			}
+			await expect(
+				fs.readFile(testFilenameFull, "utf8"),
+			).resolves.not.toBe('{"k":"key1","v":6}\n');
+
+			// Once nothing was written for the interval, compress
+			await wait(150);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":6}\n',
+			);
+
+			await db.close();
+		});
+
+		it("compresses after opening when onOpen is true", async () => {
+			const testFilenameFull = path.join(testFSRoot, "openClose");
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					onOpen: true,
+				},
+			});
+
+			// Cannot use this, since open calls compressInternal
+			// expect(compressSpy).toBeCalledTimes(1);
+			await db.open();
+			await wait(25);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+			);
+
+			db.set("key3", 1);
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n{"k":"key3","v":1}\n',
+			);
+		});
+
+		it("does not compress again when compress() is called right after opening", async () => {
+			const testFilenameFull = path.join(testFSRoot, "openClose");
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					onOpen: true,
+				},
+			});
+			const onDone = jest.fn();
+			db.on("compressDone", onDone);
+
+			await db.open();
+			await db.compress();
+			await db.compress();
+			await wait(10);
+
+			expect(onDone).toBeCalledTimes(1);
+			expect(onDone.mock.calls[0][0]).toMatchObject({ trigger: "onOpen" });
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+			);
+
+			// Once something changed, compressing does something again
+			db.set("key3", 1);
+			await db.compress();
+			expect(onDone).toBeCalledTimes(2);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":1}\n',
+			);
+			await db.close();
+		});
+
+		it("reports how many bytes and lines compressing would free up", async () => {
+			const testFilenameFull = path.join(testFSRoot, "openClose");
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+
+			expect(db.getOpenDiagnostics()).toEqual({
+				fileBytes: uncompressed.length,
+				compactedBytes: '{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n'.length,
+				wastedBytes: 53,
+				totalLines: 5,
+				liveEntries: 2,
+				overwrites: 1,
+				deletes: 1,
+				oversizedLines: 0,
+				resyncedLines: 0,
+				skippedBytes: 0,
+				readErrorCount: 0,
+				readErrors: [],
+			});
+		});
+
+		it("compresses after opening only if onOpenIfWastedBytesExceed is exceeded", async () => {
+			const testFilenameFull = path.join(testFSRoot, "openClose");
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					onOpenIfWastedBytesExceed: 100,
+				},
+			});
+			await db.open();
+			await wait(25);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				uncompressed,
+			);
+			await db.close();
+
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					onOpenIfWastedBytesExceed: 50,
+				},
+			});
+			await db.open();
+			await wait(25);
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+			);
+		});
+
+		it("compresses during close when onClose is true", async () => {
+			const testFilenameFull = path.join(testFSRoot, "openClose");
+			db = new JsonlDB(testFilenameFull, {
+				autoCompress: {
+					onClose: true,
+				},
+			});
+			// Cannot use this, since close calls compressInternal
+			// expect(compressSpy).toBeCalledTimes(1);
+			await db.open();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				uncompressed,
+			);
+
+			await db.close();
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
+			);
+		});
+	});
+
+	describe("throttling FS", () => {
+		// Tests that check file contents after certain timeouts are really flaky on CI systems, so we don't test there
+		if (process.env.CI) {
+			test.todo("skipped on CI systems");
+			return;
+		}
+
+		const testFilename = "throttled.jsonl";
+		let testFilenameFull: string;
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			await testFS.create({
+				[testFilename]: ``,
+			});
+		});
+		afterEach(async () => {
+			if (db) await db.close();
+			await testFS.remove();
+		});
+
+		async function assertFileContent(content: string): Promise<void> {
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				content,
+			);
+		}
+
+		it("changes are only written after intervalMs has passed", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 100,
+				},
+			});
+			await db.open();
+			db.clear();
+
+			// Trigger at least one scheduled cork before the first write
+			await wait(110);
+			await assertFileContent("");
+
+			db.set("1", 1);
+
+			let expected = `{"k":"1","v":1}\n`;
+			await assertFileContent("");
+
+			await wait(50);
+			for (let i = 2; i <= 10; i++) {
+				db.set(i.toString(), i);
+				await assertFileContent("");
+				expected += `{"k":"${i}","v":${i}}\n`;
+			}
+
+			// Give it a little more time than necessary
+			await wait(60);
+			await assertFileContent(expected);
+		});
+
+		it("or the maximum buffer size was reached", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 100,
+					maxBufferedCommands: 5,
+				},
+			});
+			await db.open();
+
+			db.set("1", 1);
+			let expected = `{"k":"1","v":1}\n`;
+			await assertFileContent("");
+
+			await wait(50);
+			for (let i = 2; i <= 6; i++) {
+				db.set(i.toString(), i);
+				expected += `{"k":"${i}","v":${i}}\n`;
+				if (i <= 5) await assertFileContent("");
+			}
+
+			// Give it a little time to write
+			await wait(40);
+			await assertFileContent(expected);
+		});
+
+		it("writes right away once more than maxBufferedCommands are pending", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 60000,
+					maxBufferedCommands: 100,
+				},
+			});
+			await db.open();
+
+			let expected = "";
+			for (let i = 1; i <= 100; i++) {
+				db.set(i.toString(), i);
+				expected += `{"k":"${i}","v":${i}}\n`;
+			}
+			await wait(50);
+			await assertFileContent("");
+
+			db.set("101", 101);
+			expected += `{"k":"101","v":101}\n`;
+			// Much sooner than the persistence thread checks the journal when idle
+			await wait(10);
+			await assertFileContent(expected);
+		});
+
+		it("works after compressing", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 100,
+				},
+			});
+			await db.open();
+			await db.compress();
+			await wait(25);
+
+			db.set("1", 1);
+			let expected = `{"k":"1","v":1}\n`;
+			await assertFileContent("");
+
+			await wait(50);
+			for (let i = 2; i <= 100; i++) {
+				db.set(i.toString(), i);
+				await assertFileContent("");
+				expected += `{"k":"${i}","v":${i}}\n`;
+			}
+
+			// Give it a little more time than necessary
+			await wait(500);
+			await assertFileContent(expected);
+		});
+
+		it("should still flush the buffer on close", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 100,
+				},
+			});
+			await db.open();
+
+			db.set("1", 1);
+			let expected = `{"k":"1","v":1}\n`;
+			await assertFileContent("");
+
+			await wait(50);
+			for (let i = 2; i <= 100; i++) {
+				db.set(i.toString(), i);
+				await assertFileContent("");
+				expected += `{"k":"${i}","v":${i}}\n`;
+			}
+
+			// Close the db before the next forced flush
+			await db.close();
+
+			await assertFileContent(expected);
+		});
+
+		it("coalesces updates to keys with a pending write within coalesceWindowMs", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 100,
+					coalesceWindowMs: 1000,
+				},
+			});
+			await db.open();
+
+			db.set("a", 1);
+			db.set("b", 1);
+			// Keeps the position of the pending write
+			db.set("a", 2);
+			// set-after-delete
+			db.delete("b");
+			db.set("b", 3);
+			// delete-after-set
+			db.set("c", 1);
+			db.delete("c");
+
+			await db.close();
+
+			await assertFileContent(
+				`{"k":"a","v":2}\n{"k":"b","v":3}\n{"k":"c"}\n`,
+			);
+		});
+
+		it("does not sync to disk after writing by default", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 0,
+				},
+			});
+			await db.open();
+
+			db.set("1", 1);
+			await wait(150);
+			await assertFileContent(`{"k":"1","v":1}\n`);
+			expect(db.getMetrics().syncs).toBe(0);
+		});
+
+		it("syncs to disk once nothing was written for fsyncIdleMs", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 0,
+					fsyncIdleMs: 50,
+				},
+			});
+			await db.open();
+
+			db.set("1", 1);
+			db.set("2", 2);
+			await wait(150);
+			expect(db.getMetrics().syncs).toBe(1);
+
+			// Nothing new to sync
+			await wait(100);
+			expect(db.getMetrics().syncs).toBe(1);
+
+			db.set("3", 3);
+			await wait(150);
+			expect(db.getMetrics().syncs).toBe(2);
+		});
+
+		it("syncs to disk at least every fsyncMaxIntervalMs under continuous load", async () => {
+			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+
+			db = new JsonlDB(testFilenameFull, {
+				throttleFS: {
+					intervalMs: 0,
+					fsyncIdleMs: 1000,
+					fsyncMaxIntervalMs: 100,
+				},
+			});
+			await db.open();
+
+			for (let i = 0; i < 40; i++) {
+				db.set(i.toString(), i);
+				await wait(10);
+			}
+			expect(db.getMetrics().syncs).toBeGreaterThanOrEqual(2);
+		});
+	});
+
+	describe("consistency checks", () => {
+		const testFilename = "checks.jsonl";
+		let testFilenameFull: string;
+		let db: JsonlDB;
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, testFilename);
+			await testFS.create();
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			if (db) await db.close();
+			await testFS.remove();
+		});
+
+		it("opening a complex log restores the same structure as expected", async () => {
+			const expected = [
+				/* 0: */ { key: "foobar", value: new Date().toISOString() },
+				/* 1: */ { key: "asdfasg", value: true },
+				/* 2: */ {
+					key: 'x"blub',
+					value: { lets: "get", complicated: { "!": "?" } },
+				},
+				/* 3: */ { key: "sssssss", value: null },
+				/* 4: */ { key: "1", value: 1 },
+				/* 5: */ { key: "2", value: [[[]], {}] },
+				/* 6: */ { key: "", value: "what up?!\u0000" },
+			];
+			db.set(expected[1].key, expected[1].value);
+			// 1
+			db.set(expected[3].key, expected[3].value);
+			// 1, 3
+			db.set(expected[2].key, expected[2].value);
+			// 1, 3, 2
+			db.delete(expected[1].key);
+			db.set(expected[5].key, expected[5].value);
+			// 3, 2, 5
+			db.set(expected[6].key, expected[6].value);
+			// 3, 2, 5, 6
+			db.set(expected[0].key, expected[0].value);
+			// 3, 2, 5, 6, 0
+			db.delete(expected[0].key);
+			db.set(expected[6].key, expected[6].value);
+			db.set(expected[1].key, expected[1].value);
+			db.set(expected[4].key, expected[4].value);
+			// 3, 2, 5, 6, 1, 4
+			db.set(expected[0].key, expected[0].value);
+			// 3, 2, 5, 6, 1, 4, 0
+
+			await db.close();
+			await db.open();
+
+			expect([...db.keys()].sort()).toEqual(
+				expected.map((e) => e.key).sort(),
+			);
+			expect([...db.values()].sort()).toEqual(
+				expected.map((e) => e.value).sort(),
+			);
+			expect([...db.entries()].sort()).toEqual(
+				expected.map((e) => [e.key, e.value]).sort(),
+			);
+		});
+	});
+
+	// describe("custom serializer", () => {
+	// 	const testFilename = "serializer.jsonl";
+	// 	let testFilenameFull: string;
+	// 	let db: JsonlDB;
+	// 	let testFS: TestFS;
+	// 	let testFSRoot: string;
+
+	// 	beforeEach(async () => {
+	// 		testFS = new TestFS();
+	// 		testFSRoot = await testFS.getRoot();
+	// 		testFilenameFull = path.join(testFSRoot, testFilename);
+	// 		await testFS.create();
+	// 	});
+	// 	afterEach(async () => {
+	// 		await testFS.remove();
+	// 	});
+
+	// 	it("before saving, values are transformed using the serializer function if any is passed", async () => {
+	// 		const serializer = jest.fn().mockReturnValue("ffff");
+	// 		db = new JsonlDB(testFilenameFull, { serializer });
+	// 		await db.open();
+
+	// 		const map = new Map<any, any>([
+	// 			[1, "foo"],
+	// 			[2, "bar"],
+	// 		]);
+	// 		db.set("test", map);
+	// 		await db.close();
+
+	// 		await wait(10);
+
+	// 		await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+	// 			`{"k":"test","v":"ffff"}\n`,
+	// 		);
+	// 	});
+	// });
+
+	describe("prefix counters", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+		let db: JsonlDB;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "prefixes.jsonl");
+			await testFS.create({
+				"prefixes.jsonl": `
+{"k":"device.1","v":1}
+{"k":"device.2","v":2}
+{"k":"state.1","v":1}
+{"k":"device.2"}
+{"k":"device.3","v":3}
+`,
+			});
+			db = new JsonlDB(testFilenameFull, {
+				prefixCounters: ["device.", "state."],
+			});
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("counts the entries of the prefixes from the options when opening", () => {
+			expect(db.getPrefixCount("device.")).toBe(2);
+			expect(db.getPrefixCount("state.")).toBe(1);
+			expect(db.getPrefixCount("other.")).toBeUndefined();
+		});
+
+		it("keeps the counts up to date", () => {
+			db.set("device.4", 4);
+			// Overwriting is no new entry
+			db.set("device.1", 10);
+			db.setNativeObject("device.1", { a: 1 });
+			db.setDetached("device.5", { a: 1 });
+			expect(db.getPrefixCount("device.")).toBe(4);
+
+			db.delete("device.3");
+			// Deleting a missing entry changes nothing
+			db.delete("device.3");
+			db.delete("state.404");
+			expect(db.getPrefixCount("device.")).toBe(3);
+			expect(db.getPrefixCount("state.")).toBe(1);
+
+			db.importJson({ "device.1": 1, "device.6": 6, "state.2": 2 });
+			expect(db.getPrefixCount("device.")).toBe(4);
+			expect(db.getPrefixCount("state.")).toBe(2);
+
+			db.clear();
+			expect(db.getPrefixCount("device.")).toBe(0);
+			expect(db.getPrefixCount("state.")).toBe(0);
+			db.set("state.1", 1);
+			expect(db.getPrefixCount("state.")).toBe(1);
+		});
+
+		it("can register and unregister prefixes at runtime", () => {
+			expect(db.registerPrefixCounter("device.1")).toBeTrue();
+			expect(db.registerPrefixCounter("device.1")).toBeFalse();
+			expect(db.getPrefixCount("device.1")).toBe(1);
+			db.set("device.10", 10);
+			expect(db.getPrefixCount("device.1")).toBe(2);
+			// The empty prefix counts all entries
+			db.registerPrefixCounter("");
+			expect(db.getPrefixCount("")).toBe(db.size);
+
+			expect(db.unregisterPrefixCounter("device.1")).toBeTrue();
+			expect(db.unregisterPrefixCounter("device.1")).toBeFalse();
+			expect(db.getPrefixCount("device.1")).toBeUndefined();
+		});
+
+		it("counts soft-deleted entries as removed", async () => {
+			await db.close();
+			db = new JsonlDB(testFilenameFull, {
+				prefixCounters: ["device."],
+				softDelete: { retainMs: 60000 },
+			});
+			await db.open();
+			db.delete("device.1");
+			expect(db.getPrefixCount("device.")).toBe(1);
+			await db.close();
+
+			// Tombstones in the file don't count either
+			await db.open();
+			expect(db.getPrefixCount("device.")).toBe(1);
+		});
+	});
+
+	describe("parseLine() and formatLine()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("parses lines like opening the DB does", () => {
+			expect(JsonlDB.parseLine('{"k":"key","v":{"a":[1]}}')).toEqual({
+				key: "key",
+				value: { a: [1] },
+				isDelete: false,
+				isHeader: false,
+			});
+			// An explicit null is not a delete
+			expect(JsonlDB.parseLine('{"k":"key","v":null}\r\n')).toEqual({
+				key: "key",
+				value: null,
+				isDelete: false,
+				isHeader: false,
+			});
+			expect(JsonlDB.parseLine('{"k":"key"}')).toEqual({
+				key: "key",
+				isDelete: true,
+				isHeader: false,
+			});
+			expect(JsonlDB.parseLine('{"k":"a\\"b","v":1,"t":5}')).toEqual({
+				key: 'a"b',
+				value: 1,
+				isDelete: false,
+				isHeader: false,
+			});
+		});
+
+		it("classifies tombstones, header lines and empty lines", () => {
+			expect(JsonlDB.parseLine('{"k":"key","d":1000}')).toEqual({
+				key: "key",
+				isDelete: true,
+				isHeader: false,
+				deletedAt: 1000,
+			});
+			expect(
+				JsonlDB.parseLine('{"k":"$jsonl-db-meta","v":{"app":1},"m":2}'),
+			).toEqual({
+				key: "$jsonl-db-meta",
+				value: { app: 1 },
+				isDelete: false,
+				isHeader: true,
+				migrationVersion: 2,
+			});
+			expect(JsonlDB.parseLine('{"k":"$jsonl-db-meta","m":2}')).toEqual({
+				key: "$jsonl-db-meta",
+				isDelete: false,
+				isHeader: true,
+				migrationVersion: 2,
+			});
+			// Opening the DB skips them
+			expect(JsonlDB.parseLine("")).toBeUndefined();
+			expect(JsonlDB.parseLine("\r\n")).toBeUndefined();
+		});
+
+		it("throws for malformed lines", () => {
+			expect(() => JsonlDB.parseLine('{"k":"key","v":')).toThrow(
+				"Invalid line",
+			);
+			expect(() => JsonlDB.parseLine('{"v":1}')).toThrow("Invalid line");
+			expect(() => JsonlDB.parseLine(" ")).toThrow("Invalid line");
+		});
+
+		it("formats lines that open() reads back", async () => {
+			const lines = [
+				JsonlDB.formatLine("key1", '{"a": 1}'),
+				JsonlDB.formatLine('quote"d', '"value"'),
+				// Line breaks outside of strings are removed
+				JsonlDB.formatLine("key2", '{\n  "b": "x\\ny"\n}'),
+			];
+			expect(lines[1]).toBe('{"k":"quote\\"d","v":"value"}');
+			expect(JsonlDB.parseLine(lines[2])).toEqual({
+				key: "key2",
+				value: { b: "x\ny" },
+				isDelete: false,
+				isHeader: false,
+			});
+
+			const filename = path.join(testFSRoot, "formatted.jsonl");
+			await fs.writeFile(filename, lines.join("\n") + "\n");
+			const db = new JsonlDB(filename);
+			await db.open();
+			expect(db.get("key1")).toEqual({ a: 1 });
+			expect(db.get('quote"d')).toBe("value");
+			expect(db.get("key2")).toEqual({ b: "x\ny" });
+			await db.close();
+		});
+
+		it("formatLine() refuses values that are not valid JSON", () => {
+			expect(() => JsonlDB.formatLine("key", "{")).toThrow(
+				"not valid JSON",
+			);
+			expect(() => JsonlDB.formatLine("key", "1\n2")).toThrow(
+				"not valid JSON",
+			);
+		});
+	});
+
+	describe("debugConsistencyChecks", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let db: JsonlDB;
+		let onError: jest.Mock;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			db = new JsonlDB(path.join(testFSRoot, "debug.jsonl"), {
+				debugConsistencyChecks: true,
+				softDelete: { retainMs: 60000 },
+			});
+			onError = jest.fn();
+			db.on("error", onError);
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("does not complain about correct lines", async () => {
+			db.set("primitive", 1);
+			db.set("object", { foo: "bar", nested: [1, 2, { x: null }] });
+			db.set("deleted", true);
+			db.delete("deleted");
+			await db.flush();
+			db.clear();
+			db.set("after", "clear");
+			await db.flush();
+			await wait(10);
+
+			expect(onError).not.toHaveBeenCalled();
+		});
+
+		it("reports lines that don't match the entry in memory", async () => {
+			// Simulate a wrapper bug that passes a broken serialized value
+			(db as any).db.setObject("broken", { a: 1 }, '{"a":');
+			db.set("fine", { a: 1 });
+			await db.flush();
+			await wait(10);
+
+			expect(onError).toHaveBeenCalledTimes(1);
+			expect(onError).toHaveBeenCalledWith({
+				message: expect.stringContaining('"broken"'),
+				source: "consistencyCheck",
+				key: "broken",
+			});
+		});
+	});
+
+	describe("maxValueDepth", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+
+		// Like the output of a buggy serializer. Parsing this recursively overflows the stack
+		const pathological = "[".repeat(60000) + "1" + "]".repeat(60000);
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "deep.jsonl");
+			await testFS.create({
+				"deep.jsonl": `{"k":"before","v":1}
+{"k":"deep","v":${pathological}}
+{"k":"after","v":{"s":"[[[[[["}}
+`,
+			});
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("refuses to open files with values that are nested too deeply", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await expect(db.open()).rejects.toThrow(
+				"The value in line 2 (byte offset 21) is nested deeper than maxValueDepth (128 levels)",
+			);
+		});
+
+		it("skips those values with ignoreReadErrors", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				ignoreReadErrors: true,
+			});
+			await db.open();
+			expect([...db.keys()]).toEqual(["before", "after"]);
+			// Brackets in strings don't count
+			expect(db.get("after")).toEqual({ s: "[[[[[[" });
+			await db.close();
+		});
+
+		it("refuses values that are nested too deeply when setting them", async () => {
+			await fs.writeFile(testFilenameFull, "");
+			const db = new JsonlDB(testFilenameFull, { maxValueDepth: 2 });
+			await db.open();
+			const error =
+				'The value for key "key" is nested deeper than maxValueDepth (2 levels)';
+
+			db.set("key", { a: [1] });
+			expect(() => db.set("key", { a: [[1]] })).toThrow(error);
+			expect(() => db.setDetached("key", [[[1]]])).toThrow(error);
+			expect(() => db.setNativeObject("key", { a: { b: {} } })).toThrow(
+				error,
+			);
+			expect(() => db.importJson({ other: 1, key: [[[]]] })).toThrow(
+				error,
+			);
+			// Nothing was imported
+			expect(db.has("other")).toBeFalse();
+			expect(db.get("key")).toEqual({ a: [1] });
+			await db.close();
+		});
+
+		it("refuses pathologically deep objects without overflowing the stack", async () => {
+			await fs.writeFile(testFilenameFull, "");
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			let deep: any = 1;
+			for (let i = 0; i < 60000; i++) deep = [deep];
+
+			expect(() => db.setNativeObject("key", deep)).toThrow(
+				'The value for key "key" is nested deeper than maxValueDepth (128 levels)',
+			);
+			expect(db.has("key")).toBeFalse();
+			await db.close();
+		});
+
+		it("refuses circular objects", async () => {
+			await fs.writeFile(testFilenameFull, "");
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			const circular: any = { a: 1 };
+			circular.self = circular;
+
+			expect(() => db.setNativeObject("key", circular)).toThrow(
+				'The value for key "key" is nested deeper than maxValueDepth (128 levels)',
+			);
+			expect(db.has("key")).toBeFalse();
+			await db.close();
+		});
+
+		it("validates the option", () => {
+			expect(
+				() => new JsonlDB(testFilenameFull, { maxValueDepth: 0 }),
+			).toThrow("maxValueDepth must be an integer between 1 and 128");
+			expect(
+				() => new JsonlDB(testFilenameFull, { maxValueDepth: 129 }),
+			).toThrow("maxValueDepth must be an integer between 1 and 128");
+		});
+	});
+
+	describe("nonFiniteNumbers", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "numbers.jsonl");
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		/** Sets non-finite numbers in every supported way and returns the values after reopening */
+		async function roundTrip(
+			nonFiniteNumbers: JsonlDBOptions["nonFiniteNumbers"],
+		): Promise<Record<string, any>> {
+			let db = new JsonlDB(testFilenameFull, { nonFiniteNumbers });
+			await db.open();
+			db.set("nan", NaN);
+			db.set("inf", Infinity);
+			await db.setDurable("negInf", -Infinity);
+			db.set("object", { a: NaN, b: [Infinity, 1] });
+			db.setDetached("detached", { c: -Infinity });
+			db.setNativeObject("native", { d: [NaN] });
+			await db.close();
+
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+			const ret = Object.fromEntries(db.entries());
+			await db.close();
+			return ret;
+		}
+
+		it("refuses non-finite numbers by default", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			expect(() => db.set("nan", NaN)).toThrow("NaN cannot be stored");
+			expect(() => db.set("object", { a: [Infinity] })).toThrow(
+				"Infinity cannot be stored",
+			);
+			expect(() => db.setDetached("detached", { a: -Infinity })).toThrow(
+				"-Infinity cannot be stored",
+			);
+			expect(() => db.setNativeObject("native", { a: NaN })).toThrow(
+				"NaN cannot be stored",
+			);
+			await expect(db.setDurable("durable", NaN)).rejects.toThrow(
+				"NaN cannot be stored",
+			);
+			// null and finite numbers are fine
+			db.set("fine", { a: null, b: 1.5 });
+			expect(db.size).toBe(1);
+			await db.close();
+		});
+
+		it("refused values don't show up in keys()", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			db.set("fine", 1);
+			// Populates the keys cache
+			expect([...db.keys()]).toEqual(["fine"]);
+			expect(() => db.set("object", { a: NaN })).toThrow();
+			expect(() => db.setDetached("detached", { a: NaN })).toThrow();
+			expect(() => db.setNativeObject("native", { a: NaN })).toThrow();
+			await expect(db.setDurable("durable", NaN)).rejects.toThrow();
+			expect([...db.keys()]).toEqual(["fine"]);
+			expect(db.has("object")).toBe(false);
+			await db.close();
+		});
+
+		it(`"null" stores non-finite numbers as null`, async () => {
+			await expect(roundTrip("null")).resolves.toEqual({
+				nan: null,
+				inf: null,
+				negInf: null,
+				object: { a: null, b: [null, 1] },
+				detached: { c: null },
+				native: { d: [null] },
+			});
+		});
+
+		it(`"string" stores non-finite numbers as strings`, async () => {
+			await expect(roundTrip("string")).resolves.toEqual({
+				nan: "NaN",
+				inf: "Infinity",
+				negInf: "-Infinity",
+				object: { a: "NaN", b: ["Infinity", 1] },
+				detached: { c: "-Infinity" },
+				native: { d: ["NaN"] },
+			});
+		});
+
+		it("validates the option", () => {
+			expect(
+				() =>
+					new JsonlDB(testFilenameFull, {
+						nonFiniteNumbers: "zero" as any,
+					}),
+			).toThrow("nonFiniteNumbers must be");
+		});
+	});
+
+	describe("timestamps", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "meta.jsonl");
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("are not tracked by default", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			db.set("key", 1);
+			expect(db.getMeta("key")).toBeUndefined();
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key","v":1}\n',
+			);
+		});
+
+		it("records when entries were created and updated", async () => {
+			const db = new JsonlDB(testFilenameFull, { trackTimestamps: true });
+			await db.open();
+			const before = Date.now();
+			db.set("key", 1);
+			const meta1 = db.getMeta("key")!;
+			expect(meta1.updatedAt).toBeGreaterThanOrEqual(before);
+			expect(meta1.createdAt).toBe(meta1.updatedAt);
+
+			await wait(10);
+			db.set("key", 2);
+			const meta2 = db.getMeta("key")!;
+			expect(meta2.updatedAt).toBeGreaterThan(meta1.updatedAt);
+			expect(meta2.createdAt).toBe(meta1.createdAt);
+
+			db.delete("key");
+			expect(db.getMeta("key")).toBeUndefined();
+			await db.close();
+		});
+
+		it("stores the timestamps in the file and restores them when reopening", async () => {
+			const db = new JsonlDB(testFilenameFull, { trackTimestamps: true });
+			await db.open();
+			db.set("key", 1);
+			db.set("obj", { a: 1 });
+			const { updatedAt } = db.getMeta("key")!;
+			await db.compress();
+			await db.close();
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toMatch(
+				`{"k":"key","v":1,"t":${updatedAt}}\n`,
+			);
+
+			await db.open();
+			expect(db.getMeta("key")?.updatedAt).toBe(updatedAt);
+			expect(db.getMeta("obj")).toBeDefined();
+			await db.close();
+		});
+
+		it("keeps the creation time when compressing", async () => {
+			const db = new JsonlDB(testFilenameFull, { trackTimestamps: true });
+			await db.open();
+			db.set("key", 1);
+			const { createdAt } = db.getMeta("key")!;
+			await wait(10);
+			db.set("key", 2);
+			const { updatedAt } = db.getMeta("key")!;
+			await db.compress();
+			await db.close();
+
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				`{"k":"key","v":2,"t":${updatedAt},"c":${createdAt}}\n`,
+			);
+
+			await db.open();
+			expect(db.getMeta("key")).toEqual({ createdAt, updatedAt });
+			await db.close();
+		});
+
+		it("has no metadata for entries written without timestamps", async () => {
+			await testFS.create({
+				"meta.jsonl": '{"k":"old","v":1}\n{"k":"new","v":2,"t":1000}\n',
+			});
+			const db = new JsonlDB(testFilenameFull, { trackTimestamps: true });
+			await db.open();
+			expect(db.getMeta("old")).toBeUndefined();
+			expect(db.getMeta("new")).toEqual({ createdAt: 1000, updatedAt: 1000 });
+
+			// Overwriting an old entry does not make up a creation time
+			db.set("old", 2);
+			expect(db.getMeta("old")?.createdAt).toBeUndefined();
+			// ...not even after compressing
+			await db.compress();
+			await db.close();
+			await db.open();
+			expect(db.getMeta("old")?.createdAt).toBeUndefined();
+			await db.close();
+		});
+	});
+
+	describe("getKeysPage()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+		let db: JsonlDB;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "pages.jsonl");
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("returns the keys in pages", async () => {
+			for (let i = 0; i < 25; i++) {
+				db.set(`key${i}`, i);
+			}
+			const pages: string[][] = [];
+			for (let offset = 0; offset < 25; offset += 10) {
+				pages.push(db.getKeysPage(offset, 10));
+			}
+			expect(pages.map((p) => p.length)).toEqual([10, 10, 5]);
+			expect(pages.flat()).toEqual([...db.keys()]);
+			expect(db.getKeysPage(25, 10)).toEqual([]);
+			expect(db.getKeysPage(0, 0)).toEqual([]);
+		});
+
+		it("the key cache contains all keys of large DBs", async () => {
+			for (let i = 0; i < 25000; i++) {
+				db.set(`key${i}`, i);
+			}
+			const keys = [...db.keys()];
+			expect(keys).toHaveLength(25000);
+			expect(new Set(keys).size).toBe(25000);
+		});
+	});
+
+	describe("key order", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "order.jsonl");
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		/** The keys in the order of the native DB, bypassing the key cache */
+		function nativeKeys(db: JsonlDB): string[] {
+			return db.getKeysPage(0, db.size);
+		}
+
+		it("keeps the insertion order across deletes, like a Map", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			for (const key of ["a", "b", "c", "d", "e"]) {
+				db.set(key, key);
+			}
+			db.delete("a");
+			db.delete("c");
+			// Overwriting keeps the position, setting a deleted key appends it
+			db.set("d", 1);
+			db.set("a", 1);
+
+			expect(nativeKeys(db)).toEqual(["b", "d", "e", "a"]);
+			expect([...db.keys()]).toEqual(["b", "d", "e", "a"]);
+			await db.close();
+		});
+
+		for (const softDelete of [false, true]) {
+			it(`stays the same when set, delete, compress and reopen interleave${
+				softDelete ? " (softDelete)" : ""
+			}`, async () => {
+				const options: JsonlDBOptions = softDelete
+					? { softDelete: { retainMs: 60000 } }
+					: {};
+				const expected = new Map<string, number>();
+				// Deterministic pseudo-random sequence
+				let seed = 1;
+				const random = (max: number) => {
+					seed = (seed * 16807) % 2147483647;
+					return seed % max;
+				};
+
+				let db = new JsonlDB(testFilenameFull, options);
+				await db.open();
+				for (let round = 0; round < 6; round++) {
+					for (let i = 0; i < 500; i++) {
+						const key = `key${random(200)}`;
+						if (random(3) === 0) {
+							db.delete(key);
+							expected.delete(key);
+						} else {
+							db.set(key, i);
+							expected.set(key, i);
+						}
+					}
+					expect(nativeKeys(db)).toEqual([...expected.keys()]);
+
+					if (round % 2 === 0) await db.compress();
+					expect(nativeKeys(db)).toEqual([...expected.keys()]);
+
+					// Every other reopen reads a file with deletes
+					await db.close();
+					db = new JsonlDB(testFilenameFull, options);
+					await db.open();
+					expect(nativeKeys(db)).toEqual([...expected.keys()]);
+					expect([...db.keys()]).toEqual([...expected.keys()]);
+				}
+				await db.close();
+			});
+		}
+	});
+
+	describe("queueWritesBeforeOpen", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "queue.jsonl");
+			await testFS.create({
+				"queue.jsonl": '{"k":"existing","v":1}\n{"k":"other","v":2}\n',
+			});
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		it("is disabled by default", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			const opening = db.open();
+			expect(() => db.set("key", 1)).toThrowError("not open");
+			await opening;
+			await db.close();
+		});
+
+		it("applies writes made while opening in order before open() resolves", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				queueWritesBeforeOpen: true,
+			});
+			const opening = db.open();
+			db.set("key", 1);
+			db.set("key", 2);
+			db.setDetached("obj", { a: 1 });
+			expect(db.delete("existing")).toBeFalse();
+			// Reads still require the DB to be open
+			expect(() => db.get("key")).toThrowError("not open");
+			await opening;
+
+			expect(db.get("key")).toBe(2);
+			expect(db.get("obj")).toEqual({ a: 1 });
+			expect(db.has("existing")).toBeFalse();
+			expect(db.get("other")).toBe(2);
+			await db.close();
+
+			// The queued writes are persisted
+			await db.open();
+			expect([...db.keys()].sort()).toEqual(["key", "obj", "other"]);
+			await db.close();
+		});
+
+		it("queues setNativeObject() without keeping a reference to the value", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				queueWritesBeforeOpen: true,
+			});
+			const opening = db.open();
+			const value = { a: 1 };
+			db.setNativeObject("native", value);
+			value.a = 2;
+			await opening;
+
+			expect(db.get("native")).toEqual({ a: 1 });
+			await db.close();
+		});
+
+		it("reports queued writes that fail instead of failing open()", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				queueWritesBeforeOpen: true,
+				maxValueDepth: 2,
+			});
+			const onError = jest.fn();
+			db.on("error", onError);
+			const opening = db.open();
+			db.set("before", 1);
+			db.set("deep", { a: [[1]] });
+			db.set("after", 2);
+			await expect(opening).resolves.toBeUndefined();
+
+			expect(onError).toBeCalledTimes(1);
+			expect(onError).toBeCalledWith({
+				message:
+					'The value for key "deep" is nested deeper than maxValueDepth (2 levels)',
+				source: "queuedWrite",
+				key: "deep",
+			});
+			// The other writes are still applied
+			expect(db.get("before")).toBe(1);
+			expect(db.has("deep")).toBeFalse();
+			expect(db.get("after")).toBe(2);
+			await db.close();
+		});
+
+		it("does not queue writes while the DB is closed and not opening", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				queueWritesBeforeOpen: true,
+			});
+			expect(() => db.set("key", 1)).toThrowError("not open");
+		});
+
+		it("limits how many writes can be queued", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				queueWritesBeforeOpen: true,
+			});
+			const opening = db.open();
+			for (let i = 0; i < 10000; i++) {
+				db.set(`key${i}`, i);
 			}
-			db.compress();
+			expect(() => db.set("one-too-many", 1)).toThrowError(
+				/Cannot queue more than 10000 writes/,
+			);
+			await opening;
+			expect(db.size).toBe(10002);
 			await db.close();
-			await db.open();
-
-			assertEqual(db, map);
 		});
 	});
 
-	describe("compress() regression test: backup file exists", () => {
-		const testFilename = "compress-with-bak.jsonl";
-		let testFilenameFull: string;
-		let db: JsonlDB;
+	describe("getMemoryUsage()", () => {
 		let testFS: TestFS;
 		let testFSRoot: string;
+		let testFilenameFull: string;
 
 		beforeEach(async () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create({
-				[testFilename]: '{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
-				[`${testFilename}.bak`]:
-					'{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
-			});
-			db = new JsonlDB(testFilenameFull);
-			await db.open();
+			testFilenameFull = path.join(testFSRoot, "memory.jsonl");
 		});
 		afterEach(async () => {
-			await db.close();
 			await testFS.remove();
 		});
 
-		it("does not crash", async () => {
-			await db.compress();
+		it("grows with the entries, journal and index", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				indexPaths: ["/type"],
+				throttleFS: { intervalMs: 60000 },
+			});
+			await db.open();
+			const empty = await db.getMemoryUsage();
+			expect(empty).toEqual({ entries: 0, journal: 0, index: 0, total: 0 });
+
+			for (let i = 0; i < 25000; i++) {
+				db.set(`key${i}`, { type: "foo", data: "x".repeat(100) });
+			}
+			const usage = await db.getMemoryUsage();
+			// The values alone take up at least 2.5 MB
+			expect(usage.entries).toBeGreaterThan(25000 * 100);
+			expect(usage.journal).toBeGreaterThan(0);
+			expect(usage.index).toBeGreaterThan(0);
+			expect(usage.total).toBe(usage.entries + usage.journal + usage.index);
+
 			await db.close();
 		});
-	});
 
-	// 	describe("uncompressedSize", () => {
-	// 		const testFilename = "uncompressedSize.jsonl";
-	// 		let testFilenameFull: string;
-	// 		let db: JsonlDB;
-	// 		let testFS: TestFS;
-	// 		let testFSRoot: string;
+		it("keeps the keys in memory only once for the entries and the index", async () => {
+			// Measured on the whole process instead of with getMemoryUsage(), which only estimates
+			const prefix = "some/very/long/prefix/".repeat(45);
+			const numKeys = 20000;
+			const lines: string[] = [];
+			for (let i = 0; i < numKeys; i++) {
+				lines.push(
+					JSON.stringify({
+						k: `${prefix}${i}`,
+						v: { type: i % 2 ? "foo" : "bar" },
+					}),
+				);
+			}
+			await fs.writeFile(testFilenameFull, lines.join("\n") + "\n");
+			const keyBytes = numKeys * prefix.length;
 
-	// 		beforeEach(async () => {
-	// 			testFS = new TestFS();
-	// 			testFSRoot = await testFS.getRoot();
-	// 			testFilenameFull = path.join(testFSRoot, testFilename);
-	// 			await testFS.create({
-	// 				[testFilename]: `
-	// {"k":"key1","v":1}
-	// {"k":"key2","v":"2"}
-	// {"k":"key1"}
-	// {"k":"key2"}
-	// {"k":"key2","v":"2"}
-	// {"k":"key3","v":3}
-	// {"k":"key3"}
-	// `,
-	// 			});
-	// 			db = new JsonlDB(testFilenameFull);
-	// 			await db.open();
-	// 		});
-	// 		afterEach(async () => {
-	// 			await db.close();
-	// 			await testFS.remove();
-	// 			mockMoveFileThrottle = 0;
-	// 		});
+			const db = new JsonlDB(testFilenameFull, {
+				indexPaths: ["/type"],
+			});
+			const before = process.memoryUsage().rss;
+			await db.open();
+			const growth = process.memoryUsage().rss - before;
+			// Roughly 1.9x the key bytes with shared keys (including the lookup structures),
+			// 3.7x when the entries, the index and its reverse map each had their own copy
+			expect(growth).toBeLessThan(3 * keyBytes);
+			expect(db.size).toBe(numKeys);
 
-	// 		it("throws when the DB is not open", async () => {
-	// 			await db.close();
-	// 			expect(() => db.uncompressedSize).toThrowError("not open");
-	// 		});
+			await db.close();
+		});
+	});
 
-	// 		it("returns the non-empty line count of the db file", async () => {
-	// 			expect(db.uncompressedSize).toBe(7);
-	// 		});
+	describe("selfCheck()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
 
-	// 		it("increases by 1 for each set command", async () => {
-	// 			db.set("key4", 1);
-	// 			expect(db.uncompressedSize).toBe(8);
-	// 			db.set("key4", 1);
-	// 			expect(db.uncompressedSize).toBe(9);
-	// 			db.set("key4", 1);
-	// 			expect(db.uncompressedSize).toBe(10);
-	// 			db.set("key5", 2);
-	// 			expect(db.uncompressedSize).toBe(11);
-	// 		});
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "check.jsonl");
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
 
-	// 		it("increases by 1 for each non-noop delete", async () => {
-	// 			db.delete("key4");
-	// 			expect(db.uncompressedSize).toBe(7);
-	// 			db.delete("key2");
-	// 			expect(db.uncompressedSize).toBe(8);
-	// 			db.delete("key2");
-	// 			expect(db.uncompressedSize).toBe(8);
-	// 		});
+		it("reports no violations for a healthy DB", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				indexPaths: ["/type"],
+				prefixCounters: ["a"],
+				trackTimestamps: true,
+				throttleFS: { intervalMs: 60000 },
+			});
+			await db.open();
+			db.set("a1", { type: "foo" });
+			db.set("a2", { type: "bar" });
+			db.set("b", 1);
+			db.delete("a2");
+			db.setHeader({ version: 1 });
+
+			await expect(db.selfCheck()).resolves.toEqual({
+				ok: true,
+				checkedEntries: 2,
+				violations: [],
+			});
+			await db.close();
+		});
 
-	// 		it("is reset to 0 after clear()", async () => {
-	// 			db.clear();
-	// 			expect(db.uncompressedSize).toBe(0);
-	// 		});
+		it("reports objects that were changed without setting them again before they are written", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				throttleFS: { intervalMs: 60000 },
+			});
+			await db.open();
+			const obj = { a: 1 };
+			db.set("changed", obj);
+			db.set("unchanged", { b: 2 });
+			obj.a = 2;
+
+			const report = await db.selfCheck();
+			expect(report.violations).toEqual([
+				expect.objectContaining({
+					check: "journal",
+					key: "changed",
+					message: expect.stringMatching(
+						/does not match the entry in memory/,
+					),
+				}),
+			]);
+			// Setting it again fixes that
+			db.set("changed", obj);
+			await expect(db.selfCheck()).resolves.toMatchObject({ ok: true });
+			await db.close();
+		});
 
-	// 		it("is reset to the compressed size afer compress()", async () => {
-	// 			await db.compress();
-	// 			expect(db.uncompressedSize).toBe(1);
-	// 		});
+		it("reports an incomplete line at the end of the DB file", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			await db.setDurable("a", 1);
+			await fs.appendFile(testFilenameFull, `{"k":"b","v":`);
+
+			const report = await db.selfCheck();
+			expect(report.ok).toBeFalse();
+			expect(report.violations).toEqual([
+				expect.objectContaining({
+					check: "file",
+					message: expect.stringMatching(
+						/ends with an incomplete line/,
+					),
+				}),
+			]);
+			await db.close();
+		});
 
-	// 		it("writes during compress are counted", async () => {
-	// 			// simulate a slow FS
-	// 			mockMoveFileThrottle = 50;
-	// 			const compressPromise = db.compress();
-	// 			await wait(20);
+		it("reports an invalid last line in the DB file", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			await db.setDurable("a", 1);
+			await fs.appendFile(testFilenameFull, "garbage\n");
+
+			const report = await db.selfCheck();
+			expect(report.violations).toEqual([
+				expect.objectContaining({
+					check: "file",
+					message: expect.stringMatching(/last line .* is invalid/),
+				}),
+			]);
+			await db.close();
+		});
+	});
 
-	// 			db.set("key1", "value1");
-	// 			await compressPromise;
+	describe("deleteMatching()", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+		let db: JsonlDB;
 
-	// 			expect(db.uncompressedSize).toBe(2);
-	// 		});
-	// 	});
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "match.jsonl");
+			db = new JsonlDB(testFilenameFull);
+			await db.open();
+			db.set("metadata.1.deviceClass.basic", 1);
+			db.set("metadata.2.deviceClass.generic", 2);
+			db.set("metadata.2.name", 3);
+			db.set("values.1.deviceClass.basic", 4);
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
 
-	describe("auto-compression", () => {
-		// Tests that check file contents after certain timeouts are really flaky on CI systems, so we don't test there
-		if (process.env.CI) {
-			test.todo("skipped on CI systems");
-			return;
-		}
+		it("deletes keys matching a glob pattern", async () => {
+			expect(db.deleteMatching("metadata.*.deviceClass.*", "glob")).toBe(2);
+			expect([...db.keys()]).toEqual([
+				"metadata.2.name",
+				"values.1.deviceClass.basic",
+			]);
+			expect(db.deleteMatching("metadata.?.name", "glob")).toBe(1);
+			expect(db.deleteMatching("nothing*", "glob")).toBe(0);
+			expect(db.size).toBe(1);
+		});
 
-		const testFilename = "autoCompress.jsonl";
-		let testFilenameFull: string;
-		const uncompressed = `
-{"k":"key1","v":1}
-{"k":"key2","v":"2"}
-{"k":"key3","v":3}
-{"k":"key2"}
-{"k":"key3","v":3.5}`;
+		it("deletes keys matching a regex", async () => {
+			expect(db.deleteMatching("^metadata\\.\\d+\\.", "regex")).toBe(3);
+			expect([...db.keys()]).toEqual(["values.1.deviceClass.basic"]);
+		});
 
-		let db: JsonlDB;
+		it("persists the deletions", async () => {
+			db.deleteMatching("*.basic", "glob");
+			await db.close();
+			await db.open();
+			expect([...db.keys()]).toEqual([
+				"metadata.2.deviceClass.generic",
+				"metadata.2.name",
+			]);
+		});
+
+		it("rejects invalid patterns without deleting anything", async () => {
+			expect(() => db.deleteMatching("metadata.(", "regex")).toThrow(
+				/Invalid regex/,
+			);
+			expect(() => db.deleteMatching("metadata\\", "glob")).toThrow(
+				/Invalid glob/,
+			);
+			expect(() => db.deleteMatching("(a{1000}){1000}", "regex")).toThrow(
+				/Invalid regex/,
+			);
+			expect(db.size).toBe(4);
+		});
+	});
+
+	describe("softDelete", () => {
 		let testFS: TestFS;
 		let testFSRoot: string;
+		let testFilenameFull: string;
 
 		beforeEach(async () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create({
-				[testFilename]: `{"k":"key1","v":1}`,
-				openClose: uncompressed,
-			});
+			testFilenameFull = path.join(testFSRoot, "soft.jsonl");
 		});
 		afterEach(async () => {
-			await db.close();
 			await testFS.remove();
 		});
 
-		it("triggers when uncompressedSize >= size * sizeFactor", async () => {
-			db = new JsonlDB(testFilenameFull, {
-				autoCompress: {
-					sizeFactor: 4,
-				},
-			});
+		it("is disabled by default", async () => {
+			const db = new JsonlDB(testFilenameFull);
 			await db.open();
+			db.set("key", 1);
+			db.delete("key");
+			expect(db.getDeletedKeys()).toEqual([]);
+			await db.close();
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"key"}\n',
+			);
+		});
 
-			db.set("key1", 2);
-			await wait(25);
-			db.set("key1", 3);
-			await wait(25);
+		it("treats tombstones as absent and lists them", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				softDelete: { retainMs: 60000 },
+			});
+			await db.open();
+			db.set("a", 1);
+			db.set("b", 2);
+			db.delete("a");
+			expect(db.has("a")).toBeFalse();
+			expect(db.get("a")).toBeUndefined();
+			expect(db.size).toBe(1);
+			expect(db.getDeletedKeys()).toEqual(["a"]);
+			expect(db.getDeletedKeys(Date.now() + 1000)).toEqual([]);
 
-			await expect(
-				fs.readFile(testFilenameFull, "utf8"),
-			).resolves.not.toBe('{"k":"key1","v":3}\n');
+			// Writing the key again revives it
+			db.set("a", 3);
+			expect(db.get("a")).toBe(3);
+			expect(db.getDeletedKeys()).toEqual([]);
+			await db.close();
+		});
 
-			db.set("key1", 4);
-			// compress is async, so give it some time
-			await wait(50);
+		it("restores the tombstones when reopening", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				softDelete: { retainMs: 60000 },
+			});
+			await db.open();
+			db.set("a", 1);
+			db.set("b", 2);
+			db.delete("a");
+			await db.close();
 
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":4}\n',
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toMatch(
+				/^\{"k":"a","d":\d+\}$/m,
 			);
 
+			await db.open();
+			expect(db.has("a")).toBeFalse();
+			expect(db.get("b")).toBe(2);
+			expect(db.getDeletedKeys()).toEqual(["a"]);
+
+			// The tombstones survive a compress while they are retained
+			await db.compress();
+			await db.close();
+			await db.open();
+			expect(db.getDeletedKeys()).toEqual(["a"]);
+			await db.close();
+		});
+
+		it("importing a deleted key revives it", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				softDelete: { retainMs: 60000 },
+			});
+			const jsonFilename = path.join(testFSRoot, "import.json");
+			const csvFilename = path.join(testFSRoot, "import.csv");
+			await fs.writeJSON(jsonFilename, { b: 2 });
+			await fs.writeFile(csvFilename, "key,value\nc,3\n");
+			await db.open();
+			db.set("a", 1);
+			db.set("b", 1);
+			db.set("c", 1);
+			db.delete("a");
+			db.delete("b");
+			db.delete("c");
+
+			db.importJson({ a: 1 });
+			await db.importJson(jsonFilename);
+			await db.importCsv(csvFilename);
+			expect(db.getDeletedKeys()).toEqual([]);
+
+			// The tombstones must not be written after the imported entries
+			await db.compress();
+			await db.close();
+			await db.open();
+			expect(db.get("a")).toBe(1);
+			expect(db.get("b")).toBe(2);
+			expect(db.get("c")).toBe("3");
+			expect(db.getDeletedKeys()).toEqual([]);
 			await db.close();
 		});
 
-		it("..., but only above the minimum size", async () => {
-			db = new JsonlDB(testFilenameFull, {
-				autoCompress: {
-					sizeFactor: 4,
-					sizeFactorMinimumSize: 6,
-				},
+		it("does not mistake values for tombstones", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				softDelete: { retainMs: 60000 },
+			});
+			await db.open();
+			db.set("a", { $$deleted: 1000 });
+			await db.close();
+
+			await db.open();
+			expect(db.get("a")).toEqual({ $$deleted: 1000 });
+			expect(db.getDeletedKeys()).toEqual([]);
+			await db.close();
+		});
+
+		it("reads tombstones as deletes without soft-delete mode", async () => {
+			await testFS.create({
+				"soft.jsonl": '{"k":"a","v":1}\n{"k":"a","d":1000}\n',
+			});
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			expect(db.has("a")).toBeFalse();
+			await db.close();
+		});
+
+		it("prunes tombstones older than retainMs when compressing", async () => {
+			await testFS.create({
+				"soft.jsonl": '{"k":"a","d":1000}\n{"k":"b","v":2}\n',
+			});
+			const db = new JsonlDB(testFilenameFull, {
+				softDelete: { retainMs: 60000 },
 			});
 			await db.open();
+			expect(db.getDeletedKeys()).toEqual(["a"]);
+			await db.compress();
+			expect(db.getDeletedKeys()).toEqual([]);
+			await db.close();
 
-			for (let i = 2; i <= 5; i++) {
-				db.set("key1", i);
-				await wait(25);
-			}
+			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
+				'{"k":"b","v":2}\n',
+			);
+		});
+	});
 
-			await expect(
-				fs.readFile(testFilenameFull, "utf8"),
-			).resolves.not.toBe('{"k":"key1","v":5}\n');
+	describe("autoDump", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
 
-			db.set("key1", 6);
-			// compress is async, so give it some time
-			await wait(50);
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "autodump.jsonl");
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
 
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":6}\n',
-			);
+		async function getBackups(): Promise<string[]> {
+			const dir = path.join(testFSRoot, "backups");
+			if (!(await fs.pathExists(dir))) return [];
+			return (await fs.readdir(dir)).sort();
+		}
 
-			await db.close();
+		it("validates the options", () => {
+			expect(
+				() =>
+					new JsonlDB(testFilenameFull, {
+						autoDump: {
+							intervalMs: 1000,
+							filenameTemplate: "backups/db.jsonl",
+							keep: 1,
+						},
+					}),
+			).toThrowError("{timestamp}");
+			expect(
+				() =>
+					new JsonlDB(testFilenameFull, {
+						autoDump: {
+							intervalMs: 1000,
+							filenameTemplate: "backups/{timestamp}.jsonl",
+							keep: 0,
+						},
+					}),
+			).toThrowError("keep");
 		});
 
-		it("doesn't trigger when different keys are added", async () => {
-			db = new JsonlDB(testFilenameFull, {
-				autoCompress: {
-					sizeFactor: 4,
+		it("writes rolling dumps when there were changes and keeps the newest ones", async () => {
+			const db = new JsonlDB(testFilenameFull, {
+				autoDump: {
+					intervalMs: 100,
+					filenameTemplate: "backups/db-{timestamp}.jsonl",
+					keep: 2,
 				},
 			});
-			const compressSpy = jest.spyOn(db, "compress");
+			const onDump = jest.fn();
+			db.on("dumpDone", onDump);
 			await db.open();
 
-			for (let i = 2; i <= 20; i++) {
-				db.set("key" + i, i);
+			// Without changes, nothing is dumped
+			await wait(250);
+			expect(await getBackups()).toEqual([]);
+
+			for (let i = 1; i <= 3; i++) {
+				db.set("key", i);
+				await wait(250);
+			}
+			expect(onDump).toHaveBeenCalledTimes(3);
+
+			const backups = await getBackups();
+			expect(backups).toHaveLength(2);
+			for (const backup of backups) {
+				expect(backup).toMatch(/^db-\d+\.jsonl$/);
 			}
+			// The newest dump contains the latest value
+			await expect(
+				fs.readFile(path.join(testFSRoot, "backups", backups[1]), "utf8"),
+			).resolves.toBe('{"k":"key","v":3}\n');
+
 			await db.close();
-			expect(compressSpy).not.toBeCalled();
 		});
 
-		it("triggers after intervalMs", async () => {
-			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
-
-			db = new JsonlDB(testFilenameFull, {
-				autoCompress: {
+		it("reports failures as events and keeps persisting", async () => {
+			// A file where the backup directory should be
+			await testFS.create({ backups: "not a directory" });
+			const db = new JsonlDB(testFilenameFull, {
+				autoDump: {
 					intervalMs: 100,
+					filenameTemplate: "backups/db-{timestamp}.jsonl",
+					keep: 2,
 				},
 			});
+			const onError = jest.fn();
+			db.on("error", onError);
 			await db.open();
 
-			db.set("key1", 2);
-			await wait(25);
-			db.set("key1", 3);
-			await wait(25);
-
-			await expect(
-				fs.readFile(testFilenameFull, "utf8"),
-			).resolves.not.toBe('{"k":"key1","v":3}\n');
-
-			await wait(75);
+			db.set("key", 1);
+			await wait(250);
+			expect(onError).toHaveBeenCalledWith(
+				expect.objectContaining({ source: "autoDump" }),
+			);
 
+			db.set("key", 2);
+			await db.close();
 			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":3}\n',
+				'{"k":"key","v":1}\n{"k":"key","v":2}\n',
 			);
+		});
+	});
+
+	describe("preMaterialize", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+		let db: JsonlDB;
 
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "materialize.jsonl");
+			let content = "";
+			for (let i = 0; i < 1000; i++) {
+				content += `{"k":"obj.${i}","v":{"i":${i}}}\n`;
+				content += `{"k":"num.${i}","v":${i}}\n`;
+			}
+			content += '{"k":"other","v":[1,2]}\n';
+			await testFS.create({ "materialize.jsonl": content });
+		});
+		afterEach(async () => {
 			await db.close();
+			await testFS.remove();
 		});
 
-		it("..., but only if there were at least intervalMinChanges changes", async () => {
+		/** Resolves with the last progress event */
+		function whenDone(instance: JsonlDB): Promise<unknown> {
+			return new Promise((resolve) => {
+				instance.on("materializeProgress", (progress) => {
+					if (progress.done) resolve(progress);
+				});
+			});
+		}
+
+		it("converts the objects in the background and reports the progress", async () => {
 			db = new JsonlDB(testFilenameFull, {
-				autoCompress: {
-					intervalMs: 100,
-					intervalMinChanges: 2,
-				},
+				preMaterialize: { budgetMsPerTick: 1 },
 			});
+			const done = whenDone(db);
 			await db.open();
+			expect(db.getMetrics().references).toBe(0);
 
-			db.set("key1", 2);
-			await wait(110);
-			await expect(
-				fs.readFile(testFilenameFull, "utf8"),
-			).resolves.not.toBe('{"k":"key1","v":2}\n');
+			await expect(done).resolves.toEqual({
+				converted: 1001,
+				scanned: 2001,
+				total: 2001,
+				done: true,
+			});
+			expect(db.getMetrics().references).toBe(1001);
+			expect(db.get("obj.5")).toEqual({ i: 5 });
+		});
 
-			db.set("key1", 3);
-			await wait(110);
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":3}\n',
-			);
+		it("only converts keys with the given prefix", async () => {
+			db = new JsonlDB(testFilenameFull, {
+				preMaterialize: { keysPrefix: "obj.", budgetMsPerTick: 1 },
+			});
+			const done = whenDone(db);
+			await db.open();
 
-			await db.close();
+			await done;
+			expect(db.getMetrics().references).toBe(1000);
 		});
 
-		it("compresses after opening when onOpen is true", async () => {
-			const testFilenameFull = path.join(testFSRoot, "openClose");
+		it("can be cancelled", async () => {
 			db = new JsonlDB(testFilenameFull, {
-				autoCompress: {
-					onOpen: true,
-				},
+				preMaterialize: { budgetMsPerTick: 1 },
 			});
-
-			// Cannot use this, since open calls compressInternal
-			// expect(compressSpy).toBeCalledTimes(1);
+			const onProgress = jest.fn();
+			db.on("materializeProgress", onProgress);
 			await db.open();
-			await wait(25);
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
-			);
+			db.cancelPreMaterialize();
 
-			db.set("key3", 1);
-			await db.close();
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n{"k":"key3","v":1}\n',
-			);
+			await wait(50);
+			expect(onProgress).not.toBeCalled();
+			expect(db.getMetrics().references).toBe(0);
 		});
 
-		it("compresses during close when onClose is true", async () => {
-			const testFilenameFull = path.join(testFSRoot, "openClose");
+		it("stops when the DB is closed", async () => {
 			db = new JsonlDB(testFilenameFull, {
-				autoCompress: {
-					onClose: true,
-				},
+				preMaterialize: { budgetMsPerTick: 1 },
 			});
-			// Cannot use this, since close calls compressInternal
-			// expect(compressSpy).toBeCalledTimes(1);
+			const onError = jest.fn();
+			db.on("error", onError);
 			await db.open();
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				uncompressed,
-			);
-
 			await db.close();
 
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				'{"k":"key1","v":1}\n{"k":"key3","v":3.5}\n',
-			);
+			await wait(50);
+			expect(onError).not.toBeCalled();
 		});
 	});
 
-	describe("throttling FS", () => {
-		// Tests that check file contents after certain timeouts are really flaky on CI systems, so we don't test there
-		if (process.env.CI) {
-			test.todo("skipped on CI systems");
-			return;
-		}
-
-		const testFilename = "throttled.jsonl";
-		let testFilenameFull: string;
-		let db: JsonlDB;
+	describe("shards", () => {
 		let testFS: TestFS;
 		let testFSRoot: string;
+		let testFilenameFull: string;
 
 		beforeEach(async () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create({
-				[testFilename]: ``,
-			});
+			testFilenameFull = path.join(testFSRoot, "sharded.jsonl");
 		});
 		afterEach(async () => {
-			if (db) await db.close();
 			await testFS.remove();
 		});
 
-		async function assertFileContent(content: string): Promise<void> {
-			await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-				content,
-			);
+		function shardFilename(shard: number): string {
+			return `${testFilenameFull}.${shard}.jsonl`;
 		}
 
-		it("changes are only written after intervalMs has passed", async () => {
-			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+		async function countLines(filename: string): Promise<number> {
+			const content = await fs.readFile(filename, "utf8");
+			return content.split("\n").filter((line) => !!line).length;
+		}
 
-			db = new JsonlDB(testFilenameFull, {
-				throttleFS: {
-					intervalMs: 100,
-				},
-			});
+		it("spreads the entries across the shard files", async () => {
+			const db = new JsonlDB(testFilenameFull, { shards: 3 });
 			await db.open();
-			db.clear();
+			for (let i = 0; i < 30; i++) {
+				db.set(`key${i}`, i);
+			}
+			await db.close();
 
-			// Trigger at least one scheduled cork before the first write
-			await wait(110);
-			await assertFileContent("");
+			expect(await fs.pathExists(testFilenameFull)).toBe(false);
+			let total = 0;
+			for (let shard = 0; shard < 3; shard++) {
+				const lines = await countLines(shardFilename(shard));
+				expect(lines).toBeGreaterThan(0);
+				total += lines;
+			}
+			expect(total).toBe(30);
 
-			db.set("1", 1);
+			const reopened = new JsonlDB(testFilenameFull, { shards: 3 });
+			await reopened.open();
+			expect(reopened.size).toBe(30);
+			expect(reopened.get("key17")).toBe(17);
+			await reopened.close();
+		});
 
-			let expected = `{"k":"1","v":1}\n`;
-			await assertFileContent("");
+		it("compress only rewrites the shards that contain redundant lines", async () => {
+			const db = new JsonlDB(testFilenameFull, { shards: 4 });
+			await db.open();
+			for (let i = 0; i < 40; i++) {
+				db.set(`key${i}`, i);
+			}
+			await db.close();
 
-			await wait(50);
-			for (let i = 2; i <= 10; i++) {
-				db.set(i.toString(), i);
-				await assertFileContent("");
-				expected += `{"k":"${i}","v":${i}}\n`;
+			await db.open();
+			for (let i = 0; i < 10; i++) {
+				db.set("key0", i);
 			}
+			await db.flush();
+			// Compressing replaces the files it rewrites
+			const inodes = () =>
+				Promise.all(
+					[0, 1, 2, 3].map(
+						async (i) => (await fs.stat(shardFilename(i))).ino,
+					),
+				);
+			const inodesBefore = await inodes();
+			await db.compress();
+			const inodesAfter = await inodes();
+			await db.close();
 
-			// Give it a little more time than necessary
-			await wait(60);
-			await assertFileContent(expected);
+			const rewritten = inodesBefore.filter(
+				(ino, i) => ino !== inodesAfter[i],
+			);
+			expect(rewritten).toHaveLength(1);
+			let total = 0;
+			for (let shard = 0; shard < 4; shard++) {
+				total += await countLines(shardFilename(shard));
+			}
+			expect(total).toBe(40);
 		});
 
-		it("or the maximum buffer size was reached", async () => {
-			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+		it("compress does not write changes twice that are made while compressing", async () => {
+			const db = new JsonlDB(testFilenameFull, { shards: 3 });
+			await db.open();
+			// Make every shard redundant, so all of them are rewritten
+			for (let round = 0; round < 10; round++) {
+				for (let i = 0; i < 3000; i++) {
+					db.set(`key${i}`, round);
+				}
+				await db.flush();
+			}
 
-			db = new JsonlDB(testFilenameFull, {
-				throttleFS: {
-					intervalMs: 100,
-					maxBufferedCommands: 5,
-				},
-			});
+			// Keep writing while the shards are rendered
+			const compressed = db.compress();
+			for (let i = 3000; i < 4000; i++) {
+				db.set(`key${i}`, i);
+				if (i % 10 === 0) await wait(0);
+			}
+			await compressed;
+			await db.flush();
+			const { uncompressedSize } = db.getStats();
+			await db.close();
+
+			let total = 0;
+			for (let shard = 0; shard < 3; shard++) {
+				total += await countLines(shardFilename(shard));
+			}
+			expect(total).toBe(4000);
+			expect(uncompressedSize).toBe(4000);
+		});
+
+		it("clear() empties all shards", async () => {
+			const db = new JsonlDB(testFilenameFull, { shards: 2 });
 			await db.open();
+			for (let i = 0; i < 10; i++) {
+				db.set(`key${i}`, i);
+			}
+			db.clear();
+			db.set("after", true);
+			await db.close();
 
-			db.set("1", 1);
-			let expected = `{"k":"1","v":1}\n`;
-			await assertFileContent("");
+			let total = 0;
+			for (let shard = 0; shard < 2; shard++) {
+				total += await countLines(shardFilename(shard));
+			}
+			expect(total).toBe(1);
+		});
 
-			await wait(50);
-			for (let i = 2; i <= 6; i++) {
-				db.set(i.toString(), i);
-				expected += `{"k":"${i}","v":${i}}\n`;
-				if (i <= 5) await assertFileContent("");
+		it("reshard() converts between a single file and shards", async () => {
+			const db = new JsonlDB(testFilenameFull);
+			await db.open();
+			for (let i = 0; i < 20; i++) {
+				db.set(`key${i}`, { i });
 			}
+			db.setHeader('{"version":2}');
+			await db.close();
 
-			// Give it a little time to write
-			await wait(40);
-			await assertFileContent(expected);
+			await db.reshard(3);
+			expect(await fs.pathExists(testFilenameFull)).toBe(false);
+			await db.open();
+			expect(db.size).toBe(20);
+			expect(db.get("key7")).toEqual({ i: 7 });
+			expect(db.getHeader()).toBe('{"version":2}');
+			await db.close();
+
+			await db.reshard(1);
+			for (let shard = 0; shard < 3; shard++) {
+				expect(await fs.pathExists(shardFilename(shard))).toBe(false);
+			}
+			expect(await countLines(testFilenameFull)).toBe(21);
+			await db.open();
+			expect(db.size).toBe(20);
+			await db.close();
 		});
 
-		it("works after compressing", async () => {
-			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+		it("open() refuses files that were written with another number of shards", async () => {
+			const single = new JsonlDB(testFilenameFull);
+			await single.open();
+			single.set("key", 1);
+			await single.close();
+
+			const sharded = new JsonlDB(testFilenameFull, { shards: 3 });
+			await expect(sharded.open()).rejects.toThrow("reshard(3)");
+			expect(sharded.isOpen).toBe(false);
+
+			// reshard() converts the files that are actually there
+			await sharded.reshard(3);
+			await sharded.open();
+			expect(sharded.get("key")).toBe(1);
+			await sharded.close();
+
+			const twoShards = new JsonlDB(testFilenameFull, { shards: 2 });
+			await expect(twoShards.open()).rejects.toThrow(
+				"written with 3 shard(s)",
+			);
+			await expect(single.open()).rejects.toThrow("reshard(1)");
+		});
+	});
+
+	describe("events", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+		let db: JsonlDB;
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "events.jsonl");
+			db = new JsonlDB(testFilenameFull);
+		});
+		afterEach(async () => {
+			await db.close();
+			await testFS.remove();
+		});
+
+		it("emits compressStart and compressDone to all listeners", async () => {
+			const onStart = jest.fn();
+			const onDone1 = jest.fn();
+			const onDone2 = jest.fn();
+			db.on("compressStart", onStart);
+			db.on("compressDone", onDone1);
+			db.on("compressDone", onDone2);
+			await db.open();
+
+			db.set("key", "value");
+			await db.compress();
+			await wait(10);
+
+			expect(onStart).toBeCalledTimes(1);
+			expect(onDone1).toBeCalledTimes(1);
+			expect(onDone1.mock.calls[0][0]).toMatchObject({
+				entries: 1,
+				durationMs: expect.any(Number),
+				trigger: "manual",
+			});
+			expect(onDone2).toBeCalledTimes(1);
+		});
 
+		it("warns about index paths that don't match any entry", async () => {
+			await testFS.create({
+				"events.jsonl": '{"k":"key","v":{"type":"foo"}}\n',
+			});
 			db = new JsonlDB(testFilenameFull, {
-				throttleFS: {
-					intervalMs: 100,
-				},
+				indexPaths: ["/type", "/tpye"],
 			});
+			const onWarning = jest.fn();
+			db.on("warning", onWarning);
 			await db.open();
-			await db.compress();
-			await wait(25);
+			await wait(10);
+			expect(onWarning).toHaveBeenCalledTimes(1);
+			expect(onWarning).toHaveBeenCalledWith({
+				message: expect.stringContaining('"/tpye"'),
+				source: "index",
+			});
+		});
 
-			db.set("1", 1);
-			let expected = `{"k":"1","v":1}\n`;
-			await assertFileContent("");
+		it("emits dumpDone with the target filename", async () => {
+			await db.open();
+			const onDump = jest.fn();
+			db.on("dumpDone", onDump);
+
+			db.set("key", "value");
+			const dumpFilename = path.join(testFSRoot, "dump.jsonl");
+			await db.dump(dumpFilename);
+			await wait(10);
+
+			expect(onDump).toBeCalledTimes(1);
+			expect(onDump.mock.calls[0][0]).toMatchObject({
+				filename: dumpFilename,
+				bytes: 24,
+			});
+		});
+
+		it("emits flush after data was written", async () => {
+			await db.open();
+			const onFlush = jest.fn();
+			db.on("flush", onFlush);
 
+			db.set("key", "value");
 			await wait(50);
-			for (let i = 2; i <= 100; i++) {
-				db.set(i.toString(), i);
-				await assertFileContent("");
-				expected += `{"k":"${i}","v":${i}}\n`;
-			}
 
-			// Give it a little more time than necessary
-			await wait(500);
-			await assertFileContent(expected);
+			expect(onFlush).toBeCalled();
+			expect(onFlush.mock.calls[0][0]).toMatchObject({ entries: 1 });
 		});
 
-		it("should still flush the buffer on close", async () => {
-			jest.retryTimes(3); // timeout-based tests are flaky. retry to be sure
+		it("does not call listeners that were removed", async () => {
+			await db.open();
+			const onFlush = jest.fn();
+			db.on("flush", onFlush);
+			db.off("flush", onFlush);
 
-			db = new JsonlDB(testFilenameFull, {
-				throttleFS: {
-					intervalMs: 100,
-				},
+			db.set("key", "value");
+			await wait(50);
+
+			expect(onFlush).not.toBeCalled();
+		});
+
+		it("emits recovered when the DB file was restored while opening", async () => {
+			await testFS.create({
+				"events.jsonl": "",
+				"events.jsonl.bak": `{"k":"key1","v":1}\n`,
 			});
+			const onRecovered = jest.fn();
+			db.on("recovered", onRecovered);
 			await db.open();
+			await wait(10);
 
-			db.set("1", 1);
-			let expected = `{"k":"1","v":1}\n`;
-			await assertFileContent("");
-
-			await wait(50);
-			for (let i = 2; i <= 100; i++) {
-				db.set(i.toString(), i);
-				await assertFileContent("");
-				expected += `{"k":"${i}","v":${i}}\n`;
-			}
+			expect(onRecovered).toBeCalledTimes(1);
+			expect(onRecovered.mock.calls[0][0].from).toEndWith(".bak");
+			expect(db.get("key1")).toBe(1);
+		});
 
-			// Close the db before the next forced flush
+		it("keeps the listeners when the DB is reopened", async () => {
+			const onCompress = jest.fn();
+			db.on("compressDone", onCompress);
+			await db.open();
 			await db.close();
+			await db.open();
+			await db.compress();
+			await wait(10);
 
-			await assertFileContent(expected);
+			expect(onCompress).toBeCalledTimes(1);
 		});
 	});
 
-	describe("consistency checks", () => {
-		const testFilename = "checks.jsonl";
-		let testFilenameFull: string;
-		let db: JsonlDB;
+	describe("lost lockfile", () => {
 		let testFS: TestFS;
 		let testFSRoot: string;
 
 		beforeEach(async () => {
 			testFS = new TestFS();
 			testFSRoot = await testFS.getRoot();
-			testFilenameFull = path.join(testFSRoot, testFilename);
-			await testFS.create();
-			db = new JsonlDB(testFilenameFull);
-			await db.open();
 		});
 		afterEach(async () => {
-			if (db) await db.close();
 			await testFS.remove();
 		});
 
-		it("opening a complex log restores the same structure as expected", async () => {
-			const expected = [
-				/* 0: */ { key: "foobar", value: new Date().toISOString() },
-				/* 1: */ { key: "asdfasg", value: true },
-				/* 2: */ {
-					key: 'x"blub',
-					value: { lets: "get", complicated: { "!": "?" } },
-				},
-				/* 3: */ { key: "sssssss", value: null },
-				/* 4: */ { key: "1", value: 1 },
-				/* 5: */ { key: "2", value: [[[]], {}] },
-				/* 6: */ { key: "", value: "what up?!\u0000" },
-			];
-			db.set(expected[1].key, expected[1].value);
-			// 1
-			db.set(expected[3].key, expected[3].value);
-			// 1, 3
-			db.set(expected[2].key, expected[2].value);
-			// 1, 3, 2
-			db.delete(expected[1].key);
-			db.set(expected[5].key, expected[5].value);
-			// 3, 2, 5
-			db.set(expected[6].key, expected[6].value);
-			// 3, 2, 5, 6
-			db.set(expected[0].key, expected[0].value);
-			// 3, 2, 5, 6, 0
-			db.delete(expected[0].key);
-			db.set(expected[6].key, expected[6].value);
-			db.set(expected[1].key, expected[1].value);
-			db.set(expected[4].key, expected[4].value);
-			// 3, 2, 5, 6, 1, 4
-			db.set(expected[0].key, expected[0].value);
-			// 3, 2, 5, 6, 1, 4, 0
-
-			await db.close();
+		it("becomes read-only when another process takes over the lockfile", async () => {
+			const filename = path.join(testFSRoot, "db.jsonl");
+			const dumpFilename = path.join(testFSRoot, "rescue.jsonl");
+			const db = new JsonlDB(filename, {
+				throttleFS: { intervalMs: 60000 },
+				lockLostRetries: 2,
+			});
+			const onLockLost = jest.fn();
+			db.on("lockLost", onLockLost);
 			await db.open();
+			db.set("key", "value");
 
-			expect([...db.keys()].sort()).toEqual(
-				expected.map((e) => e.key).sort(),
-			);
-			expect([...db.values()].sort()).toEqual(
-				expected.map((e) => e.value).sort(),
-			);
-			expect([...db.entries()].sort()).toEqual(
-				expected.map((e) => [e.key, e.value]).sort(),
-			);
-		});
-	});
-
-	// describe("custom serializer", () => {
-	// 	const testFilename = "serializer.jsonl";
-	// 	let testFilenameFull: string;
-	// 	let db: JsonlDB;
-	// 	let testFS: TestFS;
-	// 	let testFSRoot: string;
-
-	// 	beforeEach(async () => {
-	// 		testFS = new TestFS();
-	// 		testFSRoot = await testFS.getRoot();
-	// 		testFilenameFull = path.join(testFSRoot, testFilename);
-	// 		await testFS.create();
-	// 	});
-	// 	afterEach(async () => {
-	// 		await testFS.remove();
-	// 	});
-
-	// 	it("before saving, values are transformed using the serializer function if any is passed", async () => {
-	// 		const serializer = jest.fn().mockReturnValue("ffff");
-	// 		db = new JsonlDB(testFilenameFull, { serializer });
-	// 		await db.open();
-
-	// 		const map = new Map<any, any>([
-	// 			[1, "foo"],
-	// 			[2, "bar"],
-	// 		]);
-	// 		db.set("test", map);
-	// 		await db.close();
-
-	// 		await wait(10);
+			// Simulate a tmp-cleaner and another process that grabs and refreshes the lock
+			await fs.remove(db.lockfilePath);
+			await fs.mkdir(db.lockfilePath);
+			const refresh = setInterval(() => {
+				const now = new Date();
+				fs.utimes(db.lockfilePath, now, now).catch(() => {});
+			}, 200);
 
-	// 		await expect(fs.readFile(testFilenameFull, "utf8")).resolves.toBe(
-	// 			`{"k":"test","v":"ffff"}\n`,
-	// 		);
-	// 	});
-	// });
+			try {
+				// The lockfile is refreshed every 10 seconds
+				while (!db.isLockLost) {
+					await wait(100);
+				}
+				await wait(10);
+				expect(onLockLost).toHaveBeenCalledTimes(1);
+				expect(() => db.set("other", 1)).toThrow("read-only");
+				expect(() => db.delete("key")).toThrow("read-only");
+				expect(db.get("key")).toBe("value");
+				await expect(db.compress()).rejects.toThrow("read-only");
+
+				// The unsaved changes can be rescued
+				await db.dump(dumpFilename);
+				await expect(fs.readFile(dumpFilename, "utf8")).resolves.toBe(
+					'{"k":"key","v":"value"}\n',
+				);
+
+				await db.close();
+				await expect(fs.readFile(filename, "utf8")).resolves.toBe("");
+				// The foreign lock must not be removed
+				await expect(fs.pathExists(`${filename}.lock`)).resolves.toBeTrue();
+			} finally {
+				clearInterval(refresh);
+			}
+		}, 20000);
+	});
 
 	describe("crash recovery", () => {
 		let testFS: TestFS;
@@ -1566,5 +6767,231 @@ describe("lib/db", () => {
 
 			await db.close();
 		});
+
+		it("db truncated, .dump in the scratch directory ok -> use .dump", async () => {
+			await testFS.create({
+				// empty, broken db file
+				[testFilename]: "",
+				// (probably) half-complete .dump file, but better than nothing
+				[`scratch/${testFilename}.dump`]: `
+{"k":"key1","v":1}
+{"k":"key3","v":3}`,
+			});
+
+			const db = new JsonlDB(testFilenameFull, {
+				scratchDirectory: "scratch",
+			});
+			await db.open();
+
+			expect(db.size).toBe(2);
+			expect(db.get("key1")).toBe(1);
+			expect(db.get("key3")).toBe(3);
+
+			await assertCleanedUp();
+			await expect(
+				fs.readdir(path.join(testFSRoot, "scratch")),
+			).resolves.toEqual([]);
+
+			await db.close();
+		});
+
+		it("db truncated, .bak with a custom suffix ok -> use it", async () => {
+			await testFS.create({
+				[testFilename + ".old"]: `
+{"k":"key1","v":1}
+{"k":"key3","v":3.5}`,
+				[testFilename]: "",
+				[testFilename + ".new"]: `{"k":"key1","v":1}`,
+			});
+
+			const db = new JsonlDB(testFilenameFull, {
+				compressDumpSuffix: ".new",
+				compressBackupSuffix: ".old",
+			});
+			await db.open();
+
+			expect(db.size).toBe(2);
+			expect(db.get("key3")).toBe(3.5);
+			await expect(
+				fs.pathExists(testFilenameFull + ".old"),
+			).resolves.toBeFalse();
+			await expect(
+				fs.pathExists(testFilenameFull + ".new"),
+			).resolves.toBeFalse();
+
+			await db.close();
+		});
+
+		it("leftovers with the default suffixes are recovered after changing the suffixes", async () => {
+			await testFS.create({
+				[testFilename]: "",
+				[testFilename + ".dump"]: `
+{"k":"key1","v":1}
+{"k":"key3","v":3}`,
+			});
+
+			const db = new JsonlDB(testFilenameFull, {
+				compressDumpSuffix: ".new",
+				compressBackupSuffix: ".old",
+			});
+			await db.open();
+
+			expect(db.size).toBe(2);
+			expect(db.get("key3")).toBe(3);
+			await assertCleanedUp();
+
+			await db.close();
+		});
+	});
+
+	(hasTestHooks ? describe : describe.skip)("crash consistency", () => {
+		let testFS: TestFS;
+		let testFSRoot: string;
+		let testFilenameFull: string;
+
+		// Uncompressed, so compressing has something to do
+		const committed = `{"k":"key1","v":0}
+{"k":"key1","v":1}
+{"k":"key2","v":"2"}
+{"k":"key3","v":3}
+{"k":"key3"}
+`;
+		const expected = { key1: 1, key2: "2" };
+
+		beforeEach(async () => {
+			testFS = new TestFS();
+			testFSRoot = await testFS.getRoot();
+			testFilenameFull = path.join(testFSRoot, "crash.jsonl");
+		});
+		afterEach(async () => {
+			await testFS.remove();
+		});
+
+		/**
+		 * Opens the DB with a simulated crash at the given step. Returns whether it crashed
+		 * and the error the action was rejected with, if any.
+		 */
+		async function runWithCrashAt(
+			step: number,
+			action: (db: JsonlDB) => Promise<void>,
+			options: JsonlDBOptions = {},
+		): Promise<{ crashed: boolean; actionError: unknown }> {
+			await testFS.create({ "crash.jsonl": committed });
+			const db = new JsonlDB(testFilenameFull, options);
+			(db as any).db.__simulateCrashAt(step);
+			const onError = jest.fn();
+			db.on("error", onError);
+			await db.open();
+			const actionError = await action(db).then(
+				() => undefined,
+				(e) => e,
+			);
+			await wait(10);
+			const crashed = onError.mock.calls.some(
+				([payload]) => payload.source === "persistence",
+			);
+			if (crashed) {
+				// Nothing reaches the disk anymore, so durable writes must not report success
+				await expect(db.setDurable("key4", 4)).rejects.toThrow(
+					"Persisting the DB failed",
+				);
+			}
+			await db.close();
+			return { crashed, actionError };
+		}
+
+		async function assertNoDataLost(
+			options: JsonlDBOptions = {},
+		): Promise<void> {
+			const db = new JsonlDB(testFilenameFull, options);
+			await db.open();
+			expect(Object.fromEntries(db.entries())).toEqual(expected);
+			await db.close();
+			await expect(
+				fs.pathExists(
+					testFilenameFull + (options.compressBackupSuffix ?? ".bak"),
+				),
+			).resolves.toBeFalse();
+			await expect(
+				fs.pathExists(
+					testFilenameFull + (options.compressDumpSuffix ?? ".dump"),
+				),
+			).resolves.toBeFalse();
+			await expect(
+				fs.pathExists(testFilenameFull + ".export"),
+			).resolves.toBeFalse();
+		}
+
+		it("no committed data is lost when compress() is interrupted at any step", async () => {
+			for (let step = 1; step < 100; step++) {
+				const { crashed, actionError } = await runWithCrashAt(
+					step,
+					(db) => db.compress(),
+				);
+				// The interrupted compress must not report success
+				expect(actionError !== undefined).toBe(crashed);
+				await assertNoDataLost();
+				// Once there was no crash, all steps were covered
+				if (!crashed) return;
+			}
+			throw new Error("compress() did not finish");
+		});
+
+		it("no committed data is lost when compress() with custom suffixes is interrupted at any step", async () => {
+			const options: JsonlDBOptions = {
+				compressDumpSuffix: ".new",
+				compressBackupSuffix: ".old",
+			};
+			for (let step = 1; step < 100; step++) {
+				const { crashed, actionError } = await runWithCrashAt(
+					step,
+					(db) => db.compress(),
+					options,
+				);
+				expect(actionError !== undefined).toBe(crashed);
+				await assertNoDataLost(options);
+				// The default names are never used
+				await expect(fs.readdir(testFSRoot)).resolves.not.toContain(
+					"crash.jsonl.dump",
+				);
+				if (!crashed) return;
+			}
+			throw new Error("compress() did not finish");
+		});
+
+		it("compress() reports when the compress on open it waits for was interrupted", async () => {
+			const options: JsonlDBOptions = { autoCompress: { onOpen: true } };
+			for (let step = 1; step < 100; step++) {
+				const { crashed, actionError } = await runWithCrashAt(
+					step,
+					(db) => db.compress(),
+					options,
+				);
+				expect(actionError !== undefined).toBe(crashed);
+				await assertNoDataLost(options);
+				if (!crashed) return;
+			}
+			throw new Error("compress() did not finish");
+		});
+
+		it("no committed data is lost when dump() is interrupted at any step", async () => {
+			const dumpFilename = path.join(testFSRoot, "dump/crash.jsonl");
+			for (let step = 1; step < 100; step++) {
+				await fs.remove(dumpFilename);
+				const { crashed } = await runWithCrashAt(step, (db) =>
+					db.dump(dumpFilename),
+				);
+				await assertNoDataLost();
+				// The target either contains the complete dump or does not exist
+				if (await fs.pathExists(dumpFilename)) {
+					const dump = await fs.readFile(dumpFilename, "utf8");
+					expect(dump).toBe(
+						'{"k":"key1","v":1}\n{"k":"key2","v":"2"}\n',
+					);
+				}
+				if (!crashed) return;
+			}
+			throw new Error("dump() did not finish");
+		});
 	});
 });